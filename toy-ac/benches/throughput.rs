@@ -0,0 +1,103 @@
+// Compares coding one symbol per call through &dyn SymbolModel with the
+// batch encode_all/decode_into APIs. Uses data/shakespeare.txt when it is
+// present, and otherwise bigram text generated by toy_ac::synth::text of
+// about the same size (5.4 MB).
+//
+// Run with: cargo bench --bench throughput
+
+use std::fs;
+
+use bitbit::{BitReader, BitWriter, MSB};
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use toy_ac::decoder::Decoder;
+use toy_ac::encoder::Encoder;
+use toy_ac::symbol_model::VectorCountSymbolModel;
+use toy_ac::synth::text::{Corpus, TextSpec, generate};
+use workspace_root::get_workspace_root;
+
+const GENERATED_LENGTH: usize = 5_400_000;
+
+fn input() -> Vec<u8> {
+    let path = get_workspace_root().join("data").join("shakespeare.txt");
+    if let Ok(data) = fs::read(path) {
+        return data;
+    }
+
+    let spec = TextSpec {
+        corpus: Corpus::Bigram,
+        length: GENERATED_LENGTH,
+        ..TextSpec::default()
+    };
+    generate(&spec)
+}
+
+fn byte_model() -> VectorCountSymbolModel<u8> {
+    VectorCountSymbolModel::new((0..=255).collect())
+}
+
+fn encode_per_symbol(data: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut bw = BitWriter::new(&mut bytes);
+    let mut enc = Encoder::new();
+    let mut sm = byte_model();
+    for b in data {
+        enc.encode(b, &sm, &mut bw);
+        sm.incr_count(b);
+    }
+    enc.finish(&mut bw).unwrap();
+    bw.pad_to_byte().unwrap();
+    bytes
+}
+
+fn encode_batch(data: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut bw = BitWriter::new(&mut bytes);
+    let mut enc = Encoder::new();
+    enc.encode_all(data, &mut byte_model(), &mut bw);
+    enc.finish(&mut bw).unwrap();
+    bw.pad_to_byte().unwrap();
+    bytes
+}
+
+fn decode_per_symbol(bytes: &[u8], length: usize) -> Vec<u8> {
+    let mut br: BitReader<_, MSB> = BitReader::new(bytes);
+    let mut dec = Decoder::new();
+    let mut sm = byte_model();
+    let mut output = Vec::with_capacity(length);
+    for _ in 0..length {
+        let b = *dec.decode(&sm, &mut br).unwrap();
+        sm.incr_count(&b);
+        output.push(b);
+    }
+    output
+}
+
+fn decode_batch(bytes: &[u8], length: usize) -> Vec<u8> {
+    let mut br: BitReader<_, MSB> = BitReader::new(bytes);
+    let mut output = vec![0u8; length];
+    Decoder::new().decode_into(&mut output, &mut byte_model(), &mut br).unwrap();
+    output
+}
+
+fn throughput(c: &mut Criterion) {
+    let data = input();
+    let bytes = encode_batch(&data);
+    assert_eq!(encode_per_symbol(&data), bytes);
+
+    let mut group = c.benchmark_group("encode");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.bench_function("per symbol", |b| b.iter(|| encode_per_symbol(&data)));
+    group.bench_function("encode_all", |b| b.iter(|| encode_batch(&data)));
+    group.finish();
+
+    let mut group = c.benchmark_group("decode");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.bench_function("per symbol", |b| b.iter(|| decode_per_symbol(&bytes, data.len())));
+    group.bench_function("decode_into", |b| b.iter(|| decode_batch(&bytes, data.len())));
+    group.finish();
+}
+
+criterion_group!(benches, throughput);
+criterion_main!(benches);
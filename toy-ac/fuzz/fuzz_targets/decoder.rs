@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../tests/common/fuzz.rs"]
+mod fuzz;
+
+fuzz_target!(|data: &[u8]| {
+    fuzz::decoder(data);
+});
@@ -0,0 +1,171 @@
+// Empirical entropy and model cost measurements used by the analyze
+// subcommand. Nothing here writes compressed output; coded sizes come from
+// running the real Encoder into a sink and reading its bit count.
+
+use super::encoder::Encoder;
+use super::symbol_model::{SymbolModel, VectorCountSymbolModel, ascii_english_letter_weights_1000};
+use bitbit::BitWriter;
+use std::collections::HashMap;
+
+// Per-symbol costs at or above this many bits all land in the last
+// histogram bin. Models normalize before their totals reach 1000000, so
+// no symbol can cost much more than 20 bits.
+pub const COST_HISTOGRAM_BINS: usize = 24;
+
+pub struct ModelReport {
+    pub name: String,
+    pub order: usize,
+    pub coded_bits: u64,
+    pub ideal_bits: f64,
+    pub histogram: Vec<u64>,
+}
+
+pub struct AnalysisReport {
+    pub length: u64,
+    pub entropy: Vec<f64>,
+    pub models: Vec<ModelReport>,
+}
+
+impl ModelReport {
+    pub fn bits_per_symbol(&self, length: u64) -> f64 {
+        if length == 0 { 0.0 } else { self.coded_bits as f64 / length as f64 }
+    }
+}
+
+impl AnalysisReport {
+    // Extra bits the model spent compared with coding every symbol under the
+    // static empirical distribution of the same order, which is the best any
+    // fixed model of that order could have done with hindsight.
+    pub fn adaptation_overhead(&self, model: &ModelReport) -> f64 {
+        model.coded_bits as f64 - self.entropy[model.order] * self.length as f64
+    }
+}
+
+// The longest context empirical_entropy can use: eight bytes fill a u64.
+pub const MAX_ENTROPY_ORDER: usize = 8;
+
+// Empirical conditional entropy in bits per symbol of each byte given the
+// `order` bytes before it. Bytes before the start of the data are taken to
+// be zero. Orders above MAX_ENTROPY_ORDER are treated as MAX_ENTROPY_ORDER.
+pub fn empirical_entropy(data: &[u8], order: usize) -> f64 {
+    if data.is_empty() {
+        return 0.0;
+    }
+    let order = order.min(MAX_ENTROPY_ORDER);
+    let mask = if order == 0 { 0 } else { u64::MAX >> (64 - 8 * order) };
+
+    let mut context_counts: HashMap<u64, u64> = HashMap::new();
+    let mut pair_counts: HashMap<(u64, u8), u64> = HashMap::new();
+    let mut context = 0u64;
+    for &b in data {
+        *context_counts.entry(context).or_insert(0) += 1;
+        *pair_counts.entry((context, b)).or_insert(0) += 1;
+        context = ((context << 8) | b as u64) & mask;
+    }
+
+    let mut bits = 0.0;
+    for ((context, _), &count) in pair_counts.iter() {
+        let p = count as f64 / context_counts[context] as f64;
+        bits -= count as f64 * p.log2();
+    }
+    bits / data.len() as f64
+}
+
+fn measure_model(name: &str, data: &[u8], models: &mut [VectorCountSymbolModel<u8>], order: usize) -> ModelReport {
+    let mut enc = Encoder::new();
+    let mut sink = std::io::sink();
+    let mut bw = BitWriter::new(&mut sink);
+
+    let mut histogram = vec![0u64; COST_HISTOGRAM_BINS];
+    let mut ideal_bits = 0.0;
+    let mut prev = 0usize;
+
+    for &b in data {
+        let sm = &mut models[if order == 0 { 0 } else { prev }];
+
+        let (int_start, int_end) = sm.interval(&b);
+        let cost = -((int_end - int_start) as f64 / sm.total() as f64).log2();
+        ideal_bits += cost;
+        histogram[(cost as usize).min(COST_HISTOGRAM_BINS - 1)] += 1;
+
+        enc.encode(&b, &*sm, &mut bw);
+        sm.incr_count(&b);
+        prev = b as usize;
+    }
+    enc.finish(&mut bw).unwrap();
+
+    ModelReport {
+        name: name.to_string(),
+        order,
+        coded_bits: enc.bits_written(),
+        ideal_bits,
+        histogram,
+    }
+}
+
+fn byte_model() -> VectorCountSymbolModel<u8> {
+    VectorCountSymbolModel::new((0..=255).collect())
+}
+
+fn english_model() -> VectorCountSymbolModel<u8> {
+    let mut sm = byte_model();
+    for (b, &w) in ascii_english_letter_weights_1000().iter().enumerate() {
+        sm.set_count(&(b as u8), w);
+    }
+    sm
+}
+
+pub fn analyze(data: &[u8]) -> AnalysisReport {
+    let entropy = (0..=2).map(|order| empirical_entropy(data, order)).collect();
+
+    let models = vec![
+        measure_model("adaptive order-0", data, &mut [byte_model()], 0),
+        measure_model("adaptive order-0, English prior", data, &mut [english_model()], 0),
+        measure_model("adaptive order-1", data, &mut (0..256).map(|_| byte_model()).collect::<Vec<_>>(), 1),
+    ];
+
+    AnalysisReport {
+        length: data.len() as u64,
+        entropy,
+        models,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entropy_of_uniform_bytes() {
+        let data: Vec<u8> = (1..=255).collect();
+        assert!((empirical_entropy(&data, 0) - 255f64.log2()).abs() < 1e-9);
+        // Each byte fully determines the next one.
+        assert!(empirical_entropy(&data, 1).abs() < 1e-9);
+    }
+
+    #[test]
+    fn entropy_of_alternating_bytes() {
+        let data: Vec<u8> = (0..1000).map(|i| if i % 2 == 0 { b'a' } else { b'b' }).collect();
+        assert!((empirical_entropy(&data, 0) - 1.0).abs() < 1e-9);
+        // Each byte is determined by the one before it.
+        assert!(empirical_entropy(&data, 1).abs() < 1e-9);
+        assert!(empirical_entropy(&data, 2).abs() < 1e-9);
+        assert_eq!(empirical_entropy(&data, 64), empirical_entropy(&data, MAX_ENTROPY_ORDER));
+    }
+
+    #[test]
+    fn coded_size_tracks_ideal_cost() {
+        let data: Vec<u8> = b"abracadabra ".iter().cycle().take(5000).cloned().collect();
+        let report = analyze(&data);
+        assert_eq!(report.length, 5000);
+        for model in report.models.iter() {
+            // The coder stays within a few bits of the model's ideal cost
+            // plus the 32 bits written by finish.
+            assert!((model.coded_bits as f64) < model.ideal_bits + 64.0);
+            assert!((model.coded_bits as f64) > model.ideal_bits - 2.0);
+            assert_eq!(model.histogram.iter().sum::<u64>(), 5000);
+            assert!(report.adaptation_overhead(model) > 0.0);
+        }
+        assert!(report.models[2].coded_bits < report.models[0].coded_bits);
+    }
+}
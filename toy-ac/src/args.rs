@@ -0,0 +1,57 @@
+use std::str::FromStr;
+
+// Minimal command line handling for the toy-ac binaries. Options are
+// pulled out by name and whatever is left over is positional.
+pub struct Args {
+    args: Vec<String>,
+}
+
+impl Args {
+    pub fn new(args: Vec<String>) -> Self {
+        Self { args }
+    }
+
+    pub fn flag(&mut self, name: &str) -> bool {
+        let flag = format!("--{}", name);
+        match self.args.iter().position(|a| *a == flag) {
+            Some(idx) => {
+                self.args.remove(idx);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn value<T: FromStr>(&mut self, name: &str) -> Result<Option<T>, String> {
+        let flag = format!("--{}", name);
+        let idx = match self.args.iter().position(|a| *a == flag) {
+            Some(idx) => idx,
+            None => return Ok(None),
+        };
+        if idx + 1 >= self.args.len() {
+            return Err(format!("{} requires a value", flag));
+        }
+        let value = self.args.remove(idx + 1);
+        self.args.remove(idx);
+        match value.parse::<T>() {
+            Ok(v) => Ok(Some(v)),
+            Err(_) => Err(format!("Bad value for {}: {}", flag, value)),
+        }
+    }
+
+    pub fn value_or<T: FromStr>(&mut self, name: &str, default: T) -> Result<T, String> {
+        Ok(self.value(name)?.unwrap_or(default))
+    }
+
+    // Returns the positional arguments, failing on any option that no
+    // subcommand asked for.
+    pub fn positional(self, expected: &[&str]) -> Result<Vec<String>, String> {
+        if let Some(unknown) = self.args.iter().find(|a| a.starts_with("--")) {
+            return Err(format!("Unknown option {}", unknown));
+        }
+        if self.args.len() != expected.len() {
+            return Err(format!("Expected arguments: {}", expected.join(" ")));
+        }
+        Ok(self.args)
+    }
+}
@@ -3,6 +3,7 @@ use std::fs::File;
 use std::io::{BufReader, BufWriter, Read, Write};
 
 use bitbit::BitWriter;
+use toy_ac::container::write_header;
 use toy_ac::encoder::Encoder;
 
 use toy_ac::symbol_model::SymbolModel;
@@ -24,7 +25,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     data_folder_path.push("data");
 
     let input_file = match File::open(data_folder_path.join("shakespeare.txt")) {
-        Err(_) => panic!("Error opening file"),
+        Err(_) => panic!("Error opening data/shakespeare.txt (toy-ac-synth text data/shakespeare.txt generates a stand-in)"),
         Ok(f) => f,
     };
     let metadata = input_file.metadata()?;
@@ -48,7 +49,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let mut buf_writer = BufWriter::new(output_file);
     // First write out the input length as a u64
-    buf_writer.write(&input_length.to_be_bytes())?;
+    write_header(&mut buf_writer, input_length)?;
 
     let mut bw = BitWriter::new(&mut buf_writer);
 
@@ -1,8 +1,9 @@
 use std::env;
 use std::fs::File;
-use std::io::{BufReader, BufWriter, Read, Write};
+use std::io::{BufReader, BufWriter, Write};
 
 use bitbit::{BitReader, MSB};
+use toy_ac::container::read_header;
 use toy_ac::decoder::Decoder;
 
 use toy_ac::symbol_model::SymbolModel;
@@ -46,9 +47,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let mut buf_reader = BufReader::new(input_file);
 
-    let mut size_bytes: [u8; 8] = [0; 8];
-    buf_reader.read(&mut size_bytes)?;
-    let output_size = u64::from_be_bytes(size_bytes);
+    let output_size = read_header(&mut buf_reader)?;
 
     let mut br: BitReader<_, MSB> = BitReader::new(&mut buf_reader);
 
@@ -69,7 +68,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             log_writer = Some(lw);
         }
 
-        let next_byte = dec.decode(&sm, &mut br);
+        let next_byte = dec.decode(&sm, &mut br)?;
         let next_byte = next_byte.to_owned();
         sm.incr_count(&next_byte);
 
@@ -89,7 +88,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             log_writer = Some(lw);
         }
 
-        writer.write(&[next_byte])?;
+        writer.write_all(&[next_byte])?;
     }
 
     writer.flush()?;
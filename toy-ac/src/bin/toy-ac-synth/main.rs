@@ -0,0 +1,61 @@
+use std::env;
+
+use toy_ac::args::Args;
+
+mod text;
+mod video;
+
+const USAGE: &str = "Usage: toy-ac-synth <command> [options]
+
+Deterministic test content: the same options and --seed always give the same
+output.
+
+Commands:
+  video <output.y4m>         Moving textured objects over a background
+      [--width N --height N --frames N --fps N:D]
+      [--chroma 420|422|444|mono --bit-depth 8-12]
+      [--background texture|gradient --pan X,Y]
+                             Background and its motion in pixels per frame
+      [--objects N --speed PIXELS --rotation DEGREES]
+                             Objects and their largest motion per frame
+      [--noise SIGMA --cuts F1,F2,... --seed N]
+                             Gaussian noise in 8-bit units and scene cut frames
+      [--truth <truth.csv>]  Write the true motion of each frame as CSV
+  image <output.pgm>         The first frame's luma as a greymap
+      [--width N --height N --bit-depth 8-16 --background texture|gradient]
+      [--objects N --rotation DEGREES --noise SIGMA --seed N --ascii]
+  text <output>              A text-like corpus for the byte models
+      [--corpus letters|bigram|binary|logs|skewed --length BYTES --seed N]
+      [--train <sample>]     Train the bigram corpus on a file, not Shakespeare
+      [--skew S]             Zipf exponent of the skewed corpus";
+
+fn main() {
+    let mut argv = env::args().skip(1);
+    let command = match argv.next() {
+        Some(c) => c,
+        None => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    };
+    let args = Args::new(argv.collect());
+
+    let result = match command.as_str() {
+        "video" => video::video(args),
+        "image" => video::image(args),
+        "text" => text::run(args),
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => {
+            eprintln!("Unknown command {}\n\n{}", command, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
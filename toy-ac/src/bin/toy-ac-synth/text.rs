@@ -0,0 +1,44 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+
+use toy_ac::args::Args;
+use toy_ac::synth::Rng;
+use toy_ac::synth::text::{BigramModel, Corpus, TextSpec, generate};
+
+pub fn run(mut args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    let defaults = TextSpec::default();
+    let spec = TextSpec {
+        corpus: args.value_or("corpus", defaults.corpus)?,
+        length: args.value_or("length", defaults.length)?,
+        skew: args.value_or("skew", defaults.skew)?,
+        seed: args.value_or("seed", defaults.seed)?,
+    };
+    if !spec.skew.is_finite() {
+        return Err(format!("Bad value for --skew: {}", spec.skew).into());
+    }
+    let train: Option<String> = args.value("train")?;
+    let files = args.positional(&["<output>"])?;
+
+    let data = match train {
+        Some(path) if spec.corpus == Corpus::Bigram => {
+            let sample = match fs::read(&path) {
+                Err(e) => return Err(format!("Error reading {}: {}", path, e).into()),
+                Ok(s) => s,
+            };
+            match BigramModel::train(&sample) {
+                Some(model) => model.generate(spec.length, &mut Rng::new(spec.seed)),
+                None => return Err(format!("{} is too short to train on", path).into()),
+            }
+        }
+        Some(_) => return Err("--train only applies to the bigram corpus".into()),
+        None => generate(&spec),
+    };
+
+    let mut output = match File::create(&files[0]) {
+        Err(e) => return Err(format!("Error creating {}: {}", files[0], e).into()),
+        Ok(f) => BufWriter::new(f),
+    };
+    output.write_all(&data)?;
+    output.flush()?;
+    Ok(())
+}
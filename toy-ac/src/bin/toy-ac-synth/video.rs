@@ -0,0 +1,115 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use toy_ac::args::Args;
+use toy_ac::image::pnm::{Encoding, write_pnm};
+use toy_ac::synth::video::{self as synth, VideoSpec, VideoSynth};
+use toy_ac::video::y4m::{Y4mHeader, Y4mWriter};
+
+fn create(path: &str) -> Result<BufWriter<File>, Box<dyn std::error::Error>> {
+    match File::create(path) {
+        Err(e) => Err(format!("Error creating {}: {}", path, e).into()),
+        Ok(f) => Ok(BufWriter::new(f)),
+    }
+}
+
+fn parse_list<T: std::str::FromStr>(name: &str, list: &str) -> Result<Vec<T>, String> {
+    list.split(',')
+        .filter(|s| !s.trim().is_empty())
+        .map(|s| s.trim().parse::<T>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| format!("Bad value for --{}: {}", name, list))
+}
+
+// The scene options shared by video and image.
+fn spec(args: &mut Args) -> Result<VideoSpec, String> {
+    let defaults = VideoSpec::default();
+    let pan = match args.value::<String>("pan")? {
+        Some(pan) => match parse_list::<f64>("pan", &pan)?[..] {
+            [x, y] => (x, y),
+            _ => return Err(format!("Bad value for --pan: {}", pan)),
+        },
+        None => defaults.pan,
+    };
+    let rotation: Option<f64> = args.value("rotation")?;
+    Ok(VideoSpec {
+        width: args.value_or("width", defaults.width)?,
+        height: args.value_or("height", defaults.height)?,
+        bit_depth: args.value_or("bit-depth", defaults.bit_depth)?,
+        background: args.value_or("background", defaults.background)?,
+        pan,
+        objects: args.value_or("objects", defaults.objects)?,
+        object_speed: args.value_or("speed", defaults.object_speed)?,
+        rotation: rotation.map_or(defaults.rotation, f64::to_radians),
+        noise: args.value_or("noise", defaults.noise)?,
+        cuts: parse_list("cuts", &args.value_or("cuts", String::new())?)?,
+        seed: args.value_or("seed", defaults.seed)?,
+        ..defaults
+    })
+}
+
+pub fn video(mut args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    let mut spec = spec(&mut args)?;
+    spec.chroma = args.value_or("chroma", spec.chroma)?;
+    let frames: usize = args.value_or("frames", 30)?;
+    let fps: String = args.value_or("fps", "25:1".to_string())?;
+    let truth_path: Option<String> = args.value("truth")?;
+    let files = args.positional(&["<output.y4m>"])?;
+
+    let frame_rate = match fps.split_once(':').map(|(n, d)| (n.parse::<u32>(), d.parse::<u32>())) {
+        Some((Ok(n), Ok(d))) => (n, d),
+        _ => return Err(format!("Bad frame rate {}", fps).into()),
+    };
+    if spec.width == 0 || spec.height == 0 || !(8..=12).contains(&spec.bit_depth) {
+        return Err("Video needs a nonzero size and a bit depth of 8 to 12".into());
+    }
+
+    let synth = VideoSynth::new(spec);
+    let header = Y4mHeader::new(synth.spec().width, synth.spec().height, synth.spec().chroma, synth.spec().bit_depth, frame_rate);
+    let mut output = Y4mWriter::new(create(&files[0])?, header)?;
+    let mut truth = match truth_path {
+        Some(path) => {
+            let mut out = create(&path)?;
+            let objects: Vec<String> = (0..synth.spec().objects).map(|i| format!("object{0}_x,object{0}_y,object{0}_angle", i)).collect();
+            writeln!(out, "frame,scene,cut,pan_x,pan_y{}{}", if objects.is_empty() { "" } else { "," }, objects.join(","))?;
+            Some(out)
+        }
+        None => None,
+    };
+
+    for t in 0..frames {
+        output.write_frame(&synth.frame(t))?;
+        if let Some(out) = truth.as_mut() {
+            // The background motion since the previous frame, which is zero
+            // at the start of each scene.
+            let (dx, dy) = synth.background_motion(t).unwrap_or((0.0, 0.0));
+            let mut fields = vec![t.to_string(), synth.scene(t).0.to_string(), (synth.is_cut(t) as u8).to_string(), dx.to_string(), dy.to_string()];
+            for object in synth.objects(t) {
+                fields.push(format!("{:.3}", object.center.0));
+                fields.push(format!("{:.3}", object.center.1));
+                fields.push(format!("{:.6}", object.angle));
+            }
+            writeln!(out, "{}", fields.join(","))?;
+        }
+    }
+    output.flush()?;
+    if let Some(mut out) = truth {
+        out.flush()?;
+    }
+    Ok(())
+}
+
+pub fn image(mut args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    let spec = spec(&mut args)?;
+    let ascii = args.flag("ascii");
+    let files = args.positional(&["<output.pgm>"])?;
+    if spec.width == 0 || spec.height == 0 || !(8..=16).contains(&spec.bit_depth) {
+        return Err("Image needs a nonzero size and a bit depth of 8 to 16".into());
+    }
+
+    let image = synth::image(spec);
+    let mut output = create(&files[0])?;
+    write_pnm(&image, if ascii { Encoding::Ascii } else { Encoding::Binary }, &mut output)?;
+    output.flush()?;
+    Ok(())
+}
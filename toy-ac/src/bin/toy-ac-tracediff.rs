@@ -0,0 +1,215 @@
+use std::collections::VecDeque;
+use std::env;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Lines};
+
+use toy_ac::trace::{TraceRecord, format_symbol, mismatched_fields, parse_line};
+use workspace_root::get_workspace_root;
+
+// Reads encoder and decoder traces (as written by shakes_compress -log and
+// shakes_decompress -log), aligns them by symbol index and reports the first
+// symbol where they disagree.
+//
+// Usage: toy-ac-tracediff [encode-log decode-log] [-context N]
+//
+// With no file arguments the logs are read from data/encode-log.txt and
+// data/decode-log.txt in the workspace.
+
+struct TraceReader {
+    name: String,
+    lines: Lines<BufReader<File>>,
+    line_number: usize,
+}
+
+impl TraceReader {
+    fn open(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let file = match File::open(path) {
+            Err(e) => return Err(format!("Error opening {}: {}", path, e).into()),
+            Ok(f) => f,
+        };
+        Ok(Self {
+            name: path.to_string(),
+            lines: BufReader::new(file).lines(),
+            line_number: 0,
+        })
+    }
+
+    fn next_record(&mut self) -> Result<Option<TraceRecord>, Box<dyn std::error::Error>> {
+        for line in self.lines.by_ref() {
+            let line = line?;
+            self.line_number += 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+            return match parse_line(&line) {
+                Ok(r) => Ok(Some(r)),
+                Err(e) => Err(format!("{}:{}: {}", self.name, self.line_number, e).into()),
+            };
+        }
+        Ok(None)
+    }
+}
+
+fn describe(r: &TraceRecord) -> String {
+    format!(
+        "#{:<8} high {:#010x} low {:#010x} total {:>8} symbol {:<6} -> high {:#010x} low {:#010x}",
+        r.count,
+        r.high,
+        r.low,
+        r.total,
+        format_symbol(r.symbol),
+        r.next_high,
+        r.next_low
+    )
+}
+
+fn print_pair(enc: &TraceRecord, dec: &TraceRecord, marker: &str) {
+    println!("{} enc {}", marker, describe(enc));
+    println!("{} dec {}", marker, describe(dec));
+}
+
+fn print_model_state(enc: &TraceRecord, dec: &TraceRecord) {
+    println!("Model state at symbol {}:", enc.count);
+    match enc.interval {
+        Some((start, end)) if end >= start => println!(
+            "  encoder: total {}, interval [{}, {}) for {} (p = {:.6})",
+            enc.total,
+            start,
+            end,
+            format_symbol(enc.symbol),
+            (end - start) as f64 / enc.total as f64
+        ),
+        Some((start, end)) => println!("  encoder: total {}, malformed interval [{}, {})", enc.total, start, end),
+        None => println!("  encoder: total {}", enc.total),
+    }
+    match dec.buffer {
+        Some(buffer) => {
+            let Some(width) = dec.high.checked_sub(dec.low).and_then(|w| w.checked_add(1)) else {
+                println!("  decoder: total {}, malformed range [{:#x}, {:#x}]", dec.total, dec.low, dec.high);
+                return;
+            };
+            // Same target computation as Decoder::decode, shown so it can be
+            // checked against the encoder's interval by hand.
+            let target = if dec.total == 0 {
+                "malformed total".to_string()
+            } else if buffer >= dec.low && buffer <= dec.high {
+                (((buffer - dec.low + 1) as u128 * dec.total as u128 - 1) / width as u128).to_string()
+            } else {
+                "buffer outside range".to_string()
+            };
+            println!(
+                "  decoder: total {}, buffer {:#010x}, lookup value {}",
+                dec.total, buffer, target
+            );
+        }
+        None => println!("  decoder: total {}", dec.total),
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut context = 3;
+    let mut files = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "-context" {
+            context = match args.next().map(|v| v.parse::<usize>()) {
+                Some(Ok(n)) => n,
+                _ => return Err("-context requires a number".into()),
+            };
+        } else {
+            files.push(arg);
+        }
+    }
+
+    let (enc_path, dec_path) = match files.len() {
+        0 => {
+            let mut data_folder_path = get_workspace_root();
+            data_folder_path.push("data");
+            (
+                data_folder_path.join("encode-log.txt").to_string_lossy().into_owned(),
+                data_folder_path.join("decode-log.txt").to_string_lossy().into_owned(),
+            )
+        }
+        2 => (files[0].clone(), files[1].clone()),
+        _ => return Err("Usage: toy-ac-tracediff [encode-log decode-log] [-context N]".into()),
+    };
+
+    let mut enc_reader = TraceReader::open(&enc_path)?;
+    let mut dec_reader = TraceReader::open(&dec_path)?;
+
+    let mut history: VecDeque<(TraceRecord, TraceRecord)> = VecDeque::with_capacity(context + 1);
+    let mut enc_next = enc_reader.next_record()?;
+    let mut dec_next = dec_reader.next_record()?;
+    let mut compared = 0u64;
+
+    loop {
+        let (enc, dec) = match (enc_next.take(), dec_next.take()) {
+            (Some(e), Some(d)) => (e, d),
+            (Some(e), None) => {
+                println!("Decoder trace ends before symbol {} ({} symbols matched)", e.count, compared);
+                return Ok(());
+            }
+            (None, Some(d)) => {
+                println!("Encoder trace ends before symbol {} ({} symbols matched)", d.count, compared);
+                return Ok(());
+            }
+            (None, None) => {
+                println!("Traces agree on all {} symbols", compared);
+                return Ok(());
+            }
+        };
+
+        // Align by symbol index in case one of the logs skipped lines.
+        if enc.count < dec.count {
+            println!("Symbol {} missing from decoder trace", enc.count);
+            enc_next = enc_reader.next_record()?;
+            dec_next = Some(dec);
+            continue;
+        }
+        if dec.count < enc.count {
+            println!("Symbol {} missing from encoder trace", dec.count);
+            dec_next = dec_reader.next_record()?;
+            enc_next = Some(enc);
+            continue;
+        }
+
+        let fields = mismatched_fields(&enc, &dec);
+        if !fields.is_empty() {
+            println!(
+                "First divergence at symbol {} ({} line {}, {} line {}): {} differ",
+                enc.count,
+                enc_reader.name,
+                enc_reader.line_number,
+                dec_reader.name,
+                dec_reader.line_number,
+                fields.join(", ")
+            );
+            println!();
+            for (e, d) in history.iter() {
+                print_pair(e, d, " ");
+            }
+            print_pair(&enc, &dec, ">");
+            for _ in 0..context {
+                match (enc_reader.next_record()?, dec_reader.next_record()?) {
+                    (Some(e), Some(d)) => print_pair(&e, &d, " "),
+                    (Some(e), None) => println!("  enc {}", describe(&e)),
+                    (None, Some(d)) => println!("  dec {}", describe(&d)),
+                    (None, None) => break,
+                }
+            }
+            println!();
+            print_model_state(&enc, &dec);
+            return Ok(());
+        }
+
+        compared += 1;
+        if context > 0 {
+            if history.len() == context {
+                history.pop_front();
+            }
+            history.push_back((enc, dec));
+        }
+        enc_next = enc_reader.next_record()?;
+        dec_next = dec_reader.next_record()?;
+    }
+}
@@ -0,0 +1,107 @@
+use std::fs;
+
+use toy_ac::analysis::{AnalysisReport, COST_HISTOGRAM_BINS, analyze};
+use toy_ac::args::Args;
+
+pub fn run(mut args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    let json = args.flag("json");
+    let files = args.positional(&["<file>"])?;
+
+    let data = match fs::read(&files[0]) {
+        Err(e) => return Err(format!("Error reading {}: {}", files[0], e).into()),
+        Ok(d) => d,
+    };
+    let report = analyze(&data);
+
+    if json {
+        println!("{}", to_json(&report));
+    } else {
+        print_table(&files[0], &report);
+    }
+    Ok(())
+}
+
+fn print_table(name: &str, report: &AnalysisReport) {
+    println!("{}: {} symbols", name, report.length);
+    println!();
+    println!("Empirical entropy (bits/symbol)");
+    for (order, h) in report.entropy.iter().enumerate() {
+        println!("  order-{}  {:8.4}", order, h);
+    }
+    println!();
+    println!(
+        "{:<38} {:>12} {:>10} {:>14} {:>12}",
+        "Model", "Coded bits", "Bits/sym", "Overhead bits", "Overhead %"
+    );
+    for (i, model) in report.models.iter().enumerate() {
+        let overhead = report.adaptation_overhead(model);
+        let reference = report.entropy[model.order] * report.length as f64;
+        println!(
+            "{:<38} {:>12} {:>10.4} {:>14.1} {:>12.2}",
+            format!("{}. {}", i + 1, model.name),
+            model.coded_bits,
+            model.bits_per_symbol(report.length),
+            overhead,
+            if reference > 0.0 { 100.0 * overhead / reference } else { 0.0 }
+        );
+    }
+    println!();
+    println!("Per-symbol cost histogram (symbols coded at each whole number of bits)");
+    print!("{:>8}", "Bits");
+    for i in 0..report.models.len() {
+        print!(" {:>12}", format!("model {}", i + 1));
+    }
+    println!();
+    for bin in 0..COST_HISTOGRAM_BINS {
+        if report.models.iter().all(|m| m.histogram[bin] == 0) {
+            continue;
+        }
+        let label = if bin == COST_HISTOGRAM_BINS - 1 { format!("{}+", bin) } else { format!("{}-{}", bin, bin + 1) };
+        print!("{:>8}", label);
+        for model in report.models.iter() {
+            print!(" {:>12}", model.histogram[bin]);
+        }
+        println!();
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn to_json(report: &AnalysisReport) -> String {
+    let entropy: Vec<String> = report.entropy.iter().map(|h| format!("{:.6}", h)).collect();
+    let models: Vec<String> = report
+        .models
+        .iter()
+        .map(|m| {
+            let histogram: Vec<String> = m.histogram.iter().map(|c| c.to_string()).collect();
+            format!(
+                "{{\"name\": {}, \"order\": {}, \"coded_bits\": {}, \"bits_per_symbol\": {:.6}, \"ideal_bits\": {:.3}, \"adaptation_overhead_bits\": {:.3}, \"cost_histogram\": [{}]}}",
+                json_string(&m.name),
+                m.order,
+                m.coded_bits,
+                m.bits_per_symbol(report.length),
+                m.ideal_bits,
+                report.adaptation_overhead(m),
+                histogram.join(", ")
+            )
+        })
+        .collect();
+    format!(
+        "{{\"length\": {}, \"entropy\": [{}], \"models\": [{}]}}",
+        report.length,
+        entropy.join(", "),
+        models.join(", ")
+    )
+}
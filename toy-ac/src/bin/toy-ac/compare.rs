@@ -0,0 +1,91 @@
+use std::fs::File;
+use std::io::BufReader;
+
+use toy_ac::args::Args;
+use toy_ac::metrics::{FrameMetrics, average, compare_frames};
+use toy_ac::video::ChromaSampling;
+use toy_ac::video::y4m::Y4mReader;
+
+fn open(path: &str) -> Result<Y4mReader<BufReader<File>>, Box<dyn std::error::Error>> {
+    match File::open(path) {
+        Err(e) => Err(format!("Error opening {}: {}", path, e).into()),
+        Ok(f) => Ok(Y4mReader::new(BufReader::new(f))?),
+    }
+}
+
+// Reports PSNR, SSIM and MS-SSIM of each frame of a distorted y4m file
+// against a reference, as a table or (with --csv) as CSV on stdout.
+pub fn run(mut args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    let csv = args.flag("csv");
+    let files = args.positional(&["<reference.y4m>", "<distorted.y4m>"])?;
+
+    let mut reference = open(&files[0])?;
+    let mut distorted = open(&files[1])?;
+    let (a, b) = (reference.header(), distorted.header());
+    if (a.width, a.height, a.chroma, a.bit_depth) != (b.width, b.height, b.chroma, b.bit_depth) {
+        return Err("Inputs differ in size, chroma sampling or bit depth".into());
+    }
+    let color = a.chroma != ChromaSampling::Mono;
+
+    if csv {
+        let planes = if color { "psnr_y,psnr_u,psnr_v,psnr,ssim_y,ssim_u,ssim_v,ssim" } else { "psnr_y,ssim_y" };
+        println!("frame,{},ms_ssim", planes);
+    } else {
+        println!("{:>6} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8}", "Frame", "PSNR-Y", "PSNR-U", "PSNR-V", "PSNR", "SSIM", "MS-SSIM");
+    }
+
+    let mut metrics = Vec::new();
+    loop {
+        let (r, d) = match (reference.next(), distorted.next()) {
+            (Some(r), Some(d)) => (r?, d?),
+            (None, None) => break,
+            _ => {
+                eprintln!("Warning: inputs have different frame counts, comparing the first {}", metrics.len());
+                break;
+            }
+        };
+        let m = compare_frames(&r, &d);
+        print_row(&metrics.len().to_string(), &m, csv);
+        metrics.push(m);
+    }
+
+    match average(&metrics) {
+        Some(m) => {
+            if !csv {
+                println!();
+            }
+            print_row("mean", &m, csv);
+        }
+        None => return Err("Inputs have no frames".into()),
+    }
+    Ok(())
+}
+
+fn print_row(label: &str, m: &FrameMetrics, csv: bool) {
+    if csv {
+        let mut fields = vec![label.to_string()];
+        if m.psnr.len() > 1 {
+            fields.extend(m.psnr.iter().map(|v| format!("{:.4}", v)));
+            fields.push(format!("{:.4}", m.psnr_weighted));
+            fields.extend(m.ssim.iter().map(|v| format!("{:.6}", v)));
+            fields.push(format!("{:.6}", m.ssim_weighted));
+        } else {
+            fields.push(format!("{:.4}", m.psnr[0]));
+            fields.push(format!("{:.6}", m.ssim[0]));
+        }
+        fields.push(format!("{:.6}", m.ms_ssim));
+        println!("{}", fields.join(","));
+        return;
+    }
+    let chroma = |p: usize| m.psnr.get(p).map_or("-".to_string(), |v| format!("{:.3}", v));
+    println!(
+        "{:>6} {:>8.3} {:>8} {:>8} {:>8.3} {:>8.5} {:>8.5}",
+        label,
+        m.psnr[0],
+        chroma(1),
+        chroma(2),
+        m.psnr_weighted,
+        m.ssim_weighted,
+        m.ms_ssim
+    );
+}
@@ -0,0 +1,131 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+use toy_ac::args::Args;
+use toy_ac::image::color::{ColorRange, to_frame, to_grey, to_image};
+use toy_ac::image::pnm::{Encoding, read_pnm, write_pnm};
+use toy_ac::video::raw::{PixelFormat, RawFormat, RawReader, RawWriter};
+use toy_ac::video::y4m::{Y4mHeader, Y4mReader, Y4mWriter};
+use toy_ac::video::{ChromaSampling, Frame};
+
+// Converts between y4m, headerless .yuv files and Netpbm images. Files
+// ending in .y4m are y4m, .pgm, .ppm and .pnm are images and anything else is
+// raw, in which case --width, --height, --pix-fmt and --bit-depth describe
+// it. An image becomes a single frame with the given --chroma sampling, and
+// writing an image takes one frame (--frame) from the input.
+pub fn run(mut args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    let width: Option<usize> = args.value("width")?;
+    let height: Option<usize> = args.value("height")?;
+    let pix_fmt: Option<PixelFormat> = args.value("pix-fmt")?;
+    let bit_depth: Option<u8> = args.value("bit-depth")?;
+    let fps: String = args.value_or("fps", "25:1".to_string())?;
+    let chroma: ChromaSampling = args.value_or("chroma", ChromaSampling::Cs420)?;
+    let range: ColorRange = args.value_or("range", ColorRange::Limited)?;
+    let frame_index: usize = args.value_or("frame", 0)?;
+    let ascii = args.flag("ascii");
+    let files = args.positional(&["<input>", "<output>"])?;
+
+    let frame_rate = match fps.split_once(':').map(|(n, d)| (n.parse::<u32>(), d.parse::<u32>())) {
+        Some((Ok(n), Ok(d))) => (n, d),
+        _ => return Err(format!("Bad frame rate {}", fps).into()),
+    };
+
+    let mut input: Box<dyn Iterator<Item = Result<Frame, Box<dyn std::error::Error>>>>;
+    let mut input_rate = frame_rate;
+    if is_y4m(&files[0]) {
+        let reader = Y4mReader::new(BufReader::new(open(&files[0])?))?;
+        input_rate = reader.header().frame_rate;
+        input = Box::new(reader.map(|f| f.map_err(|e| e.into())));
+    } else if is_pnm(&files[0]) {
+        let image = read_pnm(BufReader::new(open(&files[0])?))?;
+        let frame = match to_frame(&image, chroma, range) {
+            Some(f) => f,
+            None => return Err("Image must be a greymap or pixmap".into()),
+        };
+        input = Box::new(std::iter::once(Ok(frame)));
+    } else {
+        let format = match (width, height, pix_fmt) {
+            (Some(w), Some(h), Some(p)) => RawFormat::new(w, h, p, bit_depth.unwrap_or(8))?,
+            _ => return Err("Raw input needs --width, --height and --pix-fmt".into()),
+        };
+        let reader = RawReader::new(BufReader::new(open(&files[0])?), format);
+        input = Box::new(reader.map(|f| f.map_err(|e| e.into())));
+    }
+
+    let output = match File::create(&files[1]) {
+        Err(e) => return Err(format!("Error creating {}: {}", files[1], e).into()),
+        Ok(f) => BufWriter::new(f),
+    };
+
+    let mut count = 0;
+    if is_pnm(&files[1]) {
+        let frame = match input.nth(frame_index) {
+            Some(f) => f?,
+            None => return Err(format!("Input has no frame {}", frame_index).into()),
+        };
+        let image = if has_extension(&files[1], "pgm") { to_grey(&frame, range) } else { to_image(&frame, range) };
+        let mut output = output;
+        write_pnm(&image, if ascii { Encoding::Ascii } else { Encoding::Binary }, &mut output)?;
+        output.flush()?;
+        count = 1;
+    } else if is_y4m(&files[1]) {
+        let mut writer: Option<Y4mWriter<BufWriter<File>>> = None;
+        let mut output = Some(output);
+        for frame in input.by_ref() {
+            let frame = frame?;
+            if writer.is_none() {
+                let header = Y4mHeader::for_frame(&frame, input_rate);
+                writer = Some(Y4mWriter::new(output.take().unwrap(), header)?);
+            }
+            writer.as_mut().unwrap().write_frame(&frame)?;
+            count += 1;
+        }
+        if let Some(mut w) = writer {
+            w.flush()?;
+        }
+    } else {
+        let mut writer: Option<RawWriter<BufWriter<File>>> = None;
+        let mut output = Some(output);
+        for frame in input.by_ref() {
+            let frame = frame?;
+            if writer.is_none() {
+                let pixel_format = match (pix_fmt, frame.chroma) {
+                    (Some(p), _) => p,
+                    (None, ChromaSampling::Cs420) => PixelFormat::I420,
+                    (None, ChromaSampling::Cs444) => PixelFormat::Yuv444,
+                    (None, c) => return Err(format!("No raw pixel format for {:?}", c).into()),
+                };
+                let format = RawFormat::new(frame.width(), frame.height(), pixel_format, frame.bit_depth)?;
+                writer = Some(RawWriter::new(output.take().unwrap(), format));
+            }
+            writer.as_mut().unwrap().write_frame(&frame)?;
+            count += 1;
+        }
+        if let Some(mut w) = writer {
+            w.flush()?;
+        }
+    }
+
+    eprintln!("Converted {} frames", count);
+    Ok(())
+}
+
+fn has_extension(path: &str, extension: &str) -> bool {
+    Path::new(path).extension().is_some_and(|e| e.eq_ignore_ascii_case(extension))
+}
+
+fn is_y4m(path: &str) -> bool {
+    has_extension(path, "y4m")
+}
+
+fn is_pnm(path: &str) -> bool {
+    ["pgm", "ppm", "pnm"].iter().any(|e| has_extension(path, e))
+}
+
+fn open(path: &str) -> Result<File, Box<dyn std::error::Error>> {
+    match File::open(path) {
+        Err(e) => Err(format!("Error opening {}: {}", path, e).into()),
+        Ok(f) => Ok(f),
+    }
+}
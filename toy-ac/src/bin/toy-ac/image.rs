@@ -0,0 +1,68 @@
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+
+use toy_ac::args::Args;
+use toy_ac::image::lossless;
+use toy_ac::image::pnm::{Encoding, read_pnm, write_pnm};
+
+// Refuse to decode streams describing more samples than this, to bound
+// memory use on corrupt input.
+const MAX_SAMPLES: u64 = 1 << 30;
+
+pub fn compress(mut args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    let quiet = args.flag("quiet");
+    let files = args.positional(&["<input.pnm>", "<output>"])?;
+
+    let input = match File::open(&files[0]) {
+        Err(e) => return Err(format!("Error opening {}: {}", files[0], e).into()),
+        Ok(f) => BufReader::new(f),
+    };
+    let image = read_pnm(input)?;
+
+    let mut bytes = Vec::new();
+    let plane_bits = lossless::encode(&image, &mut bytes)?;
+    if let Err(e) = fs::write(&files[1], &bytes) {
+        return Err(format!("Error writing {}: {}", files[1], e).into());
+    }
+
+    if !quiet {
+        let pixels = (image.width() * image.height()) as f64;
+        println!(
+            "{}: {}x{}, {} bit, {} planes",
+            files[0],
+            image.width(),
+            image.height(),
+            image.bit_depth(),
+            image.planes.len()
+        );
+        for (i, bits) in plane_bits.iter().enumerate() {
+            let samples = (image.planes[i].width * image.planes[i].height) as f64;
+            println!("  plane {}: {:>12} bits {:>8.4} bits/sample", i, bits, *bits as f64 / samples);
+        }
+        println!(
+            "  total:   {:>12} bytes {:>8.4} bits/pixel (raw {} bits/pixel)",
+            bytes.len(),
+            8.0 * bytes.len() as f64 / pixels,
+            image.bit_depth() as usize * image.planes.len()
+        );
+    }
+    Ok(())
+}
+
+pub fn decompress(args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    let files = args.positional(&["<input>", "<output.pnm>"])?;
+
+    let input = match File::open(&files[0]) {
+        Err(e) => return Err(format!("Error opening {}: {}", files[0], e).into()),
+        Ok(f) => BufReader::new(f),
+    };
+    let image = lossless::decode(input, MAX_SAMPLES)?;
+
+    let mut output = match File::create(&files[1]) {
+        Err(e) => return Err(format!("Error creating {}: {}", files[1], e).into()),
+        Ok(f) => BufWriter::new(f),
+    };
+    write_pnm(&image, Encoding::Binary, &mut output)?;
+    output.flush()?;
+    Ok(())
+}
@@ -0,0 +1,90 @@
+use std::env;
+
+use toy_ac::args::Args;
+
+mod analyze;
+mod compare;
+mod convert;
+mod image;
+mod motion;
+mod rd;
+mod video;
+
+const USAGE: &str = "Usage: toy-ac <command> [options]
+
+Commands:
+  analyze <file> [--json]    Report entropy and model costs without compressing
+  bd-rate <anchor.csv> <test.csv> [--metric psnr_y|psnr|ssim|ms_ssim]
+                             Bjontegaard delta rate and quality of two rd-curve outputs
+  compare <reference.y4m> <distorted.y4m> [--csv]
+                             PSNR, SSIM and MS-SSIM per frame and on average
+  convert <input> <output>   Convert between .y4m, raw .yuv and .pgm/.ppm files
+      [--width W --height H --pix-fmt i420|nv12|yuv444 --bit-depth 8|10]
+      [--fps N:D]            Geometry of raw files and frame rate for y4m output
+      [--chroma 420|422|444|mono --range limited|full]
+                             YCbCr layout and range for images read or written
+      [--frame N --ascii]    Frame to write to an image, and plain P2/P3 output
+  image-compress <input.pnm> <output> [--quiet]
+                             Lossless image coding, reporting bits per pixel
+  image-decompress <input> <output.pnm>
+  motion <input.y4m>         Report motion vector and residual bits per frame
+      [--block 8|16 --range N --search full|tss|diamond --metric sad|ssd --frames N]
+      [--precision 1|1/2|1/4]
+                             Vector precision in pixels (default 1)
+  rd-curve <input.y4m> [--qps 22,27,32,37] [video-encode options]
+                             Rate and quality at each QP, as CSV
+  video-encode <input.y4m> <output>
+                             Lossy video coding with I-, P- and B-frames
+      [--qp 0-51 --gop N]    Constant QP and I-frame interval (0 for only the first)
+      [--b-frames N]         B-frames between I- and P-frames (2 for IBBP)
+      [--scene-cut R]        SAD ratio above which a frame starts a new scene as an
+                             I-frame (default 0.7, 0 for no detection)
+      [--bitrate KBPS --vbv-size KBIT]
+                             Rate control instead of constant QP, with a buffer
+                             of one second unless given (0 for none)
+      [--decision sad|rd]    Macroblock and intra mode decision
+      [--deblock on|off|B,T] In-loop deblocking, or its beta and tc offsets (-6 to 6)
+      [--block 8|16 --range N --search full|tss|diamond --metric sad|ssd]
+      [--precision 1|1/2|1/4]
+                             Vector precision in pixels (default 1/4)
+      [--frames N --recon <recon.y4m> --quiet]
+  video-decode <input> <output.y4m>";
+
+fn main() {
+    let mut argv = env::args().skip(1);
+    let command = match argv.next() {
+        Some(c) => c,
+        None => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    };
+    let args = Args::new(argv.collect());
+
+    let result = match command.as_str() {
+        "analyze" => analyze::run(args),
+        "bd-rate" => rd::bd(args),
+        "compare" => compare::run(args),
+        "convert" => convert::run(args),
+        "image-compress" => image::compress(args),
+        "image-decompress" => image::decompress(args),
+        "motion" => motion::run(args),
+        "rd-curve" => rd::curve(args),
+        "video-encode" => video::encode(args),
+        "video-decode" => video::decode(args),
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => {
+            eprintln!("Unknown command {}\n\n{}", command, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    // Report errors with Display rather than the Debug output main would use.
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,77 @@
+use std::fs::File;
+use std::io::BufReader;
+
+use bitbit::BitWriter;
+use toy_ac::args::Args;
+use toy_ac::encoder::Encoder;
+use toy_ac::video::inter::InterEncoder;
+use toy_ac::video::motion::{Metric, SearchMethod, SearchParams};
+use toy_ac::video::y4m::Y4mReader;
+
+// Codes each frame of a y4m file from the frame before it and reports what
+// the motion vectors and residuals cost.
+pub fn run(mut args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    let defaults = SearchParams::default();
+    let params = SearchParams {
+        block_size: args.value_or("block", defaults.block_size)?,
+        range: args.value_or("range", defaults.range)?,
+        method: args.value_or("search", SearchMethod::Diamond)?,
+        metric: args.value_or("metric", Metric::Sad)?,
+        precision: args.value_or("precision", defaults.precision)?,
+    };
+    let max_frames: usize = args.value_or("frames", usize::MAX)?;
+    let files = args.positional(&["<input.y4m>"])?;
+    if params.block_size != 8 && params.block_size != 16 {
+        return Err("Block size must be 8 or 16".into());
+    }
+
+    let input = match File::open(&files[0]) {
+        Err(e) => return Err(format!("Error opening {}: {}", files[0], e).into()),
+        Ok(f) => BufReader::new(f),
+    };
+    let mut frames = Y4mReader::new(input)?.take(max_frames);
+    let mut previous = match frames.next() {
+        Some(f) => f?,
+        None => return Err("Input has no frames".into()),
+    };
+
+    let mut encoder = InterEncoder::new(params);
+    let mut enc = Encoder::new();
+    let mut bw = BitWriter::new(std::io::sink());
+    let (mut total_vectors, mut total_mv_bits, mut total_residual_bits) = (0u64, 0u64, 0u64);
+
+    println!(
+        "{:>6} {:>8} {:>10} {:>8} {:>14} {:>10}",
+        "Frame", "Vectors", "MV bits", "Bits/MV", "Residual bits", "Bits/pixel"
+    );
+    for (i, frame) in frames.enumerate() {
+        let frame = frame?;
+        let stats = encoder.encode_frame(&frame, &previous, &mut enc, &mut bw);
+        let vectors = (frame.width().div_ceil(params.block_size) * frame.height().div_ceil(params.block_size)) as u64;
+        let pixels = (frame.width() * frame.height()) as f64;
+        println!(
+            "{:>6} {:>8} {:>10} {:>8.3} {:>14} {:>10.4}",
+            i + 1,
+            vectors,
+            stats.mv_bits,
+            stats.mv_bits as f64 / vectors as f64,
+            stats.residual_bits,
+            (stats.mv_bits + stats.residual_bits) as f64 / pixels
+        );
+        total_vectors += vectors;
+        total_mv_bits += stats.mv_bits;
+        total_residual_bits += stats.residual_bits;
+        previous = frame;
+    }
+
+    if total_vectors > 0 {
+        println!();
+        println!(
+            "Average {:.3} bits per motion vector ({} vectors), {:.1}% of inter bits spent on vectors",
+            total_mv_bits as f64 / total_vectors as f64,
+            total_vectors,
+            100.0 * total_mv_bits as f64 / (total_mv_bits + total_residual_bits) as f64
+        );
+    }
+    Ok(())
+}
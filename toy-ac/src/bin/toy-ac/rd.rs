@@ -0,0 +1,117 @@
+use std::fs::{self, File};
+use std::io::BufReader;
+
+use toy_ac::args::Args;
+use toy_ac::metrics::{average, compare_frames};
+use toy_ac::rd::{RdPoint, bd_quality, bd_rate};
+use toy_ac::video::Frame;
+use toy_ac::video::codec::{EncoderConfig, HEADER_LENGTH, StreamHeader, VideoEncoder};
+use toy_ac::video::y4m::Y4mReader;
+
+use crate::video::encoder_config;
+
+const CSV_HEADER: &str = "qp,bytes,kbps,psnr_y,psnr,ssim,ms_ssim";
+
+// Encodes a y4m file at each of a list of QPs and writes one CSV line of
+// rate and quality per QP. The rate is the exact size of the stream
+// video-encode would write.
+pub fn curve(mut args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    let qps: String = args.value_or("qps", "22,27,32,37".to_string())?;
+    let config = encoder_config(&mut args)?;
+    let max_frames: usize = args.value_or("frames", usize::MAX)?;
+    let files = args.positional(&["<input.y4m>"])?;
+
+    let qps = match qps.split(',').map(|q| q.trim().parse::<u8>()).collect::<Result<Vec<_>, _>>() {
+        Ok(q) if !q.is_empty() => q,
+        _ => return Err(format!("Bad QP list {}", qps).into()),
+    };
+    let reader = match File::open(&files[0]) {
+        Err(e) => return Err(format!("Error opening {}: {}", files[0], e).into()),
+        Ok(f) => Y4mReader::new(BufReader::new(f))?,
+    };
+    let frame_rate = reader.header().frame_rate;
+    if frame_rate.0 == 0 || frame_rate.1 == 0 {
+        return Err("Input has no frame rate".into());
+    }
+    let frames = reader.take(max_frames).collect::<Result<Vec<Frame>, _>>()?;
+    if frames.is_empty() {
+        return Err("Input has no frames".into());
+    }
+    let seconds = frames.len() as f64 * frame_rate.1 as f64 / frame_rate.0 as f64;
+
+    println!("{}", CSV_HEADER);
+    for qp in qps {
+        let config = EncoderConfig { qp, ..config };
+        let header = StreamHeader::new(&frames[0], &config.search, frame_rate);
+        let mut encoder = VideoEncoder::new(header, config)?;
+        let mut bytes = HEADER_LENGTH as u64;
+        let mut metrics = Vec::with_capacity(frames.len());
+        let mut encoded = Vec::with_capacity(frames.len());
+        for frame in frames.iter() {
+            encoded.extend(encoder.encode_frame(frame)?);
+        }
+        encoded.extend(encoder.flush()?);
+        for e in encoded.iter() {
+            bytes += e.data.len() as u64 + 4;
+            metrics.push(compare_frames(&frames[e.timestamp as usize], &e.reconstruction));
+        }
+        let m = average(&metrics).unwrap();
+        println!(
+            "{},{},{:.3},{:.4},{:.4},{:.6},{:.6}",
+            qp,
+            bytes,
+            8.0 * bytes as f64 / seconds / 1000.0,
+            m.psnr[0],
+            m.psnr_weighted,
+            m.ssim_weighted,
+            m.ms_ssim
+        );
+    }
+    Ok(())
+}
+
+// Reads the rate (kbps) and the named quality column from rd-curve output.
+fn read_points(path: &str, metric: &str) -> Result<Vec<RdPoint>, Box<dyn std::error::Error>> {
+    let text = match fs::read_to_string(path) {
+        Err(e) => return Err(format!("Error reading {}: {}", path, e).into()),
+        Ok(t) => t,
+    };
+    let mut lines = text.lines().filter(|l| !l.trim().is_empty());
+    let columns: Vec<&str> = match lines.next() {
+        Some(header) => header.split(',').map(|c| c.trim()).collect(),
+        None => return Err(format!("{} is empty", path).into()),
+    };
+    let find = |name: &str| match columns.iter().position(|c| *c == name) {
+        Some(i) => Ok(i),
+        None => Err(format!("{} has no {} column", path, name)),
+    };
+    let (rate, quality) = (find("kbps")?, find(metric)?);
+
+    let mut points = Vec::new();
+    for (i, line) in lines.enumerate() {
+        let fields: Vec<&str> = line.split(',').map(|f| f.trim()).collect();
+        let value = |c: usize| fields.get(c).and_then(|f| f.parse::<f64>().ok());
+        match (value(rate), value(quality)) {
+            (Some(rate), Some(quality)) => points.push(RdPoint { rate, quality }),
+            _ => return Err(format!("{}: bad values on line {}", path, i + 2).into()),
+        }
+    }
+    Ok(points)
+}
+
+// Compares two rd-curve outputs by their Bjøntegaard delta rate and quality.
+pub fn bd(mut args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    let metric: String = args.value_or("metric", "psnr_y".to_string())?;
+    let files = args.positional(&["<anchor.csv>", "<test.csv>"])?;
+
+    let anchor = read_points(&files[0], &metric)?;
+    let test = read_points(&files[1], &metric)?;
+    match (bd_rate(&anchor, &test), bd_quality(&anchor, &test)) {
+        (Some(rate), Some(quality)) => {
+            println!("BD-rate ({}): {:+.2}%", metric, rate);
+            println!("BD-quality ({}): {:+.4}", metric, quality);
+            Ok(())
+        }
+        _ => Err("Curves need at least two distinct points each, positive rates and overlapping ranges".into()),
+    }
+}
@@ -0,0 +1,177 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+
+use toy_ac::args::Args;
+use toy_ac::video::codec::{
+    EncoderConfig, HEADER_LENGTH, ReorderBuffer, StreamHeader, VideoDecoder, VideoEncoder, read_frame, write_frame,
+};
+use toy_ac::video::motion::SearchParams;
+use toy_ac::video::ratecontrol::{RateControlledEncoder, RateController, RateParams};
+use toy_ac::video::y4m::{Y4mHeader, Y4mReader, Y4mWriter};
+
+// Refuse to decode streams with frames of more luma samples than this, to
+// bound memory use on corrupt input.
+const MAX_SAMPLES: u64 = 1 << 28;
+
+fn open(path: &str) -> Result<BufReader<File>, Box<dyn std::error::Error>> {
+    match File::open(path) {
+        Err(e) => Err(format!("Error opening {}: {}", path, e).into()),
+        Ok(f) => Ok(BufReader::new(f)),
+    }
+}
+
+fn create(path: &str) -> Result<BufWriter<File>, Box<dyn std::error::Error>> {
+    match File::create(path) {
+        Err(e) => Err(format!("Error creating {}: {}", path, e).into()),
+        Ok(f) => Ok(BufWriter::new(f)),
+    }
+}
+
+enum Encoder {
+    Fixed(Box<VideoEncoder>),
+    Controlled(Box<RateControlledEncoder>),
+}
+
+// The encoder options shared by the commands that run the encoder.
+pub fn encoder_config(args: &mut Args) -> Result<EncoderConfig, String> {
+    let defaults = EncoderConfig::default();
+    Ok(EncoderConfig {
+        qp: args.value_or("qp", defaults.qp)?,
+        gop: args.value_or("gop", defaults.gop)?,
+        b_frames: args.value_or("b-frames", defaults.b_frames)?,
+        scene_cut: args.value_or("scene-cut", defaults.scene_cut)?,
+        search: SearchParams {
+            block_size: args.value_or("block", defaults.search.block_size)?,
+            range: args.value_or("range", defaults.search.range)?,
+            method: args.value_or("search", defaults.search.method)?,
+            metric: args.value_or("metric", defaults.search.metric)?,
+            precision: args.value_or("precision", defaults.search.precision)?,
+        },
+        decision: args.value_or("decision", defaults.decision)?,
+        deblock: args.value_or("deblock", defaults.deblock)?,
+    })
+}
+
+// Lossy coding of a y4m file at a constant QP, or at the QPs rate control
+// picks to meet --bitrate, reporting the size of each frame in coding
+// order. --recon writes the encoder's reconstruction in display order, which
+// is exactly what video-decode produces from the output.
+pub fn encode(mut args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    let config = encoder_config(&mut args)?;
+    let bitrate: Option<f64> = args.value("bitrate")?;
+    let vbv_size: Option<f64> = args.value("vbv-size")?;
+    let max_frames: usize = args.value_or("frames", usize::MAX)?;
+    let recon_path: Option<String> = args.value("recon")?;
+    let quiet = args.flag("quiet");
+    let files = args.positional(&["<input.y4m>", "<output>"])?;
+
+    let reader = Y4mReader::new(open(&files[0])?)?;
+    let frame_rate = reader.header().frame_rate;
+    let mut frames = reader.take(max_frames);
+    let first = match frames.next() {
+        Some(f) => f?,
+        None => return Err("Input has no frames".into()),
+    };
+
+    let header = StreamHeader::new(&first, &config.search, frame_rate);
+    let encoder = VideoEncoder::new(header.clone(), config)?;
+    let mut encoder = match bitrate {
+        Some(kbps) if kbps > 0.0 && frame_rate.0 > 0 && frame_rate.1 > 0 => {
+            let mut params = RateParams::new(kbps * 1000.0, frame_rate);
+            if let Some(size) = vbv_size {
+                params.vbv_size = if size > 0.0 { Some(size * 1000.0) } else { None };
+            }
+            let control = RateController::new(params, header.width * header.height, config.gop);
+            Encoder::Controlled(Box::new(RateControlledEncoder::new(encoder, control)))
+        }
+        Some(_) => return Err("--bitrate needs a positive rate and an input with a frame rate".into()),
+        None => Encoder::Fixed(Box::new(encoder)),
+    };
+    let mut output = create(&files[1])?;
+    header.write(&mut output)?;
+    let mut recon = match recon_path {
+        Some(path) => Some(Y4mWriter::new(create(&path)?, Y4mHeader::for_frame(&first, frame_rate))?),
+        None => None,
+    };
+
+    if !quiet {
+        println!("{:>6} {:>4} {:>4} {:>10}", "Frame", "Type", "QP", "Bytes");
+    }
+    let (mut count, mut total_bytes) = (0usize, HEADER_LENGTH as u64);
+    let mut reorder = ReorderBuffer::new();
+    let mut frames = std::iter::once(Ok(first)).chain(frames);
+    loop {
+        let next = frames.next().transpose()?;
+        let encoded = match (&mut encoder, &next) {
+            (Encoder::Fixed(e), Some(frame)) => e.encode_frame(frame)?,
+            (Encoder::Fixed(e), None) => e.flush()?,
+            (Encoder::Controlled(e), Some(frame)) => e.encode_frame(frame)?,
+            (Encoder::Controlled(e), None) => e.flush()?,
+        };
+        for encoded in encoded {
+            write_frame(&mut output, &encoded.data)?;
+            if let Some(writer) = recon.as_mut() {
+                for frame in reorder.push(encoded.timestamp, encoded.reconstruction) {
+                    writer.write_frame(&frame)?;
+                }
+            }
+            if !quiet {
+                println!("{:>6} {:>4} {:>4} {:>10}", encoded.timestamp, encoded.frame_type, encoded.qp, encoded.data.len());
+            }
+            count += 1;
+            total_bytes += encoded.data.len() as u64 + 4;
+        }
+        if next.is_none() {
+            break;
+        }
+    }
+    output.flush()?;
+    if let Some(mut writer) = recon {
+        for frame in reorder.flush() {
+            writer.write_frame(&frame)?;
+        }
+        writer.flush()?;
+    }
+
+    if !quiet {
+        let pixels = (header.width * header.height * count) as f64;
+        println!();
+        print!("{} frames, {} bytes, {:.4} bits/pixel", count, total_bytes, 8.0 * total_bytes as f64 / pixels);
+        if frame_rate.0 > 0 && frame_rate.1 > 0 {
+            let seconds = count as f64 * frame_rate.1 as f64 / frame_rate.0 as f64;
+            print!(", {:.1} kbit/s", 8.0 * total_bytes as f64 / seconds / 1000.0);
+        }
+        println!();
+        if let Encoder::Controlled(e) = &encoder {
+            let control = e.control();
+            print!("Target {:.1} kbit/s", control.params().bitrate / 1000.0);
+            if let Some(size) = control.params().vbv_size {
+                print!(", VBV {:.1} kbit with {} underflows", size / 1000.0, control.underflows());
+            }
+            println!();
+        }
+    }
+    Ok(())
+}
+
+pub fn decode(args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    let files = args.positional(&["<input>", "<output.y4m>"])?;
+
+    let mut input = open(&files[0])?;
+    let header = StreamHeader::read(&mut input, MAX_SAMPLES)?;
+    let y4m_header = Y4mHeader::new(header.width, header.height, header.chroma, header.bit_depth, header.frame_rate);
+    let mut output = Y4mWriter::new(create(&files[1])?, y4m_header)?;
+    let mut decoder = VideoDecoder::new(header);
+    let mut index = 0;
+    while let Some(data) = read_frame(&mut input, index)? {
+        for frame in decoder.decode_frame(&data)? {
+            output.write_frame(&frame)?;
+        }
+        index += 1;
+    }
+    for frame in decoder.flush() {
+        output.write_frame(&frame)?;
+    }
+    output.flush()?;
+    Ok(())
+}
@@ -0,0 +1,103 @@
+// Values coded as a bucket, the bit length of the value, under an adaptive
+// model followed by the bits below the leading one as plain bits. Small
+// values cost little once the model has adapted, and large ones cost about
+// twice their bit length at worst. Signed values are folded to
+// non-negative ones first: 0, -1, 1, -2, 2, ... map to 0, 1, 2, 3, 4, ...
+
+use crate::decoder::{DecodeError, Decoder};
+use crate::encoder::Encoder;
+use crate::symbol_model::VectorCountSymbolModel;
+use bitbit::reader::Bit;
+use bitbit::{BitReader, BitWriter};
+use std::io::{Read, Write};
+
+pub(crate) const MAX_BUCKET: u8 = 32;
+
+pub(crate) fn bucket_model() -> VectorCountSymbolModel<u8> {
+    VectorCountSymbolModel::new((0..=MAX_BUCKET).collect())
+}
+
+pub(crate) fn bucket(v: u32) -> u8 {
+    (32 - v.leading_zeros()) as u8
+}
+
+pub(crate) fn fold(v: i32) -> u32 {
+    if v >= 0 { 2 * v as u32 } else { (-2 * v as i64 - 1) as u32 }
+}
+
+pub(crate) fn unfold(folded: u32) -> i32 {
+    // In i64 so that u32::MAX unfolds to i32::MIN rather than overflowing.
+    if folded & 0x1 == 0 { (folded / 2) as i32 } else { -(folded.div_ceil(2) as i64) as i32 }
+}
+
+pub(crate) fn encode_unsigned<W: Write>(v: u32, model: &mut VectorCountSymbolModel<u8>, enc: &mut Encoder, output: &mut BitWriter<W>) {
+    let b = bucket(v);
+    enc.encode(&b, model, output);
+    model.incr_count(&b);
+    if b > 1 {
+        enc.encode_bits(v as u64, b as u32 - 1, output);
+    }
+}
+
+pub(crate) fn decode_unsigned<R: Read, B: Bit>(
+    model: &mut VectorCountSymbolModel<u8>,
+    dec: &mut Decoder,
+    input: &mut BitReader<R, B>,
+) -> Result<u32, DecodeError> {
+    let b = *dec.decode(model, input)?;
+    model.incr_count(&b);
+    Ok(match b {
+        0 | 1 => b as u32,
+        _ => (1u32 << (b - 1)) | dec.decode_bits(b as u32 - 1, input)? as u32,
+    })
+}
+
+pub(crate) fn encode_signed<W: Write>(v: i32, model: &mut VectorCountSymbolModel<u8>, enc: &mut Encoder, output: &mut BitWriter<W>) {
+    encode_unsigned(fold(v), model, enc, output);
+}
+
+pub(crate) fn decode_signed<R: Read, B: Bit>(
+    model: &mut VectorCountSymbolModel<u8>,
+    dec: &mut Decoder,
+    input: &mut BitReader<R, B>,
+) -> Result<i32, DecodeError> {
+    Ok(unfold(decode_unsigned(model, dec, input)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitbit::MSB;
+
+    #[test]
+    fn fold_is_invertible() {
+        for v in [0, 1, -1, 255, -255, i32::MAX, i32::MIN + 1, i32::MIN] {
+            assert_eq!(unfold(fold(v)), v);
+        }
+        assert_eq!(unfold(u32::MAX), i32::MIN);
+        assert_eq!([0, -1, 1, -2, 2].map(fold), [0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn values_round_trip() {
+        let values = [0u32, 1, 2, 3, 1000, u32::MAX, 7, 0];
+        let mut bytes = Vec::new();
+        let mut bw = BitWriter::new(&mut bytes);
+        let mut enc = Encoder::new();
+        let mut model = bucket_model();
+        for &v in values.iter() {
+            encode_unsigned(v, &mut model, &mut enc, &mut bw);
+            encode_signed(-(v as i32 / 2), &mut model, &mut enc, &mut bw);
+        }
+        enc.finish(&mut bw).unwrap();
+        bw.pad_to_byte().unwrap();
+
+        let mut br: BitReader<_, MSB> = BitReader::new(&bytes[..]);
+        let mut dec = Decoder::new();
+        let mut model = bucket_model();
+        for &v in values.iter() {
+            assert_eq!(decode_unsigned(&mut model, &mut dec, &mut br).unwrap(), v);
+            assert_eq!(decode_signed(&mut model, &mut dec, &mut br).unwrap(), -(v as i32 / 2));
+        }
+    }
+}
@@ -0,0 +1,120 @@
+// The byte stream format written by shakes_compress: the number of encoded
+// bytes as a big-endian u64, followed by the arithmetic coded bits for each
+// byte under an adaptive order-0 model, padded to a whole byte.
+
+use super::decoder::{DecodeError, Decoder};
+use super::encoder::Encoder;
+use super::symbol_model::VectorCountSymbolModel;
+use bitbit::{BitReader, BitWriter, MSB};
+use std::fmt;
+use std::io::{self, Read, Write};
+
+pub const HEADER_LENGTH: usize = 8;
+
+#[derive(Debug)]
+pub enum ContainerError {
+    TruncatedHeader,
+    TooLong(u64),
+    Io(io::Error),
+    Decode { position: u64, error: DecodeError },
+}
+
+impl fmt::Display for ContainerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContainerError::TruncatedHeader => write!(f, "input shorter than the {} byte header", HEADER_LENGTH),
+            ContainerError::TooLong(length) => write!(f, "header length {} exceeds the allowed maximum", length),
+            ContainerError::Io(e) => write!(f, "{}", e),
+            ContainerError::Decode { position, error } => write!(f, "error decoding byte {}: {}", position, error),
+        }
+    }
+}
+
+impl std::error::Error for ContainerError {}
+
+impl From<io::Error> for ContainerError {
+    fn from(e: io::Error) -> Self {
+        ContainerError::Io(e)
+    }
+}
+
+pub fn byte_model() -> VectorCountSymbolModel<u8> {
+    VectorCountSymbolModel::new((0..=255).collect())
+}
+
+pub fn write_header<W: Write>(output: &mut W, length: u64) -> io::Result<()> {
+    output.write_all(&length.to_be_bytes())
+}
+
+pub fn read_header<R: Read>(input: &mut R) -> Result<u64, ContainerError> {
+    let mut size_bytes: [u8; HEADER_LENGTH] = [0; HEADER_LENGTH];
+    match input.read_exact(&mut size_bytes) {
+        Ok(()) => Ok(u64::from_be_bytes(size_bytes)),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Err(ContainerError::TruncatedHeader),
+        Err(e) => Err(ContainerError::Io(e)),
+    }
+}
+
+pub fn encode_bytes<W: Write>(data: &[u8], output: &mut W) -> io::Result<()> {
+    write_header(output, data.len() as u64)?;
+
+    let mut sm = byte_model();
+    let mut enc = Encoder::new();
+    let mut bw = BitWriter::new(output);
+    enc.encode_all(data, &mut sm, &mut bw);
+    if let Err(e) = enc.finish(&mut bw) {
+        return Err(io::Error::other(e.to_string()));
+    }
+    bw.pad_to_byte()
+}
+
+// Decodes a whole stream. Headers claiming more than max_length bytes are
+// rejected before anything is decoded, so untrusted input cannot make this
+// run for longer than it takes to decode max_length symbols.
+pub fn decode_bytes<R: Read>(mut input: R, max_length: u64) -> Result<Vec<u8>, ContainerError> {
+    let length = read_header(&mut input)?;
+    if length > max_length {
+        return Err(ContainerError::TooLong(length));
+    }
+
+    let mut sm = byte_model();
+    let mut dec = Decoder::new();
+    let mut br: BitReader<_, MSB> = BitReader::new(input);
+    let mut output = Vec::new();
+    for position in 0..length {
+        let b = match dec.decode(&sm, &mut br) {
+            Ok(b) => *b,
+            Err(error) => return Err(ContainerError::Decode { position, error }),
+        };
+        sm.incr_count(&b);
+        output.push(b);
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let data = b"To be, or not to be, that is the question".to_vec();
+        let mut bytes = Vec::new();
+        encode_bytes(&data, &mut bytes).unwrap();
+        assert_eq!(decode_bytes(&bytes[..], 1000).unwrap(), data);
+    }
+
+    #[test]
+    fn malformed_input_is_an_error() {
+        assert!(matches!(decode_bytes(&[0u8, 0, 0][..], 1000), Err(ContainerError::TruncatedHeader)));
+        assert!(matches!(decode_bytes(&[0xffu8; 8][..], 1000), Err(ContainerError::TooLong(_))));
+
+        let mut bytes = Vec::new();
+        encode_bytes(b"hello, world", &mut bytes).unwrap();
+        bytes.truncate(HEADER_LENGTH + 2);
+        assert!(matches!(
+            decode_bytes(&bytes[..], 1000),
+            Err(ContainerError::Decode { error: DecodeError::UnexpectedEof, .. })
+        ));
+    }
+}
@@ -1,84 +1,185 @@
 use super::range::Range;
-use super::symbol_model::SymbolModel;
+use super::symbol_model::{AdaptiveModel, SymbolModel};
 use bitbit::BitReader;
 use bitbit::reader::Bit;
+use std::fmt;
 use std::io::Read;
 
+// Ways a stream can fail to decode. A well-formed stream decoded with the
+// same models and precision it was encoded with never produces any of these.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    // Ran out of input before the symbol could be resolved.
+    UnexpectedEof,
+    // The decoding buffer left the coding range, which means the input was
+    // not produced by an encoder in the same state as this decoder.
+    Desync,
+    // The model cannot be coded at this precision (or is empty).
+    UnsupportedModel,
+    // The model's lookup returned an interval that is empty or outside its total.
+    InvalidInterval,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnexpectedEof => write!(f, "unexpected end of input"),
+            DecodeError::Desync => write!(f, "decoder buffer left the coding range"),
+            DecodeError::UnsupportedModel => write!(f, "model total is zero or too large for decoder precision"),
+            DecodeError::InvalidInterval => write!(f, "model returned an invalid interval"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
 #[derive(Debug)]
 pub struct Decoder {
     range: Range,
-    buffer: u32,
+    buffer: u64,
     initialized: bool,
 }
 
 impl Decoder {
     pub fn new() -> Self {
+        Self::with_precision(32)
+    }
+
+    // Must match the precision the stream was encoded with.
+    pub fn with_precision(precision: u32) -> Self {
         Self {
-            range: Range::new(32),
+            range: Range::new(precision),
             buffer: 0x0,
             initialized: false,
         }
     }
 
+    fn hob_mask(&self) -> u64 {
+        0x1 << (self.range.buffer_width() - 1)
+    }
+
+    fn buffer_mask(&self) -> u64 {
+        0xffffffffffffffff >> (64 - self.range.buffer_width())
+    }
+
     pub fn decode<'a, T: Eq, R: Read, B: Bit>(
         &mut self,
         m: &'a dyn SymbolModel<T>,
         input: &mut BitReader<R, B>,
-    ) -> &'a T {
+    ) -> Result<&'a T, DecodeError> {
+        let total = m.total();
+        let v = self.target(total, input)?;
+        let (result, int_start, int_end) = m.lookup(v);
+        self.narrow(int_start, int_end, total, input)?;
+        Ok(result)
+    }
+
+    // Fills output with decoded symbols, updating the model after each one.
+    // The counterpart of Encoder::encode_all.
+    pub fn decode_into<T: Eq + Clone, M: AdaptiveModel<T>, R: Read, B: Bit>(
+        &mut self,
+        output: &mut [T],
+        m: &mut M,
+        input: &mut BitReader<R, B>,
+    ) -> Result<(), DecodeError> {
+        for slot in output.iter_mut() {
+            let total = m.total();
+            let v = self.target(total, input)?;
+            let (result, int_start, int_end) = m.lookup_and_update(v);
+            self.narrow(int_start, int_end, total, input)?;
+            *slot = result;
+        }
+        Ok(())
+    }
+
+    // Reads count bits coded with Encoder::encode_bits.
+    pub fn decode_bits<R: Read, B: Bit>(&mut self, count: u32, input: &mut BitReader<R, B>) -> Result<u64, DecodeError> {
+        let mut value = 0;
+        for _ in 0..count {
+            let bit = self.target(2, input)?;
+            self.narrow(bit, bit + 1, 2, input)?;
+            value = value << 1 | bit as u64;
+        }
+        Ok(value)
+    }
+
+    // The value in [0, total) that identifies the next symbol's interval.
+    fn target<R: Read, B: Bit>(&mut self, total: u32, input: &mut BitReader<R, B>) -> Result<u32, DecodeError> {
         // Load bits if first time
         if !self.initialized {
-            match input.read_bits(32) {
-                Ok(bits) => self.buffer = bits,
-                Err(_) => panic!("Must have at least 32 bits to read initially."),
+            for _ in 0..self.range.buffer_width() {
+                match input.read_bit() {
+                    Ok(bit) => self.buffer = self.buffer << 1 | if bit { 0x1 } else { 0x0 },
+                    Err(_) => return Err(DecodeError::UnexpectedEof),
+                }
             }
             self.initialized = true;
         }
         // Bits in decoding buffer should always be in range [low, high]
-        assert!(self.buffer as u64 >= self.range.low());
-        assert!(self.buffer as u64 <= self.range.high());
-        
+        if self.buffer < self.range.low() || self.buffer > self.range.high() {
+            return Err(DecodeError::Desync);
+        }
+        if total == 0 || total as u64 > self.range.max_total() {
+            return Err(DecodeError::UnsupportedModel);
+        }
 
+        let range_width = self.range.width() as u128;
+        let offset = (self.buffer - self.range.low()) as u128;
+        Ok((((offset+1) * total as u128 - 1) / range_width) as u32)
+    }
+
+    // Narrows the range to the decoded symbol's interval and shifts in bits
+    // exactly as the encoder shifted them out.
+    fn narrow<R: Read, B: Bit>(
+        &mut self,
+        int_start: u32,
+        int_end: u32,
+        total: u32,
+        input: &mut BitReader<R, B>,
+    ) -> Result<(), DecodeError> {
+        if int_start >= int_end || int_end > total {
+            return Err(DecodeError::InvalidInterval);
+        }
 
-        let range_width = self.range.width();
+        let range_width = self.range.width() as u128;
         let low = self.range.low();
-        let total = m.total() as u64;
-        let offset = self.buffer as u64 - low;
+        let total = total as u128;
 
-        let v = (((offset+1)* total -1) / range_width) as u32;
-        let (result, int_start, int_end) = m.lookup(v);
+        let new_low = low + ((range_width * int_start as u128) / total) as u64;
+        let new_high = low + ((range_width * int_end as u128) / total) as u64 - 1;
 
-        let new_low = low + (range_width * int_start as u64) / total;
-        let new_high = low + (range_width * int_end as u64) / total  - 1;
+        let hob_mask = self.hob_mask();
+        let buffer_mask = self.buffer_mask();
 
         self.range.reduce(new_high, new_low);
         while self.range.hob_match() {
             let is_one = self.range.shift_hob();
-            assert!(is_one == (self.buffer & 0x80000000 != 0));
+            if is_one != (self.buffer & hob_mask != 0) {
+                return Err(DecodeError::Desync);
+            }
 
             match input.read_bit() {
-                Ok(bit) => self.buffer = self.buffer << 1 | if bit { 0x1 } else { 0x0 },
-                Err(_) => panic!("Error reading bit"),
+                Ok(bit) => self.buffer = (self.buffer << 1 | if bit { 0x1 } else { 0x0 }) & buffer_mask,
+                Err(_) => return Err(DecodeError::UnexpectedEof),
             }
         }
 
-        assert!(!self.range.hob_match());
-
         while self.range.in_middle() {
             self.range.shift_sob();
-            let buffer_hob_is_one = (self.buffer & 0x80000000) != 0;
+            let buffer_hob_is_one = (self.buffer & hob_mask) != 0;
             match input.read_bit() {
                 Ok(bit) => {
-                    self.buffer = self.buffer << 1 | if bit { 0x1 } else { 0x0 };
+                    self.buffer = (self.buffer << 1 | if bit { 0x1 } else { 0x0 }) & buffer_mask;
                     if buffer_hob_is_one {
-                        self.buffer |= 0x80000000
+                        self.buffer |= hob_mask
                     } else {
-                        self.buffer &= !0x80000000
+                        self.buffer &= !hob_mask
                     }
                 }
-                Err(_) => panic!("Erorr reading bit"),
+                Err(_) => return Err(DecodeError::UnexpectedEof),
             }
         }
-        return result;
+        Ok(())
     }
 
     pub fn high(&self) -> u64 {
@@ -89,7 +190,7 @@ impl Decoder {
         self.range.low()
     }
 
-    pub fn buffer(&self) -> u32 {
+    pub fn buffer(&self) -> u64 {
         self.buffer
     }
 }
@@ -1,39 +1,79 @@
 use super::range::Range;
-use super::symbol_model::SymbolModel;
+use super::symbol_model::{AdaptiveModel, SymbolModel};
 use std::io::Write;
 use bitbit::BitWriter;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Encoder {
     range: Range,
     pending: u32,
+    bits: u64,
     finished: bool
 }
 
 impl Encoder {
     pub fn new() -> Self {
+        Self::with_precision(32)
+    }
+
+    // Precision is the width in bits of the coding range and can be anything
+    // Range allows (2 to 63). Every model used with the encoder must have a
+    // total no larger than 2^(precision-2).
+    pub fn with_precision(precision: u32) -> Self {
         Self {
-            range: Range::new(32),
+            range: Range::new(precision),
             pending: 0,
+            bits: 0,
             finished: false
         }
     }
 
     pub fn encode<T: Eq, W: Write>(&mut self, s: &T, m: &dyn SymbolModel<T>, output: &mut BitWriter<W>) {
-        if self.finished {
-            panic!("Encoder already finished");
-        }
         if !m.contains(s) {
             panic!("Value is not in model");
         }
 
         let (int_start, int_end) = m.interval(s);        
-        let total = m.total() as u64;
-        let range_width = self.range.width();
+        self.encode_interval(int_start, int_end, m.total(), output);
+    }
+
+    // Encodes every symbol in order, updating the model after each one.
+    // Generic over the model so the calls are monomorphized and each symbol
+    // takes a single scan of the model.
+    pub fn encode_all<T: Eq, M: AdaptiveModel<T>, W: Write>(&mut self, symbols: &[T], m: &mut M, output: &mut BitWriter<W>) {
+        for s in symbols {
+            let total = m.total();
+            match m.interval_and_update(s) {
+                Some((int_start, int_end)) => self.encode_interval(int_start, int_end, total, output),
+                None => panic!("Value is not in model"),
+            }
+        }
+    }
+
+    // Codes the low count bits of value, most significant first, each with
+    // probability one half. For bits no model would predict any better, like
+    // the low bits of a large residual.
+    pub fn encode_bits<W: Write>(&mut self, value: u64, count: u32, output: &mut BitWriter<W>) {
+        for i in (0..count).rev() {
+            let bit = ((value >> i) & 0x1) as u32;
+            self.encode_interval(bit, bit + 1, 2, output);
+        }
+    }
+
+    fn encode_interval<W: Write>(&mut self, int_start: u32, int_end: u32, total: u32, output: &mut BitWriter<W>) {
+        if self.finished {
+            panic!("Encoder already finished");
+        }
+
+        let total = total as u64;
+        if total > self.range.max_total() {
+            panic!("Model total too large for encoder precision");
+        }
+        let range_width = self.range.width() as u128;
         let low = self.range.low();
 
-        let new_low = low + (range_width * int_start as u64) / total;
-        let new_high = low + (range_width * int_end as u64) / total - 1;
+        let new_low = low + ((range_width * int_start as u128) / total as u128) as u64;
+        let new_high = low + ((range_width * int_end as u128) / total as u128) as u64 - 1;
 
         self.range.reduce(new_high, new_low);
         if self.range.hob_match() {
@@ -42,9 +82,11 @@ impl Encoder {
             for _ in 0..self.pending {
                 output.write_bit(!is_one).unwrap();
             }
+            self.bits += 1 + self.pending as u64;
             self.pending = 0;
             while self.range.hob_match() {
                 output.write_bit(self.range.shift_hob()).unwrap();
+                self.bits += 1;
             }
         }
         assert!(!self.range.hob_match());
@@ -62,15 +104,34 @@ impl Encoder {
         self.range.low()
     }
 
+    // Number of bits written to the output so far, including the bits
+    // written by finish once it has been called.
+    pub fn bits_written(&self) -> u64 {
+        self.bits
+    }
+
+    // Bits written, plus the pending bits the next output bit will release,
+    // plus the fraction of a bit the range has narrowed by but not yet
+    // output: what the symbols coded so far have cost. Comparing this before
+    // and after coding on a clone of the encoder gives the cost of trial
+    // encodings to within rounding. Only meaningful before finish.
+    pub fn bits_used(&self) -> f64 {
+        let narrowed = self.range.buffer_width() as f64 - (self.range.width() as f64).log2();
+        (self.bits + self.pending as u64) as f64 + narrowed
+    }
+
     pub fn finish<W: Write>(&mut self,  output: &mut BitWriter<W>) -> Result<(), Box<dyn std::error::Error>> {
         // Write out any value between range low and high (0x80000000 for example)
         // plus any pending bits as 0. The correct understanding of this is 
-        // writing out a 1, plus any pending bits as 0, followed by 31 more zeroes.
+        // writing out a 1, plus any pending bits as 0, followed by 31 more zeroes
+        // (or however many fill out the rest of the precision).
 
+        let precision = self.range.buffer_width();
         output.write_bit(true)?;
-        for _ in 0..self.pending+31 {
+        for _ in 0..self.pending+precision-1 {
             output.write_bit(false)?;
         }
+        self.bits += (precision + self.pending) as u64;
 
         self.finished = true;
         Ok(())
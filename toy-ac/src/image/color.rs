@@ -0,0 +1,249 @@
+// Conversion between RGB images and YCbCr frames using the BT.601 matrix.
+// Limited range puts luma in [16, 235] and chroma in [16, 240] (scaled up
+// for higher bit depths), which is what y4m and raw YUV tools assume. Full
+// range uses the whole sample range as JPEG does.
+//
+// Subsampled chroma is the average of the chroma of the pixels it covers,
+// and is replicated back over them when converting to RGB.
+
+use super::Image;
+use crate::video::{ChromaSampling, Frame, Plane};
+use std::str::FromStr;
+
+const KR: f64 = 0.299;
+const KB: f64 = 0.114;
+const KG: f64 = 1.0 - KR - KB;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorRange {
+    Limited,
+    Full,
+}
+
+impl FromStr for ColorRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "limited" | "tv" => Ok(ColorRange::Limited),
+            "full" | "pc" => Ok(ColorRange::Full),
+            _ => Err(format!("unknown color range {}", s)),
+        }
+    }
+}
+
+impl ColorRange {
+    // Luma sample for y in [0, 1].
+    fn luma(&self, y: f64, bit_depth: u8) -> u16 {
+        let v = match self {
+            ColorRange::Limited => (16.0 + 219.0 * y) * scale(bit_depth),
+            ColorRange::Full => y * max_sample(bit_depth),
+        };
+        v.round().clamp(0.0, max_sample(bit_depth)) as u16
+    }
+
+    // Chroma sample for c in [-0.5, 0.5].
+    fn chroma(&self, c: f64, bit_depth: u8) -> u16 {
+        let v = match self {
+            ColorRange::Limited => (128.0 + 224.0 * c) * scale(bit_depth),
+            ColorRange::Full => (1 << (bit_depth - 1)) as f64 + c * max_sample(bit_depth),
+        };
+        v.round().clamp(0.0, max_sample(bit_depth)) as u16
+    }
+
+    fn luma_value(&self, sample: u16, bit_depth: u8) -> f64 {
+        match self {
+            ColorRange::Limited => (sample as f64 / scale(bit_depth) - 16.0) / 219.0,
+            ColorRange::Full => sample as f64 / max_sample(bit_depth),
+        }
+    }
+
+    fn chroma_value(&self, sample: u16, bit_depth: u8) -> f64 {
+        match self {
+            ColorRange::Limited => (sample as f64 / scale(bit_depth) - 128.0) / 224.0,
+            ColorRange::Full => (sample as f64 - (1 << (bit_depth - 1)) as f64) / max_sample(bit_depth),
+        }
+    }
+}
+
+fn scale(bit_depth: u8) -> f64 {
+    (1u32 << bit_depth) as f64 / 256.0
+}
+
+fn max_sample(bit_depth: u8) -> f64 {
+    ((1u32 << bit_depth) - 1) as f64
+}
+
+// Converts a greymap (one plane) or pixmap (three planes, R, G, B) to a
+// frame. The frame is 8-bit, or deeper when maxval needs more bits. Greymaps
+// get neutral chroma. None for any other number of planes.
+pub fn to_frame(image: &Image, chroma: ChromaSampling, range: ColorRange) -> Option<Frame> {
+    if image.planes.len() != 1 && image.planes.len() != 3 {
+        return None;
+    }
+    let (width, height) = (image.width(), image.height());
+    let bit_depth = image.bit_depth().max(8);
+    let max = image.max_value as f64;
+    let mut frame = Frame::new(width, height, chroma, bit_depth);
+
+    let mut cb = vec![0.0; width * height];
+    let mut cr = vec![0.0; width * height];
+    for i in 0..width * height {
+        let (r, g, b) = match image.planes.len() {
+            1 => {
+                let v = image.planes[0].data[i] as f64 / max;
+                (v, v, v)
+            }
+            _ => (
+                image.planes[0].data[i] as f64 / max,
+                image.planes[1].data[i] as f64 / max,
+                image.planes[2].data[i] as f64 / max,
+            ),
+        };
+        let y = KR * r + KG * g + KB * b;
+        frame.y.data[i] = range.luma(y, bit_depth);
+        cb[i] = (b - y) / (2.0 * (1.0 - KB));
+        cr[i] = (r - y) / (2.0 * (1.0 - KR));
+    }
+
+    if chroma != ChromaSampling::Mono {
+        let (sx, sy) = chroma.shifts();
+        for (plane, values) in [(&mut frame.u, &cb), (&mut frame.v, &cr)] {
+            for cy in 0..plane.height {
+                for cx in 0..plane.width {
+                    let mut sum = 0.0;
+                    let mut n = 0;
+                    for y in (cy << sy)..((cy + 1) << sy).min(height) {
+                        for x in (cx << sx)..((cx + 1) << sx).min(width) {
+                            sum += values[y * width + x];
+                            n += 1;
+                        }
+                    }
+                    plane.set(cx, cy, range.chroma(sum / n as f64, bit_depth));
+                }
+            }
+        }
+    }
+    Some(frame)
+}
+
+// Converts a frame to a three plane RGB image with maxval 2^bit_depth - 1.
+// Mono frames come out grey.
+pub fn to_image(frame: &Frame, range: ColorRange) -> Image {
+    let (width, height) = (frame.width(), frame.height());
+    let bit_depth = frame.bit_depth;
+    let max = max_sample(bit_depth);
+    let (sx, sy) = frame.chroma.shifts();
+    let mut image = Image::new(width, height, 3, frame.max_value());
+
+    for y in 0..height {
+        for x in 0..width {
+            let luma = range.luma_value(frame.y.get(x, y), bit_depth);
+            let (cb, cr) = match frame.chroma {
+                ChromaSampling::Mono => (0.0, 0.0),
+                _ => (
+                    range.chroma_value(frame.u.get(x >> sx, y >> sy), bit_depth),
+                    range.chroma_value(frame.v.get(x >> sx, y >> sy), bit_depth),
+                ),
+            };
+            let r = luma + 2.0 * (1.0 - KR) * cr;
+            let b = luma + 2.0 * (1.0 - KB) * cb;
+            let g = (luma - KR * r - KB * b) / KG;
+            for (plane, v) in image.planes.iter_mut().zip([r, g, b]) {
+                plane.set(x, y, (v * max).round().clamp(0.0, max) as u16);
+            }
+        }
+    }
+    image
+}
+
+// The luma plane alone as a one plane greymap.
+pub fn to_grey(frame: &Frame, range: ColorRange) -> Image {
+    let max = max_sample(frame.bit_depth);
+    let mut plane = Plane::new(frame.width(), frame.height(), 0);
+    for (s, &luma) in plane.data.iter_mut().zip(frame.y.data.iter()) {
+        *s = (range.luma_value(luma, frame.bit_depth) * max).round().clamp(0.0, max) as u16;
+    }
+    Image {
+        max_value: frame.max_value(),
+        planes: vec![plane],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixel(image: &Image, x: usize, y: usize) -> [u16; 3] {
+        [image.planes[0].get(x, y), image.planes[1].get(x, y), image.planes[2].get(x, y)]
+    }
+
+    fn rgb_image(pixels: &[[u16; 3]], width: usize, max_value: u16) -> Image {
+        let mut image = Image::new(width, pixels.len() / width, 3, max_value);
+        for (i, p) in pixels.iter().enumerate() {
+            for (plane, &s) in image.planes.iter_mut().zip(p) {
+                plane.data[i] = s;
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn reference_colours() {
+        let image = rgb_image(&[[0, 0, 0], [255, 255, 255], [255, 0, 0], [0, 0, 255]], 4, 255);
+        let limited = to_frame(&image, ChromaSampling::Cs444, ColorRange::Limited).unwrap();
+        assert_eq!(limited.y.data, vec![16, 235, 81, 41]);
+        assert_eq!(limited.u.data, vec![128, 128, 90, 240]);
+        assert_eq!(limited.v.data, vec![128, 128, 240, 110]);
+
+        let full = to_frame(&image, ChromaSampling::Cs444, ColorRange::Full).unwrap();
+        assert_eq!(full.y.data, vec![0, 255, 76, 29]);
+        assert_eq!(full.u.data[..2], [128, 128]);
+    }
+
+    #[test]
+    fn round_trip_is_close() {
+        let mut pixels = Vec::new();
+        for i in 0..64u16 {
+            pixels.push([i * 4, 255 - i * 3, (i * 37) % 256]);
+        }
+        let image = rgb_image(&pixels, 8, 255);
+        for range in [ColorRange::Limited, ColorRange::Full] {
+            let back = to_image(&to_frame(&image, ChromaSampling::Cs444, range).unwrap(), range);
+            for (a, b) in image.planes.iter().zip(back.planes.iter()) {
+                for (&x, &y) in a.data.iter().zip(b.data.iter()) {
+                    assert!((x as i32 - y as i32).abs() <= 2, "{} vs {} in {:?}", x, y, range);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn subsampled_chroma_averages() {
+        // Odd size, so the last chroma column covers a single pixel column.
+        let image = rgb_image(&[[255, 0, 0], [0, 0, 255], [255, 0, 0], [255, 0, 0], [0, 0, 255], [255, 0, 0]], 3, 255);
+        let frame = to_frame(&image, ChromaSampling::Cs420, ColorRange::Full).unwrap();
+        assert_eq!((frame.u.width, frame.u.height), (2, 1));
+        let full = to_frame(&image, ChromaSampling::Cs444, ColorRange::Full).unwrap();
+        assert_eq!(frame.u.get(1, 0), full.u.get(2, 0));
+        let mean = (full.v.get(0, 0) as f64 + full.v.get(1, 0) as f64) / 2.0;
+        assert!((frame.v.get(0, 0) as f64 - mean).abs() <= 1.0);
+
+        let back = pixel(&to_image(&frame, ColorRange::Full), 2, 1);
+        assert!(back[0] >= 253 && back[1] <= 2 && back[2] <= 2, "{:?}", back);
+    }
+
+    #[test]
+    fn deep_and_grey_images() {
+        let mut grey = Image::new(2, 1, 1, 1023);
+        grey.planes[0].data = vec![0, 1023];
+        let frame = to_frame(&grey, ChromaSampling::Cs420, ColorRange::Limited).unwrap();
+        assert_eq!(frame.bit_depth, 10);
+        assert_eq!(frame.y.data, vec![64, 940]);
+        assert_eq!(frame.u.data, vec![512]);
+        assert_eq!(to_grey(&frame, ColorRange::Limited), grey);
+        assert_eq!(pixel(&to_image(&frame, ColorRange::Limited), 1, 0), [1023, 1023, 1023]);
+
+        assert!(to_frame(&Image::new(2, 2, 2, 255), ChromaSampling::Cs444, ColorRange::Full).is_none());
+    }
+}
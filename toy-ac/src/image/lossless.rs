@@ -0,0 +1,396 @@
+// Lossless image coding in the style of LOCO-I (JPEG-LS), with the Golomb
+// codes replaced by the arithmetic coder.
+//
+// Each sample is predicted from its causal neighbours with the median edge
+// detector. The local gradients around the sample are quantized into one of
+// 365 contexts, and each context keeps a bias correction for the prediction
+// and an adaptive model of the residual size. A residual is coded as its
+// bucket (the bit length of the folded residual) under the context's model,
+// followed by the bits below the leading one as plain bits (see bucket.rs).
+//
+// Stream layout: maxval as a big-endian u16, the plane count as a u8 and
+// each plane's width and height as big-endian u32s, then the coded planes
+// in order, padded to a whole byte.
+
+use super::Image;
+use crate::bucket::{decode_signed, encode_signed};
+use crate::decoder::{DecodeError, Decoder};
+use crate::encoder::Encoder;
+use crate::symbol_model::VectorCountSymbolModel;
+use crate::video::Plane;
+use bitbit::{BitReader, BitWriter, MSB};
+use std::fmt;
+use std::io::{self, Read, Write};
+
+pub const CONTEXTS: usize = 365;
+
+// Counts in a context's bias state are halved once they reach this, so the
+// correction follows local changes in the image.
+const BIAS_RESET: i32 = 64;
+
+#[derive(Debug)]
+pub enum LosslessError {
+    Io(io::Error),
+    InvalidHeader,
+    TooLarge(u64),
+    Decode { plane: usize, x: usize, y: usize, error: DecodeError },
+}
+
+impl fmt::Display for LosslessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LosslessError::Io(e) => write!(f, "{}", e),
+            LosslessError::InvalidHeader => write!(f, "invalid image header"),
+            LosslessError::TooLarge(samples) => write!(f, "image of {} samples exceeds the allowed maximum", samples),
+            LosslessError::Decode { plane, x, y, error } => {
+                write!(f, "error decoding plane {} at ({}, {}): {}", plane, x, y, error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LosslessError {}
+
+impl From<io::Error> for LosslessError {
+    fn from(e: io::Error) -> Self {
+        LosslessError::Io(e)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Bias {
+    // Accumulated error, kept in (-count, 0] by moving it into correction.
+    error: i32,
+    count: i32,
+    correction: i32,
+}
+
+// What the encoder and decoder both know about a sample before coding it.
+struct Prediction {
+    context: usize,
+    // -1 when the context was folded onto its mirror image, in which case
+    // the residual is coded negated.
+    sign: i32,
+    predicted: i32,
+}
+
+struct Contexts {
+    max_value: i32,
+    thresholds: [i32; 3],
+    buckets: Vec<VectorCountSymbolModel<u8>>,
+    bias: Vec<Bias>,
+}
+
+impl Contexts {
+    fn new(max_value: u16) -> Self {
+        let max_bucket = 16 - max_value.leading_zeros() as u8;
+        Self {
+            max_value: max_value as i32,
+            thresholds: thresholds(max_value as i32),
+            buckets: vec![VectorCountSymbolModel::new((0..=max_bucket).collect()); CONTEXTS],
+            bias: vec![Bias { error: 0, count: 1, correction: 0 }; CONTEXTS],
+        }
+    }
+
+    fn range(&self) -> i32 {
+        self.max_value + 1
+    }
+
+    fn quantize(&self, g: i32) -> i32 {
+        let [t1, t2, t3] = self.thresholds;
+        let q = match g.abs() {
+            0 => 0,
+            a if a < t1 => 1,
+            a if a < t2 => 2,
+            a if a < t3 => 3,
+            _ => 4,
+        };
+        if g < 0 { -q } else { q }
+    }
+
+    fn predict(&self, plane: &Plane, x: usize, y: usize) -> Prediction {
+        let (n, w, nw, ne) = neighbours(plane, x, y, (self.max_value + 1) / 2);
+
+        let q1 = self.quantize(ne - n);
+        let q2 = self.quantize(n - nw);
+        let q3 = self.quantize(nw - w);
+        // The 729 gradient triples pair up with their negations around the
+        // all-zero triple at 364, leaving 365 contexts.
+        let raw = ((q1 + 4) * 81 + (q2 + 4) * 9 + (q3 + 4)) as usize;
+        let (context, sign) = if raw < 364 { (364 - raw, -1) } else { (raw - 364, 1) };
+
+        let med = if nw >= n.max(w) {
+            n.min(w)
+        } else if nw <= n.min(w) {
+            n.max(w)
+        } else {
+            n + w - nw
+        };
+        let predicted = (med + sign * self.bias[context].correction).clamp(0, self.max_value);
+
+        Prediction { context, sign, predicted }
+    }
+
+    // Residual of the sample against the prediction, reduced modulo the
+    // sample range to [-range/2, range/2).
+    fn residual(&self, p: &Prediction, sample: i32) -> i32 {
+        let mut error = p.sign * (sample - p.predicted);
+        if error < 0 {
+            error += self.range();
+        }
+        if error >= (self.range() + 1) / 2 {
+            error -= self.range();
+        }
+        error
+    }
+
+    fn reconstruct(&self, p: &Prediction, error: i32) -> u16 {
+        let mut sample = p.predicted + p.sign * error;
+        if sample < 0 {
+            sample += self.range();
+        } else if sample > self.max_value {
+            sample -= self.range();
+        }
+        sample as u16
+    }
+
+    fn update(&mut self, p: &Prediction, error: i32) {
+        let b = &mut self.bias[p.context];
+        if b.count == BIAS_RESET {
+            b.error >>= 1;
+            b.count >>= 1;
+        }
+        b.error += error;
+        b.count += 1;
+
+        if b.error <= -b.count {
+            b.error += b.count;
+            if b.correction > -128 {
+                b.correction -= 1;
+            }
+            if b.error <= -b.count {
+                b.error = -b.count + 1;
+            }
+        } else if b.error > 0 {
+            b.error -= b.count;
+            if b.correction < 127 {
+                b.correction += 1;
+            }
+            if b.error > 0 {
+                b.error = 0;
+            }
+        }
+    }
+}
+
+// Gradient thresholds from JPEG-LS, scaled for sample ranges other than 8 bits.
+fn thresholds(max_value: i32) -> [i32; 3] {
+    if max_value >= 128 {
+        let factor = (max_value.min(4095) + 128) >> 8;
+        [factor + 2, 4 * factor + 3, 17 * factor + 4].map(|t| t.min(max_value))
+    } else {
+        let factor = 256 / (max_value + 1);
+        [(3 / factor).max(2), (7 / factor).max(3), (21 / factor).max(4)]
+    }
+}
+
+// The north, west, north-west and north-east neighbours of (x, y). Along the
+// top row the missing neighbours copy the west sample, and down the left and
+// right edges they copy the north sample. The very first sample is predicted
+// from the middle of the range.
+fn neighbours(plane: &Plane, x: usize, y: usize, mid: i32) -> (i32, i32, i32, i32) {
+    let n = if y > 0 {
+        plane.get(x, y - 1) as i32
+    } else if x > 0 {
+        plane.get(x - 1, y) as i32
+    } else {
+        mid
+    };
+    let w = if x > 0 { plane.get(x - 1, y) as i32 } else { n };
+    let nw = if x > 0 && y > 0 { plane.get(x - 1, y - 1) as i32 } else { n };
+    let ne = if y > 0 && x + 1 < plane.width { plane.get(x + 1, y - 1) as i32 } else { n };
+    (n, w, nw, ne)
+}
+
+// Codes the image and returns the number of coded bits for each plane,
+// not counting the header.
+pub fn encode<W: Write>(image: &Image, output: &mut W) -> io::Result<Vec<u64>> {
+    if image.planes.len() > 255 || image.planes.iter().any(|p| p.width > u32::MAX as usize || p.height > u32::MAX as usize) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "image too large for the header"));
+    }
+    output.write_all(&image.max_value.to_be_bytes())?;
+    output.write_all(&[image.planes.len() as u8])?;
+    for p in image.planes.iter() {
+        output.write_all(&(p.width as u32).to_be_bytes())?;
+        output.write_all(&(p.height as u32).to_be_bytes())?;
+    }
+
+    let mut enc = Encoder::new();
+    let mut bw = BitWriter::new(output);
+    let mut plane_bits = Vec::new();
+    for plane in image.planes.iter() {
+        let start = enc.bits_written();
+        let mut contexts = Contexts::new(image.max_value);
+        for y in 0..plane.height {
+            for x in 0..plane.width {
+                let p = contexts.predict(plane, x, y);
+                let error = contexts.residual(&p, plane.get(x, y) as i32);
+                encode_signed(error, &mut contexts.buckets[p.context], &mut enc, &mut bw);
+                contexts.update(&p, error);
+            }
+        }
+        plane_bits.push(enc.bits_written() - start);
+    }
+
+    if let Err(e) = enc.finish(&mut bw) {
+        return Err(io::Error::other(e.to_string()));
+    }
+    bw.pad_to_byte()?;
+    Ok(plane_bits)
+}
+
+// Decodes a whole stream. Headers describing more than max_samples samples
+// in total are rejected before anything is allocated.
+pub fn decode<R: Read>(mut input: R, max_samples: u64) -> Result<Image, LosslessError> {
+    let mut header = [0u8; 3];
+    read_header_bytes(&mut input, &mut header)?;
+    let max_value = u16::from_be_bytes([header[0], header[1]]);
+    if max_value == 0 {
+        return Err(LosslessError::InvalidHeader);
+    }
+
+    let mut sizes = Vec::new();
+    let mut samples: u64 = 0;
+    for _ in 0..header[2] {
+        let mut size = [0u8; 8];
+        read_header_bytes(&mut input, &mut size)?;
+        let width = u32::from_be_bytes([size[0], size[1], size[2], size[3]]) as u64;
+        let height = u32::from_be_bytes([size[4], size[5], size[6], size[7]]) as u64;
+        samples = width
+            .checked_mul(height)
+            .and_then(|n| samples.checked_add(n))
+            .ok_or(LosslessError::InvalidHeader)?;
+        sizes.push((width as usize, height as usize));
+    }
+    if samples > max_samples {
+        return Err(LosslessError::TooLarge(samples));
+    }
+
+    let mut image = Image {
+        max_value,
+        planes: sizes.iter().map(|&(w, h)| Plane::new(w, h, 0)).collect(),
+    };
+    let mut dec = Decoder::new();
+    let mut br: BitReader<_, MSB> = BitReader::new(input);
+    for (i, plane) in image.planes.iter_mut().enumerate() {
+        let mut contexts = Contexts::new(max_value);
+        for y in 0..plane.height {
+            for x in 0..plane.width {
+                let err = |error| LosslessError::Decode { plane: i, x, y, error };
+                let p = contexts.predict(plane, x, y);
+                let error = decode_signed(&mut contexts.buckets[p.context], &mut dec, &mut br).map_err(err)?;
+                plane.set(x, y, contexts.reconstruct(&p, error));
+                contexts.update(&p, error);
+            }
+        }
+    }
+    Ok(image)
+}
+
+fn read_header_bytes<R: Read>(input: &mut R, buf: &mut [u8]) -> Result<(), LosslessError> {
+    match input.read_exact(buf) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Err(LosslessError::InvalidHeader),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_image(width: usize, height: usize, planes: usize, max_value: u16) -> Image {
+        let mut image = Image::new(width, height, planes, max_value);
+        let mut state: u32 = 12345;
+        for (p, plane) in image.planes.iter_mut().enumerate() {
+            for y in 0..height {
+                for x in 0..width {
+                    state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                    let noise = (state >> 16) % 7;
+                    let v = (x * 3 + y * 2 + p * 50) as u32 + noise;
+                    plane.set(x, y, (v % (max_value as u32 + 1)) as u16);
+                }
+            }
+        }
+        image
+    }
+
+    fn round_trip(image: &Image) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        encode(image, &mut bytes).unwrap();
+        assert_eq!(&decode(&bytes[..], 1 << 24).unwrap(), image);
+        bytes
+    }
+
+    #[test]
+    fn round_trip_depths_and_sizes() {
+        for max_value in [1, 100, 255, 1023, 65535] {
+            for (w, h) in [(1, 1), (1, 9), (9, 1), (37, 23)] {
+                round_trip(&test_image(w, h, 1, max_value));
+            }
+        }
+        round_trip(&test_image(16, 8, 3, 255));
+    }
+
+    #[test]
+    fn extreme_residuals_wrap() {
+        let mut image = Image::new(8, 8, 1, 255);
+        for (i, s) in image.planes[0].data.iter_mut().enumerate() {
+            *s = if (i + i / 8) % 2 == 0 { 0 } else { 255 };
+        }
+        round_trip(&image);
+    }
+
+    #[test]
+    fn flat_image_is_nearly_free() {
+        let mut image = Image::new(64, 64, 1, 255);
+        image.planes[0].data.fill(77);
+        let bytes = round_trip(&image);
+        assert!(bytes.len() < 64, "flat image took {} bytes", bytes.len());
+    }
+
+    #[test]
+    fn contexts_fold_to_365() {
+        let contexts = Contexts::new(255);
+        let mut seen = vec![false; CONTEXTS];
+        for q1 in -4..=4 {
+            for q2 in -4..=4 {
+                for q3 in -4..=4 {
+                    let raw = ((q1 + 4) * 81 + (q2 + 4) * 9 + (q3 + 4)) as usize;
+                    let mirror = ((-q1 + 4) * 81 + (-q2 + 4) * 9 + (-q3 + 4)) as usize;
+                    assert_eq!(raw + mirror, 728);
+                    seen[raw.abs_diff(364)] = true;
+                }
+            }
+        }
+        assert!(seen.iter().all(|&s| s));
+        assert_eq!(contexts.quantize(0), 0);
+        assert_eq!(contexts.quantize(-1000), -4);
+    }
+
+    #[test]
+    fn malformed_input_is_an_error() {
+        assert!(matches!(decode(&[0u8, 255][..], 100), Err(LosslessError::InvalidHeader)));
+        assert!(matches!(decode(&[0u8, 255, 1, 0, 1, 0, 0, 0, 1, 0, 0][..], 100), Err(LosslessError::TooLarge(_))));
+        let huge = [[0u8, 255, 2].as_slice(), &[255; 16]].concat();
+        assert!(matches!(decode(&huge[..], u64::MAX), Err(LosslessError::InvalidHeader)));
+
+        let mut bytes = Vec::new();
+        encode(&test_image(32, 32, 1, 255), &mut bytes).unwrap();
+        bytes.truncate(bytes.len() / 2);
+        assert!(matches!(
+            decode(&bytes[..], 1 << 20),
+            Err(LosslessError::Decode { error: DecodeError::UnexpectedEof, .. })
+        ));
+    }
+}
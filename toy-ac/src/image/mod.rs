@@ -0,0 +1,37 @@
+// Still images for the image codecs. An image is one or more planes of
+// samples in [0, max_value], using the same Plane as the video code so
+// that the two can share prediction and transform stages.
+
+pub mod color;
+pub mod lossless;
+pub mod pnm;
+
+use super::video::Plane;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Image {
+    pub max_value: u16,
+    pub planes: Vec<Plane>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize, planes: usize, max_value: u16) -> Self {
+        Self {
+            max_value,
+            planes: (0..planes).map(|_| Plane::new(width, height, 0)).collect(),
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.planes.first().map_or(0, |p| p.width)
+    }
+
+    pub fn height(&self) -> usize {
+        self.planes.first().map_or(0, |p| p.height)
+    }
+
+    // Bits needed to hold max_value.
+    pub fn bit_depth(&self) -> u8 {
+        (16 - self.max_value.leading_zeros()) as u8
+    }
+}
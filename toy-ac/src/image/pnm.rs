@@ -0,0 +1,294 @@
+// Netpbm greymaps and pixmaps, binary (P5, P6) or plain ASCII (P2, P3).
+// Binary files hold one byte per sample, or two big-endian bytes per sample
+// when maxval is over 255. Pixmap samples are interleaved R, G, B.
+
+use super::Image;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+#[derive(Debug)]
+pub enum PnmError {
+    Io(io::Error),
+    InvalidHeader(String),
+    UnsupportedFormat(String),
+    Truncated { expected: usize, read: usize },
+    SampleOutOfRange,
+    UnsupportedImage,
+}
+
+impl fmt::Display for PnmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PnmError::Io(e) => write!(f, "{}", e),
+            PnmError::InvalidHeader(s) => write!(f, "invalid PNM header: {}", s),
+            PnmError::UnsupportedFormat(s) => write!(f, "unsupported PNM format {}", s),
+            PnmError::Truncated { expected, read } => {
+                write!(f, "image data truncated: expected {} bytes, got {}", expected, read)
+            }
+            PnmError::SampleOutOfRange => write!(f, "sample larger than maxval"),
+            PnmError::UnsupportedImage => write!(f, "image cannot be written as PNM"),
+        }
+    }
+}
+
+impl std::error::Error for PnmError {}
+
+impl From<io::Error> for PnmError {
+    fn from(e: io::Error) -> Self {
+        PnmError::Io(e)
+    }
+}
+
+// Pulls whitespace separated header fields, skipping # comments, one byte
+// at a time so that nothing past the header is consumed.
+struct HeaderReader<R: Read> {
+    input: R,
+    at_end: bool,
+}
+
+impl<R: Read> HeaderReader<R> {
+    fn byte(&mut self) -> Result<Option<u8>, PnmError> {
+        let mut b = [0u8; 1];
+        loop {
+            match self.input.read(&mut b) {
+                Ok(0) => {
+                    self.at_end = true;
+                    return Ok(None);
+                }
+                Ok(_) => return Ok(Some(b[0])),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    // The next field as a number. Exactly one whitespace byte after the
+    // field is consumed, which for the last field is the separator before
+    // the raster.
+    fn number(&mut self, name: &str) -> Result<u32, PnmError> {
+        let mut c = self.byte()?;
+        loop {
+            match c {
+                Some(b'#') => {
+                    while !matches!(c, None | Some(b'\n') | Some(b'\r')) {
+                        c = self.byte()?;
+                    }
+                }
+                Some(b) if b.is_ascii_whitespace() => c = self.byte()?,
+                _ => break,
+            }
+        }
+
+        let mut value: u32 = 0;
+        let mut digits = 0;
+        while let Some(b) = c {
+            if !b.is_ascii_digit() {
+                break;
+            }
+            value = match value.checked_mul(10).and_then(|v| v.checked_add((b - b'0') as u32)) {
+                Some(v) => v,
+                None => return Err(PnmError::InvalidHeader(format!("{} too large", name))),
+            };
+            digits += 1;
+            c = self.byte()?;
+        }
+        if digits == 0 || !c.is_none_or(|b| b.is_ascii_whitespace()) {
+            return Err(PnmError::InvalidHeader(format!("bad {}", name)));
+        }
+        Ok(value)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Binary,
+    Ascii,
+}
+
+// Reads any of P2, P3, P5 and P6. Greymaps give one plane and pixmaps give
+// three (R, G, B).
+pub fn read_pnm<R: Read>(mut input: R) -> Result<Image, PnmError> {
+    let mut magic = [0u8; 2];
+    if let Err(e) = input.read_exact(&mut magic) {
+        return match e.kind() {
+            io::ErrorKind::UnexpectedEof => Err(PnmError::InvalidHeader("missing magic number".to_string())),
+            _ => Err(e.into()),
+        };
+    }
+    if magic[0] != b'P' {
+        return Err(PnmError::InvalidHeader("missing magic number".to_string()));
+    }
+    let (planes, encoding) = match magic[1] {
+        b'2' => (1, Encoding::Ascii),
+        b'3' => (3, Encoding::Ascii),
+        b'5' => (1, Encoding::Binary),
+        b'6' => (3, Encoding::Binary),
+        _ => return Err(PnmError::UnsupportedFormat(String::from_utf8_lossy(&magic).into_owned())),
+    };
+
+    let mut header = HeaderReader { input, at_end: false };
+    let width = header.number("width")? as usize;
+    let height = header.number("height")? as usize;
+    let max_value = header.number("maxval")?;
+    if width == 0 || height == 0 {
+        return Err(PnmError::InvalidHeader("empty image".to_string()));
+    }
+    if max_value == 0 || max_value > 65535 {
+        return Err(PnmError::InvalidHeader(format!("maxval {} out of range", max_value)));
+    }
+    let samples = match width.checked_mul(height).and_then(|n| n.checked_mul(planes)) {
+        Some(n) => n,
+        None => return Err(PnmError::InvalidHeader("image too large".to_string())),
+    };
+
+    // Samples are gathered as they are read rather than into a buffer sized
+    // from the header, so a header claiming a huge image cannot allocate
+    // more than the input actually holds.
+    let mut interleaved: Vec<u16> = Vec::new();
+    match encoding {
+        Encoding::Binary => {
+            let bytes_per_sample = if max_value > 255 { 2 } else { 1 };
+            let expected = match samples.checked_mul(bytes_per_sample) {
+                Some(n) => n,
+                None => return Err(PnmError::InvalidHeader("image too large".to_string())),
+            };
+            let mut bytes = Vec::new();
+            header.input.take(expected as u64).read_to_end(&mut bytes)?;
+            if bytes.len() != expected {
+                return Err(PnmError::Truncated { expected, read: bytes.len() });
+            }
+            if bytes_per_sample == 1 {
+                interleaved.extend(bytes.iter().map(|&b| b as u16));
+            } else {
+                interleaved.extend(bytes.chunks_exact(2).map(|b| u16::from_be_bytes([b[0], b[1]])));
+            }
+        }
+        Encoding::Ascii => {
+            while interleaved.len() < samples {
+                match header.number("sample") {
+                    Ok(s) if s <= max_value => interleaved.push(s as u16),
+                    Ok(_) => return Err(PnmError::SampleOutOfRange),
+                    Err(PnmError::InvalidHeader(_)) if header.at_end => {
+                        return Err(PnmError::Truncated { expected: samples, read: interleaved.len() });
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+    }
+    if interleaved.iter().any(|&s| s as u32 > max_value) {
+        return Err(PnmError::SampleOutOfRange);
+    }
+
+    let mut image = Image::new(width, height, planes, max_value as u16);
+    for (p, plane) in image.planes.iter_mut().enumerate() {
+        for (s, &v) in plane.data.iter_mut().zip(interleaved.iter().skip(p).step_by(planes)) {
+            *s = v;
+        }
+    }
+    Ok(image)
+}
+
+// Writes a one plane image as a greymap (P5 or P2) and a three plane image
+// as a pixmap (P6 or P3).
+pub fn write_pnm<W: Write>(image: &Image, encoding: Encoding, mut output: W) -> Result<(), PnmError> {
+    let magic = match (image.planes.len(), encoding) {
+        (1, Encoding::Binary) => "P5",
+        (3, Encoding::Binary) => "P6",
+        (1, Encoding::Ascii) => "P2",
+        (3, Encoding::Ascii) => "P3",
+        _ => return Err(PnmError::UnsupportedImage),
+    };
+    let (width, height) = (image.width(), image.height());
+    if image.max_value == 0 || image.planes.iter().any(|p| p.width != width || p.height != height) {
+        return Err(PnmError::UnsupportedImage);
+    }
+    write!(output, "{}\n{} {}\n{}\n", magic, width, height, image.max_value)?;
+
+    let mut bytes = Vec::with_capacity(width * height * image.planes.len() * 2);
+    for i in 0..width * height {
+        for (p, plane) in image.planes.iter().enumerate() {
+            let s = plane.data[i];
+            match encoding {
+                Encoding::Binary if image.max_value > 255 => bytes.extend_from_slice(&s.to_be_bytes()),
+                Encoding::Binary => bytes.push(s as u8),
+                Encoding::Ascii => {
+                    bytes.extend_from_slice(s.to_string().as_bytes());
+                    // One row of pixels per line.
+                    let end_of_row = (i + 1) % width == 0 && p + 1 == image.planes.len();
+                    bytes.push(if end_of_row { b'\n' } else { b' ' });
+                }
+            }
+        }
+    }
+    output.write_all(&bytes)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_with_comments() {
+        let mut bytes = b"P5\n# made by hand\n3 # width\n2\n255\n".to_vec();
+        bytes.extend_from_slice(&[0, 1, 2, 253, 254, 255]);
+        let image = read_pnm(&bytes[..]).unwrap();
+        assert_eq!((image.width(), image.height(), image.max_value), (3, 2, 255));
+        assert_eq!(image.planes[0].data, vec![0, 1, 2, 253, 254, 255]);
+    }
+
+    #[test]
+    fn sixteen_bit_round_trip() {
+        let mut image = Image::new(5, 3, 1, 1023);
+        for (i, s) in image.planes[0].data.iter_mut().enumerate() {
+            *s = (i as u16 * 97) % 1024;
+        }
+        let mut bytes = Vec::new();
+        write_pnm(&image, Encoding::Binary, &mut bytes).unwrap();
+        assert_eq!(&bytes[..12], b"P5\n5 3\n1023\n");
+        assert_eq!(read_pnm(&bytes[..]).unwrap(), image);
+    }
+
+    #[test]
+    fn malformed_input() {
+        assert!(matches!(read_pnm(&b"P4\n1 1\n\0"[..]), Err(PnmError::UnsupportedFormat(_))));
+        assert!(matches!(read_pnm(&b"P5\n1 x\n255\n\0"[..]), Err(PnmError::InvalidHeader(_))));
+        assert!(matches!(read_pnm(&b"P5\n2 2\n255\n\0\0"[..]), Err(PnmError::Truncated { expected: 4, read: 2 })));
+        assert!(matches!(read_pnm(&b"P5\n4294967295 2147483649\n65535\n"[..]), Err(PnmError::InvalidHeader(_))));
+        assert!(matches!(read_pnm(&b"P5\n1 1\n10\n\x0b"[..]), Err(PnmError::SampleOutOfRange)));
+        assert!(matches!(read_pnm(&b"P3\n2 1\n255\n1 2 3 4 5"[..]), Err(PnmError::Truncated { expected: 6, read: 5 })));
+        assert!(matches!(read_pnm(&b"P2\n2 1\n9\n1 10"[..]), Err(PnmError::SampleOutOfRange)));
+    }
+
+    #[test]
+    fn pixmap_samples_are_interleaved() {
+        let image = read_pnm(&b"P3\n2 1\n255\n1 2 3\n4 5 6"[..]).unwrap();
+        assert_eq!(image.planes.len(), 3);
+        assert_eq!(image.planes[0].data, vec![1, 4]);
+        assert_eq!(image.planes[1].data, vec![2, 5]);
+        assert_eq!(image.planes[2].data, vec![3, 6]);
+
+        let binary = read_pnm(&b"P6\n2 1\n255\n\x01\x02\x03\x04\x05\x06"[..]).unwrap();
+        assert_eq!(binary, image);
+    }
+
+    #[test]
+    fn every_format_round_trips() {
+        for planes in [1, 3] {
+            for max_value in [1, 255, 256, 65535] {
+                let mut image = Image::new(7, 4, planes, max_value);
+                for (p, plane) in image.planes.iter_mut().enumerate() {
+                    for (i, s) in plane.data.iter_mut().enumerate() {
+                        *s = ((i * 1009 + p * 31) % (max_value as usize + 1)) as u16;
+                    }
+                }
+                for encoding in [Encoding::Binary, Encoding::Ascii] {
+                    let mut bytes = Vec::new();
+                    write_pnm(&image, encoding, &mut bytes).unwrap();
+                    assert_eq!(read_pnm(&bytes[..]).unwrap(), image);
+                }
+            }
+        }
+    }
+}
@@ -1,4 +1,16 @@
 pub mod range;
 pub mod symbol_model;
 pub mod encoder;
-pub mod decoder;
\ No newline at end of file
+pub mod decoder;
+pub(crate) mod bucket;
+pub mod container;
+pub mod trace;
+pub mod analysis;
+pub mod video;
+pub mod image;
+pub mod metrics;
+pub mod rd;
+pub mod synth;
+// Command line handling shared by the binaries, not part of the library.
+#[doc(hidden)]
+pub mod args;
@@ -0,0 +1,333 @@
+// Objective quality metrics between a reference and a distorted frame:
+// PSNR, SSIM and MS-SSIM per plane, and PSNR and SSIM across planes weighted
+// 6:1:1 for Y, U and V as the JVET common test conditions do.
+//
+// SSIM follows Wang et al. (2004): an 11x11 Gaussian window with sigma 1.5
+// over every position where it fits inside the plane, with the constants
+// (0.01 L)^2 and (0.03 L)^2 for peak value L. Planes smaller than the window
+// are treated as a single window with uniform weights. MS-SSIM (Wang et al.
+// 2003) combines five scales, each half the size of the last by 2x2
+// averaging, stopping early if a scale would be smaller than the window.
+
+use super::video::{Frame, Plane};
+
+// PSNR reported for identical planes, which would otherwise be infinite.
+pub const MAX_PSNR: f64 = 100.0;
+
+const WINDOW: usize = 11;
+const SIGMA: f64 = 1.5;
+const MS_SSIM_WEIGHTS: [f64; 5] = [0.0448, 0.2856, 0.3001, 0.2363, 0.1333];
+
+// Per-plane weights for the combined PSNR and SSIM.
+const PLANE_WEIGHTS: [f64; 3] = [6.0, 1.0, 1.0];
+
+pub fn mse(reference: &Plane, distorted: &Plane) -> f64 {
+    assert_eq!((reference.width, reference.height), (distorted.width, distorted.height), "planes differ in size");
+    if reference.data.is_empty() {
+        return 0.0;
+    }
+    let sum: f64 = reference
+        .data
+        .iter()
+        .zip(distorted.data.iter())
+        .map(|(&a, &b)| (a as f64 - b as f64).powi(2))
+        .sum();
+    sum / reference.data.len() as f64
+}
+
+pub fn psnr_from_mse(mse: f64, max_value: u16) -> f64 {
+    if mse == 0.0 {
+        return MAX_PSNR;
+    }
+    let peak = max_value as f64;
+    (10.0 * (peak * peak / mse).log10()).min(MAX_PSNR)
+}
+
+pub fn psnr(reference: &Plane, distorted: &Plane, max_value: u16) -> f64 {
+    psnr_from_mse(mse(reference, distorted), max_value)
+}
+
+// Samples as floats, for the filtering SSIM does.
+#[derive(Clone)]
+struct Samples {
+    width: usize,
+    height: usize,
+    data: Vec<f64>,
+}
+
+impl Samples {
+    fn from_plane(plane: &Plane) -> Self {
+        Self {
+            width: plane.width,
+            height: plane.height,
+            data: plane.data.iter().map(|&s| s as f64).collect(),
+        }
+    }
+
+    fn downsample(&self) -> Self {
+        let (width, height) = (self.width / 2, self.height / 2);
+        let mut data = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let i = 2 * y * self.width + 2 * x;
+                data.push((self.data[i] + self.data[i + 1] + self.data[i + self.width] + self.data[i + self.width + 1]) / 4.0);
+            }
+        }
+        Self { width, height, data }
+    }
+}
+
+fn gaussian_window() -> [f64; WINDOW] {
+    let mut w = [0.0; WINDOW];
+    let centre = (WINDOW / 2) as f64;
+    for (i, v) in w.iter_mut().enumerate() {
+        *v = (-((i as f64 - centre).powi(2)) / (2.0 * SIGMA * SIGMA)).exp();
+    }
+    let sum: f64 = w.iter().sum();
+    w.map(|v| v / sum)
+}
+
+// Mean SSIM and mean contrast-structure term over all windows.
+fn ssim_terms(a: &Samples, b: &Samples, max_value: u16) -> (f64, f64) {
+    let peak = max_value as f64;
+    let c1 = (0.01 * peak).powi(2);
+    let c2 = (0.03 * peak).powi(2);
+    let terms = |mu_a: f64, mu_b: f64, var_a: f64, var_b: f64, cov: f64| {
+        let cs = (2.0 * cov + c2) / (var_a + var_b + c2);
+        let l = (2.0 * mu_a * mu_b + c1) / (mu_a * mu_a + mu_b * mu_b + c1);
+        (l * cs, cs)
+    };
+
+    if a.width < WINDOW || a.height < WINDOW {
+        let n = a.data.len() as f64;
+        let mu_a = a.data.iter().sum::<f64>() / n;
+        let mu_b = b.data.iter().sum::<f64>() / n;
+        let (mut var_a, mut var_b, mut cov) = (0.0, 0.0, 0.0);
+        for (&x, &y) in a.data.iter().zip(b.data.iter()) {
+            var_a += (x - mu_a).powi(2);
+            var_b += (y - mu_b).powi(2);
+            cov += (x - mu_a) * (y - mu_b);
+        }
+        return terms(mu_a, mu_b, var_a / n, var_b / n, cov / n);
+    }
+
+    // Separable filtering of a, b, a^2, b^2 and ab: rows first, keeping only
+    // positions where the window fits, then columns.
+    let window = gaussian_window();
+    let out_w = a.width - WINDOW + 1;
+    let out_h = a.height - WINDOW + 1;
+    let mut rows = vec![[0.0f64; 5]; out_w * a.height];
+    for y in 0..a.height {
+        let ra = &a.data[y * a.width..(y + 1) * a.width];
+        let rb = &b.data[y * b.width..(y + 1) * b.width];
+        for x in 0..out_w {
+            let mut s = [0.0; 5];
+            for (k, &w) in window.iter().enumerate() {
+                let (p, q) = (ra[x + k], rb[x + k]);
+                s[0] += w * p;
+                s[1] += w * q;
+                s[2] += w * p * p;
+                s[3] += w * q * q;
+                s[4] += w * p * q;
+            }
+            rows[y * out_w + x] = s;
+        }
+    }
+
+    let (mut ssim_sum, mut cs_sum) = (0.0, 0.0);
+    for y in 0..out_h {
+        for x in 0..out_w {
+            let mut s = [0.0; 5];
+            for (k, &w) in window.iter().enumerate() {
+                let r = &rows[(y + k) * out_w + x];
+                for j in 0..5 {
+                    s[j] += w * r[j];
+                }
+            }
+            let (mu_a, mu_b) = (s[0], s[1]);
+            let (ssim, cs) = terms(mu_a, mu_b, s[2] - mu_a * mu_a, s[3] - mu_b * mu_b, s[4] - mu_a * mu_b);
+            ssim_sum += ssim;
+            cs_sum += cs;
+        }
+    }
+    let n = (out_w * out_h) as f64;
+    (ssim_sum / n, cs_sum / n)
+}
+
+pub fn ssim(reference: &Plane, distorted: &Plane, max_value: u16) -> f64 {
+    assert_eq!((reference.width, reference.height), (distorted.width, distorted.height), "planes differ in size");
+    if reference.data.is_empty() {
+        return 1.0;
+    }
+    ssim_terms(&Samples::from_plane(reference), &Samples::from_plane(distorted), max_value).0
+}
+
+pub fn ms_ssim(reference: &Plane, distorted: &Plane, max_value: u16) -> f64 {
+    assert_eq!((reference.width, reference.height), (distorted.width, distorted.height), "planes differ in size");
+    if reference.data.is_empty() {
+        return 1.0;
+    }
+    let mut a = Samples::from_plane(reference);
+    let mut b = Samples::from_plane(distorted);
+    let mut scales = 1;
+    while scales < MS_SSIM_WEIGHTS.len() && (a.width >> scales) >= WINDOW && (a.height >> scales) >= WINDOW {
+        scales += 1;
+    }
+    let weights = &MS_SSIM_WEIGHTS[..scales];
+    let total: f64 = weights.iter().sum();
+
+    let mut result = 1.0;
+    for (i, &w) in weights.iter().enumerate() {
+        let (ssim, cs) = ssim_terms(&a, &b, max_value);
+        // The last scale contributes luminance as well; negative terms (from
+        // anti-correlated content) count as zero similarity.
+        let term = if i + 1 == scales { ssim } else { cs };
+        result *= term.max(0.0).powf(w / total);
+        if i + 1 < scales {
+            a = a.downsample();
+            b = b.downsample();
+        }
+    }
+    result
+}
+
+// Metrics for one frame. The per-plane vectors have one entry for
+// monochrome frames and three otherwise; MS-SSIM is for luma only.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FrameMetrics {
+    pub psnr: Vec<f64>,
+    pub psnr_weighted: f64,
+    pub ssim: Vec<f64>,
+    pub ssim_weighted: f64,
+    pub ms_ssim: f64,
+}
+
+fn weighted(values: &[f64]) -> f64 {
+    let weights = &PLANE_WEIGHTS[..values.len()];
+    values.iter().zip(weights).map(|(v, w)| v * w).sum::<f64>() / weights.iter().sum::<f64>()
+}
+
+// Compares two frames of the same size and format.
+pub fn compare_frames(reference: &Frame, distorted: &Frame) -> FrameMetrics {
+    assert_eq!(
+        (reference.chroma, reference.bit_depth),
+        (distorted.chroma, distorted.bit_depth),
+        "frames differ in format"
+    );
+    let max = reference.max_value();
+    let count = if reference.u.data.is_empty() { 1 } else { 3 };
+    let pairs: Vec<(&Plane, &Plane)> = reference.planes().into_iter().zip(distorted.planes()).take(count).collect();
+    let psnr: Vec<f64> = pairs.iter().map(|(a, b)| psnr(a, b, max)).collect();
+    let ssim: Vec<f64> = pairs.iter().map(|(a, b)| ssim(a, b, max)).collect();
+    FrameMetrics {
+        psnr_weighted: weighted(&psnr),
+        ssim_weighted: weighted(&ssim),
+        ms_ssim: ms_ssim(&reference.y, &distorted.y, max),
+        psnr,
+        ssim,
+    }
+}
+
+// The mean of each metric over frames, or None for no frames.
+pub fn average(frames: &[FrameMetrics]) -> Option<FrameMetrics> {
+    let first = frames.first()?;
+    let n = frames.len() as f64;
+    let mean = |f: &dyn Fn(&FrameMetrics) -> f64| frames.iter().map(f).sum::<f64>() / n;
+    Some(FrameMetrics {
+        psnr: (0..first.psnr.len()).map(|p| mean(&|m| m.psnr[p])).collect(),
+        psnr_weighted: mean(&|m| m.psnr_weighted),
+        ssim: (0..first.ssim.len()).map(|p| mean(&|m| m.ssim[p])).collect(),
+        ssim_weighted: mean(&|m| m.ssim_weighted),
+        ms_ssim: mean(&|m| m.ms_ssim),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::video::ChromaSampling;
+
+    fn textured(width: usize, height: usize) -> Plane {
+        let mut plane = Plane::new(width, height, 0);
+        for y in 0..height {
+            for x in 0..width {
+                let v = 128.0 + 60.0 * (x as f64 / 5.0).sin() * (y as f64 / 7.0).cos() + ((x * 7 + y * 13) % 11) as f64;
+                plane.set(x, y, v as u16);
+            }
+        }
+        plane
+    }
+
+    // Adds deterministic noise of the given amplitude.
+    fn noisy(plane: &Plane, amplitude: i32) -> Plane {
+        let mut out = plane.clone();
+        let mut state = 12345u32;
+        for s in out.data.iter_mut() {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            let noise = (state >> 8) as i32 % (2 * amplitude + 1) - amplitude;
+            *s = (*s as i32 + noise).clamp(0, 255) as u16;
+        }
+        out
+    }
+
+    #[test]
+    fn psnr_of_known_errors() {
+        let a = Plane::new(16, 16, 100);
+        assert_eq!(psnr(&a, &a, 255), MAX_PSNR);
+        let b = Plane::new(16, 16, 101);
+        assert!((psnr(&a, &b, 255) - 48.1308).abs() < 1e-3);
+        assert!((psnr(&a, &b, 1023) - 60.1975).abs() < 1e-3);
+        assert_eq!(mse(&a, &Plane::new(16, 16, 110)), 100.0);
+    }
+
+    #[test]
+    fn ssim_falls_with_noise() {
+        let a = textured(64, 48);
+        assert!((ssim(&a, &a, 255) - 1.0).abs() < 1e-12);
+        assert!((ms_ssim(&a, &a, 255) - 1.0).abs() < 1e-12);
+        let mut previous = (1.0, 1.0);
+        for amplitude in [2, 8, 32] {
+            let b = noisy(&a, amplitude);
+            let (s, ms) = (ssim(&a, &b, 255), ms_ssim(&a, &b, 255));
+            assert!(s < previous.0 && s > 0.0, "amplitude {} ssim {}", amplitude, s);
+            assert!(ms < previous.1 && ms > 0.0, "amplitude {} ms-ssim {}", amplitude, ms);
+            previous = (s, ms);
+        }
+        // A brightness shift hurts SSIM far less than noise of the same MSE.
+        let mut shifted = a.clone();
+        shifted.data.iter_mut().for_each(|s| *s += 5);
+        let noise = noisy(&a, 8);
+        assert!(mse(&a, &noise) > mse(&a, &shifted) * 0.5);
+        assert!(ssim(&a, &shifted, 255) > ssim(&a, &noise, 255));
+    }
+
+    #[test]
+    fn small_planes() {
+        let a = textured(6, 5);
+        let b = noisy(&a, 4);
+        let s = ssim(&a, &b, 255);
+        assert!(s > 0.0 && s < 1.0);
+        assert_eq!(ms_ssim(&a, &b, 255), s);
+    }
+
+    #[test]
+    fn frame_metrics_weight_planes() {
+        let mut reference = Frame::new(32, 32, ChromaSampling::Cs420, 8);
+        reference.y = textured(32, 32);
+        let mut distorted = reference.clone();
+        distorted.u.data.iter_mut().for_each(|s| *s += 1);
+        let m = compare_frames(&reference, &distorted);
+        assert_eq!(m.psnr.len(), 3);
+        assert_eq!(m.psnr[0], MAX_PSNR);
+        assert!((m.psnr_weighted - (7.0 * MAX_PSNR + m.psnr[1]) / 8.0).abs() < 1e-9);
+        assert_eq!(m.ms_ssim, 1.0);
+
+        let mono = Frame::new(16, 16, ChromaSampling::Mono, 8);
+        let m = compare_frames(&mono, &mono);
+        assert_eq!(m.psnr, vec![MAX_PSNR]);
+        assert_eq!(m.psnr_weighted, MAX_PSNR);
+
+        assert_eq!(average(&[m.clone(), m.clone()]), Some(m));
+        assert!(average(&[]).is_none());
+    }
+}
@@ -1,4 +1,4 @@
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Range {
     bw: u32,
     high: u64,
@@ -23,6 +23,17 @@ impl Range {
     fn three_quarter_mark(&self) -> u64 {0x3 << (self.bw-2)}
     fn quarter_mark(&self) -> u64 {(!self.three_quarter_mark()) & self.range_mask()}
 
+    pub fn buffer_width(&self) -> u32 {
+        self.bw
+    }
+
+    // Largest model total that can be coded without any interval collapsing
+    // to nothing. After renormalization the range is always wider than a
+    // quarter of the buffer, so every count of at least 1 gets a slot.
+    pub fn max_total(&self) -> u64 {
+        0x1 << (self.bw-2)
+    }
+
     pub fn width(&self) -> u64 {
         self.high - self.low + 1
     }
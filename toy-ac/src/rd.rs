@@ -0,0 +1,196 @@
+// Bjøntegaard delta measurements between two rate-distortion curves.
+//
+// BD-rate is the average difference in bitrate between the curves at equal
+// quality, as a percentage of the anchor's rate; negative means the test
+// curve needs fewer bits. BD-quality (BD-PSNR when the quality is PSNR) is
+// the average difference in quality at equal rate. Both integrate the
+// curves over the range where they overlap, with log10 rate on one axis.
+//
+// The curves are interpolated with monotone piecewise cubic (PCHIP)
+// Hermite splines, as in the current JVET BD-rate tools, rather than the
+// single cubic polynomial of the original VCEG-M33 method, which can swing
+// wildly between points.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RdPoint {
+    // Any unit, as long as both curves use the same one.
+    pub rate: f64,
+    pub quality: f64,
+}
+
+// A PCHIP interpolant through points sorted by x.
+struct Pchip {
+    x: Vec<f64>,
+    y: Vec<f64>,
+    slopes: Vec<f64>,
+}
+
+impl Pchip {
+    // None unless there are at least two points with distinct x.
+    fn new(mut points: Vec<(f64, f64)>) -> Option<Self> {
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        if points.len() < 2 || points.windows(2).any(|w| w[0].0 >= w[1].0) || points.iter().any(|p| !p.0.is_finite() || !p.1.is_finite()) {
+            return None;
+        }
+        let (x, y): (Vec<f64>, Vec<f64>) = points.into_iter().unzip();
+        let n = x.len();
+        let h: Vec<f64> = (0..n - 1).map(|k| x[k + 1] - x[k]).collect();
+        let delta: Vec<f64> = (0..n - 1).map(|k| (y[k + 1] - y[k]) / h[k]).collect();
+
+        let mut slopes = vec![0.0; n];
+        if n == 2 {
+            slopes = vec![delta[0]; 2];
+        } else {
+            // Interior slopes are a weighted harmonic mean of the neighbouring
+            // secants, or zero at a local extremum, which keeps each segment
+            // monotone.
+            for k in 1..n - 1 {
+                if delta[k - 1] * delta[k] > 0.0 {
+                    let w1 = 2.0 * h[k] + h[k - 1];
+                    let w2 = h[k] + 2.0 * h[k - 1];
+                    slopes[k] = (w1 + w2) / (w1 / delta[k - 1] + w2 / delta[k]);
+                }
+            }
+            slopes[0] = end_slope(h[0], h[1], delta[0], delta[1]);
+            slopes[n - 1] = end_slope(h[n - 2], h[n - 3], delta[n - 2], delta[n - 3]);
+        }
+        Some(Self { x, y, slopes })
+    }
+
+    fn min_x(&self) -> f64 {
+        self.x[0]
+    }
+
+    fn max_x(&self) -> f64 {
+        self.x[self.x.len() - 1]
+    }
+
+    // The integral over [from, to], which must lie within the points.
+    fn integrate(&self, from: f64, to: f64) -> f64 {
+        let mut total = 0.0;
+        for k in 0..self.x.len() - 1 {
+            let (x0, x1) = (self.x[k], self.x[k + 1]);
+            let (a, b) = (from.max(x0), to.min(x1));
+            if a >= b {
+                continue;
+            }
+            let h = x1 - x0;
+            let (y0, y1, d0, d1) = (self.y[k], self.y[k + 1], self.slopes[k], self.slopes[k + 1]);
+            // Antiderivative of the Hermite cubic in t = (x - x0) / h.
+            let antiderivative = |t: f64| {
+                let (t2, t3, t4) = (t * t, t * t * t, t * t * t * t);
+                y0 * (t - t3 + t4 / 2.0)
+                    + h * d0 * (t2 / 2.0 - 2.0 * t3 / 3.0 + t4 / 4.0)
+                    + y1 * (t3 - t4 / 2.0)
+                    + h * d1 * (t4 / 4.0 - t3 / 3.0)
+            };
+            total += h * (antiderivative((b - x0) / h) - antiderivative((a - x0) / h));
+        }
+        total
+    }
+}
+
+// Three-point estimate of the slope at an end, limited so the end segment
+// stays monotone.
+fn end_slope(h0: f64, h1: f64, delta0: f64, delta1: f64) -> f64 {
+    let d = ((2.0 * h0 + h1) * delta0 - h0 * delta1) / (h0 + h1);
+    if d.signum() != delta0.signum() {
+        0.0
+    } else if delta0.signum() != delta1.signum() && d.abs() > 3.0 * delta0.abs() {
+        3.0 * delta0
+    } else {
+        d
+    }
+}
+
+// Mean of g - f over the x range both cover, or None if they do not overlap.
+fn average_difference(f: &Pchip, g: &Pchip) -> Option<f64> {
+    let from = f.min_x().max(g.min_x());
+    let to = f.max_x().min(g.max_x());
+    if from >= to {
+        return None;
+    }
+    Some((g.integrate(from, to) - f.integrate(from, to)) / (to - from))
+}
+
+fn log_rate(points: &[RdPoint]) -> Option<Vec<(f64, f64)>> {
+    if points.iter().any(|p| p.rate <= 0.0) {
+        return None;
+    }
+    Some(points.iter().map(|p| (p.quality, p.rate.log10())).collect())
+}
+
+// Percentage change in rate of test over anchor at equal quality. None if
+// either curve has fewer than two distinct points, a non-positive rate, or
+// the quality ranges do not overlap.
+pub fn bd_rate(anchor: &[RdPoint], test: &[RdPoint]) -> Option<f64> {
+    let f = Pchip::new(log_rate(anchor)?)?;
+    let g = Pchip::new(log_rate(test)?)?;
+    Some((10f64.powf(average_difference(&f, &g)?) - 1.0) * 100.0)
+}
+
+// Change in quality of test over anchor at equal rate.
+pub fn bd_quality(anchor: &[RdPoint], test: &[RdPoint]) -> Option<f64> {
+    let swap = |points: Option<Vec<(f64, f64)>>| points.map(|p| p.into_iter().map(|(q, r)| (r, q)).collect::<Vec<_>>());
+    let f = Pchip::new(swap(log_rate(anchor))?)?;
+    let g = Pchip::new(swap(log_rate(test))?)?;
+    average_difference(&f, &g)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn curve(rates: &[f64], qualities: &[f64]) -> Vec<RdPoint> {
+        rates.iter().zip(qualities).map(|(&rate, &quality)| RdPoint { rate, quality }).collect()
+    }
+
+    #[test]
+    fn pchip_interpolates_and_integrates() {
+        // Linear data gives a linear interpolant.
+        let line = Pchip::new(vec![(0.0, 1.0), (1.0, 3.0), (3.0, 7.0), (4.0, 9.0)]).unwrap();
+        assert!((line.integrate(0.0, 4.0) - 20.0).abs() < 1e-12);
+        assert!((line.integrate(0.5, 2.0) - (2.0f64.powi(2) + 2.0 - 0.25 - 0.5)).abs() < 1e-12);
+        // Monotone data stays within the range of the points.
+        let steps = Pchip::new(vec![(0.0, 0.0), (1.0, 0.0), (2.0, 1.0), (3.0, 1.0)]).unwrap();
+        let middle = steps.integrate(1.0, 2.0);
+        assert!((middle - 0.5).abs() < 1e-12);
+        assert_eq!(steps.integrate(0.0, 1.0), 0.0);
+
+        assert!(Pchip::new(vec![(1.0, 1.0)]).is_none());
+        assert!(Pchip::new(vec![(1.0, 1.0), (1.0, 2.0)]).is_none());
+    }
+
+    #[test]
+    fn identical_curves_differ_by_nothing() {
+        let anchor = curve(&[100.0, 200.0, 400.0, 800.0], &[30.0, 33.0, 36.5, 39.0]);
+        assert!(bd_rate(&anchor, &anchor).unwrap().abs() < 1e-9);
+        assert!(bd_quality(&anchor, &anchor).unwrap().abs() < 1e-9);
+    }
+
+    #[test]
+    fn scaled_rates_give_the_scale() {
+        let anchor = curve(&[100.0, 200.0, 400.0, 800.0], &[30.0, 33.0, 36.5, 39.0]);
+        let rates: Vec<f64> = anchor.iter().map(|p| p.rate * 0.9).collect();
+        let qualities: Vec<f64> = anchor.iter().map(|p| p.quality).collect();
+        let test = curve(&rates, &qualities);
+        assert!((bd_rate(&anchor, &test).unwrap() + 10.0).abs() < 1e-9);
+        assert!(bd_quality(&anchor, &test).unwrap() > 0.0);
+
+        // A constant quality gain at every rate.
+        let better = curve(&[100.0, 200.0, 400.0, 800.0], &[30.5, 33.5, 37.0, 39.5]);
+        assert!((bd_quality(&anchor, &better).unwrap() - 0.5).abs() < 1e-9);
+        assert!(bd_rate(&anchor, &better).unwrap() < 0.0);
+        // Point order does not matter.
+        let reversed: Vec<RdPoint> = better.iter().rev().copied().collect();
+        assert_eq!(bd_rate(&anchor, &better), bd_rate(&anchor, &reversed));
+    }
+
+    #[test]
+    fn unusable_curves() {
+        let anchor = curve(&[100.0, 200.0], &[30.0, 33.0]);
+        assert!(bd_rate(&anchor, &curve(&[100.0, 200.0], &[40.0, 42.0])).is_none());
+        assert!(bd_rate(&anchor, &curve(&[0.0, 200.0], &[30.0, 33.0])).is_none());
+        assert!(bd_rate(&anchor, &curve(&[100.0], &[30.0])).is_none());
+    }
+}
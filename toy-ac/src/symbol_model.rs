@@ -5,6 +5,18 @@ pub trait SymbolModel<T: std::cmp::Eq> {
     fn lookup(&self, v: u32) -> (&T, u32, u32);
 }
 
+// A model that updates its counts as symbols are coded. Finding a symbol's
+// interval and updating its count happen in one pass over the symbols,
+// instead of the separate contains, interval and incr_count scans needed
+// when coding through SymbolModel.
+pub trait AdaptiveModel<T: std::cmp::Eq>: SymbolModel<T> {
+    // Interval of s before the update, or None if s is not in the model.
+    fn interval_and_update(&mut self, s: &T) -> Option<(u32, u32)>;
+    // Same as lookup, followed by an update with the symbol found.
+    fn lookup_and_update(&mut self, v: u32) -> (T, u32, u32);
+}
+
+#[derive(Clone, Debug)]
 pub struct VectorCountSymbolModel<T: std::cmp::Eq> {
     symbols: Vec<T>,
     counts: Vec<u32>,
@@ -111,6 +123,42 @@ impl<T: std::cmp::Eq> SymbolModel<T> for VectorCountSymbolModel<T> {
     }
 }
 
+impl<T: std::cmp::Eq + Clone> AdaptiveModel<T> for VectorCountSymbolModel<T> {
+    fn interval_and_update(&mut self, s: &T) -> Option<(u32, u32)> {
+        let mut sum = 0;
+        for idx in 0..self.symbols.len() {
+            if self.symbols[idx] == *s {
+                let interval = (sum, sum + self.counts[idx]);
+                self.counts[idx] += 1;
+                self.total += 1;
+                self.normalize();
+                return Some(interval);
+            }
+            sum += self.counts[idx];
+        }
+        None
+    }
+
+    fn lookup_and_update(&mut self, v: u32) -> (T, u32, u32) {
+        if v >= self.total {
+            panic!("Lookup value out of range");
+        }
+
+        let mut sum = 0;
+        for idx in 0..self.symbols.len() {
+            let next = sum + self.counts[idx];
+            if v < next {
+                self.counts[idx] += 1;
+                self.total += 1;
+                self.normalize();
+                return (self.symbols[idx].clone(), sum, next);
+            }
+            sum = next;
+        }
+        panic!("Should never happen");
+    }
+}
+
     pub fn ascii_english_letter_weights_1000() -> Vec<u32> {
     // a..z weights (frequency * 1000), roughly:
     // e=127, t=91, a=82, o=75, i=70, n=67, s=63, h=61, r=60, d=43, l=40,
@@ -220,4 +268,24 @@ mod tests {
         assert_eq!(e_interval.1, 50);
 
     }
+
+    #[test]
+    fn adaptive_update_test() {
+        let mut sm = VectorCountSymbolModel::new(vec!['a', 'b', 'c']);
+        let mut reference = sm.clone();
+
+        assert_eq!(sm.interval_and_update(&'b'), Some((1, 2)));
+        reference.incr_count(&'b');
+        assert_eq!(sm.counts, reference.counts);
+        assert_eq!(sm.total(), 4);
+        assert_eq!(sm.interval_and_update(&'z'), None);
+        assert_eq!(sm.total(), 4);
+
+        assert_eq!(sm.lookup_and_update(2), ('b', 1, 3));
+        assert_eq!(sm.lookup_and_update(4), ('c', 4, 5));
+        reference.incr_count(&'b');
+        reference.incr_count(&'c');
+        assert_eq!(sm.counts, reference.counts);
+        assert_eq!(sm.total(), 6);
+    }
 }
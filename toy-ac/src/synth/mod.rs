@@ -0,0 +1,81 @@
+// Deterministic synthetic test content, so tests and experiments do not
+// depend on media files. Everything is generated from a seed with the small
+// generator here, never from the system's randomness, so the same seed gives
+// the same content on every platform.
+
+pub mod text;
+pub mod video;
+
+// SplitMix64: tiny, fast and good enough for test content.
+#[derive(Clone, Debug)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in [0, 1).
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    // Uniform in [low, high).
+    pub fn range(&mut self, low: f64, high: f64) -> f64 {
+        low + (high - low) * self.next_f64()
+    }
+
+    // Uniform in [0, n), for n > 0.
+    pub fn below(&mut self, n: u64) -> u64 {
+        ((self.next_u64() as u128 * n as u128) >> 64) as u64
+    }
+
+    // Standard normal, by the Box-Muller transform.
+    pub fn gaussian(&mut self) -> f64 {
+        let u = 1.0 - self.next_f64();
+        let v = self.next_f64();
+        (-2.0 * u.ln()).sqrt() * (2.0 * std::f64::consts::PI * v).cos()
+    }
+}
+
+// A generator for the stream with the given index derived from seed, so
+// independent streams (frames, scenes) can be generated in any order.
+pub fn substream(seed: u64, index: u64) -> Rng {
+    let mut mix = Rng::new(seed ^ index.wrapping_mul(0xd6e8_feb8_6659_fd93));
+    Rng::new(mix.next_u64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deterministic_and_roughly_uniform() {
+        let a: Vec<u64> = (0..4).scan(Rng::new(7), |r, _| Some(r.next_u64())).collect();
+        let b: Vec<u64> = (0..4).scan(Rng::new(7), |r, _| Some(r.next_u64())).collect();
+        assert_eq!(a, b);
+        assert_ne!(substream(7, 0).next_u64(), substream(7, 1).next_u64());
+
+        let mut rng = Rng::new(1);
+        let mut counts = [0u32; 10];
+        for _ in 0..10000 {
+            counts[rng.below(10) as usize] += 1;
+        }
+        assert!(counts.iter().all(|&c| (850..1150).contains(&c)), "{:?}", counts);
+
+        let samples: Vec<f64> = (0..20000).map(|_| rng.gaussian()).collect();
+        let mean = samples.iter().sum::<f64>() / samples.len() as f64;
+        let variance = samples.iter().map(|s| (s - mean).powi(2)).sum::<f64>() / samples.len() as f64;
+        assert!(mean.abs() < 0.03 && (variance - 1.0).abs() < 0.05, "mean {} variance {}", mean, variance);
+    }
+}
@@ -0,0 +1,332 @@
+// Synthetic text-like corpora for testing and benchmarking the byte models
+// without data/shakespeare.txt:
+//
+// - letters: words of letters drawn independently by
+//   ascii_english_letter_weights_1000, with English-like word lengths,
+//   capitalised sentences, punctuation and line breaks
+// - bigram: a first order Markov chain over bytes, by default trained on an
+//   embedded passage of Shakespeare, so it has English's letter pairs
+// - binary: uniform random bytes, which no model can compress
+// - logs: timestamped log lines from a few templates, highly repetitive
+// - skewed: bytes drawn from a Zipf distribution, byte k with probability
+//   proportional to 1 / (k + 1)^skew
+
+use super::Rng;
+use crate::symbol_model::ascii_english_letter_weights_1000;
+use std::str::FromStr;
+
+// Public domain; Hamlet, act III, scene 1.
+const SAMPLE: &str = "To be, or not to be, that is the question:
+Whether 'tis nobler in the mind to suffer
+The slings and arrows of outrageous fortune,
+Or to take arms against a sea of troubles
+And by opposing end them. To die, to sleep;
+No more; and by a sleep to say we end
+The heart-ache and the thousand natural shocks
+That flesh is heir to: 'tis a consummation
+Devoutly to be wish'd. To die, to sleep;
+To sleep, perchance to dream. Ay, there's the rub:
+For in that sleep of death what dreams may come,
+When we have shuffled off this mortal coil,
+Must give us pause. There's the respect
+That makes calamity of so long life;
+For who would bear the whips and scorns of time,
+The oppressor's wrong, the proud man's contumely,
+The pangs of despised love, the law's delay,
+The insolence of office, and the spurns
+That patient merit of the unworthy takes,
+When he himself might his quietus make
+With a bare bodkin? Who would fardels bear,
+To grunt and sweat under a weary life,
+But that the dread of something after death,
+The undiscover'd country from whose bourn
+No traveller returns, puzzles the will,
+And makes us rather bear those ills we have
+Than fly to others that we know not of?
+Thus conscience does make cowards of us all;
+And thus the native hue of resolution
+Is sicklied o'er with the pale cast of thought,
+And enterprises of great pith and moment
+With this regard their currents turn awry,
+And lose the name of action.
+";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Corpus {
+    Letters,
+    Bigram,
+    Binary,
+    Logs,
+    Skewed,
+}
+
+impl FromStr for Corpus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "letters" => Ok(Corpus::Letters),
+            "bigram" => Ok(Corpus::Bigram),
+            "binary" => Ok(Corpus::Binary),
+            "logs" => Ok(Corpus::Logs),
+            "skewed" => Ok(Corpus::Skewed),
+            _ => Err(format!("unknown corpus {}", s)),
+        }
+    }
+}
+
+// Draws indices in proportion to a table of weights.
+#[derive(Clone, Debug)]
+struct Weighted {
+    cumulative: Vec<f64>,
+}
+
+impl Weighted {
+    // None if no weight is positive.
+    fn new(weights: impl IntoIterator<Item = f64>) -> Option<Self> {
+        let mut total = 0.0;
+        let cumulative: Vec<f64> = weights
+            .into_iter()
+            .map(|w| {
+                total += w.max(0.0);
+                total
+            })
+            .collect();
+        if total > 0.0 { Some(Self { cumulative }) } else { None }
+    }
+
+    fn sample(&self, rng: &mut Rng) -> usize {
+        let target = rng.next_f64() * self.cumulative[self.cumulative.len() - 1];
+        self.cumulative.partition_point(|&c| c <= target).min(self.cumulative.len() - 1)
+    }
+}
+
+// Next byte probabilities given the previous byte.
+#[derive(Clone, Debug)]
+pub struct BigramModel {
+    rows: Vec<Option<Weighted>>,
+    start: u8,
+}
+
+impl BigramModel {
+    // Counts the byte pairs of sample. None if it has fewer than two bytes.
+    pub fn train(sample: &[u8]) -> Option<Self> {
+        if sample.len() < 2 {
+            return None;
+        }
+        let mut counts = vec![[0u32; 256]; 256];
+        for pair in sample.windows(2) {
+            counts[pair[0] as usize][pair[1] as usize] += 1;
+        }
+        // The last byte may have no successor; let it continue as the
+        // sample did from the start.
+        counts[sample[sample.len() - 1] as usize][sample[0] as usize] += 1;
+        Some(Self {
+            rows: counts.iter().map(|row| Weighted::new(row.iter().map(|&c| c as f64))).collect(),
+            start: sample[0],
+        })
+    }
+
+    // Trained on the embedded passage of Shakespeare.
+    pub fn english() -> Self {
+        Self::train(SAMPLE.as_bytes()).unwrap()
+    }
+
+    pub fn generate(&self, length: usize, rng: &mut Rng) -> Vec<u8> {
+        let mut data = Vec::with_capacity(length);
+        let mut previous = self.start;
+        while data.len() < length {
+            // Every byte that occurs has a row, since the chain only visits
+            // bytes that follow something in the sample.
+            let next = match &self.rows[previous as usize] {
+                Some(row) => row.sample(rng) as u8,
+                None => self.start,
+            };
+            data.push(next);
+            previous = next;
+        }
+        data
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TextSpec {
+    pub corpus: Corpus,
+    pub length: usize,
+    // The Zipf exponent for skewed corpora; it must be finite.
+    pub skew: f64,
+    pub seed: u64,
+}
+
+impl Default for TextSpec {
+    fn default() -> Self {
+        Self {
+            corpus: Corpus::Bigram,
+            length: 1 << 20,
+            skew: 1.0,
+            seed: 1,
+        }
+    }
+}
+
+// Exactly spec.length bytes of the corpus.
+pub fn generate(spec: &TextSpec) -> Vec<u8> {
+    let mut rng = Rng::new(spec.seed);
+    let mut data = match spec.corpus {
+        Corpus::Letters => letters(spec.length, &mut rng),
+        Corpus::Bigram => BigramModel::english().generate(spec.length, &mut rng),
+        Corpus::Binary => (0..spec.length).map(|_| rng.next_u64() as u8).collect(),
+        Corpus::Logs => logs(spec.length, &mut rng),
+        Corpus::Skewed => {
+            // Relative to the most likely byte, so that no weight overflows.
+            let top = if spec.skew < 0.0 { 256.0 } else { 1.0 };
+            let zipf = Weighted::new((0..256).map(|k| ((k + 1) as f64 / top).powf(-spec.skew))).unwrap();
+            (0..spec.length).map(|_| zipf.sample(&mut rng) as u8).collect()
+        }
+    };
+    data.truncate(spec.length);
+    data
+}
+
+fn letters(length: usize, rng: &mut Rng) -> Vec<u8> {
+    let weights = ascii_english_letter_weights_1000();
+    let letters = Weighted::new((b'a'..=b'z').map(|c| weights[c as usize] as f64)).unwrap();
+    // Word lengths 1 to 12, peaking at 3 and 4 as in English text.
+    let lengths = Weighted::new([3.0, 17.0, 21.0, 16.0, 11.0, 9.0, 8.0, 6.0, 4.0, 3.0, 1.5, 0.5]).unwrap();
+
+    let mut data = Vec::with_capacity(length + 16);
+    let (mut line, mut sentence_start) = (0, true);
+    while data.len() < length {
+        let word_length = lengths.sample(rng) + 1;
+        for i in 0..word_length {
+            let c = b'a' + letters.sample(rng) as u8;
+            data.push(if i == 0 && sentence_start { c.to_ascii_uppercase() } else { c });
+        }
+        line += word_length;
+        sentence_start = false;
+        match rng.below(20) {
+            0..=1 => {
+                data.push(b'.');
+                sentence_start = true;
+            }
+            2..=3 => data.push(b','),
+            _ => {}
+        }
+        if line >= 60 + rng.below(15) as usize {
+            data.push(b'\n');
+            line = 0;
+        } else {
+            data.push(b' ');
+            line += 1;
+        }
+    }
+    data
+}
+
+fn logs(length: usize, rng: &mut Rng) -> Vec<u8> {
+    const PATHS: [&str; 5] = ["/", "/api/items", "/api/items/{}", "/api/users/{}", "/static/app.js"];
+    const USERS: [&str; 4] = ["alice", "bob", "carol", "dave"];
+    let levels = Weighted::new([90.0, 7.0, 3.0]).unwrap();
+
+    let mut data = Vec::with_capacity(length + 128);
+    // Milliseconds since the start of the day.
+    let mut time = 8 * 3_600_000u64;
+    while data.len() < length {
+        time += 1 + rng.below(250);
+        let (hours, minutes, seconds, millis) = (time / 3_600_000 % 24, time / 60_000 % 60, time / 1000 % 60, time % 1000);
+        let level = ["INFO", "WARN", "ERROR"][levels.sample(rng)];
+        let worker = 1 + rng.below(4);
+        let message = match (level, rng.below(3)) {
+            ("INFO", 0) => format!("user {} logged in from 10.0.0.{}", USERS[rng.below(4) as usize], rng.below(256)),
+            ("INFO", _) => {
+                let path = PATHS[rng.below(5) as usize].replace("{}", &rng.below(100).to_string());
+                format!("GET {} status=200 bytes={} time={}ms", path, 128 * (1 + rng.below(32)), 1 + rng.below(40))
+            }
+            ("WARN", _) => format!("slow query on items took {}ms", 500 + rng.below(2000)),
+            _ => format!("connection to db{} reset, retrying", rng.below(3)),
+        };
+        data.extend_from_slice(
+            format!("2026-01-01T{:02}:{:02}:{:02}.{:03}Z {:<5} server[{}]: {}\n", hours, minutes, seconds, millis, level, worker, message).as_bytes(),
+        );
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::empirical_entropy;
+
+    fn corpus(corpus: Corpus) -> Vec<u8> {
+        generate(&TextSpec {
+            corpus,
+            length: 50_000,
+            ..TextSpec::default()
+        })
+    }
+
+    #[test]
+    fn deterministic_and_exact_length() {
+        for kind in [Corpus::Letters, Corpus::Bigram, Corpus::Binary, Corpus::Logs, Corpus::Skewed] {
+            let spec = TextSpec {
+                corpus: kind,
+                length: 1234,
+                ..TextSpec::default()
+            };
+            let data = generate(&spec);
+            assert_eq!(data.len(), 1234, "{:?}", kind);
+            assert_eq!(data, generate(&spec), "{:?}", kind);
+            assert_ne!(data, generate(&TextSpec { seed: 2, ..spec }), "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn letters_follow_the_weights() {
+        let data = corpus(Corpus::Letters);
+        let mut counts = [0usize; 26];
+        for b in data.iter().filter(|b| b.is_ascii_alphabetic()) {
+            counts[(b.to_ascii_lowercase() - b'a') as usize] += 1;
+        }
+        let most = (0..26).max_by_key(|&i| counts[i]).unwrap();
+        assert_eq!(most, (b'e' - b'a') as usize);
+        assert!(counts[(b't' - b'a') as usize] > 10 * counts[(b'z' - b'a') as usize]);
+        assert!(data.contains(&b' ') && data.contains(&b'\n') && data.contains(&b'.'));
+    }
+
+    #[test]
+    fn bigrams_come_from_the_sample() {
+        let data = corpus(Corpus::Bigram);
+        let sample = SAMPLE.as_bytes();
+        for pair in data.windows(2) {
+            assert!(sample.windows(2).any(|s| s == pair) || pair == [sample[sample.len() - 1], sample[0]], "{:?}", pair);
+        }
+        // The previous byte says a lot about the next.
+        assert!(empirical_entropy(&data, 1) < empirical_entropy(&data, 0) - 0.8);
+        assert!(BigramModel::train(b"a").is_none());
+    }
+
+    #[test]
+    fn corpora_span_the_range_of_compressibility() {
+        let binary = empirical_entropy(&corpus(Corpus::Binary), 0);
+        let letters = empirical_entropy(&corpus(Corpus::Letters), 0);
+        let logs = empirical_entropy(&corpus(Corpus::Logs), 2);
+        assert!(binary > 7.9, "binary {}", binary);
+        assert!((3.5..5.0).contains(&letters), "letters {}", letters);
+        assert!(logs < 1.5, "logs {}", logs);
+
+        let skewed = |skew| {
+            let data = generate(&TextSpec {
+                corpus: Corpus::Skewed,
+                length: 50_000,
+                skew,
+                seed: 1,
+            });
+            (empirical_entropy(&data, 0), data.iter().filter(|&&b| b == 0).count())
+        };
+        let ((mild, mild_zeros), (steep, steep_zeros)) = (skewed(0.5), skewed(2.0));
+        assert!(steep < mild, "{} {}", steep, mild);
+        assert!(steep_zeros > mild_zeros && steep_zeros > 50_000 / 2);
+        let (_, reversed_zeros) = skewed(-1000.0);
+        assert_eq!(reversed_zeros, 0);
+    }
+}
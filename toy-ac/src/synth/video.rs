@@ -0,0 +1,393 @@
+// Synthetic video and images: a textured or gradient background, panning or
+// static, with textured squares and discs translating and rotating over it,
+// Gaussian noise and scene cuts.
+//
+// The scene is defined in continuous luma coordinates and sampled, so the
+// true motion of every part of a frame is known: the background moves by
+// the pan each frame and each object by its velocity and spin. Chroma
+// planes sample the same scene at their subsampled positions. At a scene
+// cut everything (textures, colours, objects) is generated again from a new
+// seed and the scene's clock restarts. Frames can be generated in any
+// order.
+
+use super::{Rng, substream};
+use crate::image::Image;
+use crate::video::{ChromaSampling, Frame};
+use std::f64::consts::PI;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Background {
+    // A sum of random sine waves, with detail at several scales.
+    Texture,
+    // Broad bands of smoothly varying brightness.
+    Gradient,
+}
+
+impl FromStr for Background {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "texture" => Ok(Background::Texture),
+            "gradient" => Ok(Background::Gradient),
+            _ => Err(format!("unknown background {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct VideoSpec {
+    pub width: usize,
+    pub height: usize,
+    pub chroma: ChromaSampling,
+    pub bit_depth: u8,
+    pub background: Background,
+    // Background motion per frame in luma pixels, which may be fractional.
+    // (0, 0) gives a static background.
+    pub pan: (f64, f64),
+    pub objects: usize,
+    // Largest object speed per frame along each axis, in luma pixels.
+    pub object_speed: f64,
+    // Object rotation per frame in radians; objects alternate direction.
+    pub rotation: f64,
+    // Standard deviation of the added noise, in 8-bit sample units.
+    pub noise: f64,
+    // Frames that start a new scene.
+    pub cuts: Vec<usize>,
+    pub seed: u64,
+}
+
+impl Default for VideoSpec {
+    fn default() -> Self {
+        Self {
+            width: 176,
+            height: 144,
+            chroma: ChromaSampling::Cs420,
+            bit_depth: 8,
+            background: Background::Texture,
+            pan: (1.0, 0.0),
+            objects: 2,
+            object_speed: 2.0,
+            rotation: 0.05,
+            noise: 0.0,
+            cuts: Vec::new(),
+            seed: 1,
+        }
+    }
+}
+
+// A sum of sine waves, in [-1, 1].
+#[derive(Clone, Debug)]
+struct Waves {
+    // Frequencies along x and y in radians per pixel, phase and amplitude.
+    waves: Vec<(f64, f64, f64, f64)>,
+}
+
+impl Waves {
+    fn random(rng: &mut Rng, count: usize, min_period: f64, max_period: f64) -> Self {
+        let mut waves = Vec::with_capacity(count);
+        let mut total = 0.0;
+        for _ in 0..count {
+            let direction = rng.range(0.0, PI);
+            let frequency = 2.0 * PI / rng.range(min_period, max_period);
+            let amplitude = rng.range(0.5, 1.0);
+            total += amplitude;
+            waves.push((frequency * direction.cos(), frequency * direction.sin(), rng.range(0.0, 2.0 * PI), amplitude));
+        }
+        for wave in waves.iter_mut() {
+            wave.3 /= total;
+        }
+        Self { waves }
+    }
+
+    fn at(&self, x: f64, y: f64) -> f64 {
+        self.waves.iter().map(|&(fx, fy, phase, amplitude)| amplitude * (fx * x + fy * y + phase).sin()).sum()
+    }
+}
+
+// Brightness and the two chroma values of a surface: a base level plus a
+// scaled pattern, for each plane.
+#[derive(Clone, Debug)]
+struct Surface {
+    pattern: Waves,
+    base: [f64; 3],
+    depth: [f64; 3],
+}
+
+impl Surface {
+    fn value(&self, plane: usize, x: f64, y: f64) -> f64 {
+        self.base[plane] + self.depth[plane] * self.pattern.at(x, y)
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Object {
+    square: bool,
+    size: f64,
+    start: (f64, f64),
+    velocity: (f64, f64),
+    spin: f64,
+    surface: Surface,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ObjectState {
+    pub center: (f64, f64),
+    // Radians, anticlockwise on screen.
+    pub angle: f64,
+}
+
+#[derive(Clone, Debug)]
+struct Scene {
+    background: Surface,
+    objects: Vec<Object>,
+}
+
+impl Scene {
+    fn new(spec: &VideoSpec, rng: &mut Rng) -> Self {
+        let tint = |rng: &mut Rng| [0.0, rng.range(0.3, 0.7), rng.range(0.3, 0.7)];
+        let mut base = tint(rng);
+        base[0] = 0.5;
+        let background = match spec.background {
+            Background::Texture => Surface {
+                pattern: Waves::random(rng, 6, 6.0, 60.0),
+                base,
+                depth: [0.35, 0.08, 0.08],
+            },
+            Background::Gradient => Surface {
+                pattern: Waves::random(rng, 2, 2.0 * spec.width.max(spec.height) as f64, 4.0 * spec.width.max(spec.height) as f64),
+                base,
+                depth: [0.4, 0.15, 0.15],
+            },
+        };
+
+        let (width, height) = (spec.width as f64, spec.height as f64);
+        let largest = (width.min(height) / 3.0).max(4.0);
+        let objects = (0..spec.objects)
+            .map(|i| {
+                let mut base = tint(rng);
+                base[0] = rng.range(0.3, 0.7);
+                Object {
+                    square: rng.below(2) == 0,
+                    size: rng.range(largest / 2.0, largest),
+                    start: (rng.range(0.0, width), rng.range(0.0, height)),
+                    velocity: (rng.range(-1.0, 1.0) * spec.object_speed, rng.range(-1.0, 1.0) * spec.object_speed),
+                    spin: if i % 2 == 0 { spec.rotation } else { -spec.rotation },
+                    surface: Surface {
+                        pattern: Waves::random(rng, 3, 4.0, 16.0),
+                        base,
+                        depth: [0.3, 0.1, 0.1],
+                    },
+                }
+            })
+            .collect();
+        Self { background, objects }
+    }
+}
+
+pub struct VideoSynth {
+    spec: VideoSpec,
+    scenes: Vec<Scene>,
+}
+
+impl VideoSynth {
+    pub fn new(mut spec: VideoSpec) -> Self {
+        spec.cuts.sort_unstable();
+        spec.cuts.dedup();
+        spec.cuts.retain(|&c| c > 0);
+        let scenes = (0..=spec.cuts.len()).map(|k| Scene::new(&spec, &mut substream(spec.seed, k as u64))).collect();
+        Self { spec, scenes }
+    }
+
+    pub fn spec(&self) -> &VideoSpec {
+        &self.spec
+    }
+
+    // The scene frame t belongs to and the frame that scene started at.
+    pub fn scene(&self, t: usize) -> (usize, usize) {
+        let k = self.spec.cuts.partition_point(|&c| c <= t);
+        (k, if k == 0 { 0 } else { self.spec.cuts[k - 1] })
+    }
+
+    pub fn is_cut(&self, t: usize) -> bool {
+        self.spec.cuts.binary_search(&t).is_ok()
+    }
+
+    // How far the background moved from frame t - 1 to frame t: what is at
+    // (x, y) in frame t was at (x - dx, y - dy) in frame t - 1. None for the
+    // first frame of each scene.
+    pub fn background_motion(&self, t: usize) -> Option<(f64, f64)> {
+        if t == 0 || self.is_cut(t) { None } else { Some(self.spec.pan) }
+    }
+
+    // Where each object is in frame t. Objects leaving one side of the frame
+    // come back on the other.
+    pub fn objects(&self, t: usize) -> Vec<ObjectState> {
+        let (k, start) = self.scene(t);
+        let time = (t - start) as f64;
+        let (width, height) = (self.spec.width as f64, self.spec.height as f64);
+        self.scenes[k]
+            .objects
+            .iter()
+            .map(|o| {
+                let wrap = |v: f64, extent: f64| (v + o.size).rem_euclid(extent + 2.0 * o.size) - o.size;
+                ObjectState {
+                    center: (wrap(o.start.0 + o.velocity.0 * time, width), wrap(o.start.1 + o.velocity.1 * time, height)),
+                    // Adding zero turns -0 into 0.
+                    angle: o.spin * time + 0.0,
+                }
+            })
+            .collect()
+    }
+
+    pub fn frame(&self, t: usize) -> Frame {
+        let spec = &self.spec;
+        let (k, start) = self.scene(t);
+        let scene = &self.scenes[k];
+        let time = (t - start) as f64;
+        let objects: Vec<(&Object, ObjectState)> = scene.objects.iter().zip(self.objects(t)).collect();
+        let mut noise = substream(spec.seed ^ 0x6e_6f69_7365, t as u64);
+        let sigma = spec.noise * (1u32 << (spec.bit_depth - 8)) as f64;
+
+        let mut frame = Frame::new(spec.width, spec.height, spec.chroma, spec.bit_depth);
+        let max = frame.max_value() as f64;
+        let shifts = spec.chroma.shifts();
+        for (p, plane) in frame.planes_mut().into_iter().enumerate() {
+            let (sx, sy) = if p == 0 { (0, 0) } else { shifts };
+            for y in 0..plane.height {
+                for x in 0..plane.width {
+                    let (lx, ly) = ((x << sx) as f64, (y << sy) as f64);
+                    let mut value = scene.background.value(p, lx - spec.pan.0 * time, ly - spec.pan.1 * time);
+                    for (object, state) in objects.iter() {
+                        let (dx, dy) = (lx - state.center.0, ly - state.center.1);
+                        let (sin, cos) = state.angle.sin_cos();
+                        let (u, v) = (dx * cos - dy * sin, dx * sin + dy * cos);
+                        let half = object.size / 2.0;
+                        let inside = if object.square { u.abs() <= half && v.abs() <= half } else { u * u + v * v <= half * half };
+                        if inside {
+                            value = object.surface.value(p, u, v);
+                        }
+                    }
+                    let mut sample = value * max;
+                    if sigma > 0.0 {
+                        sample += sigma * noise.gaussian();
+                    }
+                    plane.set(x, y, sample.round().clamp(0.0, max) as u16);
+                }
+            }
+        }
+        frame
+    }
+}
+
+// The luma of the first frame of spec as a one plane image.
+pub fn image(spec: VideoSpec) -> Image {
+    let frame = VideoSynth::new(spec).frame(0);
+    Image {
+        max_value: frame.max_value(),
+        planes: vec![frame.y],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::video::motion::{MotionVector, SearchParams, estimate};
+
+    fn spec(objects: usize, noise: f64) -> VideoSpec {
+        VideoSpec {
+            width: 64,
+            height: 48,
+            pan: (2.0, -1.0),
+            objects,
+            noise,
+            ..VideoSpec::default()
+        }
+    }
+
+    #[test]
+    fn frames_are_deterministic() {
+        let a = VideoSynth::new(spec(2, 3.0));
+        let b = VideoSynth::new(spec(2, 3.0));
+        assert!(a.frame(3) == b.frame(3));
+        assert!(a.frame(3) != a.frame(4));
+        let other = VideoSynth::new(VideoSpec { seed: 2, ..spec(2, 3.0) });
+        assert!(a.frame(0) != other.frame(0));
+    }
+
+    #[test]
+    fn background_moves_by_the_pan() {
+        let synth = VideoSynth::new(spec(0, 0.0));
+        let (previous, current) = (synth.frame(4), synth.frame(5));
+        assert_eq!(synth.background_motion(5), Some((2.0, -1.0)));
+        for y in 0..46 {
+            for x in 2..64 {
+                assert_eq!(current.y.get(x, y), previous.y.get(x - 2, y + 1));
+            }
+        }
+        // Motion estimation finds the displacement back into the reference.
+        let field = estimate(&current.y, &previous.y, &SearchParams::default(), previous.max_value());
+        let found = field.vectors.iter().filter(|&&v| v == MotionVector::new(-2, 1)).count();
+        assert!(found * 4 >= field.vectors.len() * 3, "{:?}", field.vectors);
+    }
+
+    #[test]
+    fn objects_translate_and_rotate() {
+        let synth = VideoSynth::new(VideoSpec {
+            pan: (0.0, 0.0),
+            rotation: 0.1,
+            ..spec(2, 0.0)
+        });
+        let (a, b) = (synth.objects(0), synth.objects(3));
+        assert!((b[0].angle - 0.3).abs() < 1e-12 && (b[1].angle + 0.3).abs() < 1e-12);
+        assert!(a[0].center != b[0].center);
+        // With a static background, only pixels near the objects change.
+        let (f0, f1) = (synth.frame(0), synth.frame(1));
+        let changed = f0.y.data.iter().zip(f1.y.data.iter()).filter(|(p, q)| p != q).count();
+        assert!(changed > 0 && changed < f0.y.data.len() / 2);
+    }
+
+    #[test]
+    fn noise_has_the_requested_sigma() {
+        let clean = VideoSynth::new(VideoSpec { bit_depth: 10, ..spec(1, 0.0) }).frame(2);
+        let noisy = VideoSynth::new(VideoSpec { bit_depth: 10, ..spec(1, 5.0) }).frame(2);
+        let differences: Vec<f64> = clean.y.data.iter().zip(noisy.y.data.iter()).map(|(&c, &n)| n as f64 - c as f64).collect();
+        let sigma = (differences.iter().map(|d| d * d).sum::<f64>() / differences.len() as f64).sqrt();
+        // 5 in 8-bit units is 20 at 10 bits.
+        assert!((sigma - 20.0).abs() < 1.5, "sigma {}", sigma);
+    }
+
+    #[test]
+    fn scene_cuts_change_everything() {
+        let synth = VideoSynth::new(VideoSpec { cuts: vec![5, 0, 5], ..spec(2, 0.0) });
+        assert_eq!(synth.spec().cuts, vec![5]);
+        assert_eq!(synth.scene(4), (0, 0));
+        assert_eq!(synth.scene(6), (1, 5));
+        assert!(synth.is_cut(5) && !synth.is_cut(6));
+        assert_eq!(synth.background_motion(5), None);
+        assert_eq!(synth.objects(5)[0].angle, 0.0);
+
+        // Mean difference from the previous frame along the pan, which only
+        // the objects disturb within a scene.
+        let mean_difference = |previous: &Frame, current: &Frame| {
+            let mut sum = 0.0;
+            for y in 0..46 {
+                for x in 2..64 {
+                    sum += (current.y.get(x, y) as f64 - previous.y.get(x - 2, y + 1) as f64).abs();
+                }
+            }
+            sum / (46.0 * 62.0)
+        };
+        let across = mean_difference(&synth.frame(4), &synth.frame(5));
+        let within = mean_difference(&synth.frame(5), &synth.frame(6));
+        assert!(across > 4.0 * within, "across {:.1}, within {:.1}", across, within);
+    }
+
+    #[test]
+    fn images_are_the_first_luma_plane() {
+        let image = image(VideoSpec { bit_depth: 12, ..spec(1, 0.0) });
+        assert_eq!((image.width(), image.height(), image.max_value), (64, 48, 4095));
+        assert!(image.planes[0] == VideoSynth::new(VideoSpec { bit_depth: 12, ..spec(1, 0.0) }).frame(0).y);
+    }
+}
@@ -0,0 +1,218 @@
+// Parsing and comparison of the per-symbol logs written by the
+// shakes_compress / shakes_decompress binaries when run with -log.
+//
+// Encoder lines look like:
+//   Count: 7, High: 0xffffffff, Low: 0x00000000, Symbol: 'e', IntStart: 101, IntEnd: 102, Total: 263, High: 0x..., Low: 0x...
+// Decoder lines look like:
+//   Count: 7, High: 0xffffffff, Low: 0x00000000, Buffer: 0x..., Total: 263, Symbol: 'e', High: 0x..., Low: 0x...
+//
+// The first High/Low pair is the range before the symbol is coded and the
+// second pair is the range after.
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub count: u64,
+    pub high: u64,
+    pub low: u64,
+    pub symbol: u32,
+    pub total: u32,
+    pub interval: Option<(u32, u32)>,
+    pub buffer: Option<u64>,
+    pub next_high: u64,
+    pub next_low: u64,
+}
+
+#[derive(Debug)]
+pub struct ParseTraceError {
+    pub message: String,
+}
+
+impl fmt::Display for ParseTraceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ParseTraceError {}
+
+fn parse_error(message: String) -> ParseTraceError {
+    ParseTraceError { message }
+}
+
+fn parse_number(key: &str, value: &str) -> Result<u64, ParseTraceError> {
+    let value = value.trim();
+    let parsed = match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse::<u64>(),
+    };
+    parsed.map_err(|_| parse_error(format!("bad value for {}: {:?}", key, value)))
+}
+
+// Symbols are logged either as \n (followed by a space to keep columns
+// lined up) or as a single quoted character. Returns the symbol and the
+// unparsed remainder of the line.
+fn parse_symbol(rest: &str) -> Result<(u32, &str), ParseTraceError> {
+    if let Some(after) = rest.strip_prefix("\\n") {
+        return Ok((10, after.strip_prefix(' ').unwrap_or(after)));
+    }
+    let mut chars = rest.char_indices();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some((_, '\'')), Some((_, c)), Some((end, '\''))) => Ok((c as u32, &rest[end + 1..])),
+        _ => Err(parse_error(format!("bad symbol: {:?}", rest))),
+    }
+}
+
+pub fn parse_line(line: &str) -> Result<TraceRecord, ParseTraceError> {
+    let mut count = None;
+    let mut highs = Vec::with_capacity(2);
+    let mut lows = Vec::with_capacity(2);
+    let mut symbol = None;
+    let mut total = None;
+    let mut int_start = None;
+    let mut int_end = None;
+    let mut buffer = None;
+
+    let mut rest = line.trim_end_matches(['\r', '\n']);
+    while !rest.is_empty() {
+        let colon = match rest.find(": ") {
+            Some(idx) => idx,
+            None => return Err(parse_error(format!("expected key at {:?}", rest))),
+        };
+        let key = &rest[..colon];
+        rest = &rest[colon + 2..];
+
+        if key == "Symbol" {
+            let (s, after) = parse_symbol(rest)?;
+            symbol = Some(s);
+            rest = after;
+        } else {
+            let end = rest.find(',').unwrap_or(rest.len());
+            let value = parse_number(key, &rest[..end])?;
+            rest = &rest[end..];
+            match key {
+                "Count" => count = Some(value),
+                "High" => highs.push(value),
+                "Low" => lows.push(value),
+                "Total" => total = Some(value as u32),
+                "IntStart" => int_start = Some(value as u32),
+                "IntEnd" => int_end = Some(value as u32),
+                "Buffer" => buffer = Some(value),
+                _ => return Err(parse_error(format!("unknown key: {:?}", key))),
+            }
+        }
+
+        rest = rest.trim_start_matches(',').trim_start();
+    }
+
+    if highs.len() != 2 || lows.len() != 2 {
+        return Err(parse_error("expected High/Low before and after symbol".to_string()));
+    }
+
+    let interval = match (int_start, int_end) {
+        (Some(s), Some(e)) => Some((s, e)),
+        (None, None) => None,
+        _ => return Err(parse_error("IntStart and IntEnd must appear together".to_string())),
+    };
+
+    Ok(TraceRecord {
+        count: count.ok_or_else(|| parse_error("missing Count".to_string()))?,
+        high: highs[0],
+        low: lows[0],
+        symbol: symbol.ok_or_else(|| parse_error("missing Symbol".to_string()))?,
+        total: total.ok_or_else(|| parse_error("missing Total".to_string()))?,
+        interval,
+        buffer,
+        next_high: highs[1],
+        next_low: lows[1],
+    })
+}
+
+pub fn format_symbol(s: u32) -> String {
+    match char::from_u32(s) {
+        Some('\n') => "\\n".to_string(),
+        Some(c) if !c.is_control() => format!("'{}'", c),
+        _ => format!("{:#04x}", s),
+    }
+}
+
+// Returns the names of the fields on which an encoder and a decoder record
+// for the same symbol index disagree, in the order they are coded.
+pub fn mismatched_fields(enc: &TraceRecord, dec: &TraceRecord) -> Vec<&'static str> {
+    let mut fields = Vec::new();
+    if enc.high != dec.high {
+        fields.push("high");
+    }
+    if enc.low != dec.low {
+        fields.push("low");
+    }
+    if enc.total != dec.total {
+        fields.push("total");
+    }
+    if enc.symbol != dec.symbol {
+        fields.push("symbol");
+    }
+    if enc.next_high != dec.next_high {
+        fields.push("next high");
+    }
+    if enc.next_low != dec.next_low {
+        fields.push("next low");
+    }
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_encoder_line() {
+        let r = parse_line("Count: 3, High: 0xffffffff, Low: 0x00000000, Symbol: 'e', IntStart:        101, IntEnd:        102, Total:        259, High: 0x64ffffff, Low: 0x63ffffff\n").unwrap();
+        assert_eq!(r.count, 3);
+        assert_eq!(r.high, 0xffffffff);
+        assert_eq!(r.low, 0);
+        assert_eq!(r.symbol, 'e' as u32);
+        assert_eq!(r.interval, Some((101, 102)));
+        assert_eq!(r.total, 259);
+        assert_eq!(r.buffer, None);
+        assert_eq!(r.next_high, 0x64ffffff);
+        assert_eq!(r.next_low, 0x63ffffff);
+    }
+
+    #[test]
+    fn parse_decoder_line() {
+        let r = parse_line("Count: 12, High: 0xfe7fffff, Low: 0x01000000, Buffer: 0x4a3b2c1d, Total:        268, Symbol: \\n , High: 0xa1ffffff, Low: 0x0c000000").unwrap();
+        assert_eq!(r.count, 12);
+        assert_eq!(r.buffer, Some(0x4a3b2c1d));
+        assert_eq!(r.total, 268);
+        assert_eq!(r.symbol, 10);
+        assert_eq!(r.interval, None);
+        assert_eq!(r.next_high, 0xa1ffffff);
+        assert_eq!(r.next_low, 0x0c000000);
+    }
+
+    #[test]
+    fn parse_punctuation_symbols() {
+        let r = parse_line("Count: 0, High: 0x1, Low: 0x0, Symbol: ',', IntStart: 1, IntEnd: 2, Total: 3, High: 0x1, Low: 0x0").unwrap();
+        assert_eq!(r.symbol, ',' as u32);
+        let r = parse_line("Count: 0, High: 0x1, Low: 0x0, Buffer: 0x0, Total: 3, Symbol: ''', High: 0x1, Low: 0x0").unwrap();
+        assert_eq!(r.symbol, '\'' as u32);
+    }
+
+    #[test]
+    fn parse_rejects_garbage() {
+        assert!(parse_line("Count: 0, High: 0x1").is_err());
+        assert!(parse_line("not a trace line").is_err());
+    }
+
+    #[test]
+    fn mismatch_detection() {
+        let enc = parse_line("Count: 5, High: 0xff, Low: 0x00, Symbol: 'a', IntStart: 0, IntEnd: 1, Total: 4, High: 0x3f, Low: 0x00").unwrap();
+        let dec = parse_line("Count: 5, High: 0xff, Low: 0x00, Buffer: 0x10, Total: 4, Symbol: 'a', High: 0x3f, Low: 0x00").unwrap();
+        assert!(mismatched_fields(&enc, &dec).is_empty());
+
+        let dec = parse_line("Count: 5, High: 0xff, Low: 0x00, Buffer: 0x10, Total: 5, Symbol: 'b', High: 0x3f, Low: 0x00").unwrap();
+        assert_eq!(mismatched_fields(&enc, &dec), vec!["total", "symbol"]);
+    }
+}
@@ -0,0 +1,1896 @@
+// Lossy video coding: intra coded I-frames, motion compensated P-frames and
+// bidirectionally predicted B-frames, with every residual transformed,
+// quantized and coded by CoeffCoder.
+//
+// I-frames code each plane as 8x8 blocks in raster order, each an intra
+// mode (see intra.rs) and the quantized residual of its prediction from the
+// blocks already reconstructed. P-frames are coded as 16x16 macroblocks in
+// raster order, predicted from the previous reconstructed I- or P-frame.
+// B-frames are coded the same way, predicted from the I- or P-frames either
+// side of them in display order, so they are coded after the later one: a
+// mini-GOP shown as I B B P is coded as I P B B. Nothing is predicted from a
+// B-frame. Each macroblock is one of:
+//
+// - skip: the predicted vectors and no residual
+// - vectors for the macroblock, and the residual
+// - vectors for each 8x8 quarter, and the residual; only when the header's
+//   motion block size is 8
+// - intra: the 8x8 blocks of each plane coded as in I-frames
+//
+// The vectors of a B-frame macroblock point into the past reference, the
+// future one or both, coded as its direction; two predictions are averaged,
+// and skipped macroblocks average both. The mode is coded with contexts
+// from how many of the macroblocks to the left and above were skipped.
+// Vectors, in whole, half or quarter pixels as the header says, are kept
+// for every 8x8 block and reference and coded as differences from the
+// median of their neighbours (see mvcoding.rs). Encoder and decoder run the
+// same reconstruction, so the decoder's output matches the encoder's
+// reconstruction exactly.
+//
+// The encoder picks macroblock and intra modes either by SAD, or by
+// rate-distortion cost: the squared error plus lambda times the exact bits
+// of the candidate, found by coding it with copies of the models and the
+// arithmetic coder and then throwing those away.
+//
+// Every reconstructed frame is deblocked (see deblock.rs) before it is
+// shown or used as a reference, unless its header turns that off.
+//
+// Each frame is a separate arithmetic coded segment. The models reset at
+// every I-frame and carry over from each frame to the next in coding order.
+// Besides every gop frames, the encoder starts an I-frame at each scene cut
+// it detects (see scenecut.rs), ending the mini-GOP before it, and counts
+// the next gop frames from there.
+// Decoding can start at any I-frame, but for the B-frames shown just before
+// it, which also refer to the I- or P-frame before it.
+//
+// Stream layout: the magic bytes "TACV"; width and height as big-endian
+// u32s; chroma sampling (0 4:2:0, 1 4:2:2, 2 4:4:4, 3 mono), bit depth,
+// smallest motion block size and vector precision (fractional bits: 0 whole,
+// 1 half, 2 quarter pixels) as u8s; frame rate numerator and denominator as
+// big-endian u32s. Then each frame in coding order as its length in bytes as
+// a big-endian u32, followed by its type (0 I, 1 P, 2 B) and QP as u8s, its
+// timestamp (the frame's index in display order) as a big-endian u32,
+// whether it is deblocked (0 or 1) as a u8 and the deblocking beta and tc
+// offsets as i8s, then the coded blocks padded to a whole byte.
+
+use super::coeff::{CoeffCoder, ScanOrder};
+use super::deblock::{BlockInfo, BlockMap, DeblockParams, deblock};
+use super::intra::{IntraModeCoder, ModeDecision, ModeMap, References, choose_mode, predict};
+use super::interp::interpolate;
+use super::motion::{MotionField, MotionVector, Precision, SearchParams, estimate, plane_vector};
+use super::mvcoding::{MvCoder, predict_with};
+use super::quant::{MAX_QP, Quantizer};
+use super::scenecut::cut_ratio;
+use super::transform::{BLOCK_SIZE, Block, forward, inverse, reconstruct_block, residual_block};
+use super::{ChromaSampling, Frame, Plane};
+use crate::decoder::{DecodeError, Decoder};
+use crate::encoder::Encoder;
+use crate::symbol_model::VectorCountSymbolModel;
+use bitbit::reader::Bit;
+use bitbit::{BitReader, BitWriter, MSB};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+
+pub const MAGIC: &[u8; 4] = b"TACV";
+pub const HEADER_LENGTH: usize = 4 + 4 + 4 + 4 + 4 + 4;
+const FRAME_HEADER_LENGTH: usize = 1 + 1 + 4 + 3;
+
+// B-frames are quantized more coarsely, since nothing is predicted from
+// them, as in the HEVC reference encoder's random access configuration.
+const B_QP_OFFSET: u8 = 2;
+
+// Quantizer rounding, as the HEVC reference encoder uses.
+const INTRA_ROUNDING: f64 = 1.0 / 3.0;
+const INTER_ROUNDING: f64 = 1.0 / 6.0;
+
+const MB_SIZE: usize = 16;
+const MB_MODE_CONTEXTS: usize = 3;
+
+// Rough bits of each macroblock mode and of each intra mode, for SAD
+// decisions, which cannot afford to code the candidates.
+const SAD_MODE_BITS: [f64; 4] = [1.0, 2.0, 4.0, 4.0];
+const SAD_INTRA_MODE_BITS: f64 = 3.0;
+const SAD_DIRECTION_BITS: f64 = 1.5;
+
+#[derive(Debug)]
+pub enum CodecError {
+    Io(io::Error),
+    InvalidHeader,
+    TooLarge(u64),
+    Unsupported(String),
+    TruncatedFrame(usize),
+    InvalidFrame(usize),
+    Decode { frame: usize, error: DecodeError },
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Io(e) => write!(f, "{}", e),
+            CodecError::InvalidHeader => write!(f, "invalid video stream header"),
+            CodecError::TooLarge(samples) => write!(f, "frames of {} samples exceed the allowed maximum", samples),
+            CodecError::Unsupported(msg) => write!(f, "unsupported video: {}", msg),
+            CodecError::TruncatedFrame(frame) => write!(f, "frame {} is truncated", frame),
+            CodecError::InvalidFrame(frame) => write!(f, "frame {} has an invalid type, QP, timestamp or deblocking", frame),
+            CodecError::Decode { frame, error } => write!(f, "error decoding frame {}: {}", frame, error),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+impl From<io::Error> for CodecError {
+    fn from(e: io::Error) -> Self {
+        CodecError::Io(e)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameType {
+    Intra,
+    Predicted,
+    Bidirectional,
+}
+
+impl FrameType {
+    fn tag(&self) -> u8 {
+        match self {
+            FrameType::Intra => 0,
+            FrameType::Predicted => 1,
+            FrameType::Bidirectional => 2,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            0 => Some(FrameType::Intra),
+            1 => Some(FrameType::Predicted),
+            2 => Some(FrameType::Bidirectional),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for FrameType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameType::Intra => f.pad("I"),
+            FrameType::Predicted => f.pad("P"),
+            FrameType::Bidirectional => f.pad("B"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StreamHeader {
+    pub width: usize,
+    pub height: usize,
+    pub chroma: ChromaSampling,
+    pub bit_depth: u8,
+    pub block_size: usize,
+    pub precision: Precision,
+    pub frame_rate: (u32, u32),
+}
+
+impl StreamHeader {
+    // The motion block size and precision come from the encoder's search.
+    pub fn new(first: &Frame, search: &SearchParams, frame_rate: (u32, u32)) -> Self {
+        Self {
+            width: first.width(),
+            height: first.height(),
+            chroma: first.chroma,
+            bit_depth: first.bit_depth,
+            block_size: search.block_size,
+            precision: search.precision,
+            frame_rate,
+        }
+    }
+
+    fn check(&self) -> Result<(), CodecError> {
+        if self.width == 0 || self.height == 0 || self.width > u32::MAX as usize || self.height > u32::MAX as usize {
+            return Err(CodecError::Unsupported(format!("frame size {}x{}", self.width, self.height)));
+        }
+        if !(8..=12).contains(&self.bit_depth) {
+            return Err(CodecError::Unsupported(format!("bit depth {}, expected 8 to 12", self.bit_depth)));
+        }
+        if self.block_size != 8 && self.block_size != 16 {
+            return Err(CodecError::Unsupported(format!("motion block size {}", self.block_size)));
+        }
+        Ok(())
+    }
+
+    pub fn write<W: Write>(&self, output: &mut W) -> io::Result<()> {
+        let chroma = match self.chroma {
+            ChromaSampling::Cs420 => 0u8,
+            ChromaSampling::Cs422 => 1,
+            ChromaSampling::Cs444 => 2,
+            ChromaSampling::Mono => 3,
+        };
+        let mut bytes = Vec::with_capacity(HEADER_LENGTH);
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&(self.width as u32).to_be_bytes());
+        bytes.extend_from_slice(&(self.height as u32).to_be_bytes());
+        bytes.extend_from_slice(&[chroma, self.bit_depth, self.block_size as u8, self.precision.bits() as u8]);
+        bytes.extend_from_slice(&self.frame_rate.0.to_be_bytes());
+        bytes.extend_from_slice(&self.frame_rate.1.to_be_bytes());
+        output.write_all(&bytes)
+    }
+
+    // Fails with TooLarge for frames of more than max_samples luma samples.
+    pub fn read<R: Read>(input: &mut R, max_samples: u64) -> Result<Self, CodecError> {
+        let mut bytes = [0u8; HEADER_LENGTH];
+        match input.read_exact(&mut bytes) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Err(CodecError::InvalidHeader),
+            Err(e) => return Err(CodecError::Io(e)),
+        }
+        if &bytes[..4] != MAGIC {
+            return Err(CodecError::InvalidHeader);
+        }
+        let be32 = |i: usize| u32::from_be_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        let chroma = match bytes[12] {
+            0 => ChromaSampling::Cs420,
+            1 => ChromaSampling::Cs422,
+            2 => ChromaSampling::Cs444,
+            3 => ChromaSampling::Mono,
+            _ => return Err(CodecError::InvalidHeader),
+        };
+        let Some(precision) = Precision::from_bits(bytes[15] as u32) else {
+            return Err(CodecError::InvalidHeader);
+        };
+        let header = Self {
+            width: be32(4) as usize,
+            height: be32(8) as usize,
+            chroma,
+            bit_depth: bytes[13],
+            block_size: bytes[14] as usize,
+            precision,
+            frame_rate: (be32(16), be32(20)),
+        };
+        let samples = header.width as u64 * header.height as u64;
+        if samples > max_samples {
+            return Err(CodecError::TooLarge(samples));
+        }
+        match header.check() {
+            Ok(()) => Ok(header),
+            Err(_) => Err(CodecError::InvalidHeader),
+        }
+    }
+}
+
+pub fn write_frame<W: Write>(output: &mut W, data: &[u8]) -> io::Result<()> {
+    if data.len() > u32::MAX as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame too large for the stream"));
+    }
+    output.write_all(&(data.len() as u32).to_be_bytes())?;
+    output.write_all(data)
+}
+
+// The next frame's data, or None at the end of the stream. frame is only
+// used to report errors.
+pub fn read_frame<R: Read>(input: &mut R, frame: usize) -> Result<Option<Vec<u8>>, CodecError> {
+    let mut length = [0u8; 4];
+    let read = super::read_fully(input, &mut length)?;
+    if read == 0 {
+        return Ok(None);
+    } else if read < length.len() {
+        return Err(CodecError::TruncatedFrame(frame));
+    }
+    let length = u32::from_be_bytes(length) as u64;
+    let mut data = Vec::new();
+    input.take(length).read_to_end(&mut data)?;
+    if (data.len() as u64) < length {
+        return Err(CodecError::TruncatedFrame(frame));
+    }
+    Ok(Some(data))
+}
+
+// Lagrange multiplier trading squared error against bits, from the HEVC
+// reference encoder. QP has the same meaning at every bit depth here, so
+// lambda does not depend on it.
+pub fn lambda(qp: u8) -> f64 {
+    0.57 * 2f64.powf((qp as f64 - 12.0) / 3.0)
+}
+
+// How the encoder picks macroblock and intra modes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Decision {
+    Sad,
+    Rd,
+}
+
+impl FromStr for Decision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "sad" => Ok(Decision::Sad),
+            "rd" => Ok(Decision::Rd),
+            _ => Err(format!("unknown mode decision {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EncoderConfig {
+    pub qp: u8,
+    // Frames from one I-frame to the next; 0 codes only the first frame as
+    // an I-frame.
+    pub gop: usize,
+    // B-frames between consecutive I- or P-frames: 2 gives I B B P.
+    pub b_frames: usize,
+    pub search: SearchParams,
+    pub decision: Decision,
+    pub deblock: DeblockParams,
+    // The cut_ratio above which a frame starts a new scene and is coded as
+    // an I-frame; 0 turns detection off.
+    pub scene_cut: f64,
+}
+
+impl Default for EncoderConfig {
+    fn default() -> Self {
+        Self {
+            qp: 32,
+            gop: 30,
+            b_frames: 0,
+            search: SearchParams {
+                precision: Precision::Quarter,
+                ..SearchParams::default()
+            },
+            decision: Decision::Sad,
+            deblock: DeblockParams::default(),
+            scene_cut: 0.7,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Models {
+    // Indexed by plane kind: luma, chroma.
+    modes: [IntraModeCoder; 2],
+    coeffs: CoeffCoder,
+    // Indexed by reference list.
+    mv: [MvCoder; 2],
+    mb_modes: Vec<VectorCountSymbolModel<u8>>,
+    directions: VectorCountSymbolModel<u8>,
+}
+
+impl Models {
+    fn new() -> Self {
+        Self {
+            modes: [IntraModeCoder::new(), IntraModeCoder::new()],
+            coeffs: CoeffCoder::new(ScanOrder::Diagonal),
+            mv: [MvCoder::new(), MvCoder::new()],
+            mb_modes: vec![VectorCountSymbolModel::new(MbMode::ALL.iter().map(|m| m.tag()).collect()); MB_MODE_CONTEXTS],
+            directions: VectorCountSymbolModel::new(Direction::ALL.iter().map(|d| d.tag()).collect()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum MbMode {
+    Skip,
+    Inter16,
+    Inter8,
+    Intra,
+}
+
+impl MbMode {
+    const ALL: [MbMode; 4] = [MbMode::Skip, MbMode::Inter16, MbMode::Inter8, MbMode::Intra];
+
+    fn tag(&self) -> u8 {
+        *self as u8
+    }
+}
+
+// The references an inter macroblock is predicted from: list 0 (the past
+// I- or P-frame), list 1 (the future one, for B-frames only) or the average
+// of both.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Direction {
+    Forward,
+    Backward,
+    Bi,
+}
+
+impl Direction {
+    const ALL: [Direction; 3] = [Direction::Forward, Direction::Backward, Direction::Bi];
+
+    fn tag(&self) -> u8 {
+        *self as u8
+    }
+
+    fn uses(&self, list: usize) -> bool {
+        match self {
+            Direction::Forward => list == 0,
+            Direction::Backward => list == 1,
+            Direction::Bi => true,
+        }
+    }
+}
+
+// A macroblock as the encoder may code it, with vectors for each reference
+// list; those of lists the direction does not use are ignored. Inter8
+// vectors are for the quarters in raster order; those outside the frame are
+// ignored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Macroblock {
+    Skip,
+    Inter16(Direction, [MotionVector; 2]),
+    Inter8(Direction, [[MotionVector; 4]; 2]),
+    Intra,
+}
+
+impl Macroblock {
+    fn mode(&self) -> MbMode {
+        match self {
+            Macroblock::Skip => MbMode::Skip,
+            Macroblock::Inter16(..) => MbMode::Inter16,
+            Macroblock::Inter8(..) => MbMode::Inter8,
+            Macroblock::Intra => MbMode::Intra,
+        }
+    }
+
+    // The direction of the coded vectors, if any.
+    fn direction(&self) -> Option<Direction> {
+        match self {
+            Macroblock::Inter16(direction, _) | Macroblock::Inter8(direction, _) => Some(*direction),
+            _ => None,
+        }
+    }
+
+    // The vector of quarter q for a list.
+    fn vector(&self, list: usize, q: usize) -> MotionVector {
+        match self {
+            Macroblock::Inter16(_, vectors) => vectors[list],
+            Macroblock::Inter8(_, vectors) => vectors[list][q],
+            _ => MotionVector::default(),
+        }
+    }
+}
+
+fn difference(a: MotionVector, b: MotionVector) -> MotionVector {
+    MotionVector::new(a.x - b.x, a.y - b.y)
+}
+
+// The state of a P- or B-frame being coded that encoder and decoder share:
+// the reconstruction so far, the vector and MVD of every 8x8 block for each
+// reference list, the mode of every macroblock, the intra modes of every
+// 8x8 block of each plane and how each luma block was coded, for
+// deblocking.
+struct InterFrame<'a> {
+    // The previous I- or P-frame, then for B-frames the next one.
+    references: Vec<&'a Frame>,
+    prediction: Frame,
+    recon: Frame,
+    // Indexed by reference list.
+    fields: Vec<MotionField>,
+    mvds: Vec<Vec<MotionVector>>,
+    mb_cols: usize,
+    mb_modes: Vec<Option<MbMode>>,
+    intra_modes: [ModeMap; 3],
+    blocks: BlockMap,
+}
+
+impl<'a> InterFrame<'a> {
+    fn new(references: Vec<&'a Frame>, precision: Precision) -> Self {
+        let reference = references[0];
+        let (width, height) = (reference.width(), reference.height());
+        let field = MotionField::new(width, height, BLOCK_SIZE).with_precision(precision);
+        let mb_cols = width.div_ceil(MB_SIZE);
+        Self {
+            prediction: Frame::new(width, height, reference.chroma, reference.bit_depth),
+            recon: Frame::new(width, height, reference.chroma, reference.bit_depth),
+            mvds: vec![vec![MotionVector::default(); field.vectors.len()]; references.len()],
+            fields: vec![field; references.len()],
+            mb_cols,
+            mb_modes: vec![None; mb_cols * height.div_ceil(MB_SIZE)],
+            intra_modes: reference.planes().map(|p| ModeMap::new(p.width, p.height)),
+            blocks: BlockMap::new(width, height, BlockInfo::default()),
+            references,
+        }
+    }
+
+    fn lists(&self) -> usize {
+        self.references.len()
+    }
+
+    fn bidirectional(&self) -> bool {
+        self.lists() == 2
+    }
+
+    // Skipped macroblocks are predicted from every reference.
+    fn skip_direction(&self) -> Direction {
+        if self.bidirectional() { Direction::Bi } else { Direction::Forward }
+    }
+
+    // Macroblock positions in coding order, as (col, row).
+    fn macroblocks(&self) -> impl Iterator<Item = (usize, usize)> + use<> {
+        let (cols, rows) = (self.mb_cols, self.mb_modes.len() / self.mb_cols);
+        (0..rows).flat_map(move |row| (0..cols).map(move |col| (col, row)))
+    }
+
+    fn mode_context(&self, mbx: usize, mby: usize) -> usize {
+        let skipped = |i: usize| (self.mb_modes[i] == Some(MbMode::Skip)) as usize;
+        let left = if mbx > 0 { skipped(mby * self.mb_cols + mbx - 1) } else { 0 };
+        let top = if mby > 0 { skipped((mby - 1) * self.mb_cols + mbx) } else { 0 };
+        left + top
+    }
+
+    // The 8x8 vector blocks of a macroblock inside the frame, in raster
+    // order, as (col, row, quarter).
+    fn quarters(&self, mbx: usize, mby: usize) -> Vec<(usize, usize, usize)> {
+        (0..4)
+            .map(|q| (2 * mbx + q % 2, 2 * mby + q / 2, q))
+            .filter(|&(col, row, _)| col < self.fields[0].cols && row < self.fields[0].rows)
+            .collect()
+    }
+
+    // The top-right block is coded unless it is in the next macroblock.
+    fn predicted_vector(&self, list: usize, col: usize, row: usize) -> MotionVector {
+        let field = &self.fields[list];
+        let top_right_coded = col + 1 < field.cols && (row.is_multiple_of(2) || col.is_multiple_of(2));
+        predict_with(field, col, row, top_right_coded)
+    }
+
+    // The predicted vector of each list, zero for lists the frame lacks.
+    fn predicted_vectors(&self, col: usize, row: usize) -> [MotionVector; 2] {
+        [0, 1].map(|list| if list < self.lists() { self.predicted_vector(list, col, row) } else { MotionVector::default() })
+    }
+
+    fn neighbour_mvds(&self, list: usize, col: usize, row: usize) -> (MotionVector, MotionVector) {
+        let (cols, mvds) = (self.fields[list].cols, &self.mvds[list]);
+        let left = if col > 0 { mvds[row * cols + col - 1] } else { MotionVector::default() };
+        let top = if row > 0 { mvds[(row - 1) * cols + col] } else { MotionVector::default() };
+        (left, top)
+    }
+
+    fn set_vector(&mut self, list: usize, col: usize, row: usize, mv: MotionVector, mvd: MotionVector) {
+        self.fields[list].set(col, row, mv);
+        self.mvds[list][row * self.fields[list].cols + col] = mvd;
+    }
+
+    // Records how the blocks of a macroblock are predicted: from the
+    // references of direction, or intra if None.
+    fn set_prediction(&mut self, mbx: usize, mby: usize, direction: Option<Direction>) {
+        for (col, row, _) in self.quarters(mbx, mby) {
+            let info = match direction {
+                None => BlockInfo::intra(),
+                Some(direction) => BlockInfo {
+                    vectors: [0, 1].map(|list| {
+                        let field = self.fields.get(list).filter(|_| direction.uses(list));
+                        field.map(|f| f.get(col, row).in_quarters(f.precision))
+                    }),
+                    ..BlockInfo::default()
+                },
+            };
+            self.blocks.set(col, row, info);
+        }
+    }
+
+    // The 8x8 blocks of plane p inside a macroblock, in raster order, as
+    // (col, row, x0, y0).
+    fn blocks(&self, p: usize, mbx: usize, mby: usize) -> Vec<(usize, usize, usize, usize)> {
+        let (sx, sy) = if p == 0 { (0, 0) } else { self.recon.chroma.shifts() };
+        let plane = self.recon.planes()[p];
+        let (x0, y0) = ((mbx * MB_SIZE) >> sx, (mby * MB_SIZE) >> sy);
+        let (x1, y1) = ((x0 + (MB_SIZE >> sx)).min(plane.width), (y0 + (MB_SIZE >> sy)).min(plane.height));
+        let mut blocks = Vec::with_capacity(4);
+        for y in (y0..y1).step_by(BLOCK_SIZE) {
+            for x in (x0..x1).step_by(BLOCK_SIZE) {
+                blocks.push((x / BLOCK_SIZE, y / BLOCK_SIZE, x, y));
+            }
+        }
+        blocks
+    }
+
+    // The prediction of the width x height block of plane p at (x0, y0)
+    // under the luma vectors of each list, as rows of samples. Two
+    // predictions are averaged.
+    fn predict(&self, p: usize, (x0, y0, width, height): (usize, usize, usize, usize), direction: Direction, vectors: [MotionVector; 2]) -> Vec<u16> {
+        let shifts = if p == 0 { (0, 0) } else { self.recon.chroma.shifts() };
+        let max_value = self.recon.max_value();
+        let mut predictions = (0..self.lists()).filter(|&list| direction.uses(list)).map(|list| {
+            let (mv, units) = plane_vector(vectors[list], self.fields[list].precision, shifts);
+            interpolate(self.references[list].planes()[p], x0, y0, width, height, mv, units, max_value)
+        });
+        let first = predictions.next().unwrap_or_default();
+        match predictions.next() {
+            Some(second) => first.iter().zip(&second).map(|(&a, &b)| ((a as u32 + b as u32 + 1) >> 1) as u16).collect(),
+            None => first,
+        }
+    }
+
+    // Motion compensates every plane of a macroblock into prediction, from
+    // the vectors in fields.
+    fn compensate(&mut self, mbx: usize, mby: usize, direction: Direction) {
+        for (col, row, _) in self.quarters(mbx, mby) {
+            let vectors = [0, 1].map(|list| if list < self.lists() { self.fields[list].get(col, row) } else { MotionVector::default() });
+            for p in 0..3 {
+                let (sx, sy) = if p == 0 { (0, 0) } else { self.recon.chroma.shifts() };
+                let (plane_width, plane_height) = (self.prediction.planes()[p].width, self.prediction.planes()[p].height);
+                let (x0, y0) = ((col * BLOCK_SIZE) >> sx, (row * BLOCK_SIZE) >> sy);
+                let width = (x0 + (BLOCK_SIZE >> sx)).min(plane_width).saturating_sub(x0);
+                let height = (y0 + (BLOCK_SIZE >> sy)).min(plane_height).saturating_sub(y0);
+                let block = self.predict(p, (x0, y0, width, height), direction, vectors);
+                let out = self.prediction.plane_mut(p);
+                for (y, row) in (y0..).zip(block.chunks(width.max(1))) {
+                    for (x, &s) in (x0..).zip(row) {
+                        out.set(x, y, s);
+                    }
+                }
+            }
+        }
+    }
+
+    // SAD of the luma prediction of an inter macroblock coded as mb.
+    fn sad(&self, source: &Plane, mbx: usize, mby: usize, mb: &Macroblock) -> u64 {
+        let direction = mb.direction().unwrap_or(self.skip_direction());
+        let mut sum = 0;
+        for (col, row, q) in self.quarters(mbx, mby) {
+            let (x0, y0) = (col * BLOCK_SIZE, row * BLOCK_SIZE);
+            let (width, height) = (BLOCK_SIZE.min(source.width - x0), BLOCK_SIZE.min(source.height - y0));
+            let prediction = self.predict(0, (x0, y0, width, height), direction, [mb.vector(0, q), mb.vector(1, q)]);
+            for y in 0..height {
+                let row = &source.row(y0 + y)[x0..x0 + width];
+                sum += row.iter().zip(&prediction[y * width..]).map(|(&s, &r)| (s as i64 - r as i64).unsigned_abs()).sum::<u64>();
+            }
+        }
+        sum
+    }
+
+    // Squared error of the reconstruction of a macroblock, over all planes.
+    fn distortion(&self, source: &Frame, mbx: usize, mby: usize) -> u64 {
+        let mut sum = 0;
+        for (p, (original, recon)) in source.planes().into_iter().zip(self.recon.planes()).enumerate() {
+            for (_, _, x0, y0) in self.blocks(p, mbx, mby) {
+                for y in y0..(y0 + BLOCK_SIZE).min(original.height) {
+                    for x in x0..(x0 + BLOCK_SIZE).min(original.width) {
+                        let d = original.get(x, y) as i64 - recon.get(x, y) as i64;
+                        sum += (d * d) as u64;
+                    }
+                }
+            }
+        }
+        sum
+    }
+}
+
+// Coding of the blocks of one frame, shared by I-frames and the
+// macroblocks of P-frames.
+struct FrameEncoder<'a> {
+    source: &'a Frame,
+    decision: Decision,
+    qp: u8,
+    intra: Quantizer,
+    inter: Quantizer,
+}
+
+impl<'a> FrameEncoder<'a> {
+    fn new(source: &'a Frame, decision: Decision, qp: u8) -> Self {
+        Self {
+            source,
+            decision,
+            qp,
+            intra: Quantizer::new(qp, source.bit_depth).with_deadzone(INTRA_ROUNDING),
+            inter: Quantizer::new(qp, source.bit_depth).with_deadzone(INTER_ROUNDING),
+        }
+    }
+
+    // Picks, codes and reconstructs the intra mode and residual of the
+    // block of plane p at (col, row, x0, y0).
+    fn encode_intra_block<W: Write>(
+        &self,
+        models: &mut Models,
+        recon: &mut Plane,
+        modes: &mut ModeMap,
+        (p, col, row, x0, y0): (usize, usize, usize, usize, usize),
+        enc: &mut Encoder,
+        output: &mut BitWriter<W>,
+    ) {
+        let source = self.source.planes()[p];
+        let bit_depth = self.source.bit_depth;
+        let kind = p.min(1);
+        let mpm = modes.most_probable_modes(col, row);
+        let decision = match self.decision {
+            Decision::Sad => ModeDecision::Sad,
+            Decision::Rd => ModeDecision::Rd {
+                quantizer: &self.intra,
+                coeffs: &models.coeffs,
+                lambda: lambda(self.qp),
+            },
+        };
+        let mode = choose_mode(source, recon, x0, y0, p, bit_depth, &mpm, &models.modes[kind], &decision);
+        models.modes[kind].encode_mode(mode, &mpm, enc, output);
+        modes.set(col, row, Some(mode));
+
+        let prediction = predict(&References::new(recon, x0, y0, bit_depth), mode);
+        let residual = intra_residual(source, &prediction, x0, y0);
+        let levels = self.intra.quantize(&forward(&residual, bit_depth));
+        models.coeffs.encode_block(&levels, p, enc, output);
+        let decoded = dequantized_residual(&self.intra, &levels, bit_depth);
+        reconstruct_intra(recon, &prediction, x0, y0, &decoded, self.source.max_value());
+    }
+
+    // Codes a macroblock as mb and reconstructs it into state.
+    fn encode_macroblock<W: Write>(
+        &self,
+        models: &mut Models,
+        state: &mut InterFrame,
+        (mbx, mby): (usize, usize),
+        mb: Macroblock,
+        enc: &mut Encoder,
+        output: &mut BitWriter<W>,
+    ) {
+        let mode = mb.mode();
+        let ctx = state.mode_context(mbx, mby);
+        enc.encode(&mode.tag(), &models.mb_modes[ctx], output);
+        models.mb_modes[ctx].incr_count(&mode.tag());
+        state.mb_modes[mby * state.mb_cols + mbx] = Some(mode);
+        if let Some(direction) = mb.direction()
+            && state.bidirectional()
+        {
+            enc.encode(&direction.tag(), &models.directions, output);
+            models.directions.incr_count(&direction.tag());
+        }
+
+        // Lists without coded vectors take the predicted vector.
+        let quarters = state.quarters(mbx, mby);
+        for list in 0..state.lists() {
+            let coded = mb.direction().is_some_and(|d| d.uses(list));
+            match mb {
+                Macroblock::Inter8(_, vectors) if coded => {
+                    for &(col, row, q) in quarters.iter() {
+                        let mvd = difference(vectors[list][q], state.predicted_vector(list, col, row));
+                        let (left, top) = state.neighbour_mvds(list, col, row);
+                        models.mv[list].encode_mvd(mvd, left, top, enc, output);
+                        state.set_vector(list, col, row, vectors[list][q], mvd);
+                    }
+                }
+                _ => {
+                    let (col, row, _) = quarters[0];
+                    let predicted = state.predicted_vector(list, col, row);
+                    let (mv, mvd) = match mb {
+                        Macroblock::Inter16(_, vectors) if coded => {
+                            let mvd = difference(vectors[list], predicted);
+                            let (left, top) = state.neighbour_mvds(list, col, row);
+                            models.mv[list].encode_mvd(mvd, left, top, enc, output);
+                            (vectors[list], mvd)
+                        }
+                        _ => (predicted, MotionVector::default()),
+                    };
+                    for &(col, row, _) in quarters.iter() {
+                        state.set_vector(list, col, row, mv, mvd);
+                    }
+                }
+            }
+        }
+
+        let bit_depth = self.source.bit_depth;
+        let max = self.source.max_value();
+        if mode == MbMode::Intra {
+            state.set_prediction(mbx, mby, None);
+            for p in 0..3 {
+                for (col, row, x0, y0) in state.blocks(p, mbx, mby) {
+                    let recon = state.recon.plane_mut(p);
+                    self.encode_intra_block(models, recon, &mut state.intra_modes[p], (p, col, row, x0, y0), enc, output);
+                }
+            }
+            return;
+        }
+        let direction = mb.direction().unwrap_or(state.skip_direction());
+        state.set_prediction(mbx, mby, Some(direction));
+        state.compensate(mbx, mby, direction);
+        for p in 0..3 {
+            for (col, row, x0, y0) in state.blocks(p, mbx, mby) {
+                state.intra_modes[p].set(col, row, None);
+                let predicted = state.prediction.planes()[p];
+                let levels = if mode == MbMode::Skip {
+                    [0; BLOCK_SIZE * BLOCK_SIZE]
+                } else {
+                    let residual = residual_block(self.source.planes()[p], predicted, x0, y0);
+                    let levels = self.inter.quantize(&forward(&residual, bit_depth));
+                    models.coeffs.encode_block(&levels, p, enc, output);
+                    levels
+                };
+                if p == 0 {
+                    state.blocks.set_coded(col, row, levels.iter().any(|&l| l != 0));
+                }
+                let decoded = dequantized_residual(&self.inter, &levels, bit_depth);
+                reconstruct_block(state.recon.plane_mut(p), predicted, x0, y0, &decoded, max);
+            }
+        }
+    }
+
+    // The modes worth trying for a macroblock, given the vectors motion
+    // estimation found for 16x16 blocks and, if allowed, 8x8 blocks, against
+    // each reference. B-frames try each direction, with the vectors found
+    // against each reference separately.
+    fn candidates(
+        &self,
+        state: &InterFrame,
+        (mbx, mby): (usize, usize),
+        fields16: &[MotionField],
+        fields8: Option<&[MotionField]>,
+    ) -> Vec<Macroblock> {
+        let found = [0, 1].map(|list| fields16.get(list).map_or(MotionVector::default(), |f| f.get(mbx, mby)));
+        let predicted = state.predicted_vectors(2 * mbx, 2 * mby);
+        let directions: &[Direction] = if state.bidirectional() { &Direction::ALL } else { &[Direction::Forward] };
+        let differs = |direction: Direction, a: [MotionVector; 2], b: [MotionVector; 2]| (0..2).any(|list| direction.uses(list) && a[list] != b[list]);
+
+        let mut candidates = vec![Macroblock::Skip];
+        for &direction in directions {
+            candidates.push(Macroblock::Inter16(direction, found));
+            if differs(direction, predicted, found) {
+                candidates.push(Macroblock::Inter16(direction, predicted));
+            }
+            if let Some(fields8) = fields8 {
+                let mut vectors = found.map(|mv| [mv; 4]);
+                for (col, row, q) in state.quarters(mbx, mby) {
+                    for (list, field) in fields8.iter().enumerate() {
+                        vectors[list][q] = field.get(col, row);
+                    }
+                }
+                if (0..4).any(|q| differs(direction, vectors.map(|v| v[q]), found)) {
+                    candidates.push(Macroblock::Inter8(direction, vectors));
+                }
+            }
+        }
+        candidates.push(Macroblock::Intra);
+        candidates
+    }
+
+    // The candidate with the lowest squared error plus lambda times bits,
+    // coding each with copies of the models and enc.
+    fn choose_rd(&self, models: &Models, state: &mut InterFrame, mb: (usize, usize), candidates: &[Macroblock], enc: &Encoder) -> Macroblock {
+        let lambda = lambda(self.qp);
+        let mut best = (Macroblock::Skip, f64::INFINITY);
+        for &candidate in candidates {
+            let mut trial_models = models.clone();
+            let mut trial_enc = enc.clone();
+            self.encode_macroblock(&mut trial_models, state, mb, candidate, &mut trial_enc, &mut BitWriter::new(io::sink()));
+            let bits = trial_enc.bits_used() - enc.bits_used();
+            let cost = state.distortion(self.source, mb.0, mb.1) as f64 + lambda * bits;
+            if cost < best.1 {
+                best = (candidate, cost);
+            }
+        }
+        best.0
+    }
+
+    // The candidate with the lowest SAD plus sqrt(lambda) times rough bits.
+    // Skip is taken outright when its residual would quantize to nothing,
+    // and otherwise left out, since SAD cannot weigh a residual against its
+    // bits.
+    fn choose_sad(&self, state: &InterFrame, (mbx, mby): (usize, usize), candidates: &[Macroblock]) -> Macroblock {
+        let source = &self.source.y;
+        let bit_depth = self.source.bit_depth;
+        let quarters = state.quarters(mbx, mby);
+        let predicted = state.predicted_vectors(2 * mbx, 2 * mby);
+
+        let skip_residual_vanishes = (0..3).all(|p| {
+            let plane = self.source.planes()[p];
+            state.blocks(p, mbx, mby).into_iter().all(|(_, _, bx, by)| {
+                let (width, height) = (BLOCK_SIZE.min(plane.width - bx), BLOCK_SIZE.min(plane.height - by));
+                let prediction = state.predict(p, (bx, by, width, height), state.skip_direction(), predicted);
+                let mut residual = [0i32; BLOCK_SIZE * BLOCK_SIZE];
+                for y in 0..height {
+                    for x in 0..width {
+                        residual[y * BLOCK_SIZE + x] = plane.get(bx + x, by + y) as i32 - prediction[y * width + x] as i32;
+                    }
+                }
+                self.inter.quantize(&forward(&residual, bit_depth)).iter().all(|&l| l == 0)
+            })
+        });
+        if skip_residual_vanishes {
+            return Macroblock::Skip;
+        }
+
+        let weight = lambda(self.qp).sqrt();
+        let vector_bits = |mvd: MotionVector| -> f64 {
+            let component = |d: i32| if d == 0 { 1.0 } else { 2.0 * (d.unsigned_abs() as f64).log2().floor() + 3.0 };
+            component(mvd.x) + component(mvd.y)
+        };
+        let direction_bits = if state.bidirectional() { SAD_DIRECTION_BITS } else { 0.0 };
+        let mut best = (Macroblock::Intra, f64::INFINITY);
+        for &candidate in candidates {
+            let cost = match candidate {
+                Macroblock::Skip => continue,
+                Macroblock::Inter16(direction, vectors) => {
+                    let mut cost = state.sad(source, mbx, mby, &candidate) as f64 + weight * (SAD_MODE_BITS[1] + direction_bits);
+                    for list in (0..state.lists()).filter(|&list| direction.uses(list)) {
+                        cost += weight * vector_bits(difference(vectors[list], predicted[list]));
+                    }
+                    cost
+                }
+                Macroblock::Inter8(direction, vectors) => {
+                    let mut cost = state.sad(source, mbx, mby, &candidate) as f64 + weight * (SAD_MODE_BITS[2] + direction_bits);
+                    for list in (0..state.lists()).filter(|&list| direction.uses(list)) {
+                        // Neighbouring quarters are predicted from each other,
+                        // so charge each against the macroblock prediction.
+                        for &(_, _, q) in quarters.iter() {
+                            cost += weight * vector_bits(difference(vectors[list][q], predicted[list]));
+                        }
+                    }
+                    cost
+                }
+                Macroblock::Intra => {
+                    // Predicted from the source rather than the
+                    // reconstruction, which does not exist yet inside the
+                    // macroblock.
+                    let mut cost = weight * SAD_MODE_BITS[3];
+                    for (col, row, bx, by) in state.blocks(0, mbx, mby) {
+                        let mpm = state.intra_modes[0].most_probable_modes(col, row);
+                        let mode = choose_mode(source, source, bx, by, 0, bit_depth, &mpm, &IntraModeCoder::new(), &ModeDecision::Sad);
+                        let prediction = predict(&References::new(source, bx, by, bit_depth), mode);
+                        let residual = intra_residual(source, &prediction, bx, by);
+                        cost += residual.iter().map(|r| r.unsigned_abs() as f64).sum::<f64>() + weight * SAD_INTRA_MODE_BITS;
+                    }
+                    cost
+                }
+            };
+            if cost < best.1 {
+                best = (candidate, cost);
+            }
+        }
+        best.0
+    }
+}
+
+// Block positions of a plane in raster order, as (col, row, x0, y0).
+fn blocks(plane: &Plane) -> impl Iterator<Item = (usize, usize, usize, usize)> + use<> {
+    let cols = plane.width.div_ceil(BLOCK_SIZE);
+    let rows = plane.height.div_ceil(BLOCK_SIZE);
+    (0..rows).flat_map(move |row| (0..cols).map(move |col| (col, row, col * BLOCK_SIZE, row * BLOCK_SIZE)))
+}
+
+// source - prediction for the block at (x0, y0), zero outside the plane.
+fn intra_residual(source: &Plane, prediction: &Block, x0: usize, y0: usize) -> Block {
+    let mut block = [0i32; BLOCK_SIZE * BLOCK_SIZE];
+    for y in 0..BLOCK_SIZE.min(source.height - y0) {
+        for x in 0..BLOCK_SIZE.min(source.width - x0) {
+            let i = y * BLOCK_SIZE + x;
+            block[i] = source.get(x0 + x, y0 + y) as i32 - prediction[i];
+        }
+    }
+    block
+}
+
+fn reconstruct_intra(plane: &mut Plane, prediction: &Block, x0: usize, y0: usize, residual: &Block, max_value: u16) {
+    for y in 0..BLOCK_SIZE.min(plane.height - y0) {
+        for x in 0..BLOCK_SIZE.min(plane.width - x0) {
+            let i = y * BLOCK_SIZE + x;
+            plane.set(x0 + x, y0 + y, (prediction[i] + residual[i]).clamp(0, max_value as i32) as u16);
+        }
+    }
+}
+
+fn dequantized_residual(quantizer: &Quantizer, levels: &Block, bit_depth: u8) -> Block {
+    if levels.iter().all(|&l| l == 0) {
+        return [0; BLOCK_SIZE * BLOCK_SIZE];
+    }
+    inverse(&quantizer.dequantize(levels), bit_depth)
+}
+
+#[derive(Clone, Debug)]
+pub struct EncodedFrame {
+    pub frame_type: FrameType,
+    pub qp: u8,
+    // The frame's index in display order.
+    pub timestamp: u32,
+    // What write_frame stores and VideoDecoder::decode_frame takes.
+    pub data: Vec<u8>,
+    // The frame as the decoder will reconstruct it.
+    pub reconstruction: Frame,
+}
+
+// Puts frames that arrive in coding order back into display order by their
+// timestamps. The first frame pushed is the first shown, so a stream can be
+// decoded from any I-frame.
+#[derive(Clone, Debug, Default)]
+pub struct ReorderBuffer {
+    // The timestamp of the next frame to show, once the first has arrived.
+    next: Option<u64>,
+    pending: BTreeMap<u32, Frame>,
+}
+
+impl ReorderBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Whether a frame with this timestamp has neither been shown nor is
+    // waiting to be.
+    pub fn accepts(&self, timestamp: u32) -> bool {
+        self.next.is_none_or(|next| timestamp as u64 >= next) && !self.pending.contains_key(&timestamp)
+    }
+
+    // Adds a frame, returning the frames now due in display order.
+    pub fn push(&mut self, timestamp: u32, frame: Frame) -> Vec<Frame> {
+        self.pending.insert(timestamp, frame);
+        let next = self.next.get_or_insert(timestamp as u64);
+        let mut due = Vec::new();
+        while let Some(frame) = u32::try_from(*next).ok().and_then(|t| self.pending.remove(&t)) {
+            due.push(frame);
+            *next += 1;
+        }
+        due
+    }
+
+    // The frames still waiting at the end of the stream, in display order,
+    // passing over any missing timestamps.
+    pub fn flush(&mut self) -> Vec<Frame> {
+        if let Some(&last) = self.pending.keys().next_back() {
+            self.next = Some(last as u64 + 1);
+        }
+        std::mem::take(&mut self.pending).into_values().collect()
+    }
+}
+
+// A frame waiting to be coded.
+#[derive(Clone, Debug)]
+struct Scheduled {
+    frame_type: FrameType,
+    timestamp: usize,
+    frame: Frame,
+}
+
+// Frames go in in display order and come out in coding order, each I- or
+// P-frame ahead of the B-frames before it. Cloning an encoder snapshots its
+// state, so a frame can be encoded again from the same starting point.
+#[derive(Clone)]
+pub struct VideoEncoder {
+    header: StreamHeader,
+    config: EncoderConfig,
+    models: Models,
+    // The reconstructions of the last two I- or P-frames, older first.
+    anchors: [Option<Frame>; 2],
+    // Frames given but not yet scheduled, in display order.
+    input: VecDeque<Frame>,
+    // Frames ready to code, in coding order.
+    queue: VecDeque<Scheduled>,
+    // Frames given so far.
+    received: usize,
+    // The last frame given, to detect scene cuts against.
+    previous: Option<Frame>,
+    // The timestamps of the scene cuts not yet scheduled.
+    cuts: Vec<usize>,
+    // The timestamps of the last I- and the last I- or P-frame scheduled.
+    last_intra: usize,
+    last_anchor: Option<usize>,
+    finished: bool,
+}
+
+impl VideoEncoder {
+    pub fn new(header: StreamHeader, config: EncoderConfig) -> Result<Self, CodecError> {
+        header.check()?;
+        if config.qp > MAX_QP {
+            return Err(CodecError::Unsupported(format!("QP {}, expected at most {}", config.qp, MAX_QP)));
+        }
+        if config.search.block_size != header.block_size {
+            return Err(CodecError::Unsupported("search block size differs from the stream header".to_string()));
+        }
+        if !config.deblock.is_valid() {
+            return Err(CodecError::Unsupported("deblocking offsets out of range".to_string()));
+        }
+        if config.scene_cut.is_nan() || config.scene_cut < 0.0 {
+            return Err(CodecError::Unsupported(format!("scene cut threshold {}", config.scene_cut)));
+        }
+        if config.search.precision != header.precision {
+            return Err(CodecError::Unsupported("vector precision differs from the stream header".to_string()));
+        }
+        Ok(Self {
+            header,
+            config,
+            models: Models::new(),
+            anchors: [None, None],
+            input: VecDeque::new(),
+            queue: VecDeque::new(),
+            received: 0,
+            previous: None,
+            cuts: Vec::new(),
+            last_intra: 0,
+            last_anchor: None,
+            finished: false,
+        })
+    }
+
+    pub fn header(&self) -> &StreamHeader {
+        &self.header
+    }
+
+    pub fn config(&self) -> &EncoderConfig {
+        &self.config
+    }
+
+    // Adds the next frame in display order. It is coded once the I- or
+    // P-frame that ends its mini-GOP has been added too, or at finish.
+    pub fn push(&mut self, frame: &Frame) -> Result<(), CodecError> {
+        if self.finished {
+            return Err(CodecError::Unsupported("frame after the end of the stream".to_string()));
+        }
+        if frame.width() != self.header.width
+            || frame.height() != self.header.height
+            || frame.chroma != self.header.chroma
+            || frame.bit_depth != self.header.bit_depth
+        {
+            return Err(CodecError::Unsupported(format!("frame {} does not match the stream header", self.received)));
+        }
+        if let Some(previous) = &self.previous
+            && self.config.scene_cut > 0.0
+            && cut_ratio(&previous.y, &frame.y, &self.config.search) > self.config.scene_cut
+        {
+            self.cuts.push(self.received);
+        }
+        self.previous = Some(frame.clone());
+        self.input.push_back(frame.clone());
+        self.received += 1;
+        self.schedule();
+        Ok(())
+    }
+
+    // Marks the end of the input, so the frames still held can be coded.
+    // The last frame becomes an I- or P-frame.
+    pub fn finish(&mut self) {
+        self.finished = true;
+        self.schedule();
+    }
+
+    // Moves the frames of each mini-GOP from input to queue once its I- or
+    // P-frame has arrived: that frame first, then the B-frames before it.
+    // I-frames come gop frames after the last one and at scene cuts, cutting
+    // a mini-GOP short if need be: a cut ends the mini-GOP before it with a
+    // P-frame, so no B-frame refers across it.
+    fn schedule(&mut self) {
+        while !self.input.is_empty() {
+            let first = self.received - self.input.len();
+            let anchor = match self.last_anchor {
+                None => 0,
+                Some(last) => {
+                    let mut next = last + 1 + self.config.b_frames;
+                    if self.config.gop > 0 {
+                        next = next.min(self.last_intra + self.config.gop);
+                    }
+                    if let Some(&cut) = self.cuts.iter().find(|&&cut| cut <= next) {
+                        next = if cut == last + 1 { cut } else { cut - 1 };
+                    }
+                    if next >= self.received {
+                        if !self.finished {
+                            return;
+                        }
+                        next = self.received - 1;
+                    }
+                    next
+                }
+            };
+            let intra = self.last_anchor.is_none()
+                || (self.config.gop > 0 && anchor == self.last_intra + self.config.gop)
+                || self.cuts.first() == Some(&anchor);
+            let frame_type = if intra { FrameType::Intra } else { FrameType::Predicted };
+            if intra {
+                self.last_intra = anchor;
+            }
+            self.cuts.retain(|&cut| cut > anchor);
+            let mut frames: Vec<Frame> = self.input.drain(..=anchor - first).collect();
+            let frame = frames.pop().unwrap();
+            self.queue.push_back(Scheduled { frame_type, timestamp: anchor, frame });
+            for (timestamp, frame) in (first..).zip(frames) {
+                self.queue.push_back(Scheduled { frame_type: FrameType::Bidirectional, timestamp, frame });
+            }
+            self.last_anchor = Some(anchor);
+        }
+    }
+
+    // The type the next frame will be coded as, or None if no frame is
+    // ready to code.
+    pub fn next_frame_type(&self) -> Option<FrameType> {
+        self.queue.front().map(|s| s.frame_type)
+    }
+
+    // Codes the next frame ready at the configured QP, offset for B-frames.
+    pub fn encode_next(&mut self) -> Result<Option<EncodedFrame>, CodecError> {
+        let qp = match self.next_frame_type() {
+            Some(FrameType::Bidirectional) => (self.config.qp + B_QP_OFFSET).min(MAX_QP),
+            _ => self.config.qp,
+        };
+        self.encode_next_with_qp(qp)
+    }
+
+    // Codes the next frame ready at qp rather than the configured QP.
+    pub fn encode_next_with_qp(&mut self, qp: u8) -> Result<Option<EncodedFrame>, CodecError> {
+        if qp > MAX_QP {
+            return Err(CodecError::Unsupported(format!("QP {}, expected at most {}", qp, MAX_QP)));
+        }
+        let Some(Scheduled { frame_type, timestamp, frame }) = self.queue.pop_front() else {
+            return Ok(None);
+        };
+
+        let mut data = vec![frame_type.tag(), qp];
+        data.extend_from_slice(&(timestamp as u32).to_be_bytes());
+        let deblocking = self.config.deblock;
+        data.extend_from_slice(&[deblocking.enabled as u8, deblocking.beta_offset as u8, deblocking.tc_offset as u8]);
+        let mut enc = Encoder::new();
+        let mut bw = BitWriter::new(&mut data);
+        let anchors = std::mem::take(&mut self.anchors);
+        let reconstruction = match (frame_type, &anchors) {
+            (FrameType::Predicted, [_, Some(latest)]) => self.encode_inter(&frame, vec![latest], qp, &mut enc, &mut bw),
+            (FrameType::Bidirectional, [Some(past), Some(future)]) => self.encode_inter(&frame, vec![past, future], qp, &mut enc, &mut bw),
+            _ => {
+                self.models = Models::new();
+                self.encode_intra(&frame, qp, &mut enc, &mut bw)
+            }
+        };
+        self.anchors = anchors;
+        if let Err(e) = enc.finish(&mut bw) {
+            return Err(CodecError::Io(io::Error::other(e.to_string())));
+        }
+        bw.pad_to_byte()?;
+
+        if frame_type != FrameType::Bidirectional {
+            let [_, latest] = std::mem::take(&mut self.anchors);
+            self.anchors = [latest, Some(reconstruction.clone())];
+        }
+        Ok(Some(EncodedFrame {
+            frame_type,
+            qp,
+            timestamp: timestamp as u32,
+            data,
+            reconstruction,
+        }))
+    }
+
+    // Adds the next frame in display order and codes the frames that are
+    // ready, in coding order.
+    pub fn encode_frame(&mut self, frame: &Frame) -> Result<Vec<EncodedFrame>, CodecError> {
+        self.push(frame)?;
+        self.encode_ready()
+    }
+
+    // Codes the frames still held at the end of the input.
+    pub fn flush(&mut self) -> Result<Vec<EncodedFrame>, CodecError> {
+        self.finish();
+        self.encode_ready()
+    }
+
+    fn encode_ready(&mut self) -> Result<Vec<EncodedFrame>, CodecError> {
+        let mut encoded = Vec::new();
+        while let Some(e) = self.encode_next()? {
+            encoded.push(e);
+        }
+        Ok(encoded)
+    }
+
+    fn encode_intra<W: Write>(&mut self, frame: &Frame, qp: u8, enc: &mut Encoder, output: &mut BitWriter<W>) -> Frame {
+        let coder = FrameEncoder::new(frame, self.config.decision, qp);
+        let mut recon = Frame::new(frame.width(), frame.height(), frame.chroma, frame.bit_depth);
+        for (p, out) in recon.planes_mut().into_iter().enumerate() {
+            let mut modes = ModeMap::new(out.width, out.height);
+            for (col, row, x0, y0) in blocks(out) {
+                coder.encode_intra_block(&mut self.models, out, &mut modes, (p, col, row, x0, y0), enc, output);
+            }
+        }
+        deblock(&mut recon, &BlockMap::new(frame.width(), frame.height(), BlockInfo::intra()), qp, &self.config.deblock);
+        recon
+    }
+
+    fn encode_inter<W: Write>(&mut self, frame: &Frame, references: Vec<&Frame>, qp: u8, enc: &mut Encoder, output: &mut BitWriter<W>) -> Frame {
+        let coder = FrameEncoder::new(frame, self.config.decision, qp);
+        let search = |block_size| -> Vec<MotionField> {
+            let params = SearchParams { block_size, ..self.config.search };
+            references.iter().map(|reference| estimate(&frame.y, &reference.y, &params, frame.max_value())).collect()
+        };
+        let fields16 = search(MB_SIZE);
+        let fields8 = if self.header.block_size < MB_SIZE { Some(search(BLOCK_SIZE)) } else { None };
+
+        let mut state = InterFrame::new(references, self.header.precision);
+        for mb in state.macroblocks() {
+            let candidates = coder.candidates(&state, mb, &fields16, fields8.as_deref());
+            let choice = match self.config.decision {
+                Decision::Sad => coder.choose_sad(&state, mb, &candidates),
+                Decision::Rd => coder.choose_rd(&self.models, &mut state, mb, &candidates, enc),
+            };
+            coder.encode_macroblock(&mut self.models, &mut state, mb, choice, enc, output);
+        }
+        deblock(&mut state.recon, &state.blocks, qp, &self.config.deblock);
+        state.recon
+    }
+}
+
+// Frames go in in coding order and come out in display order.
+pub struct VideoDecoder {
+    header: StreamHeader,
+    models: Models,
+    // The last two I- or P-frames, older first.
+    anchors: [Option<Frame>; 2],
+    reorder: ReorderBuffer,
+    frames: usize,
+}
+
+impl VideoDecoder {
+    pub fn new(header: StreamHeader) -> Self {
+        Self {
+            header,
+            models: Models::new(),
+            anchors: [None, None],
+            reorder: ReorderBuffer::new(),
+            frames: 0,
+        }
+    }
+
+    pub fn header(&self) -> &StreamHeader {
+        &self.header
+    }
+
+    // Decodes the next frame in coding order, returning the frames now due
+    // in display order.
+    pub fn decode_frame(&mut self, data: &[u8]) -> Result<Vec<Frame>, CodecError> {
+        let index = self.frames;
+        if data.len() < FRAME_HEADER_LENGTH {
+            return Err(CodecError::TruncatedFrame(index));
+        }
+        let timestamp = u32::from_be_bytes([data[2], data[3], data[4], data[5]]);
+        let deblocking = DeblockParams {
+            enabled: data[6] == 1,
+            beta_offset: data[7] as i8,
+            tc_offset: data[8] as i8,
+        };
+        let (frame_type, qp) = match FrameType::from_tag(data[0]) {
+            Some(t) if data[1] <= MAX_QP && self.reorder.accepts(timestamp) && data[6] <= 1 && deblocking.is_valid() => (t, data[1]),
+            _ => return Err(CodecError::InvalidFrame(index)),
+        };
+
+        let mut br: BitReader<_, MSB> = BitReader::new(&data[FRAME_HEADER_LENGTH..]);
+        let mut dec = Decoder::new();
+        let anchors = std::mem::take(&mut self.anchors);
+        let decoded = match (frame_type, &anchors) {
+            (FrameType::Intra, _) => {
+                self.models = Models::new();
+                self.decode_intra(qp, &deblocking, &mut dec, &mut br)
+            }
+            (FrameType::Predicted, [_, Some(latest)]) => self.decode_inter(vec![latest], qp, &deblocking, &mut dec, &mut br),
+            (FrameType::Bidirectional, [Some(past), Some(future)]) => {
+                self.decode_inter(vec![past, future], qp, &deblocking, &mut dec, &mut br)
+            }
+            _ => return Err(CodecError::InvalidFrame(index)),
+        };
+        self.anchors = anchors;
+        let frame = match decoded {
+            Ok(f) => f,
+            Err(error) => return Err(CodecError::Decode { frame: index, error }),
+        };
+
+        if frame_type != FrameType::Bidirectional {
+            let [_, latest] = std::mem::take(&mut self.anchors);
+            self.anchors = [latest, Some(frame.clone())];
+        }
+        self.frames += 1;
+        Ok(self.reorder.push(timestamp, frame))
+    }
+
+    // The frames still waiting to be shown at the end of the stream.
+    pub fn flush(&mut self) -> Vec<Frame> {
+        self.reorder.flush()
+    }
+
+    fn blank_frame(&self) -> Frame {
+        Frame::new(self.header.width, self.header.height, self.header.chroma, self.header.bit_depth)
+    }
+
+    fn decode_intra<R: Read, B: Bit>(
+        &mut self,
+        qp: u8,
+        deblocking: &DeblockParams,
+        dec: &mut Decoder,
+        input: &mut BitReader<R, B>,
+    ) -> Result<Frame, DecodeError> {
+        let quantizer = Quantizer::new(qp, self.header.bit_depth);
+        let mut frame = self.blank_frame();
+        for (p, out) in frame.planes_mut().into_iter().enumerate() {
+            let mut modes = ModeMap::new(out.width, out.height);
+            for (col, row, x0, y0) in blocks(out) {
+                self.decode_intra_block(&quantizer, out, &mut modes, (p, col, row, x0, y0), dec, input)?;
+            }
+        }
+        deblock(&mut frame, &BlockMap::new(self.header.width, self.header.height, BlockInfo::intra()), qp, deblocking);
+        Ok(frame)
+    }
+
+    fn decode_intra_block<R: Read, B: Bit>(
+        &mut self,
+        quantizer: &Quantizer,
+        recon: &mut Plane,
+        modes: &mut ModeMap,
+        (p, col, row, x0, y0): (usize, usize, usize, usize, usize),
+        dec: &mut Decoder,
+        input: &mut BitReader<R, B>,
+    ) -> Result<(), DecodeError> {
+        let bit_depth = self.header.bit_depth;
+        let mpm = modes.most_probable_modes(col, row);
+        let mode = self.models.modes[p.min(1)].decode_mode(&mpm, dec, input)?;
+        modes.set(col, row, Some(mode));
+
+        let prediction = predict(&References::new(recon, x0, y0, bit_depth), mode);
+        let levels = self.models.coeffs.decode_block(p, dec, input)?;
+        let decoded = dequantized_residual(quantizer, &levels, bit_depth);
+        reconstruct_intra(recon, &prediction, x0, y0, &decoded, (1 << bit_depth) - 1);
+        Ok(())
+    }
+
+    fn decode_inter<R: Read, B: Bit>(
+        &mut self,
+        references: Vec<&Frame>,
+        qp: u8,
+        deblocking: &DeblockParams,
+        dec: &mut Decoder,
+        input: &mut BitReader<R, B>,
+    ) -> Result<Frame, DecodeError> {
+        let quantizer = Quantizer::new(qp, self.header.bit_depth);
+        let mut state = InterFrame::new(references, self.header.precision);
+        for mb in state.macroblocks() {
+            self.decode_macroblock(&quantizer, &mut state, mb, dec, input)?;
+        }
+        deblock(&mut state.recon, &state.blocks, qp, deblocking);
+        Ok(state.recon)
+    }
+
+    fn decode_macroblock<R: Read, B: Bit>(
+        &mut self,
+        quantizer: &Quantizer,
+        state: &mut InterFrame,
+        (mbx, mby): (usize, usize),
+        dec: &mut Decoder,
+        input: &mut BitReader<R, B>,
+    ) -> Result<(), DecodeError> {
+        let ctx = state.mode_context(mbx, mby);
+        let tag = *dec.decode(&self.models.mb_modes[ctx], input)?;
+        self.models.mb_modes[ctx].incr_count(&tag);
+        let mode = match MbMode::ALL.get(tag as usize) {
+            Some(&MbMode::Inter8) if self.header.block_size == MB_SIZE => return Err(DecodeError::Desync),
+            Some(&mode) => mode,
+            None => return Err(DecodeError::Desync),
+        };
+        state.mb_modes[mby * state.mb_cols + mbx] = Some(mode);
+        let inter = matches!(mode, MbMode::Inter16 | MbMode::Inter8);
+        let direction = if !inter {
+            None
+        } else if state.bidirectional() {
+            let tag = *dec.decode(&self.models.directions, input)?;
+            self.models.directions.incr_count(&tag);
+            match Direction::ALL.get(tag as usize) {
+                Some(&d) => Some(d),
+                None => return Err(DecodeError::Desync),
+            }
+        } else {
+            Some(Direction::Forward)
+        };
+
+        // No encoder points a vector further outside the frame than its own
+        // size; larger ones would overflow scaling and deblocking later.
+        let limit = ((self.header.width + self.header.height) as i64) << self.header.precision.bits();
+        let add = |a: MotionVector, b: MotionVector| match (a.x.checked_add(b.x), a.y.checked_add(b.y)) {
+            (Some(x), Some(y)) if (x as i64).abs() <= limit && (y as i64).abs() <= limit => Ok(MotionVector::new(x, y)),
+            _ => Err(DecodeError::Desync),
+        };
+        let quarters = state.quarters(mbx, mby);
+        for list in 0..state.lists() {
+            let coded = direction.is_some_and(|d| d.uses(list));
+            if coded && mode == MbMode::Inter8 {
+                for &(col, row, _) in quarters.iter() {
+                    let (left, top) = state.neighbour_mvds(list, col, row);
+                    let mvd = self.models.mv[list].decode_mvd(left, top, dec, input)?;
+                    let mv = add(state.predicted_vector(list, col, row), mvd)?;
+                    state.set_vector(list, col, row, mv, mvd);
+                }
+            } else {
+                let (col, row, _) = quarters[0];
+                let mvd = if coded {
+                    let (left, top) = state.neighbour_mvds(list, col, row);
+                    self.models.mv[list].decode_mvd(left, top, dec, input)?
+                } else {
+                    MotionVector::default()
+                };
+                let mv = add(state.predicted_vector(list, col, row), mvd)?;
+                for &(col, row, _) in quarters.iter() {
+                    state.set_vector(list, col, row, mv, mvd);
+                }
+            }
+        }
+
+        let bit_depth = self.header.bit_depth;
+        if mode == MbMode::Intra {
+            state.set_prediction(mbx, mby, None);
+            for p in 0..3 {
+                for (col, row, x0, y0) in state.blocks(p, mbx, mby) {
+                    let recon = state.recon.plane_mut(p);
+                    self.decode_intra_block(quantizer, recon, &mut state.intra_modes[p], (p, col, row, x0, y0), dec, input)?;
+                }
+            }
+            return Ok(());
+        }
+        let direction = direction.unwrap_or(state.skip_direction());
+        state.set_prediction(mbx, mby, Some(direction));
+        state.compensate(mbx, mby, direction);
+        let max = state.recon.max_value();
+        for p in 0..3 {
+            for (col, row, x0, y0) in state.blocks(p, mbx, mby) {
+                state.intra_modes[p].set(col, row, None);
+                let predicted = state.prediction.planes()[p];
+                let levels = if mode == MbMode::Skip {
+                    [0; BLOCK_SIZE * BLOCK_SIZE]
+                } else {
+                    self.models.coeffs.decode_block(p, dec, input)?
+                };
+                if p == 0 {
+                    state.blocks.set_coded(col, row, levels.iter().any(|&l| l != 0));
+                }
+                let decoded = dequantized_residual(quantizer, &levels, bit_depth);
+                reconstruct_block(state.recon.plane_mut(p), predicted, x0, y0, &decoded, max);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::{average, compare_frames};
+    use crate::synth::video::{VideoSpec, VideoSynth};
+
+    // A textured background panning left with a bright square moving down
+    // and right across it.
+    fn synthetic_sequence(count: usize, width: usize, height: usize, chroma: ChromaSampling, bit_depth: u8) -> Vec<Frame> {
+        let scale = 1 << (bit_depth - 8);
+        (0..count)
+            .map(|t| {
+                let t = t as i32;
+                let mut frame = Frame::new(width, height, chroma, bit_depth);
+                let shifts = chroma.shifts();
+                for (p, plane) in frame.planes_mut().into_iter().enumerate() {
+                    let (sx, sy) = if p == 0 { (0, 0) } else { shifts };
+                    for y in 0..plane.height {
+                        for x in 0..plane.width {
+                            let (gx, gy) = (((x << sx) as i32 + 2 * t) as f64, (y << sy) as i32 as f64);
+                            let mut v = 120.0 + 50.0 * (gx / 7.0).sin() * (gy / 5.0).cos() + 20.0 * (p as f64);
+                            let (qx, qy) = ((x << sx) as i32 - 3 * t, (y << sy) as i32 - t);
+                            if (4..14).contains(&qx) && (6..16).contains(&qy) {
+                                v = 230.0;
+                            }
+                            plane.set(x, y, v as u16 * scale);
+                        }
+                    }
+                }
+                frame
+            })
+            .collect()
+    }
+
+    // Encodes frames into a stream, decodes it and checks the decoder
+    // reproduces the encoder's reconstruction. Returns the stream and the
+    // encoded frames in display order.
+    fn round_trip(frames: &[Frame], config: EncoderConfig) -> (Vec<u8>, Vec<EncodedFrame>) {
+        let header = StreamHeader::new(&frames[0], &config.search, (25, 1));
+        let mut encoder = VideoEncoder::new(header.clone(), config).unwrap();
+        let mut stream = Vec::new();
+        header.write(&mut stream).unwrap();
+        let mut encoded = Vec::new();
+        for frame in frames {
+            encoded.extend(encoder.encode_frame(frame).unwrap());
+        }
+        encoded.extend(encoder.flush().unwrap());
+        for e in encoded.iter() {
+            write_frame(&mut stream, &e.data).unwrap();
+        }
+        encoded.sort_by_key(|e| e.timestamp);
+
+        let mut input = &stream[..];
+        let read_header = StreamHeader::read(&mut input, 1 << 20).unwrap();
+        assert_eq!(read_header, header);
+        let mut decoder = VideoDecoder::new(read_header);
+        let (mut i, mut decoded) = (0, Vec::new());
+        while let Some(data) = read_frame(&mut input, i).unwrap() {
+            decoded.extend(decoder.decode_frame(&data).unwrap());
+            i += 1;
+        }
+        decoded.extend(decoder.flush());
+        assert_eq!(decoded.len(), frames.len());
+        for (t, frame) in decoded.iter().enumerate() {
+            assert_eq!(encoded[t].timestamp as usize, t);
+            assert!(*frame == encoded[t].reconstruction, "frame {} differs from the encoder's reconstruction", t);
+        }
+        (stream, encoded)
+    }
+
+    #[test]
+    fn decoder_matches_encoder_reconstruction() {
+        // Sizes that are not whole blocks, in luma or chroma.
+        let frames = synthetic_sequence(7, 76, 46, ChromaSampling::Cs420, 8);
+        let config = EncoderConfig {
+            qp: 24,
+            gop: 3,
+            ..EncoderConfig::default()
+        };
+        let (_, encoded) = round_trip(&frames, config);
+        let types: Vec<FrameType> = encoded.iter().map(|e| e.frame_type).collect();
+        use FrameType::{Intra as I, Predicted as P};
+        assert_eq!(types, vec![I, P, P, I, P, P, I]);
+        for (frame, e) in frames.iter().zip(encoded.iter()) {
+            let quality = compare_frames(frame, &e.reconstruction).psnr_weighted;
+            assert!(quality > 35.0, "{} frame at {:.1} dB", e.frame_type, quality);
+        }
+        // P-frames of a smooth pan cost much less than I-frames.
+        assert!(encoded[1].data.len() * 2 < encoded[0].data.len());
+    }
+
+    #[test]
+    fn other_formats_and_decisions_round_trip() {
+        let config = EncoderConfig {
+            qp: 30,
+            gop: 0,
+            decision: Decision::Rd,
+            search: SearchParams {
+                block_size: 8,
+                ..SearchParams::default()
+            },
+            ..EncoderConfig::default()
+        };
+        round_trip(&synthetic_sequence(3, 24, 17, ChromaSampling::Cs444, 10), config);
+        round_trip(&synthetic_sequence(3, 16, 16, ChromaSampling::Mono, 8), config);
+        round_trip(&synthetic_sequence(3, 20, 12, ChromaSampling::Cs422, 12), config);
+    }
+
+    #[test]
+    fn rd_decisions_lower_the_lagrangian_cost() {
+        let frames = synthetic_sequence(4, 48, 40, ChromaSampling::Cs420, 8);
+        let cost = |decision| {
+            let config = EncoderConfig {
+                qp: 30,
+                gop: 0,
+                decision,
+                search: SearchParams {
+                    block_size: 8,
+                    ..SearchParams::default()
+                },
+                ..EncoderConfig::default()
+            };
+            let (stream, encoded) = round_trip(&frames, config);
+            let sse: f64 = frames
+                .iter()
+                .zip(encoded.iter())
+                .flat_map(|(f, e)| f.planes().into_iter().zip(e.reconstruction.planes()))
+                .map(|(a, b)| a.data.iter().zip(b.data.iter()).map(|(&x, &y)| (x as f64 - y as f64).powi(2)).sum::<f64>())
+                .sum();
+            sse + lambda(config.qp) * 8.0 * stream.len() as f64
+        };
+        let (sad, rd) = (cost(Decision::Sad), cost(Decision::Rd));
+        assert!(rd < sad, "RD cost {:.0}, SAD cost {:.0}", rd, sad);
+    }
+
+    #[test]
+    fn sub_pixel_vectors_save_bits_on_slow_pans() {
+        let synth = VideoSynth::new(VideoSpec {
+            width: 64,
+            height: 48,
+            pan: (0.5, 0.25),
+            objects: 0,
+            noise: 0.0,
+            ..VideoSpec::default()
+        });
+        let frames: Vec<Frame> = (0..5).map(|t| synth.frame(t)).collect();
+        let bits = |precision| {
+            let config = EncoderConfig {
+                qp: 30,
+                gop: 0,
+                search: SearchParams {
+                    precision,
+                    ..SearchParams::default()
+                },
+                ..EncoderConfig::default()
+            };
+            let (_, encoded) = round_trip(&frames, config);
+            encoded[1..].iter().map(|e| e.data.len()).sum::<usize>()
+        };
+        let (whole, half, quarter) = (bits(Precision::Whole), bits(Precision::Half), bits(Precision::Quarter));
+        assert!(quarter < half && half < whole, "{} {} {}", whole, half, quarter);
+    }
+
+    #[test]
+    fn b_frames_are_coded_after_the_frames_they_reference() {
+        let frames = synthetic_sequence(9, 32, 32, ChromaSampling::Cs420, 8);
+        let order = |gop, b_frames| {
+            let config = EncoderConfig { gop, b_frames, ..EncoderConfig::default() };
+            let mut encoder = VideoEncoder::new(StreamHeader::new(&frames[0], &config.search, (25, 1)), config).unwrap();
+            let mut encoded = Vec::new();
+            for frame in frames.iter() {
+                encoded.extend(encoder.encode_frame(frame).unwrap());
+            }
+            encoded.extend(encoder.flush().unwrap());
+            encoded.iter().map(|e| format!("{}{}", e.frame_type, e.timestamp)).collect::<Vec<_>>().join(" ")
+        };
+        // I-frames stay every gop frames, cutting a mini-GOP short.
+        assert_eq!(order(4, 2), "I0 P3 B1 B2 I4 P7 B5 B6 I8");
+        // The last frame ends the final mini-GOP.
+        assert_eq!(order(0, 3), "I0 P4 B1 B2 B3 P8 B5 B6 B7");
+        assert_eq!(order(0, 5), "I0 P6 B1 B2 B3 B4 B5 P8 B7");
+        assert_eq!(order(3, 0), "I0 P1 P2 I3 P4 P5 I6 P7 P8");
+    }
+
+    #[test]
+    fn b_frames_round_trip() {
+        let frames = synthetic_sequence(8, 76, 46, ChromaSampling::Cs420, 8);
+        for (decision, block_size) in [(Decision::Sad, 16), (Decision::Rd, 8)] {
+            let config = EncoderConfig {
+                qp: 26,
+                gop: 6,
+                b_frames: 2,
+                decision,
+                search: SearchParams {
+                    block_size,
+                    ..SearchParams::default()
+                },
+                ..EncoderConfig::default()
+            };
+            let (_, encoded) = round_trip(&frames, config);
+            for (frame, e) in frames.iter().zip(encoded.iter()) {
+                let quality = compare_frames(frame, &e.reconstruction).psnr_weighted;
+                assert!(quality > 33.0, "{} frame at {:.1} dB", e.frame_type, quality);
+            }
+        }
+        round_trip(
+            &synthetic_sequence(5, 24, 17, ChromaSampling::Cs444, 10),
+            EncoderConfig { b_frames: 1, ..EncoderConfig::default() },
+        );
+    }
+
+    #[test]
+    fn b_frames_cost_less_than_p_frames() {
+        let synth = VideoSynth::new(VideoSpec {
+            width: 64,
+            height: 48,
+            pan: (1.0, 0.5),
+            noise: 1.0,
+            ..VideoSpec::default()
+        });
+        let frames: Vec<Frame> = (0..7).map(|t| synth.frame(t)).collect();
+        let config = EncoderConfig { qp: 30, gop: 0, b_frames: 2, ..EncoderConfig::default() };
+        let (_, encoded) = round_trip(&frames, config);
+        let bytes = |frame_type| {
+            let sizes: Vec<usize> = encoded.iter().filter(|e| e.frame_type == frame_type).map(|e| e.data.len()).collect();
+            sizes.iter().sum::<usize>() as f64 / sizes.len() as f64
+        };
+        let (p, b) = (bytes(FrameType::Predicted), bytes(FrameType::Bidirectional));
+        assert!(b < p, "B-frames {:.0} bytes, P-frames {:.0}", b, p);
+    }
+
+    #[test]
+    fn scene_cuts_start_i_frames() {
+        let synth = VideoSynth::new(VideoSpec {
+            width: 64,
+            height: 48,
+            pan: (1.0, 0.5),
+            noise: 1.0,
+            cuts: vec![4],
+            ..VideoSpec::default()
+        });
+        let frames: Vec<Frame> = (0..10).map(|t| synth.frame(t)).collect();
+        let code = |scene_cut, b_frames| {
+            let config = EncoderConfig { gop: 8, b_frames, scene_cut, ..EncoderConfig::default() };
+            let (stream, encoded) = round_trip(&frames, config);
+            let intra: Vec<u32> = encoded.iter().filter(|e| e.frame_type == FrameType::Intra).map(|e| e.timestamp).collect();
+            (stream.len(), intra)
+        };
+        for b_frames in [0, 2] {
+            let (without, intra) = code(0.0, b_frames);
+            assert_eq!(intra, vec![0, 8]);
+            // The GOP restarts at the cut.
+            let (with, intra) = code(0.7, b_frames);
+            assert_eq!(intra, vec![0, 4]);
+            assert!(with < without, "{} bytes with detection, {} without", with, without);
+        }
+    }
+
+    #[test]
+    fn reorder_buffer_restores_display_order() {
+        let frame = |v| {
+            let mut f = Frame::new(2, 2, ChromaSampling::Mono, 8);
+            f.y.set(0, 0, v);
+            f
+        };
+        let mut buffer = ReorderBuffer::new();
+        assert!(buffer.push(0, frame(0)).len() == 1);
+        assert!(buffer.push(3, frame(3)).is_empty());
+        assert!(!buffer.accepts(3) && !buffer.accepts(0) && buffer.accepts(1));
+        assert!(buffer.push(2, frame(2)).is_empty());
+        let due: Vec<u16> = buffer.push(1, frame(1)).iter().map(|f| f.y.get(0, 0)).collect();
+        assert_eq!(due, vec![1, 2, 3]);
+        buffer.push(6, frame(6));
+        buffer.push(5, frame(5));
+        let rest: Vec<u16> = buffer.flush().iter().map(|f| f.y.get(0, 0)).collect();
+        assert_eq!(rest, vec![5, 6]);
+        assert!(!buffer.accepts(6) && buffer.accepts(7));
+
+        // Starting part way through a stream.
+        let mut late = ReorderBuffer::new();
+        assert!(late.push(6, frame(6)).len() == 1);
+        assert!(!late.accepts(5) && late.accepts(8));
+        assert!(late.push(8, frame(8)).is_empty());
+    }
+
+    #[test]
+    fn deblocking_raises_quality_at_high_qp() {
+        let synth = VideoSynth::new(VideoSpec {
+            width: 64,
+            height: 48,
+            pan: (0.75, 0.5),
+            ..VideoSpec::default()
+        });
+        let frames: Vec<Frame> = (0..6).map(|t| synth.frame(t)).collect();
+        let quality = |deblock| {
+            let config = EncoderConfig { qp: 40, gop: 0, deblock, ..EncoderConfig::default() };
+            let (_, encoded) = round_trip(&frames, config);
+            let metrics: Vec<_> = frames.iter().zip(encoded.iter()).map(|(f, e)| compare_frames(f, &e.reconstruction)).collect();
+            average(&metrics).unwrap()
+        };
+        let (off, on) = (quality(DeblockParams::off()), quality(DeblockParams::default()));
+        assert!(
+            on.psnr_weighted > off.psnr_weighted + 0.2,
+            "{:.2} dB with deblocking, {:.2} dB without",
+            on.psnr_weighted,
+            off.psnr_weighted
+        );
+        assert!(
+            on.ssim_weighted > off.ssim_weighted,
+            "SSIM {:.4} with deblocking, {:.4} without",
+            on.ssim_weighted,
+            off.ssim_weighted
+        );
+        let stronger = DeblockParams { beta_offset: 3, tc_offset: 3, ..DeblockParams::default() };
+        round_trip(&frames, EncoderConfig { deblock: stronger, b_frames: 1, ..EncoderConfig::default() });
+    }
+
+    #[test]
+    fn qp_trades_rate_for_quality() {
+        let frames = synthetic_sequence(3, 32, 32, ChromaSampling::Cs420, 8);
+        let mut previous: Option<(usize, f64)> = None;
+        for qp in [12, 24, 36, 48] {
+            let config = EncoderConfig { qp, ..EncoderConfig::default() };
+            let (stream, encoded) = round_trip(&frames, config);
+            let quality = compare_frames(&frames[0], &encoded[0].reconstruction).psnr_weighted;
+            if let Some((bytes, q)) = previous {
+                assert!(stream.len() < bytes, "qp {}: {} bytes", qp, stream.len());
+                assert!(quality < q, "qp {}: {:.1} dB", qp, quality);
+            }
+            previous = Some((stream.len(), quality));
+        }
+    }
+
+    #[test]
+    fn rejects_bad_streams() {
+        let frames = synthetic_sequence(2, 16, 16, ChromaSampling::Cs420, 8);
+        let (stream, _) = round_trip(&frames, EncoderConfig::default());
+
+        let mut bad_magic = stream.clone();
+        bad_magic[0] = b'X';
+        assert!(matches!(StreamHeader::read(&mut &bad_magic[..], 1 << 20), Err(CodecError::InvalidHeader)));
+        assert!(matches!(StreamHeader::read(&mut &stream[..], 100), Err(CodecError::TooLarge(256))));
+
+        let mut input = &stream[HEADER_LENGTH..];
+        let first = read_frame(&mut input, 0).unwrap().unwrap();
+        let second = read_frame(&mut input, 1).unwrap().unwrap();
+        let header = StreamHeader::read(&mut &stream[..], 1 << 20).unwrap();
+        // A P-frame cannot start a stream.
+        assert!(matches!(VideoDecoder::new(header.clone()).decode_frame(&second), Err(CodecError::InvalidFrame(0))));
+        let mut bad_qp = first.clone();
+        bad_qp[1] = 52;
+        assert!(matches!(VideoDecoder::new(header.clone()).decode_frame(&bad_qp), Err(CodecError::InvalidFrame(0))));
+        let mut bad_offset = first.clone();
+        bad_offset[8] = 7;
+        assert!(matches!(VideoDecoder::new(header).decode_frame(&bad_offset), Err(CodecError::InvalidFrame(0))));
+        let truncated = &stream[..stream.len() - 1];
+        let mut input = &truncated[HEADER_LENGTH..];
+        read_frame(&mut input, 0).unwrap();
+        assert!(matches!(read_frame(&mut input, 1), Err(CodecError::TruncatedFrame(1))));
+    }
+}
@@ -0,0 +1,476 @@
+// Context-adaptive coding of quantized 8x8 coefficient blocks, in the style
+// of HEVC's residual coding.
+//
+// A block is coded as:
+//   - a coded block flag, false for an all-zero block, which ends it;
+//   - the position of the last non-zero coefficient in scan order, as its x
+//     and y coordinates, each a prefix symbol plus suffix bits;
+//   - a significance flag for every earlier position, in reverse scan
+//     order, with a context from the position's frequency band and how many
+//     of its right and lower neighbours are significant;
+//   - a greater-than-1 flag for every significant coefficient, and a
+//     greater-than-2 flag for those greater than 1, with contexts that
+//     track how many coefficients so far were exactly 1;
+//   - the signs, as plain bits;
+//   - for levels above 2, the remainder as a Golomb-Rice code in plain bits,
+//     with a Rice parameter that grows as larger remainders are seen.
+//
+// Contexts are kept separately for luma and chroma and carry over from
+// block to block.
+
+use super::transform::{BLOCK_AREA, BLOCK_SIZE, Block};
+use crate::decoder::{DecodeError, Decoder};
+use crate::encoder::Encoder;
+use crate::symbol_model::VectorCountSymbolModel;
+use bitbit::reader::Bit;
+use bitbit::{BitReader, BitWriter};
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+// Decoded levels larger than this can only come from a corrupt stream.
+const MAX_LEVEL: u32 = 1 << 24;
+const MAX_RICE: u32 = 4;
+// Remainders whose Rice prefix would reach this switch to Exp-Golomb.
+const RICE_PREFIX_LIMIT: u32 = 4;
+
+// Prefix groups for last position coordinates: values 0, 1, 2, 3, 4-5, 6-7.
+const LAST_GROUPS: [(u32, u32); 6] = [(0, 0), (1, 0), (2, 0), (3, 0), (4, 1), (6, 1)];
+
+const SIG_BANDS: usize = 6;
+const SIG_CONTEXTS: usize = SIG_BANDS * 3;
+const GT1_CONTEXTS: usize = 4 * 2;
+const GT2_CONTEXTS: usize = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScanOrder {
+    Zigzag,
+    Diagonal,
+}
+
+impl FromStr for ScanOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "zigzag" => Ok(ScanOrder::Zigzag),
+            "diagonal" => Ok(ScanOrder::Diagonal),
+            _ => Err(format!("unknown scan order {}", s)),
+        }
+    }
+}
+
+impl ScanOrder {
+    // Raster positions in scan order. Both scans walk the anti-diagonals
+    // from DC outwards. Zigzag alternates direction on each diagonal as in
+    // JPEG, while the diagonal scan always runs up and to the right as in
+    // HEVC.
+    pub fn positions(&self) -> [usize; BLOCK_AREA] {
+        let mut positions = [0; BLOCK_AREA];
+        let mut i = 0;
+        for d in 0..(2 * BLOCK_SIZE - 1) {
+            let ys: Vec<usize> = (0..BLOCK_SIZE).filter(|&y| d >= y && d - y < BLOCK_SIZE).collect();
+            let up_right = match self {
+                ScanOrder::Diagonal => true,
+                ScanOrder::Zigzag => d % 2 == 0,
+            };
+            let ordered: Vec<usize> = if up_right { ys.into_iter().rev().collect() } else { ys };
+            for y in ordered {
+                positions[i] = y * BLOCK_SIZE + (d - y);
+                i += 1;
+            }
+        }
+        positions
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Models {
+    cbf: VectorCountSymbolModel<bool>,
+    last_x: VectorCountSymbolModel<u8>,
+    last_y: VectorCountSymbolModel<u8>,
+    sig: Vec<VectorCountSymbolModel<bool>>,
+    gt1: Vec<VectorCountSymbolModel<bool>>,
+    gt2: Vec<VectorCountSymbolModel<bool>>,
+}
+
+impl Models {
+    fn new() -> Self {
+        let flag = || VectorCountSymbolModel::new(vec![false, true]);
+        let groups = || VectorCountSymbolModel::new((0..LAST_GROUPS.len() as u8).collect());
+        Self {
+            cbf: flag(),
+            last_x: groups(),
+            last_y: groups(),
+            sig: vec![flag(); SIG_CONTEXTS],
+            gt1: vec![flag(); GT1_CONTEXTS],
+            gt2: vec![flag(); GT2_CONTEXTS],
+        }
+    }
+}
+
+fn last_group(v: usize) -> u8 {
+    match v {
+        0..=3 => v as u8,
+        4 | 5 => 4,
+        _ => 5,
+    }
+}
+
+// Frequency band of a position, finest at DC.
+fn sig_band(x: usize, y: usize) -> usize {
+    match x + y {
+        0 => 0,
+        1 | 2 => 1,
+        3 | 4 => 2,
+        5..=7 => 3,
+        8..=10 => 4,
+        _ => 5,
+    }
+}
+
+fn sig_context(levels: &Block, pos: usize) -> usize {
+    let (x, y) = (pos % BLOCK_SIZE, pos / BLOCK_SIZE);
+    let right = x + 1 < BLOCK_SIZE && levels[pos + 1] != 0;
+    let below = y + 1 < BLOCK_SIZE && levels[pos + BLOCK_SIZE] != 0;
+    sig_band(x, y) * 3 + right as usize + below as usize
+}
+
+// Tracks the greater-than-1 context through a block: it starts at 1 and
+// counts up with each level of exactly 1, and drops to 0 for good once a
+// level above 1 is seen.
+fn next_gt1_state(state: usize, greater_than_1: bool) -> usize {
+    if greater_than_1 || state == 0 { 0 } else { (state + 1).min(3) }
+}
+
+fn encode_rice<W: Write>(remainder: u32, k: u32, enc: &mut Encoder, output: &mut BitWriter<W>) {
+    let prefix = remainder >> k;
+    if prefix < RICE_PREFIX_LIMIT {
+        // prefix ones, a zero, then k bits.
+        enc.encode_bits(((1u64 << prefix) - 1) << 1, prefix + 1, output);
+        enc.encode_bits(remainder as u64, k, output);
+    } else {
+        enc.encode_bits((1u64 << RICE_PREFIX_LIMIT) - 1, RICE_PREFIX_LIMIT, output);
+        encode_exp_golomb(remainder - (RICE_PREFIX_LIMIT << k), k + 1, enc, output);
+    }
+}
+
+fn decode_rice<R: Read, B: Bit>(k: u32, dec: &mut Decoder, input: &mut BitReader<R, B>) -> Result<u32, DecodeError> {
+    let mut prefix = 0;
+    while prefix < RICE_PREFIX_LIMIT && dec.decode_bits(1, input)? == 1 {
+        prefix += 1;
+    }
+    if prefix < RICE_PREFIX_LIMIT {
+        Ok(prefix << k | dec.decode_bits(k, input)? as u32)
+    } else {
+        Ok((RICE_PREFIX_LIMIT << k) + decode_exp_golomb(k + 1, dec, input)?)
+    }
+}
+
+fn encode_exp_golomb<W: Write>(mut v: u32, mut k: u32, enc: &mut Encoder, output: &mut BitWriter<W>) {
+    while v >= 1 << k {
+        enc.encode_bits(1, 1, output);
+        v -= 1 << k;
+        k += 1;
+    }
+    enc.encode_bits(0, 1, output);
+    enc.encode_bits(v as u64, k, output);
+}
+
+fn decode_exp_golomb<R: Read, B: Bit>(mut k: u32, dec: &mut Decoder, input: &mut BitReader<R, B>) -> Result<u32, DecodeError> {
+    let mut v: u32 = 0;
+    while dec.decode_bits(1, input)? == 1 {
+        v += 1 << k;
+        k += 1;
+        if v > MAX_LEVEL {
+            return Err(DecodeError::Desync);
+        }
+    }
+    Ok(v + dec.decode_bits(k, input)? as u32)
+}
+
+fn next_rice(k: u32, remainder: u32) -> u32 {
+    if remainder > 3 << k { (k + 1).min(MAX_RICE) } else { k }
+}
+
+#[derive(Clone, Debug)]
+pub struct CoeffCoder {
+    scan: [usize; BLOCK_AREA],
+    // Indexed by plane kind: luma, chroma.
+    models: [Models; 2],
+}
+
+impl CoeffCoder {
+    pub fn new(order: ScanOrder) -> Self {
+        Self {
+            scan: order.positions(),
+            models: [Models::new(), Models::new()],
+        }
+    }
+
+    // Codes the levels of one block of plane (0 for luma, 1 or 2 for chroma).
+    pub fn encode_block<W: Write>(&mut self, levels: &Block, plane: usize, enc: &mut Encoder, output: &mut BitWriter<W>) {
+        let m = &mut self.models[plane.min(1)];
+        let last = match self.scan.iter().rposition(|&pos| levels[pos] != 0) {
+            Some(i) => i,
+            None => {
+                enc.encode(&false, &m.cbf, output);
+                m.cbf.incr_count(&false);
+                return;
+            }
+        };
+        enc.encode(&true, &m.cbf, output);
+        m.cbf.incr_count(&true);
+
+        let last_pos = self.scan[last];
+        for (coord, model) in [(last_pos % BLOCK_SIZE, &mut m.last_x), (last_pos / BLOCK_SIZE, &mut m.last_y)] {
+            let group = last_group(coord);
+            enc.encode(&group, model, output);
+            model.incr_count(&group);
+            let (start, bits) = LAST_GROUPS[group as usize];
+            enc.encode_bits((coord as u32 - start) as u64, bits, output);
+        }
+
+        // Significance, from just before the last position back to DC.
+        for i in (0..last).rev() {
+            let pos = self.scan[i];
+            let significant = levels[pos] != 0;
+            let ctx = sig_context(levels, pos);
+            enc.encode(&significant, &m.sig[ctx], output);
+            m.sig[ctx].incr_count(&significant);
+        }
+
+        let coded: Vec<usize> = (0..=last).rev().map(|i| self.scan[i]).filter(|&pos| levels[pos] != 0).collect();
+        let mut state = 1;
+        for &pos in coded.iter() {
+            let greater_than_1 = levels[pos].unsigned_abs() > 1;
+            let ctx = state * 2 + (pos == 0) as usize;
+            enc.encode(&greater_than_1, &m.gt1[ctx], output);
+            m.gt1[ctx].incr_count(&greater_than_1);
+            state = next_gt1_state(state, greater_than_1);
+        }
+        for &pos in coded.iter().filter(|&&pos| levels[pos].unsigned_abs() > 1) {
+            let greater_than_2 = levels[pos].unsigned_abs() > 2;
+            let ctx = (pos == 0) as usize;
+            enc.encode(&greater_than_2, &m.gt2[ctx], output);
+            m.gt2[ctx].incr_count(&greater_than_2);
+        }
+        for &pos in coded.iter() {
+            enc.encode_bits((levels[pos] < 0) as u64, 1, output);
+        }
+        let mut k = 0;
+        for &pos in coded.iter().filter(|&&pos| levels[pos].unsigned_abs() > 2) {
+            let remainder = levels[pos].unsigned_abs() - 3;
+            encode_rice(remainder, k, enc, output);
+            k = next_rice(k, remainder);
+        }
+    }
+
+    pub fn decode_block<R: Read, B: Bit>(
+        &mut self,
+        plane: usize,
+        dec: &mut Decoder,
+        input: &mut BitReader<R, B>,
+    ) -> Result<Block, DecodeError> {
+        let m = &mut self.models[plane.min(1)];
+        let mut levels = [0i32; BLOCK_AREA];
+        let cbf = *dec.decode(&m.cbf, input)?;
+        m.cbf.incr_count(&cbf);
+        if !cbf {
+            return Ok(levels);
+        }
+
+        let mut coords = [0usize; 2];
+        for (coord, model) in coords.iter_mut().zip([&mut m.last_x, &mut m.last_y]) {
+            let group = *dec.decode(model, input)?;
+            model.incr_count(&group);
+            let (start, bits) = LAST_GROUPS[group as usize];
+            *coord = (start as u64 + dec.decode_bits(bits, input)?) as usize;
+        }
+        let last_pos = coords[1] * BLOCK_SIZE + coords[0];
+        let last = self.scan.iter().position(|&pos| pos == last_pos).unwrap();
+
+        // Magnitudes are filled in as 1 until the level passes.
+        levels[last_pos] = 1;
+        for i in (0..last).rev() {
+            let pos = self.scan[i];
+            let ctx = sig_context(&levels, pos);
+            let significant = *dec.decode(&m.sig[ctx], input)?;
+            m.sig[ctx].incr_count(&significant);
+            levels[pos] = significant as i32;
+        }
+
+        let coded: Vec<usize> = (0..=last).rev().map(|i| self.scan[i]).filter(|&pos| levels[pos] != 0).collect();
+        let mut state = 1;
+        for &pos in coded.iter() {
+            let ctx = state * 2 + (pos == 0) as usize;
+            let greater_than_1 = *dec.decode(&m.gt1[ctx], input)?;
+            m.gt1[ctx].incr_count(&greater_than_1);
+            state = next_gt1_state(state, greater_than_1);
+            levels[pos] += greater_than_1 as i32;
+        }
+        let above_1: Vec<usize> = coded.iter().copied().filter(|&pos| levels[pos] > 1).collect();
+        for pos in above_1 {
+            let ctx = (pos == 0) as usize;
+            let greater_than_2 = *dec.decode(&m.gt2[ctx], input)?;
+            m.gt2[ctx].incr_count(&greater_than_2);
+            levels[pos] += greater_than_2 as i32;
+        }
+        let mut negative = [false; BLOCK_AREA];
+        for &pos in coded.iter() {
+            negative[pos] = dec.decode_bits(1, input)? == 1;
+        }
+        let mut k = 0;
+        let above_2: Vec<usize> = coded.iter().copied().filter(|&pos| levels[pos] > 2).collect();
+        for pos in above_2 {
+            let remainder = decode_rice(k, dec, input)?;
+            if remainder > MAX_LEVEL {
+                return Err(DecodeError::Desync);
+            }
+            levels[pos] += remainder as i32;
+            k = next_rice(k, remainder);
+        }
+        for &pos in coded.iter() {
+            if negative[pos] {
+                levels[pos] = -levels[pos];
+            }
+        }
+        Ok(levels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::video::quant::Quantizer;
+    use crate::video::transform::forward;
+    use bitbit::MSB;
+
+    fn round_trip(blocks: &[(Block, usize)], order: ScanOrder) -> u64 {
+        let mut bytes = Vec::new();
+        let mut bw = BitWriter::new(&mut bytes);
+        let mut enc = Encoder::new();
+        let mut coder = CoeffCoder::new(order);
+        for (levels, plane) in blocks.iter() {
+            coder.encode_block(levels, *plane, &mut enc, &mut bw);
+        }
+        let bits = enc.bits_written();
+        enc.finish(&mut bw).unwrap();
+        bw.pad_to_byte().unwrap();
+
+        let mut br: BitReader<_, MSB> = BitReader::new(&bytes[..]);
+        let mut dec = Decoder::new();
+        let mut coder = CoeffCoder::new(order);
+        for (levels, plane) in blocks.iter() {
+            assert_eq!(&coder.decode_block(*plane, &mut dec, &mut br).unwrap(), levels);
+        }
+        bits
+    }
+
+    fn sparse_block(seed: u32) -> Block {
+        let mut state = seed;
+        let mut levels = [0i32; BLOCK_AREA];
+        for (pos, v) in levels.iter_mut().enumerate() {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            let r = (state >> 16) % 100;
+            let (x, y) = (pos % BLOCK_SIZE, pos / BLOCK_SIZE);
+            if (r as usize) < 60usize.saturating_sub(8 * (x + y)) {
+                let magnitude = match r % 10 {
+                    0 => 1000 + r as i32 * 37,
+                    1..=3 => 3 + (r as i32 % 7),
+                    _ => 1 + (r as i32 % 2),
+                };
+                *v = if state & 0x100 == 0 { magnitude } else { -magnitude };
+            }
+        }
+        levels
+    }
+
+    #[test]
+    fn scans_are_permutations() {
+        for order in [ScanOrder::Zigzag, ScanOrder::Diagonal] {
+            let mut positions = order.positions().to_vec();
+            assert_eq!(positions[0], 0);
+            positions.sort();
+            assert_eq!(positions, (0..BLOCK_AREA).collect::<Vec<_>>());
+        }
+        assert_eq!(&ScanOrder::Zigzag.positions()[..6], &[0, 1, 8, 16, 9, 2]);
+        assert_eq!(&ScanOrder::Diagonal.positions()[..6], &[0, 8, 1, 16, 9, 2]);
+    }
+
+    #[test]
+    fn blocks_round_trip() {
+        let mut blocks: Vec<(Block, usize)> = (0..200).map(|i| (sparse_block(i), i as usize % 3)).collect();
+        blocks.push(([0; BLOCK_AREA], 0));
+        let mut full = [0; BLOCK_AREA];
+        full[63] = -1;
+        blocks.push((full, 1));
+        full[0] = MAX_LEVEL as i32;
+        full[7] = -(MAX_LEVEL as i32);
+        blocks.push((full, 0));
+        blocks.push(([-2; BLOCK_AREA], 2));
+        for order in [ScanOrder::Zigzag, ScanOrder::Diagonal] {
+            round_trip(&blocks, order);
+        }
+    }
+
+    #[test]
+    fn empty_blocks_are_nearly_free() {
+        let blocks = vec![([0; BLOCK_AREA], 0); 1000];
+        assert!(round_trip(&blocks, ScanOrder::Diagonal) < 50);
+    }
+
+    #[test]
+    fn rice_codes_round_trip() {
+        let mut bytes = Vec::new();
+        let mut bw = BitWriter::new(&mut bytes);
+        let mut enc = Encoder::new();
+        let values: Vec<(u32, u32)> = (0..=MAX_RICE).flat_map(|k| [0, 1, 5, 17, 100, 65535, MAX_LEVEL].map(|v| (v, k))).collect();
+        for &(v, k) in values.iter() {
+            encode_rice(v, k, &mut enc, &mut bw);
+        }
+        enc.finish(&mut bw).unwrap();
+        bw.pad_to_byte().unwrap();
+
+        let mut br: BitReader<_, MSB> = BitReader::new(&bytes[..]);
+        let mut dec = Decoder::new();
+        for &(v, k) in values.iter() {
+            assert_eq!(decode_rice(k, &mut dec, &mut br).unwrap(), v);
+        }
+    }
+
+    #[test]
+    fn transformed_residuals_round_trip() {
+        let q = Quantizer::new(22, 8);
+        let blocks: Vec<(Block, usize)> = (0..20)
+            .map(|i| {
+                let mut residual = [0; BLOCK_AREA];
+                for (j, v) in residual.iter_mut().enumerate() {
+                    *v = ((j as i32 * (i + 3)) % 29) - 14 + (j / 8) as i32 * i;
+                }
+                (q.quantize(&forward(&residual, 8)), 0)
+            })
+            .collect();
+        round_trip(&blocks, ScanOrder::Zigzag);
+    }
+
+    #[test]
+    fn garbage_input_does_not_panic() {
+        let mut state: u32 = 7;
+        for _ in 0..50 {
+            let bytes: Vec<u8> = (0..64)
+                .map(|_| {
+                    state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                    (state >> 16) as u8
+                })
+                .collect();
+            let mut br: BitReader<_, MSB> = BitReader::new(&bytes[..]);
+            let mut dec = Decoder::new();
+            let mut coder = CoeffCoder::new(ScanOrder::Diagonal);
+            for _ in 0..20 {
+                if coder.decode_block(0, &mut dec, &mut br).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
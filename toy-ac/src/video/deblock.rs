@@ -0,0 +1,411 @@
+// In-loop deblocking, after HEVC: smooths the edges of the 8x8 transform
+// blocks of a reconstructed frame before it is shown or used as a
+// reference, so encoder and decoder both run it.
+//
+// Each edge gets a strength from the blocks either side: 2 if either is
+// intra, 1 if either has coded luma coefficients or they are predicted
+// from different references or vectors a whole pixel or more apart, and
+// otherwise 0, which leaves it alone. The frame QP picks two thresholds
+// from HEVC's tables: beta, how flat the samples either side must be for a
+// step between them to look like a block edge rather than a real one, and
+// tc, how far a sample may move. Luma edges are decided four lines at a
+// time and get the normal filter, changing up to two samples each side, or
+// on flat enough content the strong one, changing three. Chroma edges are
+// only filtered at strength 2, one sample each side. All vertical edges of
+// a plane are filtered before its horizontal ones, in integer arithmetic.
+//
+// Offsets, in steps of 2 QP, move beta and tc up or down the tables.
+
+use super::motion::MotionVector;
+use super::transform::BLOCK_SIZE;
+use super::{Frame, Plane};
+use std::str::FromStr;
+
+pub const MAX_OFFSET: i8 = 6;
+
+// Indexed by QP.
+const BETA_TABLE: [i32; 52] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 20, 22, 24, 26, 28, 30, 32,
+    34, 36, 38, 40, 42, 44, 46, 48, 50, 52, 54, 56, 58, 60, 62, 64,
+];
+// Indexed by QP plus 2 for strength 2.
+const TC_TABLE: [i32; 54] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 5, 5, 6,
+    6, 7, 8, 9, 10, 11, 13, 14, 16, 18, 20, 22, 24,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeblockParams {
+    pub enabled: bool,
+    // From -MAX_OFFSET to MAX_OFFSET.
+    pub beta_offset: i8,
+    pub tc_offset: i8,
+}
+
+impl DeblockParams {
+    pub fn off() -> Self {
+        Self {
+            enabled: false,
+            beta_offset: 0,
+            tc_offset: 0,
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        let range = -MAX_OFFSET..=MAX_OFFSET;
+        range.contains(&self.beta_offset) && range.contains(&self.tc_offset)
+    }
+}
+
+impl Default for DeblockParams {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            beta_offset: 0,
+            tc_offset: 0,
+        }
+    }
+}
+
+// "on", "off", or the beta and tc offsets as "beta,tc", which turns
+// deblocking on.
+impl FromStr for DeblockParams {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "on" => return Ok(Self::default()),
+            "off" => return Ok(Self::off()),
+            _ => {}
+        }
+        let offsets: Vec<Option<i8>> = s.split(',').map(|o| o.trim().parse().ok()).collect();
+        let params = match offsets[..] {
+            [Some(beta_offset), Some(tc_offset)] => Self {
+                enabled: true,
+                beta_offset,
+                tc_offset,
+            },
+            _ => return Err(format!("expected on, off or beta,tc offsets, got {}", s)),
+        };
+        if !params.is_valid() {
+            return Err(format!("deblocking offsets {}, expected -{} to {}", s, MAX_OFFSET, MAX_OFFSET));
+        }
+        Ok(params)
+    }
+}
+
+// How an 8x8 luma block was coded, as far as the strength of its edges
+// goes. Chroma blocks take the luma block at the same position.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BlockInfo {
+    pub intra: bool,
+    // Whether the luma residual has any non-zero level.
+    pub coded: bool,
+    // The vector into each reference the block is predicted from, in
+    // quarter pixels.
+    pub vectors: [Option<MotionVector>; 2],
+}
+
+impl BlockInfo {
+    pub fn intra() -> Self {
+        Self {
+            intra: true,
+            ..Self::default()
+        }
+    }
+}
+
+// BlockInfo for every 8x8 block of a frame's luma, in raster order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockMap {
+    pub cols: usize,
+    pub rows: usize,
+    blocks: Vec<BlockInfo>,
+}
+
+impl BlockMap {
+    pub fn new(width: usize, height: usize, fill: BlockInfo) -> Self {
+        let (cols, rows) = (width.div_ceil(BLOCK_SIZE), height.div_ceil(BLOCK_SIZE));
+        Self {
+            cols,
+            rows,
+            blocks: vec![fill; cols * rows],
+        }
+    }
+
+    pub fn get(&self, col: usize, row: usize) -> BlockInfo {
+        self.blocks[row * self.cols + col]
+    }
+
+    pub fn set(&mut self, col: usize, row: usize, info: BlockInfo) {
+        self.blocks[row * self.cols + col] = info;
+    }
+
+    pub fn set_coded(&mut self, col: usize, row: usize, coded: bool) {
+        self.blocks[row * self.cols + col].coded = coded;
+    }
+}
+
+// The strength of the edge between blocks p and q.
+pub fn strength(p: &BlockInfo, q: &BlockInfo) -> u8 {
+    if p.intra || q.intra {
+        return 2;
+    }
+    if p.coded || q.coded {
+        return 1;
+    }
+    let far = |a: MotionVector, b: MotionVector| (a.x - b.x).abs() >= 4 || (a.y - b.y).abs() >= 4;
+    let moved = p.vectors.iter().zip(&q.vectors).any(|pair| match pair {
+        (Some(a), Some(b)) => far(*a, *b),
+        (None, None) => false,
+        _ => true,
+    });
+    moved as u8
+}
+
+// Deblocks every plane of frame, coded at qp with blocks as in the map.
+pub fn deblock(frame: &mut Frame, blocks: &BlockMap, qp: u8, params: &DeblockParams) {
+    if !params.enabled {
+        return;
+    }
+    let scale = 1 << (frame.bit_depth - 8);
+    let max = frame.max_value() as i32;
+    let shifts = frame.chroma.shifts();
+    let beta = BETA_TABLE[(qp as i32 + 2 * params.beta_offset as i32).clamp(0, 51) as usize] * scale;
+    let tc = |bs: u8| TC_TABLE[(qp as i32 + 2 * (bs as i32 - 1) + 2 * params.tc_offset as i32).clamp(0, 53) as usize] * scale;
+    let thresholds = Thresholds { beta, tc: [0, tc(1), tc(2)], max };
+
+    for (p, plane) in frame.planes_mut().into_iter().enumerate() {
+        let (sx, sy) = if p == 0 { (0, 0) } else { shifts };
+        for vertical in [true, false] {
+            let edges = Edges { vertical, shifts: (sx, sy), blocks };
+            if p == 0 {
+                edges.filter_luma(plane, &thresholds);
+            } else {
+                edges.filter_chroma(plane, &thresholds);
+            }
+        }
+    }
+}
+
+struct Thresholds {
+    beta: i32,
+    // Indexed by strength.
+    tc: [i32; 3],
+    max: i32,
+}
+
+// The edges of one plane in one direction: vertical edges run down the
+// plane between columns, horizontal ones across it between rows.
+struct Edges<'a> {
+    vertical: bool,
+    shifts: (usize, usize),
+    blocks: &'a BlockMap,
+}
+
+impl Edges<'_> {
+    // Samples across and along the edges.
+    fn extent(&self, plane: &Plane) -> (usize, usize) {
+        if self.vertical { (plane.width, plane.height) } else { (plane.height, plane.width) }
+    }
+
+    fn position(&self, edge: usize, line: usize, i: isize) -> (usize, usize) {
+        let across = (edge as isize + i) as usize;
+        if self.vertical { (across, line) } else { (line, across) }
+    }
+
+    // The samples p3 p2 p1 p0 q0 q1 q2 q3 of a line, where the edge lies
+    // between p0 and q0.
+    fn read(&self, plane: &Plane, edge: usize, line: usize, taps: usize) -> [i32; 8] {
+        let mut s = [0; 8];
+        for (k, i) in (-(taps as isize)..taps as isize).enumerate() {
+            let (x, y) = self.position(edge, line, i);
+            s[4 - taps + k] = plane.get(x, y) as i32;
+        }
+        s
+    }
+
+    fn write(&self, plane: &mut Plane, edge: usize, line: usize, taps: usize, s: &[i32; 8]) {
+        for (k, i) in (-(taps as isize)..taps as isize).enumerate() {
+            let (x, y) = self.position(edge, line, i);
+            plane.set(x, y, s[4 - taps + k] as u16);
+        }
+    }
+
+    // The strength of the edge at a sample position of the plane.
+    fn strength(&self, edge: usize, line: usize) -> u8 {
+        let (sx, sy) = self.shifts;
+        let luma = |(x, y): (usize, usize)| ((x << sx) / BLOCK_SIZE, (y << sy) / BLOCK_SIZE);
+        let (pc, pr) = luma(self.position(edge, line, -1));
+        let (qc, qr) = luma(self.position(edge, line, 0));
+        strength(&self.blocks.get(pc, pr), &self.blocks.get(qc, qr))
+    }
+
+    fn filter_luma(&self, plane: &mut Plane, t: &Thresholds) {
+        let (across, along) = self.extent(plane);
+        for edge in (BLOCK_SIZE..across.saturating_sub(3)).step_by(BLOCK_SIZE) {
+            for start in (0..along).step_by(4) {
+                let bs = self.strength(edge, start);
+                if bs > 0 {
+                    self.filter_luma_segment(plane, edge, start..(start + 4).min(along), t.beta, t.tc[bs as usize], t.max);
+                }
+            }
+        }
+    }
+
+    // Decides from its first and last lines whether and how to filter up
+    // to four lines of a luma edge.
+    fn filter_luma_segment(&self, plane: &mut Plane, edge: usize, lines: std::ops::Range<usize>, beta: i32, tc: i32, max: i32) {
+        let (a, b) = (self.read(plane, edge, lines.start, 4), self.read(plane, edge, lines.end - 1, 4));
+        let dp = |s: &[i32; 8]| (s[1] - 2 * s[2] + s[3]).abs();
+        let dq = |s: &[i32; 8]| (s[4] - 2 * s[5] + s[6]).abs();
+        let (dp0, dq0, dp3, dq3) = (dp(&a), dq(&a), dp(&b), dq(&b));
+        if dp0 + dq0 + dp3 + dq3 >= beta {
+            return;
+        }
+        let flat = |s: &[i32; 8], d: i32| {
+            2 * d < beta >> 2 && (s[0] - s[3]).abs() + (s[4] - s[7]).abs() < beta >> 3 && (s[3] - s[4]).abs() < (5 * tc + 1) >> 1
+        };
+        let strong = flat(&a, dp0 + dq0) && flat(&b, dp3 + dq3);
+        let side = (beta + (beta >> 1)) >> 3;
+        let (filter_p1, filter_q1) = (dp0 + dp3 < side, dq0 + dq3 < side);
+
+        for line in lines {
+            let mut s = self.read(plane, edge, line, 4);
+            let [p3, p2, p1, p0, q0, q1, q2, q3] = s;
+            if strong {
+                let clip = |v: i32, x: i32| x.clamp(v - 2 * tc, v + 2 * tc);
+                s[1] = clip(p2, (2 * p3 + 3 * p2 + p1 + p0 + q0 + 4) >> 3);
+                s[2] = clip(p1, (p2 + p1 + p0 + q0 + 2) >> 2);
+                s[3] = clip(p0, (p2 + 2 * p1 + 2 * p0 + 2 * q0 + q1 + 4) >> 3);
+                s[4] = clip(q0, (p1 + 2 * p0 + 2 * q0 + 2 * q1 + q2 + 4) >> 3);
+                s[5] = clip(q1, (p0 + q0 + q1 + q2 + 2) >> 2);
+                s[6] = clip(q2, (p0 + q0 + q1 + 3 * q2 + 2 * q3 + 4) >> 3);
+            } else {
+                let delta = (9 * (q0 - p0) - 3 * (q1 - p1) + 8) >> 4;
+                if delta.abs() >= tc * 10 {
+                    continue;
+                }
+                let delta = delta.clamp(-tc, tc);
+                s[3] = (p0 + delta).clamp(0, max);
+                s[4] = (q0 - delta).clamp(0, max);
+                let half = tc >> 1;
+                if filter_p1 {
+                    s[2] = (p1 + ((((p2 + p0 + 1) >> 1) - p1 + delta) >> 1).clamp(-half, half)).clamp(0, max);
+                }
+                if filter_q1 {
+                    s[5] = (q1 + ((((q2 + q0 + 1) >> 1) - q1 - delta) >> 1).clamp(-half, half)).clamp(0, max);
+                }
+            }
+            self.write(plane, edge, line, 4, &s);
+        }
+    }
+
+    fn filter_chroma(&self, plane: &mut Plane, t: &Thresholds) {
+        let (across, along) = self.extent(plane);
+        let tc = t.tc[2];
+        for edge in (BLOCK_SIZE..across.saturating_sub(1)).step_by(BLOCK_SIZE) {
+            for line in 0..along {
+                if self.strength(edge, line) < 2 {
+                    continue;
+                }
+                let mut s = self.read(plane, edge, line, 2);
+                let [_, _, p1, p0, q0, q1, _, _] = s;
+                let delta = ((((q0 - p0) << 2) + p1 - q1 + 4) >> 3).clamp(-tc, tc);
+                s[3] = (p0 + delta).clamp(0, t.max);
+                s[4] = (q0 - delta).clamp(0, t.max);
+                self.write(plane, edge, line, 2, &s);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::video::ChromaSampling;
+
+    // Two flat halves meeting at x = 8.
+    fn step_frame(low: u16, high: u16) -> Frame {
+        let mut frame = Frame::new(16, 16, ChromaSampling::Cs444, 8);
+        for plane in frame.planes_mut() {
+            for y in 0..16 {
+                for x in 0..16 {
+                    plane.set(x, y, if x < 8 { low } else { high });
+                }
+            }
+        }
+        frame
+    }
+
+    #[test]
+    fn strength_follows_coding() {
+        let inter = |x, coded| BlockInfo {
+            coded,
+            vectors: [Some(MotionVector::new(x, 0)), None],
+            ..BlockInfo::default()
+        };
+        assert_eq!(strength(&BlockInfo::intra(), &inter(0, false)), 2);
+        assert_eq!(strength(&inter(0, true), &inter(0, false)), 1);
+        assert_eq!(strength(&inter(0, false), &inter(4, false)), 1);
+        assert_eq!(strength(&inter(0, false), &inter(3, false)), 0);
+        let both = BlockInfo {
+            vectors: [Some(MotionVector::default()); 2],
+            ..BlockInfo::default()
+        };
+        assert_eq!(strength(&inter(0, false), &both), 1);
+    }
+
+    #[test]
+    fn small_steps_are_smoothed_and_large_ones_kept() {
+        let blocks = BlockMap::new(16, 16, BlockInfo::intra());
+        let params = DeblockParams::default();
+
+        let mut frame = step_frame(100, 106);
+        deblock(&mut frame, &blocks, 37, &params);
+        for plane in frame.planes() {
+            let row = plane.row(0);
+            assert!(row[8] - row[7] < 3, "{:?}", row);
+            // Monotonic, with nothing moved far from the edge.
+            assert!(row.windows(2).all(|w| w[0] <= w[1]));
+            assert_eq!((row[3], row[12]), (100, 106));
+        }
+
+        // Chroma edges of intra blocks are always filtered.
+        let mut frame = step_frame(60, 180);
+        let original = frame.clone();
+        deblock(&mut frame, &blocks, 30, &params);
+        assert!(frame.y == original.y && frame.u != original.u);
+
+        let mut frame = step_frame(100, 106);
+        let original = frame.clone();
+        deblock(&mut frame, &blocks, 37, &DeblockParams::off());
+        deblock(&mut frame, &BlockMap::new(16, 16, BlockInfo::default()), 37, &params);
+        assert!(frame == original);
+    }
+
+    #[test]
+    fn offsets_change_the_strength() {
+        let blocks = BlockMap::new(16, 16, BlockInfo::intra());
+        let step = |tc_offset| {
+            let mut frame = step_frame(100, 112);
+            let params = DeblockParams {
+                tc_offset,
+                ..DeblockParams::default()
+            };
+            deblock(&mut frame, &blocks, 30, &params);
+            frame.y.get(8, 0) - frame.y.get(7, 0)
+        };
+        assert!(step(3) < step(0) && step(0) < step(-3));
+    }
+
+    #[test]
+    fn parses_params() {
+        assert_eq!("off".parse::<DeblockParams>(), Ok(DeblockParams::off()));
+        assert_eq!("on".parse::<DeblockParams>(), Ok(DeblockParams::default()));
+        let params = "-2,3".parse::<DeblockParams>().unwrap();
+        assert_eq!((params.enabled, params.beta_offset, params.tc_offset), (true, -2, 3));
+        assert!("7,0".parse::<DeblockParams>().is_err());
+        assert!("1".parse::<DeblockParams>().is_err());
+    }
+}
@@ -0,0 +1,201 @@
+// Lossless coding of a frame predicted from a reference frame: the motion
+// field from motion::estimate followed by the residual of every plane.
+//
+// Vectors are coded by mvcoding as differences from their predictions.
+// Residual samples are coded as buckets (see bucket.rs) modelled in the
+// context of the size of the residuals to the left and above, separately
+// for luma and chroma. The models carry over from frame to frame.
+
+use super::Frame;
+use super::motion::{MotionField, Precision, SearchParams, compensate, estimate, residual};
+use super::mvcoding::MvCoder;
+use crate::bucket::{bucket, bucket_model, decode_signed, encode_signed};
+use crate::decoder::{DecodeError, Decoder};
+use crate::encoder::Encoder;
+use crate::symbol_model::VectorCountSymbolModel;
+use bitbit::reader::Bit;
+use bitbit::{BitReader, BitWriter};
+use std::io::{Read, Write};
+
+const RESIDUAL_CONTEXTS: usize = 12;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct InterStats {
+    pub mv_bits: u64,
+    pub residual_bits: u64,
+}
+
+#[derive(Clone, Debug)]
+struct Models {
+    mv: MvCoder,
+    // Indexed by plane kind (luma, chroma) then context.
+    residual: [Vec<VectorCountSymbolModel<u8>>; 2],
+}
+
+impl Models {
+    fn new() -> Self {
+        let buckets = bucket_model;
+        Self {
+            mv: MvCoder::new(),
+            residual: [vec![buckets(); RESIDUAL_CONTEXTS], vec![buckets(); RESIDUAL_CONTEXTS]],
+        }
+    }
+}
+
+// Context for a residual sample from the residuals already coded to its
+// left and above.
+fn residual_context(values: &[i32], width: usize, i: usize) -> usize {
+    let (x, y) = (i % width, i / width);
+    let left = if x > 0 { values[i - 1].unsigned_abs() } else { 0 };
+    let above = if y > 0 { values[i - width].unsigned_abs() } else { 0 };
+    (bucket(left + above) as usize).min(RESIDUAL_CONTEXTS - 1)
+}
+
+pub struct InterEncoder {
+    params: SearchParams,
+    models: Models,
+}
+
+impl InterEncoder {
+    pub fn new(params: SearchParams) -> Self {
+        Self { params, models: Models::new() }
+    }
+
+    // Codes current as a prediction from reference, which must have the same
+    // size and format. The decoder reproduces current exactly.
+    pub fn encode_frame<W: Write>(&mut self, current: &Frame, reference: &Frame, enc: &mut Encoder, output: &mut BitWriter<W>) -> InterStats {
+        let field = estimate(&current.y, &reference.y, &self.params, current.max_value());
+        let start = enc.bits_written();
+        self.models.mv.encode_field(&field, enc, output);
+        let mv_bits = enc.bits_written() - start;
+
+        let prediction = compensate(reference, &field);
+        for (p, values) in residual(current, &prediction).iter().enumerate() {
+            let width = current.planes()[p].width;
+            let models = &mut self.models.residual[p.min(1)];
+            for (i, &v) in values.iter().enumerate() {
+                encode_signed(v, &mut models[residual_context(values, width, i)], enc, output);
+            }
+        }
+
+        InterStats {
+            mv_bits,
+            residual_bits: enc.bits_written() - start - mv_bits,
+        }
+    }
+}
+
+pub struct InterDecoder {
+    block_size: usize,
+    precision: Precision,
+    models: Models,
+}
+
+impl InterDecoder {
+    // block_size and precision must match the encoder's search parameters.
+    pub fn new(block_size: usize, precision: Precision) -> Self {
+        Self {
+            block_size,
+            precision,
+            models: Models::new(),
+        }
+    }
+
+    pub fn decode_frame<R: Read, B: Bit>(
+        &mut self,
+        reference: &Frame,
+        dec: &mut Decoder,
+        input: &mut BitReader<R, B>,
+    ) -> Result<Frame, DecodeError> {
+        let mut field = MotionField::new(reference.width(), reference.height(), self.block_size).with_precision(self.precision);
+        self.models.mv.decode_field(&mut field, dec, input)?;
+
+        let mut frame = compensate(reference, &field);
+        let max = frame.max_value() as i32;
+        for (p, plane) in frame.planes_mut().into_iter().enumerate() {
+            let models = &mut self.models.residual[p.min(1)];
+            let mut values = vec![0i32; plane.data.len()];
+            for i in 0..values.len() {
+                values[i] = decode_signed(&mut models[residual_context(&values, plane.width, i)], dec, input)?;
+                let sample = (plane.data[i] as i32).checked_add(values[i]).ok_or(DecodeError::Desync)?;
+                if sample < 0 || sample > max {
+                    return Err(DecodeError::Desync);
+                }
+                plane.data[i] = sample as u16;
+            }
+        }
+        Ok(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::video::ChromaSampling;
+    use crate::video::motion::SearchMethod;
+    use bitbit::MSB;
+
+    fn moving_frames(count: usize) -> Vec<Frame> {
+        (0..count)
+            .map(|t| {
+                let mut frame = Frame::new(40, 24, ChromaSampling::Cs420, 8);
+                for (p, plane) in frame.planes_mut().into_iter().enumerate() {
+                    let scale = if p == 0 { 1 } else { 2 };
+                    for y in 0..plane.height {
+                        for x in 0..plane.width {
+                            let (gx, gy) = ((x * scale) as i32 - 2 * t as i32, (y * scale) as i32 + t as i32);
+                            let v = 128.0 + 80.0 * ((gx as f64) / 6.0).sin() * ((gy as f64) / 5.0).cos() + (p * 10) as f64;
+                            plane.set(x, y, v as u16);
+                        }
+                    }
+                }
+                frame
+            })
+            .collect()
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let frames = moving_frames(4);
+        for (method, precision) in [
+            (SearchMethod::Full, Precision::Whole),
+            (SearchMethod::Diamond, Precision::Whole),
+            (SearchMethod::Diamond, Precision::Quarter),
+        ] {
+            let params = SearchParams { block_size: 8, range: 4, method, precision, ..SearchParams::default() };
+            let mut bytes = Vec::new();
+            let mut bw = BitWriter::new(&mut bytes);
+            let mut enc = Encoder::new();
+            let mut encoder = InterEncoder::new(params);
+            let mut moving = InterStats::default();
+            for pair in frames.windows(2) {
+                let stats = encoder.encode_frame(&pair[1], &pair[0], &mut enc, &mut bw);
+                moving.mv_bits += stats.mv_bits;
+                moving.residual_bits += stats.residual_bits;
+            }
+            enc.finish(&mut bw).unwrap();
+            bw.pad_to_byte().unwrap();
+            assert!(moving.mv_bits > 0 && moving.residual_bits > 0);
+
+            let mut br: BitReader<_, MSB> = BitReader::new(&bytes[..]);
+            let mut dec = Decoder::new();
+            let mut decoder = InterDecoder::new(8, precision);
+            for pair in frames.windows(2) {
+                assert_eq!(decoder.decode_frame(&pair[0], &mut dec, &mut br).unwrap(), pair[1]);
+            }
+        }
+    }
+
+    #[test]
+    fn motion_makes_residuals_cheaper() {
+        let frames = moving_frames(2);
+        let cost = |range| {
+            let params = SearchParams { block_size: 8, range, method: SearchMethod::Full, ..SearchParams::default() };
+            let mut bytes = Vec::new();
+            let mut bw = BitWriter::new(&mut bytes);
+            let mut enc = Encoder::new();
+            InterEncoder::new(params).encode_frame(&frames[1], &frames[0], &mut enc, &mut bw).residual_bits
+        };
+        assert!(cost(4) < cost(0) / 2);
+    }
+}
@@ -0,0 +1,154 @@
+// Sub-pixel interpolation for motion compensation.
+//
+// Samples between pixels are interpolated with the 8-tap filters HEVC uses
+// for luma, at quarter-pixel positions. Both dimensions are filtered
+// separably in exact integer arithmetic, horizontally first, with a single
+// rounding at the end, so encoder and decoder produce identical
+// predictions. Chroma uses the same filters at its own quarter positions.
+// Samples outside the reference repeat its edges.
+
+use super::Plane;
+use super::motion::{MotionVector, Precision};
+
+// Taps for positions 0, 1/4, 1/2 and 3/4 of a pixel, each summing to 64.
+const FILTERS: [[i32; 8]; 4] = [
+    [0, 0, 0, 64, 0, 0, 0, 0],
+    [-1, 4, -10, 58, 17, -5, 1, 0],
+    [-1, 4, -11, 40, 40, -11, 4, -1],
+    [0, 1, -5, 17, 58, -10, 4, -1],
+];
+// Taps before the sample the filter is centred on.
+const BEFORE: i64 = 3;
+
+// The width x height block at (x0, y0) of reference displaced by mv, in
+// units of precision, as rows of samples.
+#[allow(clippy::too_many_arguments)]
+pub fn interpolate(
+    reference: &Plane,
+    x0: usize,
+    y0: usize,
+    width: usize,
+    height: usize,
+    mv: MotionVector,
+    precision: Precision,
+    max_value: u16,
+) -> Vec<u16> {
+    // Quarter pixels, as whole pixels and a fraction.
+    let shift = 2 - precision.bits();
+    let (qx, qy) = ((mv.x as i64) << shift, (mv.y as i64) << shift);
+    let (bx, by) = (x0 as i64 + (qx >> 2), y0 as i64 + (qy >> 2));
+    let (fx, fy) = ((qx & 3) as usize, (qy & 3) as usize);
+    let sample = |x: i64, y: i64| reference.get_clamped(x as isize, y as isize) as i32;
+    let max = max_value as i32;
+    let mut out = Vec::with_capacity(width * height);
+
+    if fx == 0 && fy == 0 {
+        for y in 0..height as i64 {
+            for x in 0..width as i64 {
+                out.push(sample(bx + x, by + y) as u16);
+            }
+        }
+        return out;
+    }
+    let filter = |taps: &[i32; 8], at: &dyn Fn(i64) -> i32| (0..8).map(|k| taps[k] * at(k as i64 - BEFORE)).sum::<i32>();
+    if fy == 0 {
+        for y in 0..height as i64 {
+            for x in 0..width as i64 {
+                let sum = filter(&FILTERS[fx], &|k| sample(bx + x + k, by + y));
+                out.push(((sum + 32) >> 6).clamp(0, max) as u16);
+            }
+        }
+        return out;
+    }
+    if fx == 0 {
+        for y in 0..height as i64 {
+            for x in 0..width as i64 {
+                let sum = filter(&FILTERS[fy], &|k| sample(bx + x, by + y + k));
+                out.push(((sum + 32) >> 6).clamp(0, max) as u16);
+            }
+        }
+        return out;
+    }
+
+    // Horizontal pass over the rows the vertical taps reach, kept at full
+    // precision (scaled by 64), then the vertical pass.
+    let rows = height + 7;
+    let mut horizontal = vec![0i32; rows * width];
+    for r in 0..rows {
+        for x in 0..width {
+            let y = by + r as i64 - BEFORE;
+            horizontal[r * width + x] = filter(&FILTERS[fx], &|k| sample(bx + x as i64 + k, y));
+        }
+    }
+    for y in 0..height {
+        for x in 0..width {
+            let sum = filter(&FILTERS[fy], &|k| horizontal[(y as i64 + k + BEFORE) as usize * width + x]);
+            out.push(((sum + 2048) >> 12).clamp(0, max) as u16);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ramp(width: usize, height: usize) -> Plane {
+        let mut plane = Plane::new(width, height, 0);
+        for y in 0..height {
+            for x in 0..width {
+                plane.set(x, y, (40 + 8 * x + 4 * y) as u16);
+            }
+        }
+        plane
+    }
+
+    #[test]
+    fn filters_sum_to_unity() {
+        assert!(FILTERS.iter().all(|f| f.iter().sum::<i32>() == 64));
+    }
+
+    #[test]
+    fn whole_vectors_copy() {
+        let plane = ramp(16, 16);
+        let block = interpolate(&plane, 4, 4, 4, 4, MotionVector::new(-2, 3), Precision::Whole, 255);
+        assert_eq!(block[0], plane.get(2, 7));
+        assert_eq!(block[15], plane.get(5, 10));
+        // The same displacement in quarter pixels.
+        assert_eq!(block, interpolate(&plane, 4, 4, 4, 4, MotionVector::new(-8, 12), Precision::Quarter, 255));
+    }
+
+    #[test]
+    fn ramps_interpolate_linearly() {
+        // Away from the edges a linear ramp interpolates to its value at the
+        // fractional position, to within rounding.
+        let plane = ramp(32, 32);
+        for (mv, dx, dy) in [((2, 0), 4, 0), ((1, 0), 2, 0), ((0, 3), 0, 3), ((1, 1), 2, 1), ((-3, -1), -6, -1)] {
+            let block = interpolate(&plane, 12, 12, 4, 4, MotionVector::new(mv.0, mv.1), Precision::Quarter, 255);
+            for y in 0..4 {
+                for x in 0..4 {
+                    let expected = 40 + 8 * (12 + x) as i32 + 4 * (12 + y) as i32 + dx + dy;
+                    assert_eq!(block[y * 4 + x] as i32, expected, "{:?} at {} {}", mv, x, y);
+                }
+            }
+        }
+        let half = interpolate(&plane, 12, 12, 1, 1, MotionVector::new(1, 0), Precision::Half, 255);
+        assert_eq!(half[0], plane.get(12, 12) + 4);
+    }
+
+    #[test]
+    fn results_stay_in_range() {
+        // A sharp edge makes the filters overshoot, which must be clipped.
+        let mut plane = Plane::new(16, 16, 0);
+        for y in 0..16 {
+            for x in 8..16 {
+                plane.set(x, y, 1023);
+            }
+        }
+        for fx in 1..4 {
+            let block = interpolate(&plane, 4, 4, 8, 8, MotionVector::new(fx, fx), Precision::Quarter, 1023);
+            assert!(block.iter().all(|&s| s <= 1023));
+            assert!(block.contains(&0) && block.contains(&1023));
+        }
+    }
+}
@@ -0,0 +1,494 @@
+// Intra prediction of 8x8 blocks from reconstructed neighbouring samples,
+// with the 35 modes of HEVC: planar (0), DC (1) and 33 angular modes (2 to
+// 34) including pure horizontal (10) and vertical (26).
+//
+// Predictions use the row above and the column to the left of the block and
+// the corner sample between them. Samples above-right and below-left are
+// never used, since whether they have been reconstructed depends on the
+// order blocks are coded in; the last sample of the row and column is
+// repeated in their place. Missing neighbours at the frame edges are filled
+// from whichever side is available, or mid-grey when neither is.
+//
+// Modes are coded against three most probable modes derived from the left
+// and above blocks as in HEVC: a flag for whether the mode is one of them,
+// then either its index among them or its index among the other 32 modes.
+
+use super::Plane;
+use super::coeff::CoeffCoder;
+use super::quant::Quantizer;
+use super::transform::{BLOCK_AREA, BLOCK_SIZE, Block, forward, inverse};
+use crate::decoder::{DecodeError, Decoder};
+use crate::encoder::Encoder;
+use crate::symbol_model::{SymbolModel, VectorCountSymbolModel};
+use bitbit::reader::Bit;
+use bitbit::{BitReader, BitWriter};
+use std::io::{self, Read, Write};
+
+pub const NUM_MODES: u8 = 35;
+pub const PLANAR: u8 = 0;
+pub const DC: u8 = 1;
+pub const HORIZONTAL: u8 = 10;
+pub const VERTICAL: u8 = 26;
+
+const N: usize = BLOCK_SIZE;
+
+// Displacement per row (or column) in 1/32 sample for modes 2 to 34.
+const ANGLES: [i32; 33] = [
+    32, 26, 21, 17, 13, 9, 5, 2, 0, -2, -5, -9, -13, -17, -21, -26, -32, -26, -21, -17, -13, -9, -5, -2, 0, 2, 5, 9, 13,
+    17, 21, 26, 32,
+];
+
+// 256 * 32 / angle for the negative angles, used to project the other
+// reference onto the main one.
+fn inverse_angle(angle: i32) -> i32 {
+    match angle {
+        -2 => -4096,
+        -5 => -1638,
+        -9 => -910,
+        -13 => -630,
+        -17 => -482,
+        -21 => -390,
+        -26 => -315,
+        -32 => -256,
+        _ => 0,
+    }
+}
+
+// The samples a block is predicted from. top[i] and left[i] for i >= 8 repeat
+// the last real sample.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct References {
+    pub corner: i32,
+    pub top: [i32; 2 * N],
+    pub left: [i32; 2 * N],
+}
+
+impl References {
+    pub fn new(recon: &Plane, x0: usize, y0: usize, bit_depth: u8) -> Self {
+        let mid = 1 << (bit_depth - 1);
+        let has_top = y0 > 0;
+        let has_left = x0 > 0;
+        let mut top = [mid; 2 * N];
+        let mut left = [mid; 2 * N];
+        if has_top {
+            for (i, t) in top.iter_mut().enumerate().take(N) {
+                *t = recon.get((x0 + i).min(recon.width - 1), y0 - 1) as i32;
+            }
+        }
+        if has_left {
+            for (i, l) in left.iter_mut().enumerate().take(N) {
+                *l = recon.get(x0 - 1, (y0 + i).min(recon.height - 1)) as i32;
+            }
+        }
+        if has_top && !has_left {
+            left = [top[0]; 2 * N];
+        } else if has_left && !has_top {
+            top = [left[0]; 2 * N];
+        }
+        for i in N..2 * N {
+            top[i] = top[N - 1];
+            left[i] = left[N - 1];
+        }
+        let corner = match (has_top, has_left) {
+            (true, true) => recon.get(x0 - 1, y0 - 1) as i32,
+            (true, false) => top[0],
+            (false, true) => left[0],
+            (false, false) => mid,
+        };
+        Self { corner, top, left }
+    }
+}
+
+// The prediction for mode, in raster order.
+pub fn predict(refs: &References, mode: u8) -> Block {
+    let mut block = [0i32; BLOCK_AREA];
+    match mode {
+        PLANAR => {
+            let top_right = refs.top[N];
+            let bottom_left = refs.left[N];
+            for y in 0..N {
+                for x in 0..N {
+                    let h = (N - 1 - x) as i32 * refs.left[y] + (x + 1) as i32 * top_right;
+                    let v = (N - 1 - y) as i32 * refs.top[x] + (y + 1) as i32 * bottom_left;
+                    block[y * N + x] = (h + v + N as i32) >> 4;
+                }
+            }
+        }
+        DC => {
+            let sum: i32 = refs.top[..N].iter().sum::<i32>() + refs.left[..N].iter().sum::<i32>();
+            block.fill((sum + N as i32) >> 4);
+        }
+        _ => predict_angular(refs, mode, &mut block),
+    }
+    block
+}
+
+fn predict_angular(refs: &References, mode: u8, block: &mut Block) {
+    let angle = ANGLES[mode as usize - 2];
+    let vertical = mode >= 18;
+    let (main, side) = if vertical { (&refs.top, &refs.left) } else { (&refs.left, &refs.top) };
+
+    // reference[OFFSET + i] is sample i of the main reference, with -1 the
+    // corner and negative indices projected from the side reference.
+    const OFFSET: i32 = N as i32;
+    let mut reference = [0i32; 3 * N + 1];
+    reference[OFFSET as usize] = refs.corner;
+    for i in 0..2 * N {
+        reference[OFFSET as usize + 1 + i] = main[i];
+    }
+    if angle < 0 {
+        let inv = inverse_angle(angle);
+        let first = (N as i32 * angle) >> 5;
+        for x in first..=-1 {
+            let side_index = -1 + ((x * inv + 128) >> 8);
+            reference[(OFFSET + x) as usize] = if side_index < 0 { refs.corner } else { side[side_index as usize] };
+        }
+    }
+
+    for j in 0..N as i32 {
+        let pos = (j + 1) * angle;
+        let (idx, fact) = (pos >> 5, pos & 31);
+        for i in 0..N as i32 {
+            let a = reference[(OFFSET + i + idx + 1) as usize];
+            let b = reference[(OFFSET + i + idx + 2).min(3 * N as i32) as usize];
+            let v = ((32 - fact) * a + fact * b + 16) >> 5;
+            // For vertical modes j runs down the rows; for horizontal ones
+            // the roles of rows and columns swap.
+            let (x, y) = if vertical { (i, j) } else { (j, i) };
+            block[(y * N as i32 + x) as usize] = v;
+        }
+    }
+}
+
+// HEVC's most probable modes from the modes of the left and above blocks.
+// None for a neighbour that is missing or not intra coded, which counts as DC.
+pub fn most_probable_modes(left: Option<u8>, above: Option<u8>) -> [u8; 3] {
+    let a = left.unwrap_or(DC);
+    let b = above.unwrap_or(DC);
+    if a == b {
+        if a < 2 {
+            [PLANAR, DC, VERTICAL]
+        } else {
+            [a, 2 + ((a + 29) % 32), 2 + ((a - 2 + 1) % 32)]
+        }
+    } else {
+        let third = if a != PLANAR && b != PLANAR {
+            PLANAR
+        } else if a != DC && b != DC {
+            DC
+        } else {
+            VERTICAL
+        };
+        [a, b, third]
+    }
+}
+
+// Intra modes chosen for each 8x8 block of a plane, None where a block is
+// not intra coded (or not coded yet).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ModeMap {
+    pub cols: usize,
+    pub rows: usize,
+    modes: Vec<Option<u8>>,
+}
+
+impl ModeMap {
+    pub fn new(width: usize, height: usize) -> Self {
+        let cols = width.div_ceil(N);
+        let rows = height.div_ceil(N);
+        Self {
+            cols,
+            rows,
+            modes: vec![None; cols * rows],
+        }
+    }
+
+    pub fn get(&self, col: usize, row: usize) -> Option<u8> {
+        self.modes[row * self.cols + col]
+    }
+
+    pub fn set(&mut self, col: usize, row: usize, mode: Option<u8>) {
+        self.modes[row * self.cols + col] = mode;
+    }
+
+    pub fn most_probable_modes(&self, col: usize, row: usize) -> [u8; 3] {
+        let left = if col > 0 { self.get(col - 1, row) } else { None };
+        let above = if row > 0 { self.get(col, row - 1) } else { None };
+        most_probable_modes(left, above)
+    }
+}
+
+// Bits to code symbol under model at its current counts.
+fn symbol_cost<T: Eq>(model: &VectorCountSymbolModel<T>, symbol: &T) -> f64 {
+    let (start, end) = model.interval(symbol);
+    (model.total() as f64 / (end - start) as f64).log2()
+}
+
+#[derive(Clone, Debug)]
+pub struct IntraModeCoder {
+    is_mpm: VectorCountSymbolModel<bool>,
+    mpm_index: VectorCountSymbolModel<u8>,
+    remaining: VectorCountSymbolModel<u8>,
+}
+
+impl Default for IntraModeCoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IntraModeCoder {
+    pub fn new() -> Self {
+        Self {
+            is_mpm: VectorCountSymbolModel::new(vec![false, true]),
+            mpm_index: VectorCountSymbolModel::new(vec![0, 1, 2]),
+            remaining: VectorCountSymbolModel::new((0..NUM_MODES - 3).collect()),
+        }
+    }
+
+    // The index of mode among the modes that are not most probable.
+    fn remaining_index(mode: u8, mpm: &[u8; 3]) -> u8 {
+        mode - mpm.iter().filter(|&&m| m < mode).count() as u8
+    }
+
+    pub fn encode_mode<W: Write>(&mut self, mode: u8, mpm: &[u8; 3], enc: &mut Encoder, output: &mut BitWriter<W>) {
+        let index = mpm.iter().position(|&m| m == mode);
+        let is_mpm = index.is_some();
+        enc.encode(&is_mpm, &self.is_mpm, output);
+        self.is_mpm.incr_count(&is_mpm);
+        match index {
+            Some(i) => {
+                let i = i as u8;
+                enc.encode(&i, &self.mpm_index, output);
+                self.mpm_index.incr_count(&i);
+            }
+            None => {
+                let r = Self::remaining_index(mode, mpm);
+                enc.encode(&r, &self.remaining, output);
+                self.remaining.incr_count(&r);
+            }
+        }
+    }
+
+    pub fn decode_mode<R: Read, B: Bit>(&mut self, mpm: &[u8; 3], dec: &mut Decoder, input: &mut BitReader<R, B>) -> Result<u8, DecodeError> {
+        let is_mpm = *dec.decode(&self.is_mpm, input)?;
+        self.is_mpm.incr_count(&is_mpm);
+        if is_mpm {
+            let i = *dec.decode(&self.mpm_index, input)?;
+            self.mpm_index.incr_count(&i);
+            return Ok(mpm[i as usize]);
+        }
+        let r = *dec.decode(&self.remaining, input)?;
+        self.remaining.incr_count(&r);
+        let mut sorted = *mpm;
+        sorted.sort();
+        let mut mode = r;
+        for m in sorted {
+            if m <= mode {
+                mode += 1;
+            }
+        }
+        Ok(mode)
+    }
+
+    // Estimated bits to code mode with the models as they stand.
+    pub fn cost(&self, mode: u8, mpm: &[u8; 3]) -> f64 {
+        match mpm.iter().position(|&m| m == mode) {
+            Some(i) => symbol_cost(&self.is_mpm, &true) + symbol_cost(&self.mpm_index, &(i as u8)),
+            None => symbol_cost(&self.is_mpm, &false) + symbol_cost(&self.remaining, &Self::remaining_index(mode, mpm)),
+        }
+    }
+}
+
+// How the encoder picks a mode: lowest SAD of the prediction, or lowest
+// distortion plus lambda times bits after transform and quantization.
+pub enum ModeDecision<'a> {
+    Sad,
+    Rd {
+        quantizer: &'a Quantizer,
+        coeffs: &'a CoeffCoder,
+        lambda: f64,
+    },
+}
+
+// Picks the mode for the 8x8 block of original at (x0, y0), predicting from
+// the reconstruction recon. plane selects the coefficient contexts for RD
+// decisions.
+#[allow(clippy::too_many_arguments)]
+pub fn choose_mode(
+    original: &Plane,
+    recon: &Plane,
+    x0: usize,
+    y0: usize,
+    plane: usize,
+    bit_depth: u8,
+    mpm: &[u8; 3],
+    modes: &IntraModeCoder,
+    decision: &ModeDecision,
+) -> u8 {
+    let refs = References::new(recon, x0, y0, bit_depth);
+    let mut source = [0i32; BLOCK_AREA];
+    for y in 0..N {
+        for x in 0..N {
+            source[y * N + x] = original.get_clamped((x0 + x) as isize, (y0 + y) as isize) as i32;
+        }
+    }
+
+    let mut best = (DC, f64::INFINITY);
+    for mode in 0..NUM_MODES {
+        let prediction = predict(&refs, mode);
+        let cost = match decision {
+            ModeDecision::Sad => source.iter().zip(prediction.iter()).map(|(s, p)| (s - p).abs()).sum::<i32>() as f64,
+            ModeDecision::Rd { quantizer, coeffs, lambda } => {
+                let mut residual = [0i32; BLOCK_AREA];
+                for i in 0..BLOCK_AREA {
+                    residual[i] = source[i] - prediction[i];
+                }
+                let levels = quantizer.quantize(&forward(&residual, bit_depth));
+                let recon_residual = inverse(&quantizer.dequantize(&levels), bit_depth);
+                let max = (1 << bit_depth) - 1;
+                let distortion: f64 = (0..BLOCK_AREA)
+                    .map(|i| (source[i] - (prediction[i] + recon_residual[i]).clamp(0, max)) as f64)
+                    .map(|d| d * d)
+                    .sum();
+
+                let mut enc = Encoder::new();
+                let mut sink = BitWriter::new(io::sink());
+                let mut scratch = CoeffCoder::clone(coeffs);
+                scratch.encode_block(&levels, plane, &mut enc, &mut sink);
+                let bits = enc.bits_used() + modes.cost(mode, mpm);
+                distortion + lambda * bits
+            }
+        };
+        if cost < best.1 {
+            best = (mode, cost);
+        }
+    }
+    best.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::video::coeff::ScanOrder;
+    use bitbit::MSB;
+
+    fn refs_from(top: [i32; N], left: [i32; N], corner: i32) -> References {
+        let mut refs = References { corner, top: [top[N - 1]; 2 * N], left: [left[N - 1]; 2 * N] };
+        refs.top[..N].copy_from_slice(&top);
+        refs.left[..N].copy_from_slice(&left);
+        refs
+    }
+
+    #[test]
+    fn simple_modes() {
+        let top = [10, 20, 30, 40, 50, 60, 70, 80];
+        let left = [1, 2, 3, 4, 5, 6, 7, 8];
+        let refs = refs_from(top, left, 0);
+
+        let v = predict(&refs, VERTICAL);
+        let h = predict(&refs, HORIZONTAL);
+        for y in 0..N {
+            for x in 0..N {
+                assert_eq!(v[y * N + x], top[x]);
+                assert_eq!(h[y * N + x], left[y]);
+            }
+        }
+        assert!(predict(&refs, DC).iter().all(|&p| p == (360 + 36 + 8) >> 4));
+
+        // Mode 34 runs down and to the left at 45 degrees.
+        let diagonal = predict(&refs, 34);
+        assert_eq!(diagonal[0], top[1]);
+        assert_eq!(diagonal[N + 2], top[4]);
+        assert_eq!(diagonal[N * N - 1], top[N - 1]);
+        // Mode 18 runs down and to the right from the corner.
+        let down_right = predict(&refs, 18);
+        assert_eq!(down_right[0], 0);
+        assert_eq!(down_right[N + 3], top[1]);
+        assert_eq!(down_right[3 * N + 1], left[1]);
+        assert_eq!(down_right[N], left[0]);
+    }
+
+    #[test]
+    fn planar_of_flat_references_is_flat() {
+        let refs = refs_from([77; N], [77; N], 77);
+        for mode in 0..NUM_MODES {
+            assert!(predict(&refs, mode).iter().all(|&p| p == 77), "mode {}", mode);
+        }
+    }
+
+    #[test]
+    fn references_at_frame_edges() {
+        let mut recon = Plane::new(16, 16, 0);
+        for (i, s) in recon.data.iter_mut().enumerate() {
+            *s = i as u16;
+        }
+        let first = References::new(&recon, 0, 0, 8);
+        assert_eq!(first.corner, 128);
+        assert!(first.top.iter().chain(first.left.iter()).all(|&s| s == 128));
+
+        let top_row = References::new(&recon, 8, 0, 8);
+        assert_eq!(top_row.left[0], 7);
+        assert!(top_row.top.iter().all(|&s| s == 7));
+
+        let inner = References::new(&recon, 8, 8, 8);
+        assert_eq!(inner.corner, 7 * 16 + 7);
+        assert_eq!(inner.top[0], 7 * 16 + 8);
+        assert_eq!(inner.top[12], 7 * 16 + 15);
+        assert_eq!(inner.left[3], 11 * 16 + 7);
+    }
+
+    #[test]
+    fn mpm_derivation() {
+        assert_eq!(most_probable_modes(None, None), [PLANAR, DC, VERTICAL]);
+        assert_eq!(most_probable_modes(Some(PLANAR), Some(PLANAR)), [PLANAR, DC, VERTICAL]);
+        assert_eq!(most_probable_modes(Some(10), Some(10)), [10, 9, 11]);
+        assert_eq!(most_probable_modes(Some(2), Some(2)), [2, 33, 3]);
+        assert_eq!(most_probable_modes(Some(34), Some(34)), [34, 33, 3]);
+        assert_eq!(most_probable_modes(Some(10), Some(26)), [10, 26, PLANAR]);
+        assert_eq!(most_probable_modes(Some(PLANAR), Some(26)), [PLANAR, 26, DC]);
+        assert_eq!(most_probable_modes(Some(PLANAR), None), [PLANAR, DC, VERTICAL]);
+    }
+
+    #[test]
+    fn modes_round_trip() {
+        let mut bytes = Vec::new();
+        let mut bw = BitWriter::new(&mut bytes);
+        let mut enc = Encoder::new();
+        let mut coder = IntraModeCoder::new();
+        let cases: Vec<(u8, [u8; 3])> = (0..NUM_MODES)
+            .flat_map(|mode| [(mode, most_probable_modes(None, None)), (mode, most_probable_modes(Some(mode), Some(34)))])
+            .collect();
+        for (mode, mpm) in cases.iter() {
+            coder.encode_mode(*mode, mpm, &mut enc, &mut bw);
+        }
+        enc.finish(&mut bw).unwrap();
+        bw.pad_to_byte().unwrap();
+
+        let mut br: BitReader<_, MSB> = BitReader::new(&bytes[..]);
+        let mut dec = Decoder::new();
+        let mut coder = IntraModeCoder::new();
+        for (mode, mpm) in cases.iter() {
+            assert_eq!(coder.decode_mode(mpm, &mut dec, &mut br).unwrap(), *mode);
+        }
+    }
+
+    #[test]
+    fn decisions_follow_the_content() {
+        // Vertical stripes above a block that continues them.
+        let mut original = Plane::new(16, 16, 0);
+        for y in 0..16 {
+            for x in 0..16 {
+                original.set(x, y, if x % 3 == 0 { 200 } else { 40 });
+            }
+        }
+        let mpm = most_probable_modes(None, None);
+        let modes = IntraModeCoder::new();
+        assert_eq!(choose_mode(&original, &original, 8, 8, 0, 8, &mpm, &modes, &ModeDecision::Sad), VERTICAL);
+
+        let quantizer = Quantizer::new(22, 8);
+        let coeffs = CoeffCoder::new(ScanOrder::Diagonal);
+        let rd = ModeDecision::Rd { quantizer: &quantizer, coeffs: &coeffs, lambda: 10.0 };
+        assert_eq!(choose_mode(&original, &original, 8, 8, 0, 8, &mpm, &modes, &rd), VERTICAL);
+    }
+}
@@ -0,0 +1,192 @@
+// Frames and planes shared by the video readers, writers and codec stages.
+// Samples are stored as u16 whatever the bit depth so that 8-bit and
+// high bit depth content go through the same code.
+
+pub mod codec;
+pub mod coeff;
+pub mod deblock;
+pub mod inter;
+pub mod interp;
+pub mod intra;
+pub mod motion;
+pub mod mvcoding;
+pub mod quant;
+pub mod ratecontrol;
+pub mod raw;
+pub mod scenecut;
+pub mod transform;
+pub mod y4m;
+
+use std::io::{self, Read};
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChromaSampling {
+    Cs420,
+    Cs422,
+    Cs444,
+    Mono,
+}
+
+impl ChromaSampling {
+    // Horizontal and vertical subsampling of the chroma planes as shifts.
+    pub fn shifts(&self) -> (usize, usize) {
+        match self {
+            ChromaSampling::Cs420 => (1, 1),
+            ChromaSampling::Cs422 => (1, 0),
+            ChromaSampling::Cs444 | ChromaSampling::Mono => (0, 0),
+        }
+    }
+
+    // Size of each chroma plane for a frame of the given luma size. Odd
+    // luma sizes round the chroma size up.
+    pub fn chroma_size(&self, width: usize, height: usize) -> (usize, usize) {
+        if *self == ChromaSampling::Mono {
+            return (0, 0);
+        }
+        let (sx, sy) = self.shifts();
+        ((width + (1 << sx) - 1) >> sx, (height + (1 << sy) - 1) >> sy)
+    }
+}
+
+impl FromStr for ChromaSampling {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "420" => Ok(ChromaSampling::Cs420),
+            "422" => Ok(ChromaSampling::Cs422),
+            "444" => Ok(ChromaSampling::Cs444),
+            "mono" => Ok(ChromaSampling::Mono),
+            _ => Err(format!("unknown chroma sampling {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Plane {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<u16>,
+}
+
+impl Plane {
+    pub fn new(width: usize, height: usize, fill: u16) -> Self {
+        Self {
+            width,
+            height,
+            data: vec![fill; width * height],
+        }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> u16 {
+        self.data[y * self.width + x]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, value: u16) {
+        self.data[y * self.width + x] = value;
+    }
+
+    // Sample at (x, y) with coordinates outside the plane clamped to the
+    // nearest edge sample.
+    pub fn get_clamped(&self, x: isize, y: isize) -> u16 {
+        let x = x.clamp(0, self.width as isize - 1) as usize;
+        let y = y.clamp(0, self.height as isize - 1) as usize;
+        self.data[y * self.width + x]
+    }
+
+    pub fn row(&self, y: usize) -> &[u16] {
+        &self.data[y * self.width..(y + 1) * self.width]
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub chroma: ChromaSampling,
+    pub bit_depth: u8,
+    pub y: Plane,
+    pub u: Plane,
+    pub v: Plane,
+}
+
+impl Frame {
+    // A black frame: zero luma and mid-grey chroma.
+    pub fn new(width: usize, height: usize, chroma: ChromaSampling, bit_depth: u8) -> Self {
+        let (cw, ch) = chroma.chroma_size(width, height);
+        let mid = 1 << (bit_depth - 1);
+        Self {
+            chroma,
+            bit_depth,
+            y: Plane::new(width, height, 0),
+            u: Plane::new(cw, ch, mid),
+            v: Plane::new(cw, ch, mid),
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.y.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.y.height
+    }
+
+    pub fn max_value(&self) -> u16 {
+        ((1u32 << self.bit_depth) - 1) as u16
+    }
+
+    pub fn planes(&self) -> [&Plane; 3] {
+        [&self.y, &self.u, &self.v]
+    }
+
+    pub fn planes_mut(&mut self) -> [&mut Plane; 3] {
+        [&mut self.y, &mut self.u, &mut self.v]
+    }
+
+    // Plane p of y, u, v.
+    pub fn plane_mut(&mut self, p: usize) -> &mut Plane {
+        match p {
+            0 => &mut self.y,
+            1 => &mut self.u,
+            _ => &mut self.v,
+        }
+    }
+}
+
+// Reads until buf is full or the input ends, returning the bytes read.
+pub(crate) fn read_fully<R: Read>(input: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match input.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+// Fills a plane from 8-bit samples, or little-endian 16-bit samples when
+// bytes_per_sample is 2.
+pub(crate) fn unpack_plane(bytes: &[u8], plane: &mut Plane, bytes_per_sample: usize) {
+    if bytes_per_sample == 1 {
+        for (s, &b) in plane.data.iter_mut().zip(bytes.iter()) {
+            *s = b as u16;
+        }
+    } else {
+        for (s, b) in plane.data.iter_mut().zip(bytes.chunks_exact(2)) {
+            *s = u16::from_le_bytes([b[0], b[1]]);
+        }
+    }
+}
+
+pub(crate) fn pack_plane(plane: &Plane, bytes: &mut Vec<u8>, bytes_per_sample: usize) {
+    if bytes_per_sample == 1 {
+        bytes.extend(plane.data.iter().map(|&s| s as u8));
+    } else {
+        for &s in plane.data.iter() {
+            bytes.extend_from_slice(&s.to_le_bytes());
+        }
+    }
+}
@@ -0,0 +1,539 @@
+// Block motion estimation and compensation.
+//
+// The luma plane is split into square blocks and each block is matched
+// against the reference frame within a window of +/- range pixels. Blocks
+// at the right and bottom edges may be partial, and the reference is
+// extended by repeating its edge samples so vectors may point outside it.
+// Chroma blocks use the luma vector scaled down by the chroma subsampling,
+// rounded to the nearest whole sample.
+//
+// Vectors may also be in half or quarter pixels. The search then refines
+// the best whole pixel vector to each finer step in turn, checking the
+// eight neighbours at that step, and predictions between pixels are
+// interpolated (see interp.rs). Fractional chroma vectors are rounded to
+// quarter chroma samples rather than whole ones.
+
+use super::interp::interpolate;
+use super::{Frame, Plane};
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MotionVector {
+    pub x: i32,
+    pub y: i32,
+}
+
+impl MotionVector {
+    pub fn new(x: i32, y: i32) -> Self {
+        Self { x, y }
+    }
+
+    // The vector for a plane subsampled by the given shifts.
+    pub fn scaled(&self, shifts: (usize, usize)) -> Self {
+        Self {
+            x: scale_component(self.x, shifts.0),
+            y: scale_component(self.y, shifts.1),
+        }
+    }
+
+    // The vector in quarter pixels, from units of precision.
+    pub fn in_quarters(&self, precision: Precision) -> Self {
+        let shift = 2 - precision.bits();
+        Self::new(self.x << shift, self.y << shift)
+    }
+}
+
+fn scale_component(v: i32, shift: usize) -> i32 {
+    if shift == 0 { v } else { (v + (1 << (shift - 1))) >> shift }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SearchMethod {
+    Full,
+    ThreeStep,
+    Diamond,
+}
+
+impl FromStr for SearchMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "full" => Ok(SearchMethod::Full),
+            "tss" | "three-step" => Ok(SearchMethod::ThreeStep),
+            "diamond" => Ok(SearchMethod::Diamond),
+            _ => Err(format!("unknown search method {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Metric {
+    Sad,
+    Ssd,
+}
+
+impl FromStr for Metric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "sad" => Ok(Metric::Sad),
+            "ssd" => Ok(Metric::Ssd),
+            _ => Err(format!("unknown cost metric {}", s)),
+        }
+    }
+}
+
+// The unit of vector components.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Precision {
+    Whole,
+    Half,
+    Quarter,
+}
+
+impl Precision {
+    // Fractional bits: the log2 of steps per pixel.
+    pub fn bits(&self) -> u32 {
+        match self {
+            Precision::Whole => 0,
+            Precision::Half => 1,
+            Precision::Quarter => 2,
+        }
+    }
+
+    pub fn from_bits(bits: u32) -> Option<Self> {
+        match bits {
+            0 => Some(Precision::Whole),
+            1 => Some(Precision::Half),
+            2 => Some(Precision::Quarter),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Precision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "1" | "whole" => Ok(Precision::Whole),
+            "1/2" | "half" => Ok(Precision::Half),
+            "1/4" | "quarter" => Ok(Precision::Quarter),
+            _ => Err(format!("unknown vector precision {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SearchParams {
+    // 8 or 16.
+    pub block_size: usize,
+    // Largest vector component searched, in pixels.
+    pub range: i32,
+    pub method: SearchMethod,
+    pub metric: Metric,
+    pub precision: Precision,
+}
+
+impl Default for SearchParams {
+    fn default() -> Self {
+        Self {
+            block_size: 16,
+            range: 16,
+            method: SearchMethod::Diamond,
+            metric: Metric::Sad,
+            precision: Precision::Whole,
+        }
+    }
+}
+
+// One vector per luma block, in raster order, in units of precision.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MotionField {
+    pub block_size: usize,
+    pub precision: Precision,
+    pub cols: usize,
+    pub rows: usize,
+    pub vectors: Vec<MotionVector>,
+}
+
+impl MotionField {
+    // An all-zero field of whole pixel vectors covering a width x height
+    // luma plane.
+    pub fn new(width: usize, height: usize, block_size: usize) -> Self {
+        let cols = width.div_ceil(block_size);
+        let rows = height.div_ceil(block_size);
+        Self {
+            block_size,
+            precision: Precision::Whole,
+            cols,
+            rows,
+            vectors: vec![MotionVector::default(); cols * rows],
+        }
+    }
+
+    pub fn with_precision(mut self, precision: Precision) -> Self {
+        self.precision = precision;
+        self
+    }
+
+    pub fn get(&self, col: usize, row: usize) -> MotionVector {
+        self.vectors[row * self.cols + col]
+    }
+
+    pub fn set(&mut self, col: usize, row: usize, mv: MotionVector) {
+        self.vectors[row * self.cols + col] = mv;
+    }
+}
+
+// Cost of predicting the size x size block of current at (x0, y0) from the
+// block of reference displaced by mv, in units of precision. Fractional
+// predictions are clipped to max_value, as compensation clips them. The part
+// of the block outside current is ignored.
+#[allow(clippy::too_many_arguments)]
+pub fn block_cost(
+    current: &Plane,
+    reference: &Plane,
+    x0: usize,
+    y0: usize,
+    size: usize,
+    mv: MotionVector,
+    precision: Precision,
+    metric: Metric,
+    max_value: u16,
+) -> u64 {
+    let distance = |c: u16, r: u16| {
+        let d = (c as i64 - r as i64).unsigned_abs();
+        match metric {
+            Metric::Sad => d,
+            Metric::Ssd => d * d,
+        }
+    };
+    let (width, height) = ((x0 + size).min(current.width) - x0, (y0 + size).min(current.height) - y0);
+    let fractional = precision != Precision::Whole && (mv.x | mv.y) & ((1 << precision.bits()) - 1) != 0;
+    let mut cost = 0u64;
+    if fractional {
+        let predicted = interpolate(reference, x0, y0, width, height, mv, precision, max_value);
+        for y in 0..height {
+            let row = &current.row(y0 + y)[x0..x0 + width];
+            cost += row.iter().zip(&predicted[y * width..(y + 1) * width]).map(|(&c, &r)| distance(c, r)).sum::<u64>();
+        }
+        return cost;
+    }
+    let (dx, dy) = ((mv.x >> precision.bits()) as isize, (mv.y >> precision.bits()) as isize);
+    for y in y0..y0 + height {
+        let row = &current.row(y)[x0..x0 + width];
+        for (x, &c) in (x0..).zip(row.iter()) {
+            cost += distance(c, reference.get_clamped(x as isize + dx, y as isize + dy));
+        }
+    }
+    cost
+}
+
+struct BlockSearch<'a> {
+    current: &'a Plane,
+    reference: &'a Plane,
+    x0: usize,
+    y0: usize,
+    params: &'a SearchParams,
+    max_value: u16,
+    // The units of the vectors tried so far.
+    precision: Precision,
+    best: MotionVector,
+    best_cost: u64,
+}
+
+impl BlockSearch<'_> {
+    // Evaluates a candidate inside the window, keeping it if it is cheaper
+    // than the best so far, or as cheap and shorter. Returns whether it was
+    // kept.
+    fn try_vector(&mut self, mv: MotionVector) -> bool {
+        let range = self.params.range << self.precision.bits();
+        if mv.x.abs() > range || mv.y.abs() > range {
+            return false;
+        }
+        let cost = self.cost(mv);
+        let shorter = mv.x.abs() + mv.y.abs() < self.best.x.abs() + self.best.y.abs();
+        if cost < self.best_cost || (cost == self.best_cost && shorter) {
+            self.best = mv;
+            self.best_cost = cost;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn cost(&self, mv: MotionVector) -> u64 {
+        let (size, metric) = (self.params.block_size, self.params.metric);
+        block_cost(self.current, self.reference, self.x0, self.y0, size, mv, self.precision, metric, self.max_value)
+    }
+
+    fn full(&mut self) {
+        let range = self.params.range;
+        for y in -range..=range {
+            for x in -range..=range {
+                self.try_vector(MotionVector::new(x, y));
+            }
+        }
+    }
+
+    // Checks the eight points around the best vector at a step that starts
+    // at half the window and halves down to one pixel.
+    fn three_step(&mut self) {
+        let mut step = 1;
+        while step * 2 <= self.params.range {
+            step *= 2;
+        }
+        while step > 0 {
+            let center = self.best;
+            for (dx, dy) in [(-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1), (1, 1)] {
+                self.try_vector(MotionVector::new(center.x + dx * step, center.y + dy * step));
+            }
+            step /= 2;
+        }
+    }
+
+    // Moves the large diamond until its center is the best point, then
+    // refines once with the small diamond.
+    fn diamond(&mut self) {
+        const LARGE: [(i32, i32); 8] = [(0, -2), (1, -1), (2, 0), (1, 1), (0, 2), (-1, 1), (-2, 0), (-1, -1)];
+        const SMALL: [(i32, i32); 4] = [(0, -1), (1, 0), (0, 1), (-1, 0)];
+
+        // Every move lowers the cost or shortens the vector so the diamond
+        // cannot cycle, but cap the moves to bound the work on noisy blocks.
+        for _ in 0..4 * self.params.range.max(1) {
+            let center = self.best;
+            let mut moved = false;
+            for (dx, dy) in LARGE {
+                moved |= self.try_vector(MotionVector::new(center.x + dx, center.y + dy));
+            }
+            if !moved {
+                break;
+            }
+        }
+        let center = self.best;
+        for (dx, dy) in SMALL {
+            self.try_vector(MotionVector::new(center.x + dx, center.y + dy));
+        }
+    }
+
+    // Halves the step down to the wanted precision, checking the eight
+    // points around the best vector at each step.
+    fn refine(&mut self) {
+        for bits in 1..=self.params.precision.bits() {
+            self.best = MotionVector::new(self.best.x * 2, self.best.y * 2);
+            self.precision = Precision::from_bits(bits).unwrap();
+            let center = self.best;
+            for (dx, dy) in [(-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1), (1, 1)] {
+                self.try_vector(MotionVector::new(center.x + dx, center.y + dy));
+            }
+        }
+    }
+}
+
+// Finds a vector for every block of current, whose samples are at most
+// max_value.
+pub fn estimate(current: &Plane, reference: &Plane, params: &SearchParams, max_value: u16) -> MotionField {
+    let mut field = MotionField::new(current.width, current.height, params.block_size).with_precision(params.precision);
+    for row in 0..field.rows {
+        for col in 0..field.cols {
+            let (x0, y0) = (col * params.block_size, row * params.block_size);
+            let zero = MotionVector::default();
+            let mut search = BlockSearch {
+                current,
+                reference,
+                x0,
+                y0,
+                params,
+                max_value,
+                precision: Precision::Whole,
+                best: zero,
+                best_cost: 0,
+            };
+            search.best_cost = search.cost(zero);
+            match params.method {
+                SearchMethod::Full => search.full(),
+                SearchMethod::ThreeStep => search.three_step(),
+                SearchMethod::Diamond => search.diamond(),
+            }
+            search.refine();
+            field.set(col, row, search.best);
+        }
+    }
+    field
+}
+
+// The prediction of a frame from reference under field.
+pub fn compensate(reference: &Frame, field: &MotionField) -> Frame {
+    let mut prediction = Frame::new(reference.width(), reference.height(), reference.chroma, reference.bit_depth);
+    for row in 0..field.rows {
+        for col in 0..field.cols {
+            let (x0, y0) = (col * field.block_size, row * field.block_size);
+            compensate_block(reference, &mut prediction, x0, y0, field.block_size, field.get(col, row), field.precision);
+        }
+    }
+    prediction
+}
+
+// Writes the prediction of the size x size luma block at (x0, y0), and the
+// chroma samples under it, into prediction. mv is in units of precision.
+#[allow(clippy::too_many_arguments)]
+pub fn compensate_block(
+    reference: &Frame,
+    prediction: &mut Frame,
+    x0: usize,
+    y0: usize,
+    size: usize,
+    mv: MotionVector,
+    precision: Precision,
+) {
+    let chroma_shifts = reference.chroma.shifts();
+    for (p, (out, plane)) in prediction.planes_mut().into_iter().zip(reference.planes()).enumerate() {
+        let shifts = if p == 0 { (0, 0) } else { chroma_shifts };
+        let (bx, by) = (x0 >> shifts.0, y0 >> shifts.1);
+        let width = (bx + (size >> shifts.0)).min(out.width).saturating_sub(bx);
+        let height = (by + (size >> shifts.1)).min(out.height).saturating_sub(by);
+        let (mv, precision) = plane_vector(mv, precision, shifts);
+        let block = interpolate(plane, bx, by, width, height, mv, precision, reference.max_value());
+        for (y, row) in (by..).zip(block.chunks(width.max(1))) {
+            for (x, &s) in (bx..).zip(row) {
+                out.set(x, y, s);
+            }
+        }
+    }
+}
+
+// The vector for a plane subsampled by the given shifts, and its units:
+// whole vectors round to whole samples, others to quarter samples.
+pub fn plane_vector(mv: MotionVector, precision: Precision, shifts: (usize, usize)) -> (MotionVector, Precision) {
+    if precision == Precision::Whole {
+        return (mv.scaled(shifts), Precision::Whole);
+    }
+    (mv.in_quarters(precision).scaled(shifts), Precision::Quarter)
+}
+
+// Difference between current and prediction, one vector of samples per plane.
+pub fn residual(current: &Frame, prediction: &Frame) -> [Vec<i32>; 3] {
+    let planes = current.planes();
+    let predicted = prediction.planes();
+    [0, 1, 2].map(|p| {
+        planes[p].data.iter().zip(predicted[p].data.iter()).map(|(&c, &r)| c as i32 - r as i32).collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synth::video::{VideoSpec, VideoSynth};
+    use crate::video::ChromaSampling;
+
+    // Smooth content, so the fast searches can follow the cost downhill.
+    fn scene(width: usize, height: usize, dx: i32, dy: i32) -> Frame {
+        let mut frame = Frame::new(width, height, ChromaSampling::Cs420, 8);
+        let value = |x: i32, y: i32, scale: f64| {
+            let (x, y) = ((x - dx) as f64 * scale, (y - dy) as f64 * scale);
+            (128.0 + 60.0 * (x / 9.0).sin() + 50.0 * (y / 7.0).cos() + 10.0 * ((x + y) / 5.0).sin()) as u16
+        };
+        for y in 0..height {
+            for x in 0..width {
+                frame.y.set(x, y, value(x as i32, y as i32, 1.0));
+            }
+        }
+        for y in 0..frame.u.height {
+            for x in 0..frame.u.width {
+                frame.u.set(x, y, value(2 * x as i32, 2 * y as i32, 1.0));
+                frame.v.set(x, y, value(2 * x as i32, 2 * y as i32, 0.5));
+            }
+        }
+        frame
+    }
+
+    #[test]
+    fn every_method_finds_a_global_shift() {
+        let reference = scene(64, 48, 0, 0);
+        let current = scene(64, 48, 3, -2);
+        for method in [SearchMethod::Full, SearchMethod::ThreeStep, SearchMethod::Diamond] {
+            for metric in [Metric::Sad, Metric::Ssd] {
+                let params = SearchParams { range: 7, method, metric, ..SearchParams::default() };
+                let field = estimate(&current.y, &reference.y, &params, reference.max_value());
+                // Interior blocks only: edge blocks see the repeated border.
+                for row in 1..field.rows - 1 {
+                    for col in 1..field.cols - 1 {
+                        assert_eq!(field.get(col, row), MotionVector::new(-3, 2), "{:?} {:?}", method, metric);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn partial_blocks_and_window() {
+        let reference = scene(20, 12, 0, 0);
+        let params = SearchParams { block_size: 8, range: 2, method: SearchMethod::Full, ..SearchParams::default() };
+        let field = estimate(&scene(20, 12, 5, 0).y, &reference.y, &params, reference.max_value());
+        assert_eq!((field.cols, field.rows), (3, 2));
+        assert!(field.vectors.iter().all(|mv| mv.x.abs() <= 2 && mv.y.abs() <= 2));
+    }
+
+    #[test]
+    fn compensation_reproduces_shifted_frame() {
+        let reference = scene(32, 32, 0, 0);
+        let mut field = MotionField::new(32, 32, 16);
+        field.vectors.fill(MotionVector::new(-4, 2));
+        let prediction = compensate(&reference, &field);
+        assert_eq!(prediction.y.get(10, 10), reference.y.get(6, 12));
+        assert_eq!(prediction.u.get(5, 5), reference.u.get(3, 6));
+        // Clamped at the edges.
+        assert_eq!(prediction.y.get(0, 31), reference.y.get(0, 31));
+
+        let r = residual(&reference, &prediction);
+        assert_eq!(r[0].len(), 32 * 32);
+        assert_eq!(r[1].len(), 16 * 16);
+    }
+
+    #[test]
+    fn sub_pixel_search_finds_fractional_pans() {
+        let spec = VideoSpec {
+            width: 64,
+            height: 64,
+            pan: (1.25, -0.75),
+            objects: 0,
+            noise: 0.0,
+            ..VideoSpec::default()
+        };
+        let synth = VideoSynth::new(spec);
+        let (reference, current) = (synth.frame(0), synth.frame(1));
+        let params = |precision| SearchParams { range: 4, precision, ..SearchParams::default() };
+        // The pan is (-5, 3) in quarter pixels, which half pixels can only
+        // come within a quarter of.
+        for precision in [Precision::Half, Precision::Quarter] {
+            let field = estimate(&current.y, &reference.y, &params(precision), reference.max_value());
+            assert_eq!(field.precision, precision);
+            let scale = 1 << (2 - precision.bits());
+            for row in 1..field.rows - 1 {
+                for col in 1..field.cols - 1 {
+                    let mv = field.get(col, row);
+                    assert!((mv.x * scale + 5).abs() <= scale / 2 && (mv.y * scale - 3).abs() <= scale / 2, "{:?} {:?}", precision, mv);
+                }
+            }
+        }
+        // Quarter pixel vectors predict the frame much better than whole ones.
+        let whole = estimate(&current.y, &reference.y, &params(Precision::Whole), reference.max_value());
+        let quarter = estimate(&current.y, &reference.y, &params(Precision::Quarter), reference.max_value());
+        let error = |field: &MotionField| residual(&current, &compensate(&reference, field))[0].iter().map(|r| r.unsigned_abs() as u64).sum::<u64>();
+        assert!(error(&quarter) * 3 < error(&whole), "{} {}", error(&quarter), error(&whole));
+    }
+
+    #[test]
+    fn chroma_vectors_round() {
+        assert_eq!(MotionVector::new(3, -3).scaled((1, 1)), MotionVector::new(2, -1));
+        assert_eq!(MotionVector::new(-4, 5).scaled((1, 0)), MotionVector::new(-2, 5));
+        assert_eq!(MotionVector::new(7, 7).scaled((0, 0)), MotionVector::new(7, 7));
+        // Fractional vectors keep quarter chroma samples.
+        assert_eq!(plane_vector(MotionVector::new(3, -2), Precision::Quarter, (1, 1)), (MotionVector::new(2, -1), Precision::Quarter));
+        assert_eq!(plane_vector(MotionVector::new(3, -2), Precision::Half, (1, 0)), (MotionVector::new(3, -4), Precision::Quarter));
+        assert_eq!(plane_vector(MotionVector::new(3, -2), Precision::Whole, (1, 1)), (MotionVector::new(2, -1), Precision::Whole));
+    }
+}
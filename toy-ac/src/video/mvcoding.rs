@@ -0,0 +1,239 @@
+// Coding of motion fields as differences from predicted vectors.
+//
+// Each vector is predicted by the component-wise median of the vectors of
+// its left, top and top-right neighbours (top-left when there is no
+// top-right), and only the difference (MVD) is coded. Each MVD component
+// is coded as a zero flag, then the magnitude less one as a bucket plus
+// plain bits, then the sign. The zero flag and magnitude models are chosen
+// by the size of the same component of the left and top MVDs, since motion
+// that is hard to predict tends to come in patches.
+
+use super::motion::{MotionField, MotionVector};
+use crate::bucket::{bucket_model, decode_unsigned, encode_unsigned};
+use crate::decoder::{DecodeError, Decoder};
+use crate::encoder::Encoder;
+use crate::symbol_model::VectorCountSymbolModel;
+use bitbit::reader::Bit;
+use bitbit::{BitReader, BitWriter};
+use std::io::{Read, Write};
+
+pub const MVD_CONTEXTS: usize = 3;
+
+// The prediction for the block at (col, row) from the blocks already coded.
+// Along the top row that is just the left neighbour.
+pub fn predict(field: &MotionField, col: usize, row: usize) -> MotionVector {
+    predict_with(field, col, row, col + 1 < field.cols)
+}
+
+// As predict, for coding orders where the top-right block may not have been
+// coded yet. top_right_coded says whether it has.
+pub fn predict_with(field: &MotionField, col: usize, row: usize, top_right_coded: bool) -> MotionVector {
+    if row == 0 {
+        return if col > 0 { field.get(col - 1, row) } else { MotionVector::default() };
+    }
+    let left = if col > 0 { field.get(col - 1, row) } else { MotionVector::default() };
+    let top = field.get(col, row - 1);
+    let top_right = if top_right_coded {
+        field.get(col + 1, row - 1)
+    } else if col > 0 {
+        field.get(col - 1, row - 1)
+    } else {
+        MotionVector::default()
+    };
+    MotionVector::new(median(left.x, top.x, top_right.x), median(left.y, top.y, top_right.y))
+}
+
+fn median(a: i32, b: i32, c: i32) -> i32 {
+    a.max(b).min(a.min(b).max(c))
+}
+
+// Context from the MVD magnitudes of the left and top blocks, split as
+// H.264 does for CABAC.
+fn context(left: i32, top: i32) -> usize {
+    match left.unsigned_abs().saturating_add(top.unsigned_abs()) {
+        0..=2 => 0,
+        3..=32 => 1,
+        _ => 2,
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MvCoder {
+    // Indexed by component (x, y) then context.
+    zero: [Vec<VectorCountSymbolModel<bool>>; 2],
+    magnitude: [Vec<VectorCountSymbolModel<u8>>; 2],
+    sign: [VectorCountSymbolModel<bool>; 2],
+}
+
+impl Default for MvCoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MvCoder {
+    pub fn new() -> Self {
+        let flags = || VectorCountSymbolModel::new(vec![false, true]);
+        Self {
+            zero: [vec![flags(); MVD_CONTEXTS], vec![flags(); MVD_CONTEXTS]],
+            magnitude: [vec![bucket_model(); MVD_CONTEXTS], vec![bucket_model(); MVD_CONTEXTS]],
+            sign: [flags(), flags()],
+        }
+    }
+
+    fn encode_component<W: Write>(&mut self, c: usize, ctx: usize, mvd: i32, enc: &mut Encoder, output: &mut BitWriter<W>) {
+        let is_zero = mvd == 0;
+        enc.encode(&is_zero, &self.zero[c][ctx], output);
+        self.zero[c][ctx].incr_count(&is_zero);
+        if is_zero {
+            return;
+        }
+        encode_unsigned(mvd.unsigned_abs() - 1, &mut self.magnitude[c][ctx], enc, output);
+        let negative = mvd < 0;
+        enc.encode(&negative, &self.sign[c], output);
+        self.sign[c].incr_count(&negative);
+    }
+
+    fn decode_component<R: Read, B: Bit>(
+        &mut self,
+        c: usize,
+        ctx: usize,
+        dec: &mut Decoder,
+        input: &mut BitReader<R, B>,
+    ) -> Result<i32, DecodeError> {
+        let is_zero = *dec.decode(&self.zero[c][ctx], input)?;
+        self.zero[c][ctx].incr_count(&is_zero);
+        if is_zero {
+            return Ok(0);
+        }
+        let magnitude = decode_unsigned(&mut self.magnitude[c][ctx], dec, input)? as i64 + 1;
+        let negative = *dec.decode(&self.sign[c], input)?;
+        self.sign[c].incr_count(&negative);
+        let mvd = if negative { -magnitude } else { magnitude };
+        match i32::try_from(mvd) {
+            Ok(v) => Ok(v),
+            Err(_) => Err(DecodeError::Desync),
+        }
+    }
+
+    // Codes one MVD given the MVDs of the blocks to the left and above.
+    pub fn encode_mvd<W: Write>(&mut self, mvd: MotionVector, left: MotionVector, top: MotionVector, enc: &mut Encoder, output: &mut BitWriter<W>) {
+        self.encode_component(0, context(left.x, top.x), mvd.x, enc, output);
+        self.encode_component(1, context(left.y, top.y), mvd.y, enc, output);
+    }
+
+    pub fn decode_mvd<R: Read, B: Bit>(
+        &mut self,
+        left: MotionVector,
+        top: MotionVector,
+        dec: &mut Decoder,
+        input: &mut BitReader<R, B>,
+    ) -> Result<MotionVector, DecodeError> {
+        let x = self.decode_component(0, context(left.x, top.x), dec, input)?;
+        let y = self.decode_component(1, context(left.y, top.y), dec, input)?;
+        Ok(MotionVector::new(x, y))
+    }
+
+    // Codes every vector of field in raster order.
+    pub fn encode_field<W: Write>(&mut self, field: &MotionField, enc: &mut Encoder, output: &mut BitWriter<W>) {
+        let mut mvds = vec![MotionVector::default(); field.vectors.len()];
+        for row in 0..field.rows {
+            for col in 0..field.cols {
+                let i = row * field.cols + col;
+                let mv = field.get(col, row);
+                let pred = predict(field, col, row);
+                let mvd = MotionVector::new(mv.x - pred.x, mv.y - pred.y);
+                let (left, top) = neighbour_mvds(&mvds, field.cols, col, row);
+                self.encode_mvd(mvd, left, top, enc, output);
+                mvds[i] = mvd;
+            }
+        }
+    }
+
+    // Decodes the vectors of field, whose size gives the block layout.
+    pub fn decode_field<R: Read, B: Bit>(
+        &mut self,
+        field: &mut MotionField,
+        dec: &mut Decoder,
+        input: &mut BitReader<R, B>,
+    ) -> Result<(), DecodeError> {
+        let mut mvds = vec![MotionVector::default(); field.vectors.len()];
+        for row in 0..field.rows {
+            for col in 0..field.cols {
+                let i = row * field.cols + col;
+                let pred = predict(field, col, row);
+                let (left, top) = neighbour_mvds(&mvds, field.cols, col, row);
+                let mvd = self.decode_mvd(left, top, dec, input)?;
+                mvds[i] = mvd;
+                match (pred.x.checked_add(mvd.x), pred.y.checked_add(mvd.y)) {
+                    (Some(x), Some(y)) => field.set(col, row, MotionVector::new(x, y)),
+                    _ => return Err(DecodeError::Desync),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn neighbour_mvds(mvds: &[MotionVector], cols: usize, col: usize, row: usize) -> (MotionVector, MotionVector) {
+    let left = if col > 0 { mvds[row * cols + col - 1] } else { MotionVector::default() };
+    let top = if row > 0 { mvds[(row - 1) * cols + col] } else { MotionVector::default() };
+    (left, top)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitbit::MSB;
+
+    fn round_trip(field: &MotionField) -> u64 {
+        let mut bytes = Vec::new();
+        let mut bw = BitWriter::new(&mut bytes);
+        let mut enc = Encoder::new();
+        MvCoder::new().encode_field(field, &mut enc, &mut bw);
+        let bits = enc.bits_written();
+        enc.finish(&mut bw).unwrap();
+        bw.pad_to_byte().unwrap();
+
+        let mut br: BitReader<_, MSB> = BitReader::new(&bytes[..]);
+        let mut decoded = MotionField::new(field.cols * 16, field.rows * 16, 16);
+        MvCoder::new().decode_field(&mut decoded, &mut Decoder::new(), &mut br).unwrap();
+        assert_eq!(&decoded, field);
+        bits
+    }
+
+    #[test]
+    fn median_prediction() {
+        let mut field = MotionField::new(48, 32, 16);
+        field.set(0, 0, MotionVector::new(1, 1));
+        field.set(1, 0, MotionVector::new(5, -2));
+        field.set(2, 0, MotionVector::new(3, 7));
+        field.set(0, 1, MotionVector::new(4, 0));
+        assert_eq!(predict(&field, 1, 0), MotionVector::new(1, 1));
+        assert_eq!(predict(&field, 1, 1), MotionVector::new(4, 0));
+        // No top-right in the last column, so top-left stands in for it.
+        field.set(1, 1, MotionVector::new(-1, 9));
+        assert_eq!(predict(&field, 2, 1), MotionVector::new(3, 7));
+        assert_eq!(predict(&field, 0, 0), MotionVector::default());
+    }
+
+    #[test]
+    fn fields_round_trip() {
+        let mut field = MotionField::new(160, 96, 16);
+        for (i, mv) in field.vectors.iter_mut().enumerate() {
+            let i = i as i32;
+            *mv = MotionVector::new((i * 7919) % 41 - 20, (i * 104729) % 13 - 6);
+        }
+        field.vectors[5] = MotionVector::new(i32::MAX / 2, i32::MIN / 2);
+        round_trip(&field);
+        assert_eq!(context(i32::MIN, i32::MIN), 2);
+    }
+
+    #[test]
+    fn uniform_motion_is_cheap() {
+        let mut field = MotionField::new(320, 240, 16);
+        field.vectors.fill(MotionVector::new(5, -3));
+        let bits = round_trip(&field);
+        assert!((bits as f64) / (field.vectors.len() as f64) < 0.5, "{} bits", bits);
+    }
+}
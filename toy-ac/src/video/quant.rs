@@ -0,0 +1,269 @@
+// Scalar quantization of transform coefficients, following HEVC.
+//
+// The step size doubles every 6 QP, and QP 4 is a step of one in the units
+// of the orthonormal DCT. A quantization matrix scales the step for each
+// coefficient position by m / 16, so a flat matrix of 16s leaves it alone.
+// Dequantization is all integer and is what the decoder runs, so it is
+// bit-exact. Quantization is only run by the encoder, which is free to
+// choose its rounding: rounding down more than half a step (a deadzone)
+// sends more small coefficients to zero.
+
+use super::transform::{BLOCK_AREA, Block};
+use std::fmt;
+use std::str::FromStr;
+
+pub const MAX_QP: u8 = 51;
+
+const QUANT_SCALES: [i64; 6] = [26214, 23302, 20560, 18396, 16384, 14564];
+const DEQUANT_SCALES: [i64; 6] = [40, 45, 51, 57, 64, 72];
+
+// Dequantized coefficients are clipped to 16 bits like HEVC, which also
+// keeps the inverse transform's intermediates far from overflowing.
+const COEFF_MIN: i64 = -32768;
+const COEFF_MAX: i64 = 32767;
+
+// The step size at qp in units of the orthonormal DCT.
+pub fn qp_step(qp: u8) -> f64 {
+    2f64.powf((qp as f64 - 4.0) / 6.0)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QuantMatrix(pub [u8; BLOCK_AREA]);
+
+impl QuantMatrix {
+    pub fn flat() -> Self {
+        Self([16; BLOCK_AREA])
+    }
+
+    // HEVC's default 8x8 matrices, which quantize high frequencies more
+    // coarsely than low ones.
+    pub fn default_intra() -> Self {
+        Self([
+            16, 16, 16, 16, 17, 18, 21, 24, //
+            16, 16, 16, 16, 17, 19, 22, 25, //
+            16, 16, 17, 18, 20, 22, 25, 29, //
+            16, 16, 18, 21, 24, 27, 31, 36, //
+            17, 17, 20, 24, 30, 35, 41, 47, //
+            18, 19, 22, 27, 35, 44, 54, 65, //
+            21, 22, 25, 31, 41, 54, 70, 88, //
+            24, 25, 29, 36, 47, 65, 88, 115,
+        ])
+    }
+
+    pub fn default_inter() -> Self {
+        Self([
+            16, 16, 16, 16, 17, 18, 20, 24, //
+            16, 16, 16, 17, 18, 20, 24, 25, //
+            16, 16, 17, 18, 20, 24, 25, 28, //
+            16, 17, 18, 20, 24, 25, 28, 33, //
+            17, 18, 20, 24, 25, 28, 33, 41, //
+            18, 20, 24, 25, 28, 33, 41, 54, //
+            20, 24, 25, 28, 33, 41, 54, 71, //
+            24, 25, 28, 33, 41, 54, 71, 91,
+        ])
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseMatrixError(String);
+
+impl fmt::Display for ParseMatrixError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid quantization matrix: {}", self.0)
+    }
+}
+
+impl std::error::Error for ParseMatrixError {}
+
+// A custom matrix is 64 whitespace or comma separated entries from 1 to
+// 255 in raster order, or one of "flat", "intra" and "inter".
+impl FromStr for QuantMatrix {
+    type Err = ParseMatrixError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "flat" => return Ok(QuantMatrix::flat()),
+            "intra" => return Ok(QuantMatrix::default_intra()),
+            "inter" => return Ok(QuantMatrix::default_inter()),
+            _ => {}
+        }
+        let mut entries = [0u8; BLOCK_AREA];
+        let mut count = 0;
+        for token in s.split(|c: char| c.is_whitespace() || c == ',').filter(|t| !t.is_empty()) {
+            if count == BLOCK_AREA {
+                return Err(ParseMatrixError("more than 64 entries".to_string()));
+            }
+            entries[count] = match token.parse::<u8>() {
+                Ok(v) if v > 0 => v,
+                _ => return Err(ParseMatrixError(format!("bad entry {}", token))),
+            };
+            count += 1;
+        }
+        if count != BLOCK_AREA {
+            return Err(ParseMatrixError(format!("{} entries, expected 64", count)));
+        }
+        Ok(QuantMatrix(entries))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Quantizer {
+    qp: u8,
+    bit_depth: u8,
+    matrix: QuantMatrix,
+    // Fraction of a step added before truncating: 1/2 rounds to nearest,
+    // less than that widens the zero bin.
+    rounding: f64,
+}
+
+impl Quantizer {
+    pub fn new(qp: u8, bit_depth: u8) -> Self {
+        assert!(qp <= MAX_QP, "QP must be at most {}", MAX_QP);
+        assert!((8..=12).contains(&bit_depth), "quantizer supports bit depths 8 to 12");
+        Self {
+            qp,
+            bit_depth,
+            matrix: QuantMatrix::flat(),
+            rounding: 0.5,
+        }
+    }
+
+    pub fn with_matrix(mut self, matrix: QuantMatrix) -> Self {
+        self.matrix = matrix;
+        self
+    }
+
+    // rounding of 1/2 is plain rounding; HEVC reference encoders use 1/3
+    // for intra blocks and 1/6 for inter blocks.
+    pub fn with_deadzone(mut self, rounding: f64) -> Self {
+        self.rounding = rounding.clamp(0.0, 0.5);
+        self
+    }
+
+    pub fn qp(&self) -> u8 {
+        self.qp
+    }
+
+    pub fn matrix(&self) -> &QuantMatrix {
+        &self.matrix
+    }
+
+    // Converts from transform scale (see transform.rs) to orthonormal scale.
+    fn transform_shift(&self) -> u32 {
+        12 - self.bit_depth as u32
+    }
+
+    pub fn quantize(&self, coeffs: &Block) -> Block {
+        let shift = 14 + self.qp as u32 / 6 + self.transform_shift();
+        let offset = (self.rounding * (1i64 << shift) as f64) as i64;
+        let mut levels = [0i32; BLOCK_AREA];
+        for i in 0..BLOCK_AREA {
+            let scale = (QUANT_SCALES[self.qp as usize % 6] << 4) / self.matrix.0[i] as i64;
+            let level = ((coeffs[i] as i64).abs() * scale + offset) >> shift;
+            levels[i] = if coeffs[i] < 0 { -level as i32 } else { level as i32 };
+        }
+        levels
+    }
+
+    pub fn dequantize(&self, levels: &Block) -> Block {
+        let shift = self.bit_depth as u32 - 2;
+        let scale = DEQUANT_SCALES[self.qp as usize % 6] << (self.qp / 6);
+        let mut coeffs = [0i32; BLOCK_AREA];
+        for i in 0..BLOCK_AREA {
+            let c = (levels[i] as i64 * self.matrix.0[i] as i64 * scale + (1 << (shift - 1))) >> shift;
+            coeffs[i] = c.clamp(COEFF_MIN, COEFF_MAX) as i32;
+        }
+        coeffs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::video::transform::{forward, inverse};
+
+    #[test]
+    fn qp_4_is_unit_step() {
+        // At 8 bits the transform scale is 16, so a step of one is 16.
+        let q = Quantizer::new(4, 8);
+        let mut coeffs = [0; BLOCK_AREA];
+        coeffs[0] = 160;
+        coeffs[1] = -24;
+        coeffs[2] = 7;
+        let levels = q.quantize(&coeffs);
+        assert_eq!(&levels[..3], &[10, -2, 0]);
+        assert_eq!(&q.dequantize(&levels)[..3], &[160, -32, 0]);
+    }
+
+    #[test]
+    fn step_doubles_every_six() {
+        let mut coeffs = [0; BLOCK_AREA];
+        coeffs[0] = 16 * 1024;
+        for qp in [4, 10, 16, 22] {
+            let level = Quantizer::new(qp, 8).quantize(&coeffs)[0];
+            assert_eq!(level, 1024 >> ((qp - 4) / 6));
+        }
+        assert!((qp_step(28) - 16.0).abs() < 1e-9);
+        for qp in 0..=MAX_QP {
+            let q = Quantizer::new(qp, 8);
+            let back = q.dequantize(&q.quantize(&coeffs))[0];
+            let step = 16.0 * qp_step(qp);
+            assert!(((back - coeffs[0]) as f64).abs() <= step / 2.0 + step * 0.02 + 1.0, "qp {}", qp);
+        }
+    }
+
+    #[test]
+    fn deadzone_zeroes_small_coefficients() {
+        let mut coeffs = [0; BLOCK_AREA];
+        coeffs[5] = 10;
+        assert_eq!(Quantizer::new(4, 8).quantize(&coeffs)[5], 1);
+        assert_eq!(Quantizer::new(4, 8).with_deadzone(1.0 / 6.0).quantize(&coeffs)[5], 0);
+    }
+
+    #[test]
+    fn matrices_scale_the_step() {
+        let mut coeffs = [0; BLOCK_AREA];
+        coeffs[63] = 16 * 115;
+        coeffs[0] = 16 * 115;
+        let q = Quantizer::new(4, 8).with_matrix(QuantMatrix::default_intra());
+        let levels = q.quantize(&coeffs);
+        assert_eq!(levels[0], 115);
+        assert_eq!(levels[63], 16);
+        assert_eq!(q.dequantize(&levels)[63], 16 * 115);
+    }
+
+    #[test]
+    fn parse_matrices() {
+        assert_eq!("intra".parse::<QuantMatrix>().unwrap(), QuantMatrix::default_intra());
+        let text: Vec<String> = (1..=64).map(|i| i.to_string()).collect();
+        let m: QuantMatrix = text.join(", ").parse().unwrap();
+        assert_eq!(m.0[63], 64);
+        assert!("1 2 3".parse::<QuantMatrix>().is_err());
+        assert!(text.join(" ").replace("17", "0").parse::<QuantMatrix>().is_err());
+    }
+
+    #[test]
+    fn extreme_levels_do_not_overflow() {
+        let q = Quantizer::new(MAX_QP, 8).with_matrix(QuantMatrix([255; BLOCK_AREA]));
+        let coeffs = q.dequantize(&[i32::MAX / 2; BLOCK_AREA]);
+        assert!(coeffs.iter().all(|&c| c == 32767));
+        inverse(&coeffs, 8);
+    }
+
+    #[test]
+    fn lossy_round_trip_error_tracks_qp() {
+        let mut block = [0; BLOCK_AREA];
+        for (i, v) in block.iter_mut().enumerate() {
+            *v = ((i * 37) % 61) as i32 - 30;
+        }
+        let mut previous = 0.0;
+        for qp in [0, 12, 24, 36] {
+            let q = Quantizer::new(qp, 8);
+            let back = inverse(&q.dequantize(&q.quantize(&forward(&block, 8))), 8);
+            let mse: f64 = block.iter().zip(back.iter()).map(|(a, b)| ((a - b) as f64).powi(2)).sum::<f64>() / 64.0;
+            assert!(mse >= previous, "qp {} mse {}", qp, mse);
+            assert!(mse <= qp_step(qp).powi(2) / 6.0 + 1.0, "qp {} mse {}", qp, mse);
+            previous = mse;
+        }
+    }
+}
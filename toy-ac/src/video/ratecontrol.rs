@@ -0,0 +1,358 @@
+// Rate control: picks each frame's QP so the stream averages a target
+// bitrate, following the R-lambda scheme of the HEVC reference encoder.
+//
+// Each frame gets a bit target: the average bits per frame, scaled up for
+// I-frames by how much more the last I-frame cost than the P-frames after
+// it, and corrected by the drift of the bits spent so far from the target,
+// paid back over a few frames. B-frames get the same target as P-frames,
+// and come out smaller at the same lambda. A model lambda = alpha * bpp^beta
+// per frame type turns the target's bits per pixel into a lambda, and so a QP (see
+// codec::lambda). After each frame, alpha and beta move towards the values
+// that would have predicted the bits it actually cost.
+//
+// A virtual buffer (VBV) models a decoder that receives bits at the target
+// rate into a buffer of fixed size and takes each frame's bits out at once
+// when decoding it. A frame larger than the buffer holds at that point
+// would underflow it, so the encoder codes it again at a higher QP.
+
+use super::Frame;
+use super::codec::{CodecError, EncodedFrame, FrameType, VideoEncoder, lambda};
+use super::quant::MAX_QP;
+
+// The initial R-lambda parameters of the HEVC reference encoder.
+const INITIAL_ALPHA: f64 = 3.2003;
+const INITIAL_BETA: f64 = -1.367;
+const ALPHA_STEP: f64 = 0.1;
+const BETA_STEP: f64 = 0.05;
+
+// Frames over which a drift from the target rate is paid back.
+const SMOOTHING_WINDOW: f64 = 8.0;
+const INITIAL_INTRA_WEIGHT: f64 = 4.0;
+// Largest QP change between frames of the same type.
+const MAX_QP_CHANGE: i32 = 4;
+// Fraction of the buffer a frame's target may use.
+const VBV_MARGIN: f64 = 0.8;
+// QP increase for each attempt to fit a frame into the buffer.
+const VBV_QP_STEP: u8 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateParams {
+    // Bits per second.
+    pub bitrate: f64,
+    pub frame_rate: (u32, u32),
+    // Buffer size in bits, or None for no buffer constraint.
+    pub vbv_size: Option<f64>,
+    // Fraction of the buffer full when decoding starts.
+    pub vbv_initial: f64,
+}
+
+impl RateParams {
+    // A one second buffer, starting 90% full.
+    pub fn new(bitrate: f64, frame_rate: (u32, u32)) -> Self {
+        Self {
+            bitrate,
+            frame_rate,
+            vbv_size: Some(bitrate),
+            vbv_initial: 0.9,
+        }
+    }
+}
+
+// The QP whose lambda (see codec::lambda) is l, unrounded.
+pub fn qp_for_lambda(l: f64) -> f64 {
+    12.0 + 3.0 * (l / 0.57).log2()
+}
+
+#[derive(Clone, Copy, Debug)]
+struct RLambda {
+    alpha: f64,
+    beta: f64,
+}
+
+impl RLambda {
+    fn lambda(&self, bpp: f64) -> f64 {
+        self.alpha * bpp.powf(self.beta)
+    }
+
+    fn update(&mut self, used: f64, bpp: f64) {
+        let bpp = bpp.max(1e-4);
+        let error = used.ln() - self.lambda(bpp).ln();
+        self.alpha = (self.alpha + ALPHA_STEP * error * self.alpha).clamp(0.05, 500.0);
+        self.beta = (self.beta + BETA_STEP * error * bpp.ln()).clamp(-3.0, -0.1);
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RateController {
+    params: RateParams,
+    pixels: f64,
+    gop: usize,
+    frame_bits: f64,
+    // Indexed by frame type: intra, predicted, bidirectional.
+    models: [RLambda; 3],
+    last_qp: [Option<u8>; 3],
+    intra_weight: f64,
+    last_intra_bits: Option<f64>,
+    frames: u64,
+    total_bits: u64,
+    buffer: Option<f64>,
+    underflows: usize,
+}
+
+fn index(frame_type: FrameType) -> usize {
+    match frame_type {
+        FrameType::Intra => 0,
+        FrameType::Predicted => 1,
+        FrameType::Bidirectional => 2,
+    }
+}
+
+impl RateController {
+    // pixels is the luma size of a frame and gop the encoder's I-frame
+    // interval.
+    pub fn new(params: RateParams, pixels: usize, gop: usize) -> Self {
+        let frame_bits = params.bitrate * params.frame_rate.1 as f64 / params.frame_rate.0 as f64;
+        Self {
+            params,
+            pixels: pixels as f64,
+            gop,
+            frame_bits,
+            models: [RLambda { alpha: INITIAL_ALPHA, beta: INITIAL_BETA }; 3],
+            last_qp: [None; 3],
+            intra_weight: INITIAL_INTRA_WEIGHT,
+            last_intra_bits: None,
+            frames: 0,
+            total_bits: 0,
+            buffer: params.vbv_size.map(|size| size * params.vbv_initial),
+            underflows: 0,
+        }
+    }
+
+    pub fn params(&self) -> &RateParams {
+        &self.params
+    }
+
+    // Bits the next frame can take without underflowing the buffer.
+    pub fn max_frame_bits(&self) -> Option<f64> {
+        self.buffer
+    }
+
+    // Frames so far that underflowed the buffer even at the highest QP.
+    pub fn underflows(&self) -> usize {
+        self.underflows
+    }
+
+    pub fn target_bits(&self, frame_type: FrameType) -> f64 {
+        // Weights average to one over a GOP, so the GOP as a whole gets its
+        // share of the rate.
+        let (weight, mean_weight) = match self.gop {
+            1 => (1.0, 1.0),
+            0 => (if frame_type == FrameType::Intra { self.intra_weight } else { 1.0 }, 1.0),
+            gop => {
+                let weight = if frame_type == FrameType::Intra { self.intra_weight } else { 1.0 };
+                (weight, (self.intra_weight + (gop - 1) as f64) / gop as f64)
+            }
+        };
+        let drift = self.frames as f64 * self.frame_bits - self.total_bits as f64;
+        let mut target = (self.frame_bits * weight / mean_weight + drift / SMOOTHING_WINDOW).max(0.1 * self.frame_bits);
+        if let Some(buffer) = self.buffer {
+            target = target.min(VBV_MARGIN * buffer).max(1.0);
+        }
+        target
+    }
+
+    pub fn frame_qp(&self, frame_type: FrameType) -> u8 {
+        let t = index(frame_type);
+        let bpp = self.target_bits(frame_type) / self.pixels;
+        let qp = qp_for_lambda(self.models[t].lambda(bpp)).round().clamp(0.0, MAX_QP as f64) as i32;
+        match self.last_qp[t] {
+            Some(last) => qp.clamp(last as i32 - MAX_QP_CHANGE, last as i32 + MAX_QP_CHANGE).clamp(0, MAX_QP as i32) as u8,
+            None => qp as u8,
+        }
+    }
+
+    // Records that a frame of frame_type took bits at qp.
+    pub fn update(&mut self, frame_type: FrameType, qp: u8, bits: u64) {
+        let t = index(frame_type);
+        self.models[t].update(lambda(qp), bits as f64 / self.pixels);
+        self.last_qp[t] = Some(qp);
+        match frame_type {
+            FrameType::Intra => self.last_intra_bits = Some(bits as f64),
+            FrameType::Predicted => {
+                if let Some(intra) = self.last_intra_bits {
+                    let ratio = (intra / (bits as f64).max(1.0)).clamp(1.0, 20.0);
+                    self.intra_weight = 0.5 * self.intra_weight + 0.5 * ratio;
+                }
+            }
+            FrameType::Bidirectional => {}
+        }
+        self.frames += 1;
+        self.total_bits += bits;
+
+        if let (Some(buffer), Some(size)) = (self.buffer, self.params.vbv_size) {
+            let mut level = buffer - bits as f64;
+            if level < 0.0 {
+                self.underflows += 1;
+                level = 0.0;
+            }
+            self.buffer = Some((level + self.frame_bits).min(size));
+        }
+    }
+}
+
+// A VideoEncoder whose QPs come from a RateController.
+pub struct RateControlledEncoder {
+    encoder: VideoEncoder,
+    control: RateController,
+}
+
+impl RateControlledEncoder {
+    pub fn new(encoder: VideoEncoder, control: RateController) -> Self {
+        Self { encoder, control }
+    }
+
+    pub fn control(&self) -> &RateController {
+        &self.control
+    }
+
+    // Adds the next frame in display order and codes the frames that are
+    // ready, in coding order.
+    pub fn encode_frame(&mut self, frame: &Frame) -> Result<Vec<EncodedFrame>, CodecError> {
+        self.encoder.push(frame)?;
+        self.encode_ready()
+    }
+
+    // Codes the frames still held at the end of the input.
+    pub fn flush(&mut self) -> Result<Vec<EncodedFrame>, CodecError> {
+        self.encoder.finish();
+        self.encode_ready()
+    }
+
+    fn encode_ready(&mut self) -> Result<Vec<EncodedFrame>, CodecError> {
+        let mut encoded = Vec::new();
+        while let Some(e) = self.encode_next()? {
+            encoded.push(e);
+        }
+        Ok(encoded)
+    }
+
+    fn encode_next(&mut self) -> Result<Option<EncodedFrame>, CodecError> {
+        let Some(frame_type) = self.encoder.next_frame_type() else {
+            return Ok(None);
+        };
+        let mut qp = self.control.frame_qp(frame_type);
+        loop {
+            let limit = self.control.max_frame_bits();
+            let saved = limit.map(|_| self.encoder.clone());
+            let Some(encoded) = self.encoder.encode_next_with_qp(qp)? else {
+                return Ok(None);
+            };
+            // The length in front of each frame counts too.
+            let bits = 8 * (encoded.data.len() as u64 + 4);
+            if let (Some(limit), Some(saved)) = (limit, saved)
+                && bits as f64 > limit
+                && qp < MAX_QP
+            {
+                self.encoder = saved;
+                qp = (qp + VBV_QP_STEP).min(MAX_QP);
+                continue;
+            }
+            self.control.update(frame_type, qp, bits);
+            return Ok(Some(encoded));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::video::ChromaSampling;
+    use crate::video::codec::{EncoderConfig, StreamHeader};
+
+    // Textured content panning at a varying speed.
+    fn clip(count: usize) -> Vec<Frame> {
+        let mut offset = 0.0;
+        (0..count)
+            .map(|t| {
+                offset += 1.0 + (t % 7) as f64 * 0.5;
+                let mut frame = Frame::new(64, 48, ChromaSampling::Cs420, 8);
+                for (p, plane) in frame.planes_mut().into_iter().enumerate() {
+                    let scale = if p == 0 { 1.0 } else { 2.0 };
+                    for y in 0..plane.height {
+                        for x in 0..plane.width {
+                            let (gx, gy) = (x as f64 * scale + offset, y as f64 * scale);
+                            let v = 128.0 + 50.0 * (gx / 6.0).sin() * (gy / 4.0).cos() + 30.0 * ((gx + 2.0 * gy) / 11.0).sin();
+                            plane.set(x, y, v as u16);
+                        }
+                    }
+                }
+                frame
+            })
+            .collect()
+    }
+
+    fn encoder(frames: &[Frame], config: EncoderConfig) -> VideoEncoder {
+        VideoEncoder::new(StreamHeader::new(&frames[0], &config.search, (25, 1)), config).unwrap()
+    }
+
+    // Bits per second of the frames coded at a fixed QP.
+    fn fixed_qp_rate(frames: &[Frame], config: EncoderConfig) -> f64 {
+        let mut e = encoder(frames, config);
+        let mut encoded: Vec<EncodedFrame> = frames.iter().flat_map(|f| e.encode_frame(f).unwrap()).collect();
+        encoded.extend(e.flush().unwrap());
+        let bytes: usize = encoded.iter().map(|e| e.data.len() + 4).sum();
+        8.0 * bytes as f64 * 25.0 / frames.len() as f64
+    }
+
+    fn controlled_rate(frames: &[Frame], config: EncoderConfig, params: RateParams) -> (f64, RateControlledEncoder) {
+        let control = RateController::new(params, 64 * 48, config.gop);
+        let mut e = RateControlledEncoder::new(encoder(frames, config), control);
+        let mut encoded: Vec<EncodedFrame> = frames.iter().flat_map(|f| e.encode_frame(f).unwrap()).collect();
+        encoded.extend(e.flush().unwrap());
+        let bytes: usize = encoded.iter().map(|e| e.data.len() + 4).sum();
+        (8.0 * bytes as f64 * 25.0 / frames.len() as f64, e)
+    }
+
+    #[test]
+    fn lambda_and_qp_agree() {
+        for qp in 0..=MAX_QP {
+            assert!((qp_for_lambda(lambda(qp)) - qp as f64).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn achieved_rate_is_near_target() {
+        let frames = clip(40);
+        for (gop, qp, b_frames) in [(10, 26, 0), (0, 34, 0), (12, 22, 2)] {
+            let config = EncoderConfig { gop, qp, b_frames, ..EncoderConfig::default() };
+            let target = fixed_qp_rate(&frames, config);
+            let (rate, e) = controlled_rate(&frames, config, RateParams::new(target, (25, 1)));
+            let error = (rate - target).abs() / target;
+            assert!(error < 0.1, "gop {}: target {:.0} got {:.0} bits/s", gop, target, rate);
+            assert_eq!(e.control().underflows(), 0);
+        }
+    }
+
+    #[test]
+    fn small_buffers_are_respected() {
+        let frames = clip(20);
+        let config = EncoderConfig { gop: 5, ..EncoderConfig::default() };
+        let target = fixed_qp_rate(&frames, EncoderConfig { qp: 30, ..config });
+        // Half a second of buffer, less than an I-frame at the QP the rate
+        // alone would suggest.
+        let params = RateParams {
+            vbv_size: Some(target / 2.0),
+            vbv_initial: 0.5,
+            ..RateParams::new(target, (25, 1))
+        };
+        let control = RateController::new(params, 64 * 48, config.gop);
+        let mut e = RateControlledEncoder::new(encoder(&frames, config), control);
+        for frame in frames.iter() {
+            let limit = e.control().max_frame_bits().unwrap();
+            for encoded in e.encode_frame(frame).unwrap() {
+                assert!((8 * (encoded.data.len() + 4)) as f64 <= limit || encoded.qp == MAX_QP);
+            }
+        }
+        assert_eq!(e.control().underflows(), 0);
+    }
+}
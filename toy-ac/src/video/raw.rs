@@ -0,0 +1,322 @@
+// Headerless planar YUV files as dumped by capture tools. Nothing in the
+// file says how big a frame is, so the geometry and pixel format have to be
+// given up front and every frame must be exactly that size.
+//
+// I420 stores the Y, U and V planes one after another at 4:2:0. NV12 stores
+// the Y plane followed by a single plane of interleaved U/V pairs at 4:2:0.
+// YUV444 stores three full size planes. 10-bit samples are stored in the low
+// bits of little-endian 16-bit words.
+
+use super::{ChromaSampling, Frame, Plane, pack_plane, read_fully, unpack_plane};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PixelFormat {
+    I420,
+    Nv12,
+    Yuv444,
+}
+
+impl PixelFormat {
+    pub fn chroma(&self) -> ChromaSampling {
+        match self {
+            PixelFormat::I420 | PixelFormat::Nv12 => ChromaSampling::Cs420,
+            PixelFormat::Yuv444 => ChromaSampling::Cs444,
+        }
+    }
+}
+
+impl FromStr for PixelFormat {
+    type Err = RawError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "i420" | "yuv420p" => Ok(PixelFormat::I420),
+            "nv12" => Ok(PixelFormat::Nv12),
+            "yuv444" | "yuv444p" => Ok(PixelFormat::Yuv444),
+            _ => Err(RawError::UnsupportedFormat(s.to_string())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RawError {
+    Io(io::Error),
+    UnsupportedFormat(String),
+    InvalidGeometry,
+    TruncatedFrame { frame: usize, expected: usize, read: usize },
+    SampleOutOfRange { frame: usize },
+    FrameMismatch,
+}
+
+impl fmt::Display for RawError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RawError::Io(e) => write!(f, "{}", e),
+            RawError::UnsupportedFormat(s) => write!(f, "unsupported raw format: {}", s),
+            RawError::InvalidGeometry => write!(f, "frame size must be non-zero and bit depth 8 or 10"),
+            RawError::TruncatedFrame { frame, expected, read } => write!(
+                f,
+                "last frame ({}) is truncated: expected {} bytes, got {}",
+                frame, expected, read
+            ),
+            RawError::SampleOutOfRange { frame } => {
+                write!(f, "frame {} has samples outside the bit depth (wrong format?)", frame)
+            }
+            RawError::FrameMismatch => write!(f, "frame does not match the raw format"),
+        }
+    }
+}
+
+impl std::error::Error for RawError {}
+
+impl From<io::Error> for RawError {
+    fn from(e: io::Error) -> Self {
+        RawError::Io(e)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RawFormat {
+    pub width: usize,
+    pub height: usize,
+    pub pixel_format: PixelFormat,
+    pub bit_depth: u8,
+}
+
+impl RawFormat {
+    pub fn new(width: usize, height: usize, pixel_format: PixelFormat, bit_depth: u8) -> Result<Self, RawError> {
+        if width == 0 || height == 0 || (bit_depth != 8 && bit_depth != 10) {
+            return Err(RawError::InvalidGeometry);
+        }
+        Ok(Self {
+            width,
+            height,
+            pixel_format,
+            bit_depth,
+        })
+    }
+
+    fn bytes_per_sample(&self) -> usize {
+        if self.bit_depth > 8 { 2 } else { 1 }
+    }
+
+    pub fn frame_bytes(&self) -> usize {
+        let (cw, ch) = self.pixel_format.chroma().chroma_size(self.width, self.height);
+        (self.width * self.height + 2 * cw * ch) * self.bytes_per_sample()
+    }
+}
+
+pub struct RawReader<R: Read> {
+    input: R,
+    format: RawFormat,
+    frames_read: usize,
+}
+
+impl<R: Read> RawReader<R> {
+    pub fn new(input: R, format: RawFormat) -> Self {
+        Self {
+            input,
+            format,
+            frames_read: 0,
+        }
+    }
+
+    pub fn format(&self) -> &RawFormat {
+        &self.format
+    }
+
+    // The next frame, or None when the input ends exactly on a frame
+    // boundary. Any partial frame at the end is an error.
+    pub fn read_frame(&mut self) -> Result<Option<Frame>, RawError> {
+        let f = self.format;
+        let mut bytes = vec![0u8; f.frame_bytes()];
+        let read = read_fully(&mut self.input, &mut bytes)?;
+        if read == 0 {
+            return Ok(None);
+        }
+        if read != bytes.len() {
+            return Err(RawError::TruncatedFrame {
+                frame: self.frames_read,
+                expected: bytes.len(),
+                read,
+            });
+        }
+
+        let mut frame = Frame::new(f.width, f.height, f.pixel_format.chroma(), f.bit_depth);
+        let bps = f.bytes_per_sample();
+        let luma_bytes = frame.y.data.len() * bps;
+        unpack_plane(&bytes[..luma_bytes], &mut frame.y, bps);
+
+        let chroma_bytes = &bytes[luma_bytes..];
+        match f.pixel_format {
+            PixelFormat::I420 | PixelFormat::Yuv444 => {
+                let plane_bytes = frame.u.data.len() * bps;
+                unpack_plane(&chroma_bytes[..plane_bytes], &mut frame.u, bps);
+                unpack_plane(&chroma_bytes[plane_bytes..], &mut frame.v, bps);
+            }
+            PixelFormat::Nv12 => {
+                let mut interleaved = Plane::new(frame.u.data.len() * 2, 1, 0);
+                unpack_plane(chroma_bytes, &mut interleaved, bps);
+                for (i, pair) in interleaved.data.chunks_exact(2).enumerate() {
+                    frame.u.data[i] = pair[0];
+                    frame.v.data[i] = pair[1];
+                }
+            }
+        }
+
+        let max = frame.max_value();
+        if frame.planes().iter().any(|p| p.data.iter().any(|&s| s > max)) {
+            return Err(RawError::SampleOutOfRange {
+                frame: self.frames_read,
+            });
+        }
+
+        self.frames_read += 1;
+        Ok(Some(frame))
+    }
+}
+
+impl<R: Read> Iterator for RawReader<R> {
+    type Item = Result<Frame, RawError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_frame().transpose()
+    }
+}
+
+pub struct RawWriter<W: Write> {
+    output: W,
+    format: RawFormat,
+}
+
+impl<W: Write> RawWriter<W> {
+    pub fn new(output: W, format: RawFormat) -> Self {
+        Self { output, format }
+    }
+
+    pub fn write_frame(&mut self, frame: &Frame) -> Result<(), RawError> {
+        let f = self.format;
+        if frame.width() != f.width
+            || frame.height() != f.height
+            || frame.chroma != f.pixel_format.chroma()
+            || frame.bit_depth != f.bit_depth
+        {
+            return Err(RawError::FrameMismatch);
+        }
+
+        let bps = f.bytes_per_sample();
+        let mut bytes = Vec::with_capacity(f.frame_bytes());
+        pack_plane(&frame.y, &mut bytes, bps);
+        match f.pixel_format {
+            PixelFormat::I420 | PixelFormat::Yuv444 => {
+                pack_plane(&frame.u, &mut bytes, bps);
+                pack_plane(&frame.v, &mut bytes, bps);
+            }
+            PixelFormat::Nv12 => {
+                let mut interleaved = Plane::new(frame.u.data.len() * 2, 1, 0);
+                for i in 0..frame.u.data.len() {
+                    interleaved.data[2 * i] = frame.u.data[i];
+                    interleaved.data[2 * i + 1] = frame.v.data[i];
+                }
+                pack_plane(&interleaved, &mut bytes, bps);
+            }
+        }
+        self.output.write_all(&bytes)?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), RawError> {
+        self.output.flush()?;
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_frame(width: usize, height: usize, chroma: ChromaSampling, bit_depth: u8, seed: u32) -> Frame {
+        let mut frame = Frame::new(width, height, chroma, bit_depth);
+        let modulus = frame.max_value() as u32 + 1;
+        for (p, plane) in frame.planes_mut().into_iter().enumerate() {
+            for (i, s) in plane.data.iter_mut().enumerate() {
+                *s = ((i as u32 * 13 + p as u32 * 71 + seed * 5) % modulus) as u16;
+            }
+        }
+        frame
+    }
+
+    #[test]
+    fn round_trip_all_formats() {
+        for pixel_format in [PixelFormat::I420, PixelFormat::Nv12, PixelFormat::Yuv444] {
+            for bit_depth in [8, 10] {
+                let format = RawFormat::new(11, 7, pixel_format, bit_depth).unwrap();
+                let frames: Vec<Frame> = (0..3)
+                    .map(|i| test_frame(11, 7, pixel_format.chroma(), bit_depth, i))
+                    .collect();
+
+                let mut writer = RawWriter::new(Vec::new(), format);
+                for f in frames.iter() {
+                    writer.write_frame(f).unwrap();
+                }
+                let bytes = writer.into_inner();
+                assert_eq!(bytes.len(), 3 * format.frame_bytes());
+
+                let read: Vec<Frame> = RawReader::new(&bytes[..], format).map(|f| f.unwrap()).collect();
+                assert_eq!(read, frames);
+            }
+        }
+    }
+
+    #[test]
+    fn nv12_layout() {
+        let format = RawFormat::new(2, 2, PixelFormat::Nv12, 8).unwrap();
+        let bytes = [1u8, 2, 3, 4, 10, 20];
+        let frame = RawReader::new(&bytes[..], format).read_frame().unwrap().unwrap();
+        assert_eq!(frame.y.data, vec![1, 2, 3, 4]);
+        assert_eq!(frame.u.data, vec![10]);
+        assert_eq!(frame.v.data, vec![20]);
+    }
+
+    #[test]
+    fn truncated_last_frame() {
+        let format = RawFormat::new(4, 4, PixelFormat::I420, 10).unwrap();
+        let bytes = vec![0u8; format.frame_bytes() * 2 + 5];
+        let mut reader = RawReader::new(&bytes[..], format);
+        assert!(reader.read_frame().unwrap().is_some());
+        assert!(reader.read_frame().unwrap().is_some());
+        match reader.read_frame() {
+            Err(RawError::TruncatedFrame { frame, expected, read }) => {
+                assert_eq!(frame, 2);
+                assert_eq!(expected, 48);
+                assert_eq!(read, 5);
+            }
+            other => panic!("expected truncated frame, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn ten_bit_range_checked() {
+        let format = RawFormat::new(2, 2, PixelFormat::Yuv444, 10).unwrap();
+        let mut bytes = vec![0u8; format.frame_bytes()];
+        bytes[1] = 0x04;
+        assert!(matches!(
+            RawReader::new(&bytes[..], format).read_frame(),
+            Err(RawError::SampleOutOfRange { frame: 0 })
+        ));
+    }
+
+    #[test]
+    fn invalid_formats() {
+        assert!("yuyv".parse::<PixelFormat>().is_err());
+        assert!(RawFormat::new(0, 4, PixelFormat::I420, 8).is_err());
+        assert!(RawFormat::new(4, 4, PixelFormat::I420, 12).is_err());
+    }
+}
@@ -0,0 +1,177 @@
+// 8x8 integer DCT using the HEVC core transform matrix.
+//
+// Every row of the matrix has the same norm (64 * sqrt(8)), so the
+// coefficients are a fixed scaling of the orthonormal DCT and quantization
+// needs no per-position correction. The stages shift by the same amounts as
+// HEVC, which leaves forward coefficients at 2^(12 - bit_depth) times the
+// orthonormal DCT and brings the inverse back to the residual's scale. All
+// arithmetic is integer, so every decoder reconstructs exactly the same
+// samples. Bit depths from 8 to 12 are supported.
+
+use super::Plane;
+
+pub const BLOCK_SIZE: usize = 8;
+pub const BLOCK_AREA: usize = BLOCK_SIZE * BLOCK_SIZE;
+
+// Samples or coefficients of one block in raster order.
+pub type Block = [i32; BLOCK_AREA];
+
+const MATRIX: [[i32; 8]; 8] = [
+    [64, 64, 64, 64, 64, 64, 64, 64],
+    [89, 75, 50, 18, -18, -50, -75, -89],
+    [83, 36, -36, -83, -83, -36, 36, 83],
+    [75, -18, -89, -50, 50, 89, 18, -75],
+    [64, -64, -64, 64, 64, -64, -64, 64],
+    [50, -89, 18, 75, -75, -18, 89, -50],
+    [36, -83, 83, -36, -36, 83, -83, 36],
+    [18, -50, 75, -89, 89, -75, 50, -18],
+];
+
+fn round_shift(v: i64, shift: u32) -> i32 {
+    if shift == 0 { v as i32 } else { ((v + (1 << (shift - 1))) >> shift) as i32 }
+}
+
+fn check_bit_depth(bit_depth: u8) {
+    assert!((8..=12).contains(&bit_depth), "transform supports bit depths 8 to 12");
+}
+
+pub fn forward(residual: &Block, bit_depth: u8) -> Block {
+    check_bit_depth(bit_depth);
+    let shift1 = bit_depth as u32 - 6;
+    let shift2 = 9;
+
+    // Columns first: rows of tmp are the 1D transform of each column.
+    let mut tmp = [0i32; BLOCK_AREA];
+    for k in 0..8 {
+        for x in 0..8 {
+            let sum: i64 = (0..8).map(|y| MATRIX[k][y] as i64 * residual[y * 8 + x] as i64).sum();
+            tmp[k * 8 + x] = round_shift(sum, shift1);
+        }
+    }
+    let mut coeffs = [0i32; BLOCK_AREA];
+    for v in 0..8 {
+        for u in 0..8 {
+            let sum: i64 = (0..8).map(|x| MATRIX[u][x] as i64 * tmp[v * 8 + x] as i64).sum();
+            coeffs[v * 8 + u] = round_shift(sum, shift2);
+        }
+    }
+    coeffs
+}
+
+pub fn inverse(coeffs: &Block, bit_depth: u8) -> Block {
+    check_bit_depth(bit_depth);
+    let shift1 = 7;
+    let shift2 = 20 - bit_depth as u32;
+
+    let mut tmp = [0i32; BLOCK_AREA];
+    for y in 0..8 {
+        for u in 0..8 {
+            let sum: i64 = (0..8).map(|v| MATRIX[v][y] as i64 * coeffs[v * 8 + u] as i64).sum();
+            tmp[y * 8 + u] = round_shift(sum, shift1);
+        }
+    }
+    let mut residual = [0i32; BLOCK_AREA];
+    for y in 0..8 {
+        for x in 0..8 {
+            let sum: i64 = (0..8).map(|u| MATRIX[u][x] as i64 * tmp[y * 8 + u] as i64).sum();
+            residual[y * 8 + x] = round_shift(sum, shift2);
+        }
+    }
+    residual
+}
+
+// The 8x8 block of current - prediction with its top left corner at
+// (x0, y0). Positions outside the planes are zero.
+pub fn residual_block(current: &Plane, prediction: &Plane, x0: usize, y0: usize) -> Block {
+    let mut block = [0i32; BLOCK_AREA];
+    for y in 0..BLOCK_SIZE.min(current.height.saturating_sub(y0)) {
+        for x in 0..BLOCK_SIZE.min(current.width.saturating_sub(x0)) {
+            block[y * BLOCK_SIZE + x] = current.get(x0 + x, y0 + y) as i32 - prediction.get(x0 + x, y0 + y) as i32;
+        }
+    }
+    block
+}
+
+// Writes prediction + residual into plane for the block at (x0, y0),
+// clipped to [0, max_value] and to the plane.
+pub fn reconstruct_block(plane: &mut Plane, prediction: &Plane, x0: usize, y0: usize, residual: &Block, max_value: u16) {
+    for y in 0..BLOCK_SIZE.min(plane.height.saturating_sub(y0)) {
+        for x in 0..BLOCK_SIZE.min(plane.width.saturating_sub(x0)) {
+            let v = prediction.get(x0 + x, y0 + y) as i32 + residual[y * BLOCK_SIZE + x];
+            plane.set(x0 + x, y0 + y, v.clamp(0, max_value as i32) as u16);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pseudo_random_block(seed: u32, amplitude: i32) -> Block {
+        let mut state = seed;
+        let mut block = [0i32; BLOCK_AREA];
+        for v in block.iter_mut() {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            *v = (state >> 8) as i32 % (2 * amplitude + 1) - amplitude;
+        }
+        block
+    }
+
+    #[test]
+    fn matrix_is_nearly_orthogonal() {
+        for (i, a) in MATRIX.iter().enumerate() {
+            for (j, b) in MATRIX.iter().enumerate() {
+                let dot: i32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+                if i == j {
+                    assert!((dot - 32768).abs() <= 256, "row {} norm {}", i, dot);
+                } else {
+                    assert!(dot.abs() <= 256, "rows {} and {} dot {}", i, j, dot);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn flat_block_is_all_dc() {
+        let block = [10; BLOCK_AREA];
+        let coeffs = forward(&block, 8);
+        // 10 * 8 (orthonormal DC) * 16 (scale at 8 bits).
+        assert_eq!(coeffs[0], 1280);
+        assert!(coeffs[1..].iter().all(|&c| c == 0));
+        assert_eq!(inverse(&coeffs, 8), block);
+    }
+
+    // The matrix is not exactly orthogonal, so even without quantization
+    // full range noise comes back a little off.
+    #[test]
+    fn round_trip_is_close() {
+        for bit_depth in [8, 10, 12] {
+            let max = (1 << bit_depth) - 1;
+            for seed in 0..50 {
+                let block = pseudo_random_block(seed, max);
+                let back = inverse(&forward(&block, bit_depth), bit_depth);
+                for (a, b) in block.iter().zip(back.iter()) {
+                    assert!((a - b).abs() <= 1 + max / 64, "{} vs {} at {} bits", a, b, bit_depth);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn blocks_at_plane_edges() {
+        let mut current = Plane::new(10, 9, 100);
+        let prediction = Plane::new(10, 9, 90);
+        let block = residual_block(&current, &prediction, 8, 8);
+        assert_eq!(block[0], 10);
+        assert_eq!(block[1], 10);
+        assert_eq!(block[2], 0);
+        assert_eq!(block[8], 0);
+
+        let mut residual = [0; BLOCK_AREA];
+        residual[0] = 200;
+        residual[1] = -100;
+        reconstruct_block(&mut current, &prediction, 8, 8, &residual, 255);
+        assert_eq!(current.get(8, 8), 255);
+        assert_eq!(current.get(9, 8), 0);
+    }
+}
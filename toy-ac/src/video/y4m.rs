@@ -0,0 +1,538 @@
+// YUV4MPEG2 (.y4m) reading and writing.
+//
+// A stream is a single header line, "YUV4MPEG2" followed by space separated
+// parameters (W width, H height, F rate, I interlacing, A aspect, C colour
+// space, X extensions), then any number of frames. Each frame is a "FRAME"
+// line followed by the Y, U and V planes. Samples deeper than 8 bits are
+// stored as little-endian 16-bit words.
+
+use super::{ChromaSampling, Frame, pack_plane, read_fully, unpack_plane};
+use std::fmt;
+use std::io::{self, Read, Write};
+
+const SIGNATURE: &str = "YUV4MPEG2";
+const FRAME_TAG: &str = "FRAME";
+const MAX_LINE_LENGTH: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interlacing {
+    Progressive,
+    TopFieldFirst,
+    BottomFieldFirst,
+    Mixed,
+    Unknown,
+}
+
+// Where the chroma samples of 4:2:0 content sit relative to luma. Only
+// recorded so that it can be written back out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChromaSiting {
+    Center,
+    Left,
+    PalDv,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Y4mHeader {
+    pub width: usize,
+    pub height: usize,
+    pub frame_rate: (u32, u32),
+    pub interlacing: Interlacing,
+    pub aspect: (u32, u32),
+    pub chroma: ChromaSampling,
+    pub siting: ChromaSiting,
+    pub bit_depth: u8,
+    // X parameters, kept verbatim (without the leading X).
+    pub extensions: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum Y4mError {
+    Io(io::Error),
+    InvalidHeader(String),
+    UnsupportedColorspace(String),
+    TruncatedFrame { frame: usize, expected: usize, read: usize },
+    SampleOutOfRange { frame: usize },
+    FrameMismatch,
+}
+
+impl fmt::Display for Y4mError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Y4mError::Io(e) => write!(f, "{}", e),
+            Y4mError::InvalidHeader(msg) => write!(f, "invalid y4m header: {}", msg),
+            Y4mError::UnsupportedColorspace(c) => write!(f, "unsupported y4m colour space: C{}", c),
+            Y4mError::TruncatedFrame { frame, expected, read } => write!(
+                f,
+                "frame {} is truncated: expected {} bytes of samples, got {}",
+                frame, expected, read
+            ),
+            Y4mError::SampleOutOfRange { frame } => write!(f, "frame {} has samples outside the bit depth", frame),
+            Y4mError::FrameMismatch => write!(f, "frame does not match the stream geometry"),
+        }
+    }
+}
+
+impl std::error::Error for Y4mError {}
+
+impl From<io::Error> for Y4mError {
+    fn from(e: io::Error) -> Self {
+        Y4mError::Io(e)
+    }
+}
+
+impl Y4mHeader {
+    pub fn new(width: usize, height: usize, chroma: ChromaSampling, bit_depth: u8, frame_rate: (u32, u32)) -> Self {
+        Self {
+            width,
+            height,
+            frame_rate,
+            interlacing: Interlacing::Progressive,
+            aspect: (1, 1),
+            chroma,
+            siting: ChromaSiting::Center,
+            bit_depth,
+            extensions: Vec::new(),
+        }
+    }
+
+    // The header of a stream holding frames like this one.
+    pub fn for_frame(frame: &Frame, frame_rate: (u32, u32)) -> Self {
+        Self::new(frame.width(), frame.height(), frame.chroma, frame.bit_depth, frame_rate)
+    }
+
+    pub fn parse(line: &str) -> Result<Self, Y4mError> {
+        let mut tokens = line.split(' ').filter(|t| !t.is_empty());
+        if tokens.next() != Some(SIGNATURE) {
+            return Err(Y4mError::InvalidHeader("missing YUV4MPEG2 signature".to_string()));
+        }
+
+        let mut width = None;
+        let mut height = None;
+        let mut frame_rate = None;
+        let mut interlacing = Interlacing::Unknown;
+        let mut aspect = (0, 0);
+        let mut colorspace = "420jpeg".to_string();
+        let mut extensions = Vec::new();
+
+        for token in tokens {
+            if !token.is_char_boundary(1) {
+                return Err(Y4mError::InvalidHeader(format!("unknown parameter {}", token)));
+            }
+            let (tag, value) = token.split_at(1);
+            match tag {
+                "W" => width = Some(parse_number(token, value)?),
+                "H" => height = Some(parse_number(token, value)?),
+                "F" => frame_rate = Some(parse_ratio(token, value)?),
+                "A" => aspect = parse_ratio(token, value)?,
+                "I" => {
+                    interlacing = match value {
+                        "p" => Interlacing::Progressive,
+                        "t" => Interlacing::TopFieldFirst,
+                        "b" => Interlacing::BottomFieldFirst,
+                        "m" => Interlacing::Mixed,
+                        "?" => Interlacing::Unknown,
+                        _ => return Err(Y4mError::InvalidHeader(format!("bad interlacing {}", token))),
+                    }
+                }
+                "C" => colorspace = value.to_string(),
+                "X" => extensions.push(value.to_string()),
+                _ => return Err(Y4mError::InvalidHeader(format!("unknown parameter {}", token))),
+            }
+        }
+
+        let (chroma, siting, bit_depth) = parse_colorspace(&colorspace)?;
+        let width = width.ok_or_else(|| Y4mError::InvalidHeader("missing width".to_string()))?;
+        let height = height.ok_or_else(|| Y4mError::InvalidHeader("missing height".to_string()))?;
+        if width == 0 || height == 0 {
+            return Err(Y4mError::InvalidHeader("zero frame size".to_string()));
+        }
+
+        let header = Self {
+            width,
+            height,
+            frame_rate: frame_rate.ok_or_else(|| Y4mError::InvalidHeader("missing frame rate".to_string()))?,
+            interlacing,
+            aspect,
+            chroma,
+            siting,
+            bit_depth,
+            extensions,
+        };
+        if header.checked_frame_bytes().is_none() {
+            return Err(Y4mError::InvalidHeader("frame size too large".to_string()));
+        }
+        Ok(header)
+    }
+
+    pub fn colorspace_tag(&self) -> String {
+        let base = match self.chroma {
+            ChromaSampling::Cs420 => "420",
+            ChromaSampling::Cs422 => "422",
+            ChromaSampling::Cs444 => "444",
+            ChromaSampling::Mono => "mono",
+        };
+        if self.bit_depth > 8 {
+            return match self.chroma {
+                ChromaSampling::Mono => format!("{}{}", base, self.bit_depth),
+                _ => format!("{}p{}", base, self.bit_depth),
+            };
+        }
+        match (self.chroma, self.siting) {
+            (ChromaSampling::Cs420, ChromaSiting::Center) => "420jpeg".to_string(),
+            (ChromaSampling::Cs420, ChromaSiting::Left) => "420mpeg2".to_string(),
+            (ChromaSampling::Cs420, ChromaSiting::PalDv) => "420paldv".to_string(),
+            _ => base.to_string(),
+        }
+    }
+
+    pub fn to_line(&self) -> String {
+        let interlacing = match self.interlacing {
+            Interlacing::Progressive => "p",
+            Interlacing::TopFieldFirst => "t",
+            Interlacing::BottomFieldFirst => "b",
+            Interlacing::Mixed => "m",
+            Interlacing::Unknown => "?",
+        };
+        let mut line = format!(
+            "{} W{} H{} F{}:{} I{} A{}:{} C{}",
+            SIGNATURE,
+            self.width,
+            self.height,
+            self.frame_rate.0,
+            self.frame_rate.1,
+            interlacing,
+            self.aspect.0,
+            self.aspect.1,
+            self.colorspace_tag()
+        );
+        for x in self.extensions.iter() {
+            line.push_str(" X");
+            line.push_str(x);
+        }
+        line
+    }
+
+    pub fn frame_bytes(&self) -> usize {
+        let (cw, ch) = self.chroma.chroma_size(self.width, self.height);
+        (self.width * self.height + 2 * cw * ch) * self.bytes_per_sample()
+    }
+
+    // frame_bytes, or None if it or the plane sizes do not fit in a usize.
+    fn checked_frame_bytes(&self) -> Option<usize> {
+        if self.width > u32::MAX as usize || self.height > u32::MAX as usize {
+            return None;
+        }
+        let (cw, ch) = self.chroma.chroma_size(self.width, self.height);
+        let chroma = cw.checked_mul(ch)?.checked_mul(2)?;
+        self.width.checked_mul(self.height)?.checked_add(chroma)?.checked_mul(self.bytes_per_sample())
+    }
+
+    fn bytes_per_sample(&self) -> usize {
+        if self.bit_depth > 8 { 2 } else { 1 }
+    }
+}
+
+fn parse_number(token: &str, value: &str) -> Result<usize, Y4mError> {
+    value
+        .parse::<usize>()
+        .map_err(|_| Y4mError::InvalidHeader(format!("bad value in {}", token)))
+}
+
+fn parse_ratio(token: &str, value: &str) -> Result<(u32, u32), Y4mError> {
+    let bad = || Y4mError::InvalidHeader(format!("bad ratio in {}", token));
+    let (n, d) = value.split_once(':').ok_or_else(bad)?;
+    Ok((n.parse().map_err(|_| bad())?, d.parse().map_err(|_| bad())?))
+}
+
+fn parse_colorspace(tag: &str) -> Result<(ChromaSampling, ChromaSiting, u8), Y4mError> {
+    let unsupported = || Y4mError::UnsupportedColorspace(tag.to_string());
+    let (chroma, rest) = if let Some(rest) = tag.strip_prefix("420") {
+        (ChromaSampling::Cs420, rest)
+    } else if let Some(rest) = tag.strip_prefix("422") {
+        (ChromaSampling::Cs422, rest)
+    } else if let Some(rest) = tag.strip_prefix("444") {
+        (ChromaSampling::Cs444, rest)
+    } else if let Some(rest) = tag.strip_prefix("mono") {
+        (ChromaSampling::Mono, rest)
+    } else {
+        return Err(unsupported());
+    };
+
+    let (siting, depth) = match rest {
+        "" | "jpeg" => (ChromaSiting::Center, "8"),
+        "mpeg2" => (ChromaSiting::Left, "8"),
+        "paldv" => (ChromaSiting::PalDv, "8"),
+        _ => (ChromaSiting::Center, rest.strip_prefix('p').unwrap_or(rest)),
+    };
+    match depth.parse::<u8>() {
+        Ok(d) if (8..=16).contains(&d) => Ok((chroma, siting, d)),
+        _ => Err(unsupported()),
+    }
+}
+
+// Reads one '\n' terminated line (without the newline). Returns None at a
+// clean end of input.
+fn read_line<R: Read>(input: &mut R) -> Result<Option<String>, Y4mError> {
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    loop {
+        if read_fully(input, &mut byte)? == 0 {
+            if line.is_empty() {
+                return Ok(None);
+            }
+            return Err(Y4mError::InvalidHeader("unterminated line".to_string()));
+        }
+        if byte[0] == b'\n' {
+            break;
+        }
+        if line.len() == MAX_LINE_LENGTH {
+            return Err(Y4mError::InvalidHeader("line too long".to_string()));
+        }
+        line.push(byte[0]);
+    }
+    match String::from_utf8(line) {
+        Ok(s) => Ok(Some(s)),
+        Err(_) => Err(Y4mError::InvalidHeader("line is not text".to_string())),
+    }
+}
+
+// Reads frames from a y4m stream. Wrap the input in a BufReader; headers
+// are read a byte at a time.
+pub struct Y4mReader<R: Read> {
+    input: R,
+    header: Y4mHeader,
+    frames_read: usize,
+}
+
+impl<R: Read> Y4mReader<R> {
+    pub fn new(mut input: R) -> Result<Self, Y4mError> {
+        let line = match read_line(&mut input)? {
+            Some(l) => l,
+            None => return Err(Y4mError::InvalidHeader("empty input".to_string())),
+        };
+        let header = Y4mHeader::parse(&line)?;
+        Ok(Self {
+            input,
+            header,
+            frames_read: 0,
+        })
+    }
+
+    pub fn header(&self) -> &Y4mHeader {
+        &self.header
+    }
+
+    // The next frame, or None at the end of the stream.
+    pub fn read_frame(&mut self) -> Result<Option<Frame>, Y4mError> {
+        let line = match read_line(&mut self.input)? {
+            Some(l) => l,
+            None => return Ok(None),
+        };
+        if line.split(' ').next() != Some(FRAME_TAG) {
+            return Err(Y4mError::InvalidHeader(format!("expected FRAME, got {:?}", line)));
+        }
+
+        let h = &self.header;
+        let mut bytes = vec![0u8; h.frame_bytes()];
+        let read = read_fully(&mut self.input, &mut bytes)?;
+        if read != bytes.len() {
+            return Err(Y4mError::TruncatedFrame {
+                frame: self.frames_read,
+                expected: bytes.len(),
+                read,
+            });
+        }
+
+        let mut frame = Frame::new(h.width, h.height, h.chroma, h.bit_depth);
+        let bps = h.bytes_per_sample();
+        let mut offset = 0;
+        for plane in frame.planes_mut() {
+            let length = plane.data.len() * bps;
+            unpack_plane(&bytes[offset..offset + length], plane, bps);
+            offset += length;
+        }
+        let max = frame.max_value();
+        if frame.planes().iter().any(|p| p.data.iter().any(|&s| s > max)) {
+            return Err(Y4mError::SampleOutOfRange { frame: self.frames_read });
+        }
+        self.frames_read += 1;
+        Ok(Some(frame))
+    }
+}
+
+impl<R: Read> Iterator for Y4mReader<R> {
+    type Item = Result<Frame, Y4mError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_frame().transpose()
+    }
+}
+
+pub struct Y4mWriter<W: Write> {
+    output: W,
+    header: Y4mHeader,
+}
+
+impl<W: Write> Y4mWriter<W> {
+    pub fn new(mut output: W, header: Y4mHeader) -> Result<Self, Y4mError> {
+        writeln!(output, "{}", header.to_line())?;
+        Ok(Self { output, header })
+    }
+
+    pub fn header(&self) -> &Y4mHeader {
+        &self.header
+    }
+
+    pub fn write_frame(&mut self, frame: &Frame) -> Result<(), Y4mError> {
+        let h = &self.header;
+        if frame.width() != h.width
+            || frame.height() != h.height
+            || frame.chroma != h.chroma
+            || frame.bit_depth != h.bit_depth
+        {
+            return Err(Y4mError::FrameMismatch);
+        }
+
+        let bps = h.bytes_per_sample();
+        let mut bytes = Vec::with_capacity(h.frame_bytes() + FRAME_TAG.len() + 1);
+        bytes.extend_from_slice(FRAME_TAG.as_bytes());
+        bytes.push(b'\n');
+        for plane in frame.planes() {
+            pack_plane(plane, &mut bytes, bps);
+        }
+        self.output.write_all(&bytes)?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), Y4mError> {
+        self.output.flush()?;
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_frame(width: usize, height: usize, chroma: ChromaSampling, bit_depth: u8, seed: u16) -> Frame {
+        let mut frame = Frame::new(width, height, chroma, bit_depth);
+        let max = frame.max_value();
+        for (p, plane) in frame.planes_mut().into_iter().enumerate() {
+            for (i, s) in plane.data.iter_mut().enumerate() {
+                *s = ((i as u32 * 37 + p as u32 * 101 + seed as u32 * 7) % (max as u32 + 1)) as u16;
+            }
+        }
+        frame
+    }
+
+    #[test]
+    fn parse_header() {
+        let h = Y4mHeader::parse("YUV4MPEG2 W352 H288 F30000:1001 It A128:117 C420mpeg2 XYSCSS=420MPEG2").unwrap();
+        assert_eq!(h.width, 352);
+        assert_eq!(h.height, 288);
+        assert_eq!(h.frame_rate, (30000, 1001));
+        assert_eq!(h.interlacing, Interlacing::TopFieldFirst);
+        assert_eq!(h.aspect, (128, 117));
+        assert_eq!(h.chroma, ChromaSampling::Cs420);
+        assert_eq!(h.siting, ChromaSiting::Left);
+        assert_eq!(h.bit_depth, 8);
+        assert_eq!(h.extensions, vec!["YSCSS=420MPEG2".to_string()]);
+        assert_eq!(h.to_line(), "YUV4MPEG2 W352 H288 F30000:1001 It A128:117 C420mpeg2 XYSCSS=420MPEG2");
+    }
+
+    #[test]
+    fn parse_colorspaces() {
+        let cases = [
+            ("C420jpeg", ChromaSampling::Cs420, 8),
+            ("C422", ChromaSampling::Cs422, 8),
+            ("C444", ChromaSampling::Cs444, 8),
+            ("Cmono", ChromaSampling::Mono, 8),
+            ("C420p10", ChromaSampling::Cs420, 10),
+            ("C444p16", ChromaSampling::Cs444, 16),
+            ("Cmono12", ChromaSampling::Mono, 12),
+        ];
+        for (tag, chroma, depth) in cases {
+            let h = Y4mHeader::parse(&format!("YUV4MPEG2 W16 H16 F25:1 {}", tag)).unwrap();
+            assert_eq!(h.chroma, chroma);
+            assert_eq!(h.bit_depth, depth);
+        }
+        // No C parameter means 4:2:0.
+        let h = Y4mHeader::parse("YUV4MPEG2 W16 H16 F25:1").unwrap();
+        assert_eq!(h.chroma, ChromaSampling::Cs420);
+
+        assert!(matches!(
+            Y4mHeader::parse("YUV4MPEG2 W16 H16 F25:1 C444alpha"),
+            Err(Y4mError::UnsupportedColorspace(_))
+        ));
+        assert!(matches!(Y4mHeader::parse("YUV4MPEG2 W16 F25:1"), Err(Y4mError::InvalidHeader(_))));
+        assert!(matches!(Y4mHeader::parse("YUV4MPEG W16 H16 F25:1"), Err(Y4mError::InvalidHeader(_))));
+        assert!(matches!(
+            Y4mHeader::parse("YUV4MPEG2 W99999999999 H99999999999 F25:1"),
+            Err(Y4mError::InvalidHeader(_))
+        ));
+    }
+
+    #[test]
+    fn round_trip() {
+        for (chroma, depth) in [
+            (ChromaSampling::Cs420, 8),
+            (ChromaSampling::Cs422, 8),
+            (ChromaSampling::Cs444, 10),
+            (ChromaSampling::Mono, 8),
+        ] {
+            let frames: Vec<Frame> = (0..3).map(|i| test_frame(17, 9, chroma, depth, i)).collect();
+            let mut writer = Y4mWriter::new(Vec::new(), Y4mHeader::for_frame(&frames[0], (25, 1))).unwrap();
+            for f in frames.iter() {
+                writer.write_frame(f).unwrap();
+            }
+            let bytes = writer.into_inner();
+
+            let reader = Y4mReader::new(&bytes[..]).unwrap();
+            assert_eq!(reader.header().chroma, chroma);
+            let read: Vec<Frame> = reader.map(|f| f.unwrap()).collect();
+            assert_eq!(read, frames);
+        }
+    }
+
+    #[test]
+    fn truncated_frame() {
+        let frame = test_frame(8, 8, ChromaSampling::Cs420, 8, 0);
+        let mut writer = Y4mWriter::new(Vec::new(), Y4mHeader::for_frame(&frame, (25, 1))).unwrap();
+        writer.write_frame(&frame).unwrap();
+        writer.write_frame(&frame).unwrap();
+        let mut bytes = writer.into_inner();
+        bytes.truncate(bytes.len() - 10);
+
+        let mut reader = Y4mReader::new(&bytes[..]).unwrap();
+        assert!(reader.read_frame().unwrap().is_some());
+        match reader.read_frame() {
+            Err(Y4mError::TruncatedFrame { frame, expected, read }) => {
+                assert_eq!(frame, 1);
+                assert_eq!(expected, 96);
+                assert_eq!(read, 86);
+            }
+            other => panic!("expected truncated frame, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn samples_above_the_bit_depth() {
+        let mut bytes = b"YUV4MPEG2 W2 H2 F25:1 Cmono10\nFRAME\n".to_vec();
+        bytes.extend_from_slice(&[0, 0, 0xff, 0x03, 0x00, 0x04, 0, 0]);
+        let mut reader = Y4mReader::new(&bytes[..]).unwrap();
+        assert!(matches!(reader.read_frame(), Err(Y4mError::SampleOutOfRange { frame: 0 })));
+    }
+
+    #[test]
+    fn writer_rejects_mismatched_frames() {
+        let frame = test_frame(8, 8, ChromaSampling::Cs420, 8, 0);
+        let mut writer = Y4mWriter::new(Vec::new(), Y4mHeader::for_frame(&frame, (25, 1))).unwrap();
+        let other = test_frame(8, 8, ChromaSampling::Cs444, 8, 0);
+        assert!(matches!(writer.write_frame(&other), Err(Y4mError::FrameMismatch)));
+    }
+}
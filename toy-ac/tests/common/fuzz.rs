@@ -0,0 +1,46 @@
+// Fuzz entry points, shared by the cargo-fuzz targets in fuzz/ and by
+// tests/fuzz_regressions.rs, which replays the saved corpus on every test
+// run. Each function must return (not panic, not hang) for any input.
+
+// Each fuzz target only calls one of these.
+#![allow(dead_code)]
+
+use bitbit::{BitReader, MSB};
+use toy_ac::container::decode_bytes;
+use toy_ac::decoder::Decoder;
+use toy_ac::symbol_model::VectorCountSymbolModel;
+
+// Upper bound on symbols decoded from one input. An adaptive model that has
+// grown very skewed can decode many symbols per input bit, so this is what
+// keeps every run short.
+pub const MAX_SYMBOLS: u64 = 1 << 14;
+
+pub fn container(data: &[u8]) {
+    let _ = decode_bytes(data, MAX_SYMBOLS);
+}
+
+// The first byte chooses the decoder precision (22 to 63 bits, the range
+// where a byte model can adapt freely) and whether the model adapts. The
+// rest of the input is the coded stream.
+pub fn decoder(data: &[u8]) {
+    let (config, stream) = match data.split_first() {
+        Some((c, s)) => (*c, s),
+        None => return,
+    };
+    let precision = 22 + (config & 0x7f) as u32 % 42;
+    let adaptive = config & 0x80 != 0;
+
+    let mut sm = VectorCountSymbolModel::new((0..=255).collect::<Vec<u8>>());
+    let mut dec = Decoder::with_precision(precision);
+    let mut br: BitReader<_, MSB> = BitReader::new(stream);
+    for _ in 0..MAX_SYMBOLS {
+        match dec.decode(&sm, &mut br) {
+            Ok(&b) => {
+                if adaptive {
+                    sm.incr_count(&b);
+                }
+            }
+            Err(_) => return,
+        }
+    }
+}
@@ -0,0 +1,37 @@
+// Replays every saved fuzz input through the same entry points the fuzz
+// targets use. Inputs that once crashed or hung the decoder are added to
+// tests/fuzz_corpus/<target>/ so they stay fixed.
+
+use std::fs;
+use std::path::PathBuf;
+
+#[path = "common/fuzz.rs"]
+mod fuzz;
+
+fn corpus(target: &str) -> Vec<(String, Vec<u8>)> {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("fuzz_corpus").join(target);
+    let mut inputs: Vec<(String, Vec<u8>)> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| {
+            let path = entry.unwrap().path();
+            (path.display().to_string(), fs::read(&path).unwrap())
+        })
+        .collect();
+    inputs.sort();
+    assert!(!inputs.is_empty(), "empty corpus in {}", dir.display());
+    inputs
+}
+
+#[test]
+fn container_corpus() {
+    for (_name, data) in corpus("container") {
+        fuzz::container(&data);
+    }
+}
+
+#[test]
+fn decoder_corpus() {
+    for (_name, data) in corpus("decoder") {
+        fuzz::decoder(&data);
+    }
+}
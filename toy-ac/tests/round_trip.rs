@@ -0,0 +1,233 @@
+// Encodes random symbol sequences, decodes them again and checks that the
+// decoder reproduces the encoder's input exactly. Failing proptest cases are
+// shrunk to a minimal reproducer (shortest sequence, smallest alphabet).
+
+use std::fmt::Debug;
+
+use bitbit::{BitReader, BitWriter, MSB};
+use proptest::prelude::*;
+use proptest::sample::Index;
+use toy_ac::decoder::Decoder;
+use toy_ac::encoder::Encoder;
+use toy_ac::symbol_model::{VectorCountSymbolModel, ascii_english_letter_weights_1000};
+
+// VectorCountSymbolModel normalizes as soon as its total reaches 1000000.
+const NORMALIZE_THRESHOLD: u64 = 1000000;
+
+// Largest model total every precision can code, capped at the most a
+// model can ever hold.
+fn total_budget(precision: u32) -> u64 {
+    (1u64 << (precision - 2)).min(NORMALIZE_THRESHOLD - 1)
+}
+
+fn encode<T: Eq + Clone>(precision: u32, mut sm: VectorCountSymbolModel<T>, input: &[T], adaptive: bool) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut bw = BitWriter::new(&mut bytes);
+    let mut enc = Encoder::with_precision(precision);
+    for s in input {
+        enc.encode(s, &sm, &mut bw);
+        if adaptive {
+            sm.incr_count(s);
+        }
+    }
+    enc.finish(&mut bw).unwrap();
+    bw.pad_to_byte().unwrap();
+    bytes
+}
+
+fn decode<T: Eq + Clone>(precision: u32, mut sm: VectorCountSymbolModel<T>, bytes: &[u8], length: usize, adaptive: bool) -> Vec<T> {
+    let mut br: BitReader<_, MSB> = BitReader::new(bytes);
+    let mut dec = Decoder::with_precision(precision);
+    let mut output = Vec::with_capacity(length);
+    for _ in 0..length {
+        let s = dec.decode(&sm, &mut br).unwrap().clone();
+        if adaptive {
+            sm.incr_count(&s);
+        }
+        output.push(s);
+    }
+    output
+}
+
+fn assert_round_trip<T: Eq + Clone + Debug>(
+    precision: u32,
+    make_model: impl Fn() -> VectorCountSymbolModel<T>,
+    input: &[T],
+    adaptive: bool,
+) {
+    let bytes = encode(precision, make_model(), input, adaptive);
+    let output = decode(precision, make_model(), &bytes, input.len(), adaptive);
+    assert!(output == input, "round trip failed at precision {}", precision);
+}
+
+fn model_with_counts(alphabet: &[u16], counts: &[u32]) -> VectorCountSymbolModel<u16> {
+    let mut sm = VectorCountSymbolModel::new(alphabet.to_vec());
+    for (s, &c) in alphabet.iter().zip(counts.iter()) {
+        sm.set_count(s, c);
+    }
+    sm
+}
+
+#[derive(Debug, Clone)]
+struct Case {
+    precision: u32,
+    alphabet: Vec<u16>,
+    counts: Vec<u32>,
+    input: Vec<u16>,
+    adaptive: bool,
+}
+
+// Random alphabets, initial counts and sequences at any precision Range
+// accepts. The alphabet and counts are cut down to what the precision can
+// code, and models that would grow past that budget are kept static.
+fn case() -> impl Strategy<Value = Case> {
+    (
+        2u32..=63,
+        prop::collection::btree_set(any::<u16>(), 1..=64),
+        prop::collection::vec(1u32..=2000, 64),
+        prop::collection::vec(any::<Index>(), 0..400),
+        any::<bool>(),
+    )
+        .prop_map(|(precision, alphabet, counts, picks, adaptive)| {
+            let budget = total_budget(precision);
+            let alphabet: Vec<u16> = alphabet.into_iter().take(budget as usize).collect();
+            let mut counts = counts[..alphabet.len()].to_vec();
+            if counts.iter().map(|&c| c as u64).sum::<u64>() > budget {
+                counts = vec![1; alphabet.len()];
+            }
+            let input: Vec<u16> = picks.iter().map(|i| alphabet[i.index(alphabet.len())]).collect();
+
+            let grown_total = counts.iter().map(|&c| c as u64).sum::<u64>() + input.len() as u64;
+            let adaptive = adaptive && (budget == NORMALIZE_THRESHOLD - 1 || grown_total <= budget);
+
+            Case { precision, alphabet, counts, input, adaptive }
+        })
+}
+
+proptest! {
+    #[test]
+    fn random_alphabets_round_trip(case in case()) {
+        let bytes = encode(case.precision, model_with_counts(&case.alphabet, &case.counts), &case.input, case.adaptive);
+        let output = decode(case.precision, model_with_counts(&case.alphabet, &case.counts), &bytes, case.input.len(), case.adaptive);
+        prop_assert_eq!(output, case.input);
+    }
+
+    #[test]
+    fn english_prior_round_trip(precision in 22u32..=63, input in prop::collection::vec(any::<u8>(), 0..2000)) {
+        let make_model = || {
+            let mut sm = VectorCountSymbolModel::new((0..=255).collect());
+            for (b, &w) in ascii_english_letter_weights_1000().iter().enumerate() {
+                sm.set_count(&(b as u8), w);
+            }
+            sm
+        };
+        let bytes = encode(precision, make_model(), &input, true);
+        let output = decode(precision, make_model(), &bytes, input.len(), true);
+        prop_assert_eq!(output, input);
+    }
+
+    #[test]
+    fn batch_api_matches_per_symbol_api(precision in 22u32..=63, input in prop::collection::vec(any::<u8>(), 0..2000)) {
+        let make_model = || VectorCountSymbolModel::new((0..=255).collect::<Vec<u8>>());
+        let bytes = encode(precision, make_model(), &input, true);
+
+        let mut batch_bytes = Vec::new();
+        let mut bw = BitWriter::new(&mut batch_bytes);
+        let mut enc = Encoder::with_precision(precision);
+        enc.encode_all(&input, &mut make_model(), &mut bw);
+        enc.finish(&mut bw).unwrap();
+        bw.pad_to_byte().unwrap();
+        prop_assert_eq!(&batch_bytes, &bytes);
+
+        let mut output = vec![0u8; input.len()];
+        let mut br: BitReader<_, MSB> = BitReader::new(&bytes[..]);
+        Decoder::with_precision(precision).decode_into(&mut output, &mut make_model(), &mut br).unwrap();
+        prop_assert_eq!(output, input);
+    }
+}
+
+#[test]
+fn single_symbol_alphabet_at_every_precision() {
+    for precision in 2..=63 {
+        assert_round_trip(precision, || VectorCountSymbolModel::new(vec!['x']), &['x'; 100], false);
+    }
+}
+
+#[test]
+fn two_symbols_at_every_precision() {
+    let input: Vec<bool> = (0..1000).map(|i| i % 3 == 0 || i % 7 == 0).collect();
+    for precision in 3..=63 {
+        assert_round_trip(precision, || VectorCountSymbolModel::new(vec![false, true]), &input, false);
+    }
+}
+
+#[test]
+fn single_symbol_repeated_millions_of_times() {
+    let input = vec![b'e'; 2000000];
+    for precision in [22, 32, 63] {
+        assert_round_trip(precision, || VectorCountSymbolModel::new(vec![b'a', b'e', b'z']), &input, true);
+    }
+}
+
+#[test]
+fn maximal_skew_at_normalize_threshold() {
+    // One symbol holds all but 15 of the 999999 counts, so the very first
+    // update pushes the model over the threshold and it normalizes.
+    let alphabet: Vec<u16> = (0..16).collect();
+    let mut counts = vec![1u32; 16];
+    counts[0] = (NORMALIZE_THRESHOLD - 1) as u32 - 15;
+
+    let mut input = vec![0u16; 5000];
+    for i in 0..16 {
+        input[i * 311] = i as u16;
+    }
+    input.extend(1..16u16);
+
+    for precision in 22..=63 {
+        assert_round_trip(precision, || model_with_counts(&alphabet, &counts), &input, true);
+        assert_round_trip(precision, || model_with_counts(&alphabet, &counts), &input, false);
+    }
+}
+
+#[test]
+fn bypass_bits_interleaved_with_symbols() {
+    let values: Vec<(u8, u64, u32)> = (0..500u64).map(|i| ((i * 7 % 26) as u8 + b'a', i * 2654435761 % 65536, (i % 17) as u32)).collect();
+    let make_model = || VectorCountSymbolModel::new((b'a'..=b'z').collect::<Vec<u8>>());
+
+    let mut bytes = Vec::new();
+    let mut bw = BitWriter::new(&mut bytes);
+    let mut enc = Encoder::new();
+    let mut sm = make_model();
+    for &(s, bits, count) in values.iter() {
+        enc.encode(&s, &sm, &mut bw);
+        sm.incr_count(&s);
+        enc.encode_bits(bits, count, &mut bw);
+    }
+    enc.finish(&mut bw).unwrap();
+    bw.pad_to_byte().unwrap();
+
+    let mut br: BitReader<_, MSB> = BitReader::new(&bytes[..]);
+    let mut dec = Decoder::new();
+    let mut sm = make_model();
+    for &(s, bits, count) in values.iter() {
+        let decoded = *dec.decode(&sm, &mut br).unwrap();
+        assert_eq!(decoded, s);
+        sm.incr_count(&s);
+        assert_eq!(dec.decode_bits(count, &mut br).unwrap(), bits & ((1 << count) - 1));
+    }
+}
+
+#[test]
+fn bits_used_counts_fractions_of_a_bit() {
+    let sm = model_with_counts(&[0, 1], &[3, 1]);
+    let mut enc = Encoder::new();
+    let mut bw = BitWriter::new(std::io::sink());
+    enc.encode(&0u16, &sm, &mut bw);
+    let one = enc.bits_used();
+    assert!((one - 0.75f64.log2().abs()).abs() < 1e-6, "{}", one);
+    for _ in 1..1000 {
+        enc.encode(&0u16, &sm, &mut bw);
+    }
+    let ideal = 1000.0 * 0.75f64.log2().abs();
+    assert!((enc.bits_used() - ideal).abs() < 1.0, "{} bits, ideal {}", enc.bits_used(), ideal);
+}